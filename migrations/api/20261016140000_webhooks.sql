@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS webhooks (id INTEGER PRIMARY KEY AUTOINCREMENT, owner TEXT NOT NULL, url TEXT NOT NULL, player TEXT, asset TEXT);
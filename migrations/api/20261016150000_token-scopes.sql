@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE tokens ADD COLUMN scopes TEXT;
@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE tokens ADD COLUMN expires INTEGER;
+ALTER TABLE tokens ADD COLUMN ttl_secs INTEGER;
+ALTER TABLE tokens ADD COLUMN revoked INTEGER;
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE tokens ADD COLUMN last_used INTEGER;
+ALTER TABLE tokens ADD COLUMN last_ip TEXT;
+ALTER TABLE tokens ADD COLUMN uses INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tokens ADD COLUMN actions INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS token_actions (action_id INTEGER PRIMARY KEY NOT NULL, token_id INTEGER NOT NULL, user TEXT NOT NULL);
+CREATE INDEX IF NOT EXISTS token_actions_token_idx ON token_actions(token_id);
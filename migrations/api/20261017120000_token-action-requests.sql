@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE token_actions ADD COLUMN request_id TEXT;
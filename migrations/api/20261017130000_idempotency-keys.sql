@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS idempotency_keys (token_id INTEGER NOT NULL, key TEXT NOT NULL, outcome TEXT NOT NULL, PRIMARY KEY(token_id, key));
@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS trade_log (seq INTEGER PRIMARY KEY AUTOINCREMENT, data BLOB NOT NULL);
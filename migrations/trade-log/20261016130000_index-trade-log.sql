@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE trade_log ADD COLUMN action_id INTEGER;
+CREATE TABLE IF NOT EXISTS trade_log_players (player TEXT NOT NULL, action_id INTEGER NOT NULL, PRIMARY KEY (player, action_id));
+CREATE INDEX trade_log_action_idx ON trade_log(action_id);
@@ -0,0 +1,4 @@
+-- Alerts are one-shot, so are removed once they go off
+CREATE TABLE IF NOT EXISTS price_alerts (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id TEXT NOT NULL, asset TEXT NOT NULL, direction TEXT NOT NULL, millicoins INTEGER NOT NULL);
+CREATE INDEX IF NOT EXISTS price_alerts_asset_idx ON price_alerts(asset);
+CREATE INDEX IF NOT EXISTS price_alerts_user_idx ON price_alerts(user_id);
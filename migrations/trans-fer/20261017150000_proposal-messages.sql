@@ -0,0 +1,2 @@
+-- Where each open proposal was posted, so its message can be updated once it is settled
+CREATE TABLE IF NOT EXISTS proposal_messages (proposal_id INTEGER PRIMARY KEY NOT NULL, channel_id TEXT NOT NULL, message_id TEXT NOT NULL);
@@ -0,0 +1,11 @@
+fn main() {
+    // The gRPC service is generated from its protobuf definitions, so only needs building when it is served
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("No bundled protoc for this platform"));
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/tpex.proto"], &["proto"])
+            .expect("Unable to compile protobuf definitions");
+    }
+}
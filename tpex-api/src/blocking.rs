@@ -0,0 +1,74 @@
+//! A client that doesn't need an async runtime, for scripts and plugins
+//!
+//! Each call runs the async client on a runtime of its own, so these can't be called from inside another tokio runtime
+
+use crate::*;
+
+/// Makes a blocking method for each async method of the same name on [`crate::Remote`]
+macro_rules! blocking {
+    ($($(#[$meta:meta])* fn $name:ident(&self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            $(#[$meta])*
+            // The errors are the same as the async client's, which clippy only checks on sync functions
+            #[allow(clippy::result_large_err)]
+            pub fn $name(&self $(, $arg: $ty)*) -> $ret {
+                self.runtime.block_on(self.inner.$name($($arg),*))
+            }
+        )*
+    };
+}
+
+pub struct Remote {
+    inner: crate::Remote,
+    runtime: tokio::runtime::Runtime
+}
+impl Remote {
+    pub fn new(endpoint: reqwest::Url, token: Token) -> Remote {
+        Remote::from_async(crate::Remote::new(endpoint, token))
+    }
+    /// Wrap an async client, keeping its settings such as its retry policy
+    pub fn from_async(inner: crate::Remote) -> Remote {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Unable to start runtime");
+        Remote { inner, runtime }
+    }
+    pub fn with_retries(self, policy: RetryPolicy) -> Remote {
+        Remote { inner: self.inner.with_retries(policy), ..self }
+    }
+
+    blocking! {
+        fn get_state(&self, from: u64) -> Result<Vec<u8>>;
+        fn get_state_page(&self, args: &StateGetArgs) -> Result<(Vec<u8>, Option<u64>)>;
+        fn get_webhooks(&self) -> Result<Vec<Webhook>>;
+        fn create_webhook(&self, args: &WebhookPostArgs) -> Result<Webhook>;
+        fn delete_webhook(&self, id: i64) -> Result<()>;
+        fn get_events(&self, from: u64) -> Result<Vec<tpex::analytics::Event>>;
+        fn apply(&self, action: &tpex::Action) -> Result<ApplyOutcome>;
+        /// Apply several actions in order, with nothing else applied in between, or none of them if any fail
+        fn apply_batch(&self, actions: &[tpex::Action]) -> Result<Vec<ApplyOutcome>>;
+        /// Find out what an action would do if it were applied now, without applying it
+        fn simulate(&self, action: &tpex::Action) -> Result<ApplyOutcome>;
+        /// Apply an action that has already been signed, sending the exact text that was signed
+        fn apply_signed(&self, signature: &tpex::ActionSignature) -> Result<ApplyOutcome>;
+        fn get_candles(&self, args: &CandlesGetArgs) -> Result<Vec<tpex::analytics::Candle>>;
+        fn get_statement(&self, args: &StatementGetArgs) -> Result<Vec<tpex::analytics::StatementEntry>>;
+        fn get_actions_involving(&self, args: &ActionsGetArgs) -> Result<Vec<u8>>;
+        fn get_orders(&self, args: &OrdersGetArgs) -> Result<std::collections::BTreeMap<u64, tpex::PendingOrder>>;
+        fn get_withdrawals(&self) -> Result<std::collections::BTreeMap<u64, tpex::PendingWithdrawal>>;
+        fn get_prices(&self, args: &PricesGetArgs) -> Result<Prices>;
+        fn get_restricted(&self) -> Result<std::collections::BTreeSet<AssetId>>;
+        /// Find every action matching the given filters, as trade log lines
+        fn search_actions(&self, args: &ActionsSearchArgs) -> Result<Vec<u8>>;
+        fn get_banker_queue(&self) -> Result<BankerQueue>;
+        fn promote(&self) -> Result<()>;
+        fn get_reserves(&self) -> Result<SignedReservesReport>;
+        fn hard_audit(&self) -> Result<HardAuditReport>;
+        /// Fetch a backup of the server's state, which can be restored with `--restore` alongside a copy of the trade log taken after it
+        fn get_backup(&self) -> Result<Vec<u8>>;
+        fn get_token(&self, token: &Token) -> Result<TokenInfo>;
+        fn create_token(&self, args: &TokenPostArgs) -> Result<Token>;
+        fn list_tokens(&self) -> Result<Vec<TokenUsage>>;
+        fn get_submitter(&self, action_id: u64) -> Result<Option<ActionSubmitter>>;
+        fn rotate_token(&self) -> Result<Token>;
+        fn delete_token(&self, args: &TokenDeleteArgs) -> Result<()>;
+    }
+}
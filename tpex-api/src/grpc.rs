@@ -0,0 +1,104 @@
+use axum::http::StatusCode;
+use futures_util::StreamExt;
+use tonic::{Request, Response, Status};
+use crate::shared::*;
+
+mod proto {
+    tonic::include_proto!("tpex");
+}
+
+use proto::exchange_server::{Exchange, ExchangeServer};
+
+impl From<super::Error> for Status {
+    fn from(value: super::Error) -> Self {
+        let (code, err) = value.describe();
+        let code = match code {
+            400 => tonic::Code::InvalidArgument,
+            403 => tonic::Code::PermissionDenied,
+            409 => tonic::Code::FailedPrecondition,
+            501 => tonic::Code::Unimplemented,
+            502 | 503 => tonic::Code::Unavailable,
+            _ => tonic::Code::Internal
+        };
+        Status::new(code, err)
+    }
+}
+
+/// The gRPC service, which does what the matching REST endpoints do
+pub struct ExchangeService {
+    state: super::State
+}
+impl ExchangeService {
+    /// Check the token in the call's metadata, just as for a REST request
+    async fn authenticate<T>(&self, request: &Request<T>, read: bool) -> Result<TokenInfo, Status> {
+        let address = request.extensions().get::<axum::extract::ConnectInfo<std::net::SocketAddr>>().map(|info| info.0.ip().to_string());
+        crate::tokens::authenticate(&self.state, &request.metadata().clone().into_headers(), read, address).await
+        .map_err(|code| match code {
+            StatusCode::UNAUTHORIZED => Status::unauthenticated("The token is missing, invalid, or can no longer be used"),
+            StatusCode::FORBIDDEN => Status::permission_denied("This token is not allowed to do this"),
+            _ => Status::internal("Could not check the token")
+        })
+    }
+}
+
+/// Serve the gRPC service at the paths its clients expect, so it can sit beside the REST API
+pub fn router(state: super::State) -> axum::Router {
+    tonic::service::Routes::new(ExchangeServer::new(ExchangeService { state })).into_axum_router()
+}
+
+#[tonic::async_trait]
+impl Exchange for ExchangeService {
+    async fn apply(&self, request: Request<proto::ApplyRequest>) -> Result<Response<proto::ApplyReply>, Status> {
+        let token = self.authenticate(&request, false).await?;
+        let request = request.into_inner();
+        let json = request.action.ok_or(super::Error::MalformedAction)?.json;
+        let signature = match (request.signature, request.public_key) {
+            // A signature covers the exact text sent
+            (Some(signature), Some(public_key)) => Some(tpex::ActionSignature { payload: json.clone(), public_key, signature }),
+            (None, None) => None,
+            _ => return Err(super::Error::from(tpex::Error::InvalidSignature).into())
+        };
+        // A signed action is sent as a SignedAction, carrying the id it was signed for
+        let action = match &signature {
+            Some(signature) => signature.signed_action().map_err(|_| super::Error::MalformedAction)?.action,
+            None => serde_json::from_str(&json).map_err(|_| super::Error::MalformedAction)?
+        };
+        let outcome = super::submit(&self.state, &token, action, signature, None).await?;
+        Ok(Response::new(proto::ApplyReply {
+            id: outcome.id,
+            outcome_json: serde_json::to_string(&outcome).expect("Unable to serialise outcome")
+        }))
+    }
+
+    async fn fastsync(&self, request: Request<proto::FastsyncRequest>) -> Result<Response<proto::StateSync>, Status> {
+        self.authenticate(&request, true).await?;
+        let tpex = self.state.tpex.read().await;
+        Ok(Response::new(proto::StateSync {
+            next_id: tpex.state.get_next_id(),
+            log_digest: tpex.state.get_log_digest().to_owned(),
+            state_json: serde_json::to_string(&tpex.state).expect("Unable to serialise state")
+        }))
+    }
+
+    type StreamActionsStream = std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<proto::LoggedAction, Status>> + Send>>;
+
+    async fn stream_actions(&self, request: Request<proto::StreamActionsRequest>) -> Result<Response<Self::StreamActionsStream>, Status> {
+        self.authenticate(&request, true).await?;
+        let request = request.into_inner();
+        let args = StateGetArgs {
+            from: request.from,
+            #[allow(deprecated)]
+            player: request.player.map(tpex::PlayerId::evil_constructor),
+            asset: request.asset,
+            ..Default::default()
+        };
+        let logged = |line: &[u8]| proto::LoggedAction {
+            id: super::line_id(line),
+            line: String::from_utf8_lossy(line.trim_ascii_end()).into_owned()
+        };
+        let (backlog, receiver) = super::subscribe_lines(&self.state, &args).await;
+        let backlog: Vec<_> = backlog.split_inclusive(|byte| *byte == b'\n').map(logged).map(Ok).collect();
+        let live = super::live_lines(receiver, args.player, args.asset).map(move |notification| logged(&notification.data)).map(Ok);
+        Ok(Response::new(Box::pin(futures_util::stream::iter(backlog).chain(live))))
+    }
+}
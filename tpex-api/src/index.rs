@@ -0,0 +1,81 @@
+use tpex::{Action, AssetId, PlayerId};
+use crate::shared::*;
+
+/// The name of an action's type, as it is tagged in the trade log
+pub fn kind(action: &Action) -> String {
+    match serde_json::to_value(action).expect("Unable to serialise action") {
+        serde_json::Value::Object(map) => map.into_iter().next().map(|(kind, _)| kind).unwrap_or_default(),
+        serde_json::Value::String(kind) => kind,
+        _ => String::new()
+    }
+}
+
+/// Which actions in the trade log involve each player, item and type of action, and when each was applied
+#[derive(Default)]
+pub struct ActionIndex {
+    /// When each action was applied, where action n is at n - 1
+    times: Vec<chrono::DateTime<chrono::Utc>>,
+    by_player: std::collections::HashMap<PlayerId, Vec<u64>>,
+    by_asset: std::collections::HashMap<AssetId, Vec<u64>>,
+    by_kind: std::collections::HashMap<String, Vec<u64>>
+}
+impl ActionIndex {
+    /// Index a whole trade log, replaying it from the given state, which must not have applied any of it yet
+    pub async fn build(mut state: tpex::State, data: &[u8]) -> Result<ActionIndex, tpex::Error> {
+        let mut ret = ActionIndex::default();
+        state.replay_involved(&mut &data[..], |time, action, outcome, players, assets| ret.record_applied(time, action, outcome, players, assets)).await?;
+        Ok(ret)
+    }
+    /// Add an action to the index as it is replayed, given who and what it involved before it was applied
+    ///
+    /// This matches what is recorded when it is first applied, so searches don't change when the server restarts
+    pub fn record_applied(
+        &mut self,
+        time: chrono::DateTime<chrono::Utc>,
+        action: &Action,
+        outcome: &tpex::ApplyOutcome,
+        players: std::collections::BTreeSet<PlayerId>,
+        assets: std::collections::BTreeSet<AssetId>
+    ) {
+        let notification = crate::notification(Vec::new(), players, assets, outcome);
+        self.record(outcome.id, time, action, &notification.players, &notification.assets);
+    }
+    /// Add the next action to the index
+    pub fn record(
+        &mut self,
+        id: u64,
+        time: chrono::DateTime<chrono::Utc>,
+        action: &Action,
+        players: &std::collections::BTreeSet<PlayerId>,
+        assets: &std::collections::BTreeSet<AssetId>
+    ) {
+        assert_eq!(id, self.times.len() as u64 + 1, "Actions indexed out of order");
+        self.times.push(time);
+        for player in players {
+            self.by_player.entry(player.clone()).or_default().push(id);
+        }
+        for asset in assets {
+            self.by_asset.entry(asset.clone()).or_default().push(id);
+        }
+        self.by_kind.entry(kind(action)).or_default().push(id);
+    }
+    /// Find the ids of every action matching all of the given filters, in order
+    pub fn search(&self, args: &ActionsSearchArgs) -> Vec<u64> {
+        let lists: Vec<&[u64]> = [
+            args.player.as_ref().map(|player| self.by_player.get(player)),
+            args.asset.as_ref().map(|asset| self.by_asset.get(asset)),
+            args.kind.as_ref().map(|kind| self.by_kind.get(kind))
+        ].into_iter().flatten().map(|list| list.map_or(&[][..], Vec::as_slice)).collect();
+        let in_time = |id: &u64| {
+            let time = self.times[(*id - 1) as usize];
+            args.from.is_none_or(|from| time >= from) && args.to.is_none_or(|to| time < to)
+        };
+        // Walk the shortest list, checking the others, which are sorted
+        let Some(shortest) = lists.iter().min_by_key(|list| list.len())
+        else { return (1..=self.times.len() as u64).filter(in_time).collect(); };
+        shortest.iter().copied()
+            .filter(|id| lists.iter().all(|list| list.binary_search(id).is_ok()))
+            .filter(in_time)
+            .collect()
+    }
+}
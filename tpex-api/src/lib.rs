@@ -1,20 +1,36 @@
 mod shared;
+#[cfg(all(feature = "blocking", not(target_arch = "wasm32")))]
+pub mod blocking;
+mod trading;
+#[cfg(not(target_arch = "wasm32"))]
+mod market;
 
 pub use shared::*;
-use tpex::{AssetId, AssetInfo, State};
+pub use trading::{Quote, Refusal};
+#[cfg(not(target_arch = "wasm32"))]
+pub use market::{MarketData, Ticker};
+use tpex::{ApplyOutcome, AssetId, AssetInfo, State};
 
 pub use shared::Token;
 
 #[derive(Debug)]
 pub enum Error {
     RequestFailure(reqwest::Error),
+    /// The exchange refused the action, with the details the server sent back
+    Rejected(tpex::Error, ErrorInfo),
     TPExFailure(ErrorInfo),
+    /// An order helper didn't place an order, as it would break the caller's limits
+    Refused(Refusal),
 }
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::RequestFailure(err) => write!(f, "Request failure: {err}"),
-            Error::TPExFailure(err) => write!(f, "TPEx failure: {}", err.error)
+            Error::Rejected(err, ErrorInfo { request_id: Some(request_id), .. }) => write!(f, "TPEx rejected the action: {err} (request {request_id})"),
+            Error::Rejected(err, _) => write!(f, "TPEx rejected the action: {err}"),
+            Error::TPExFailure(ErrorInfo { error, request_id: Some(request_id), .. }) => write!(f, "TPEx failure: {error} (request {request_id})"),
+            Error::TPExFailure(err) => write!(f, "TPEx failure: {}", err.error),
+            Error::Refused(refusal) => write!(f, "Order refused: {refusal}")
         }
     }
 }
@@ -23,30 +39,178 @@ impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self { Error::RequestFailure(value) }
 }
 impl From<ErrorInfo> for Error {
-    fn from(value: ErrorInfo) -> Self { Error::TPExFailure(value) }
+    fn from(mut value: ErrorInfo) -> Self {
+        match value.tpex.take() {
+            Some(err) => Error::Rejected(err, value),
+            None => Error::TPExFailure(value)
+        }
+    }
+}
+impl Error {
+    /// What kind of failure the server reported, if it got that far
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::RequestFailure(_) | Error::Refused(_) => None,
+            Error::Rejected(_, info) | Error::TPExFailure(info) => Some(info.code)
+        }
+    }
 }
 
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+// Streams and retries need timers, which tokio can't give us in a browser
+/// How long a stream waits before reconnecting the first time, doubling for each failure after
+#[cfg(not(target_arch = "wasm32"))]
+const STREAM_MIN_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+/// The longest a stream waits between reconnects
+#[cfg(not(target_arch = "wasm32"))]
+const STREAM_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How a [`Remote`] retries actions that fail in ways that might not happen again
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// The most times to send an action, including the first
+    pub attempts: u32,
+    /// How long to wait before the first retry, doubling for each retry after
+    pub backoff: std::time::Duration,
+    /// How long to wait for the server before giving up on an attempt
+    pub timeout: Option<std::time::Duration>
+}
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { attempts: 3, backoff: std::time::Duration::from_millis(500), timeout: Some(std::time::Duration::from_secs(10)) }
+    }
+}
+
+#[derive(Clone)]
 pub struct Remote {
     client: reqwest::Client,
-    endpoint: reqwest::Url
+    endpoint: reqwest::Url,
+    #[cfg(not(target_arch = "wasm32"))]
+    retry: Option<RetryPolicy>
+}
+/// Sets up a [`Remote`] that needs more than the defaults, such as going through a proxy
+pub struct RemoteBuilder {
+    endpoint: reqwest::Url,
+    token: Token,
+    client: reqwest::ClientBuilder,
+    headers: reqwest::header::HeaderMap,
+    #[cfg(not(target_arch = "wasm32"))]
+    retry: Option<RetryPolicy>
+}
+impl RemoteBuilder {
+    /// Start from a reqwest client set up elsewhere. The token is still sent with every request
+    pub fn client(mut self, client: reqwest::ClientBuilder) -> RemoteBuilder {
+        self.client = client;
+        self
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> RemoteBuilder {
+        self.client = self.client.proxy(proxy);
+        self
+    }
+    /// How long to wait for a whole request, from connecting to reading the body
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn timeout(mut self, timeout: std::time::Duration) -> RemoteBuilder {
+        self.client = self.client.timeout(timeout);
+        self
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn connect_timeout(mut self, timeout: std::time::Duration) -> RemoteBuilder {
+        self.client = self.client.connect_timeout(timeout);
+        self
+    }
+    pub fn user_agent(mut self, user_agent: &str) -> RemoteBuilder {
+        self.client = self.client.user_agent(user_agent.to_owned());
+        self
+    }
+    /// Send an extra header with every request
+    pub fn header(mut self, name: reqwest::header::HeaderName, value: reqwest::header::HeaderValue) -> RemoteBuilder {
+        self.headers.insert(name, value);
+        self
+    }
+    /// Retry actions that time out or hit a server error, sending an idempotency key so none are applied twice
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn retries(mut self, policy: RetryPolicy) -> RemoteBuilder {
+        self.retry = Some(policy);
+        self
+    }
+    pub fn build(self) -> reqwest::Result<Remote> {
+        let mut headers = self.headers;
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", self.token)).expect("Unable to make token header"));
+        Ok(Remote {
+            client: self.client.default_headers(headers).build()?,
+            endpoint: self.endpoint,
+            #[cfg(not(target_arch = "wasm32"))]
+            retry: self.retry
+        })
+    }
 }
+
 impl Remote {
     pub fn new(endpoint: reqwest::Url, token: Token) -> Remote {
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.append(
-            "Authorization",
-            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token)).expect("Unable to make token header"));
-        Remote {
-            client: reqwest::Client::builder().default_headers(headers).build().expect("Unable to build reqwest client"),
-            endpoint
+        Remote::builder(endpoint, token).build().expect("Unable to build reqwest client")
+    }
+    pub fn builder(endpoint: reqwest::Url, token: Token) -> RemoteBuilder {
+        RemoteBuilder {
+            endpoint,
+            token,
+            client: reqwest::Client::builder(),
+            headers: reqwest::header::HeaderMap::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            retry: None
+        }
+    }
+    /// Retry actions that time out or hit a server error, sending an idempotency key so none are applied twice
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_retries(mut self, policy: RetryPolicy) -> Remote {
+        self.retry = Some(policy);
+        self
+    }
+    /// Send an action, retrying under the same idempotency key if there is a retry policy
+    async fn send_action(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(policy) = self.retry {
+            return self.send_with_retries(request, policy).await;
         }
+        Self::check_response(request.send().await?).await
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_with_retries(&self, request: reqwest::RequestBuilder, policy: RetryPolicy) -> Result<reqwest::Response> {
+        let mut key = [0u8; 16];
+        getrandom::getrandom(&mut key).expect("Could not generate idempotency key");
+        let key: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+        let mut request = request.header(IDEMPOTENCY_KEY_HEADER, key);
+        if let Some(timeout) = policy.timeout {
+            request = request.timeout(timeout);
+        }
+        let mut backoff = policy.backoff;
+        for _ in 1..policy.attempts {
+            let attempt = request.try_clone().expect("Action body cannot be resent");
+            match attempt.send().await {
+                Ok(response) if !response.status().is_server_error() => return Self::check_response(response).await,
+                Err(err) if !(err.is_timeout() || err.is_connect() || err.is_request()) => return Err(err.into()),
+                _ => ()
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        Self::check_response(request.send().await?).await
     }
     async fn check_response(response: reqwest::Response) -> Result<reqwest::Response> {
         if response.status().is_success() { Ok(response) }
-        else { Err(Error::TPExFailure(response.json().await.expect("Invalid error json"))) }
+        else {
+            let status = response.status();
+            // Failures from before a handler runs, like a bad token, only have a status
+            let info = response.json::<ErrorInfo>().await
+                .unwrap_or_else(|_| ErrorInfo { error: status.to_string(), ..Default::default() });
+            Err(info.into())
+        }
     }
 
     pub async fn get_state(&self, from: u64) -> Result<Vec<u8>> {
@@ -56,11 +220,202 @@ impl Remote {
 
         Ok(Self::check_response(self.client.get(target).send().await?).await?.bytes().await?.to_vec())
     }
-    pub async fn apply(&self, action: &tpex::Action) -> Result<u64> {
+    pub async fn get_state_page(&self, args: &StateGetArgs) -> Result<(Vec<u8>, Option<u64>)> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /state").push("state");
+        for (name, value) in [("from", args.from), ("to", args.to), ("limit", args.limit)] {
+            if let Some(value) = value {
+                target.query_pairs_mut().append_pair(name, &value.to_string());
+            }
+        }
+        if let Some(player) = &args.player {
+            target.query_pairs_mut().append_pair("player", &player.to_string());
+        }
+        if let Some(asset) = &args.asset {
+            target.query_pairs_mut().append_pair("asset", asset);
+        }
+
+        let response = Self::check_response(self.client.get(target).send().await?).await?;
+        let next = response.headers().get(NEXT_HEADER).and_then(|next| next.to_str().ok()?.parse().ok());
+        Ok((response.bytes().await?.to_vec(), next))
+    }
+    /// Follow the trade log from the given action, reconnecting whenever the connection drops
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn stream_actions(&self, from: u64) -> ActionStream {
+        ActionStream { remote: self.clone(), next_id: from, response: None, buffer: Vec::new(), backoff: STREAM_MIN_BACKOFF }
+    }
+    pub async fn get_webhooks(&self) -> Result<Vec<Webhook>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /webhooks").push("webhooks");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn create_webhook(&self, args: &WebhookPostArgs) -> Result<Webhook> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /webhooks").push("webhooks");
+
+        Ok(Self::check_response(self.client.post(target).json(args).send().await?).await?.json().await?)
+    }
+    pub async fn delete_webhook(&self, id: i64) -> Result<()> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /webhooks").push("webhooks").push(&id.to_string());
+
+        Ok(Self::check_response(self.client.delete(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_events(&self, from: u64) -> Result<Vec<tpex::analytics::Event>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /events").push("events");
+        target.query_pairs_mut().append_pair("from", &from.to_string());
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn apply(&self, action: &tpex::Action) -> Result<ApplyOutcome> {
         let mut target = self.endpoint.clone();
         target.path_segments_mut().expect("Unable to nav to /state").push("state");
+        target.query_pairs_mut().append_pair("outcome", "true");
 
-        Ok(Self::check_response(self.client.patch(target).json(action).send().await?).await?.json().await?)
+        Ok(self.send_action(self.client.patch(target).json(action)).await?.json().await?)
+    }
+    /// Apply several actions in order, with nothing else applied in between, or none of them if any fail
+    pub async fn apply_batch(&self, actions: &[tpex::Action]) -> Result<Vec<ApplyOutcome>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /state/batch").push("state").push("batch");
+
+        Ok(Self::check_response(self.client.patch(target).json(actions).send().await?).await?.json().await?)
+    }
+    /// Find out what an action would do if it were applied now, without applying it
+    pub async fn simulate(&self, action: &tpex::Action) -> Result<ApplyOutcome> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /simulate").push("simulate");
+
+        Ok(Self::check_response(self.client.post(target).json(action).send().await?).await?.json().await?)
+    }
+    /// Apply an action that has already been signed, sending the exact text that was signed
+    ///
+    /// The signature only covers the id it was signed for, so it has to be signed again if another action gets there first
+    pub async fn apply_signed(&self, signature: &tpex::ActionSignature) -> Result<ApplyOutcome> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /state").push("state");
+        target.query_pairs_mut().append_pair("outcome", "true");
+
+        let request = self.client.patch(target)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature.signature)
+            .header(PUBLIC_KEY_HEADER, &signature.public_key)
+            .body(signature.payload.clone());
+        Ok(self.send_action(request).await?.json().await?)
+    }
+    pub async fn get_candles(&self, args: &CandlesGetArgs) -> Result<Vec<tpex::analytics::Candle>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/candles").push("inspect").push("candles");
+        target.query_pairs_mut().append_pair("asset", &args.asset);
+        if let Some(interval_secs) = args.interval_secs {
+            target.query_pairs_mut().append_pair("interval_secs", &interval_secs.to_string());
+        }
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_statement(&self, args: &StatementGetArgs) -> Result<Vec<tpex::analytics::StatementEntry>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/statement").push("inspect").push("statement");
+        target.query_pairs_mut().append_pair("player", &args.player.to_string());
+        if let Some(from) = args.from {
+            target.query_pairs_mut().append_pair("from", &from.to_string());
+        }
+        if let Some(to) = args.to {
+            target.query_pairs_mut().append_pair("to", &to.to_string());
+        }
+        // The client only understands JSON
+        target.query_pairs_mut().append_pair("format", "json");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_actions_involving(&self, args: &ActionsGetArgs) -> Result<Vec<u8>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/actions").push("inspect").push("actions");
+        target.query_pairs_mut().append_pair("player", &args.player.to_string());
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.bytes().await?.to_vec())
+    }
+    pub async fn get_orders(&self, args: &OrdersGetArgs) -> Result<std::collections::BTreeMap<u64, tpex::PendingOrder>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/orders").push("inspect").push("orders");
+        if let Some(player) = &args.player {
+            target.query_pairs_mut().append_pair("player", &player.to_string());
+        }
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_withdrawals(&self) -> Result<std::collections::BTreeMap<u64, tpex::PendingWithdrawal>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/withdrawals").push("inspect").push("withdrawals");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_prices(&self, args: &PricesGetArgs) -> Result<Prices> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/prices").push("inspect").push("prices");
+        target.query_pairs_mut().append_pair("asset", &args.asset);
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_restricted(&self) -> Result<std::collections::BTreeSet<AssetId>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/restricted").push("inspect").push("restricted");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    /// Find every action matching the given filters, as trade log lines
+    pub async fn search_actions(&self, args: &ActionsSearchArgs) -> Result<Vec<u8>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /actions").push("actions");
+        if let Some(player) = &args.player {
+            target.query_pairs_mut().append_pair("player", &player.to_string());
+        }
+        if let Some(asset) = &args.asset {
+            target.query_pairs_mut().append_pair("asset", asset);
+        }
+        if let Some(kind) = &args.kind {
+            target.query_pairs_mut().append_pair("type", kind);
+        }
+        for (name, value) in [("from", args.from), ("to", args.to)] {
+            if let Some(value) = value {
+                target.query_pairs_mut().append_pair(name, &value.to_rfc3339());
+            }
+        }
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.bytes().await?.to_vec())
+    }
+    pub async fn get_banker_queue(&self) -> Result<BankerQueue> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/banker-queue").push("inspect").push("banker-queue");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn promote(&self) -> Result<()> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /replication/promote").push("replication").push("promote");
+
+        Ok(Self::check_response(self.client.post(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_reserves(&self) -> Result<SignedReservesReport> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /inspect/reserves").push("inspect").push("reserves");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn hard_audit(&self) -> Result<HardAuditReport> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /admin/hard_audit").push("admin").push("hard_audit");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    /// Fetch a backup of the server's state, which can be restored with `--restore` alongside a copy of the trade log taken after it
+    pub async fn get_backup(&self) -> Result<Vec<u8>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /admin/backup").push("admin").push("backup");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.bytes().await?.to_vec())
     }
     pub async fn get_token(&self, token: &Token) -> Result<TokenInfo> {
         let mut target = self.endpoint.clone();
@@ -74,6 +429,24 @@ impl Remote {
 
         Ok(Self::check_response(self.client.post(target).json(args).send().await?).await?.json().await?)
     }
+    pub async fn list_tokens(&self) -> Result<Vec<TokenUsage>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /token/list").push("token").push("list");
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn get_submitter(&self, action_id: u64) -> Result<Option<ActionSubmitter>> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /token/submitter").push("token").push("submitter").push(&action_id.to_string());
+
+        Ok(Self::check_response(self.client.get(target).send().await?).await?.json().await?)
+    }
+    pub async fn rotate_token(&self) -> Result<Token> {
+        let mut target = self.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /token/rotate").push("token").push("rotate");
+
+        Ok(Self::check_response(self.client.post(target).send().await?).await?.json().await?)
+    }
     pub async fn delete_token(&self, args: &TokenDeleteArgs) -> Result<()> {
         let mut target = self.endpoint.clone();
         target.path_segments_mut().expect("Unable to nav to /token").push("token");
@@ -82,32 +455,157 @@ impl Remote {
     }
 }
 
+/// The trade log as it is applied, from /state/sse
+///
+/// Dropped connections are retried with exponential backoff, and resume from the last action received, so no action is missed or repeated
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ActionStream {
+    remote: Remote,
+    next_id: u64,
+    response: Option<reqwest::Response>,
+    buffer: Vec<u8>,
+    backoff: std::time::Duration
+}
+#[cfg(not(target_arch = "wasm32"))]
+impl ActionStream {
+    /// The id of the next action this stream will give
+    pub fn next_id(&self) -> u64 { self.next_id }
+    /// Wait for the next action
+    ///
+    /// Only errors the server gives back on connecting, like a revoked token, are returned. Anything else is retried
+    pub async fn next(&mut self) -> Result<tpex::WrappedAction> {
+        let line = self.next_line().await?;
+        let record = serde_json::from_slice(&line).expect("Corrupted trade log");
+        Ok(tpex::migrate::upgrade(record).expect("Trade log record could not be upgraded"))
+    }
+    /// Wait for the next action, as the trade log line it was sent as
+    pub async fn next_line(&mut self) -> Result<Vec<u8>> {
+        loop {
+            while let Some((id, line)) = self.take_event() {
+                // Anything before where we are was already given out before a reconnect
+                if id < self.next_id { continue; }
+                // Skipping ahead means the server lost track of us, so start again from where we are
+                if id > self.next_id {
+                    self.response = None;
+                    self.buffer.clear();
+                    break;
+                }
+                self.next_id += 1;
+                return Ok(line);
+            }
+            if self.response.is_none() {
+                self.response = Some(self.connect().await?);
+            }
+            if let Ok(Some(chunk)) = self.response.as_mut().expect("Stream not connected").chunk().await {
+                self.buffer.extend_from_slice(&chunk);
+                self.backoff = STREAM_MIN_BACKOFF;
+                continue;
+            }
+            // The server hung up, most likely because we fell too far behind
+            self.response = None;
+            self.buffer.clear();
+        }
+    }
+    /// Connect from the next action, waiting out failures
+    async fn connect(&mut self) -> Result<reqwest::Response> {
+        let mut target = self.remote.endpoint.clone();
+        target.path_segments_mut().expect("Unable to nav to /state/sse").push("state").push("sse");
+        target.query_pairs_mut().append_pair("from", &self.next_id.to_string());
+        loop {
+            match self.remote.client.get(target.clone()).send().await {
+                // The server being down or overloaded is worth waiting out, but it refusing us isn't
+                Ok(response) if !response.status().is_server_error() => { return Remote::check_response(response).await; }
+                _ => ()
+            }
+            tokio::time::sleep(self.backoff).await;
+            self.backoff = (self.backoff * 2).min(STREAM_MAX_BACKOFF);
+        }
+    }
+    /// Pull the next whole event out of what has been received, if there is one
+    fn take_event(&mut self) -> Option<(u64, Vec<u8>)> {
+        loop {
+            let end = self.buffer.windows(2).position(|window| window == b"\n\n")?;
+            let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let (mut id, mut data) = (None, Vec::new());
+            for line in event.split(|byte| *byte == b'\n') {
+                if let Some(value) = line.strip_prefix(b"id:") {
+                    id = std::str::from_utf8(value).ok().and_then(|value| value.trim().parse().ok());
+                }
+                else if let Some(value) = line.strip_prefix(b"data:") {
+                    data.extend_from_slice(value.strip_prefix(b" ").unwrap_or(value));
+                }
+            }
+            // Keep-alives have no id
+            if let Some(id) = id {
+                return Some((id, data));
+            }
+        }
+    }
+}
+
+/// How many applied actions a stream from a mirror can fall behind by before it misses some
+const MIRROR_BACKLOG: usize = 1024;
+
 pub struct Mirrored {
     pub remote: Remote,
-    state: tokio::sync::RwLock<State>
+    state: tokio::sync::RwLock<State>,
+    applied: tokio::sync::broadcast::Sender<(tpex::Action, ApplyOutcome)>
 }
 impl Mirrored {
     pub fn new(endpoint: reqwest::Url, token: Token) -> Mirrored {
         Mirrored {
             remote: Remote::new(endpoint, token),
-            state: tokio::sync::RwLock::new(State::new())
+            state: tokio::sync::RwLock::new(State::new()),
+            applied: tokio::sync::broadcast::channel(MIRROR_BACKLOG).0
         }
     }
     pub async fn update_asset_info(&self, asset_info: std::collections::HashMap<AssetId, AssetInfo>) {
         self.state.write().await.update_asset_info(asset_info)
     }
-    pub async fn sync(&self) -> tokio::sync::RwLockReadGuard<State> {
+    pub async fn sync(&self) -> tokio::sync::RwLockReadGuard<'_, State> {
         let mut state = self.state.write().await;
         let cursor = std::io::Cursor::new(self.remote.get_state(state.get_next_id()).await.expect("Could not fetch state"));
         let mut buf = tokio::io::BufReader::new(cursor);
-        state.replay(&mut buf).await.expect("State unable to replay");
+        // Nobody listening right now isn't a problem
+        state.replay_with(&mut buf, |_, action, outcome| { let _ = self.applied.send((action.clone(), outcome.clone())); }).await.expect("State unable to replay");
         state.downgrade()
     }
-    pub async fn apply(&self, action: tpex::Action) -> Result<u64> {
+    /// Get told about every action the mirror applies and how it went, whether a sync or following caught it
+    ///
+    /// Only actions applied after subscribing are sent
+    pub fn stream(&self) -> tokio::sync::broadcast::Receiver<(tpex::Action, ApplyOutcome)> { self.applied.subscribe() }
+    pub async fn apply(&self, action: tpex::Action) -> Result<ApplyOutcome> {
         // The remote could be desynced, so we send our update
-        let id = self.remote.apply(&action).await?;
+        let outcome = self.remote.apply(&action).await?;
         drop(self.sync().await);
-        Ok(id)
+        Ok(outcome)
+    }
+    /// Keep the mirror up to date as actions are applied, rather than only when synced
+    ///
+    /// This only returns if the server refuses to stream to us
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn follow(&self) -> Result<()> {
+        self.follow_with(|_, _| ()).await
+    }
+    /// Like follow, telling the callback about each action it applies to the mirror, along with the mirror just after
+    ///
+    /// Actions a sync applied first are skipped over, so use stream to hear about every action
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn follow_with(&self, mut on_apply: impl FnMut(&State, &ApplyOutcome)) -> Result<()> {
+        let mut stream = self.remote.stream_actions(self.state.read().await.get_next_id());
+        loop {
+            let line = stream.next_line().await?;
+            let mut state = self.state.write().await;
+            // A sync may have already caught up past this
+            if stream.next_id() - 1 == state.get_next_id() {
+                let mut applied = Vec::new();
+                state.replay_with(&mut line.as_slice(), |_, action, outcome| applied.push((action.clone(), outcome.clone()))).await.expect("State unable to replay");
+                for (action, outcome) in applied {
+                    on_apply(&state, &outcome);
+                    let _ = self.applied.send((action, outcome));
+                }
+            }
+        }
     }
     // This isn't synced
     pub async fn asset_info(&self, asset: &AssetId) -> std::result::Result<AssetInfo, tpex::Error> {
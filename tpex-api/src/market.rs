@@ -0,0 +1,81 @@
+//! Prices for each item, kept up to date from one stream of the trade log and shared with anything that subscribes
+
+use tpex::{ApplyOutcome, AssetId, Coins, PriceLevel, State};
+
+use crate::{Mirrored, Result};
+
+/// How many updates a subscriber can fall behind by before it misses some
+const TICKER_BACKLOG: usize = 64;
+
+/// The latest prices for an item
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Ticker {
+    /// The best buy order's price and visible amount
+    pub bid: Option<PriceLevel>,
+    /// The best sell order's price and visible amount
+    pub ask: Option<PriceLevel>,
+    /// The price of the most recent trade
+    pub last_price: Option<Coins>,
+    /// How many have traded since the market data started following
+    pub volume: u64
+}
+
+#[derive(Default)]
+struct Tickers {
+    latest: std::collections::HashMap<AssetId, Ticker>,
+    volumes: std::collections::HashMap<AssetId, u64>,
+    subscribers: std::collections::HashMap<AssetId, tokio::sync::broadcast::Sender<Ticker>>
+}
+impl Tickers {
+    fn build(&self, state: &State, asset: &AssetId) -> Ticker {
+        let (bid, ask) = state.get_bbo(asset);
+        Ticker { bid, ask, last_price: state.get_last_price(asset), volume: self.volumes.get(asset).copied().unwrap_or_default() }
+    }
+}
+
+/// Market data on top of a mirror, so that everything wanting prices can share one stream
+pub struct MarketData {
+    mirror: Mirrored,
+    tickers: std::sync::Mutex<Tickers>
+}
+impl MarketData {
+    pub fn new(mirror: Mirrored) -> MarketData {
+        MarketData { mirror, tickers: Default::default() }
+    }
+    /// The mirror the market data is kept from
+    pub fn mirror(&self) -> &Mirrored { &self.mirror }
+    /// The latest prices for an item, as of the last action followed
+    pub async fn ticker(&self, asset: &AssetId) -> Ticker {
+        let state = self.mirror.state.read().await;
+        self.tickers.lock().expect("Market data poisoned").build(&state, asset)
+    }
+    /// Get told whenever an item's prices change
+    pub fn subscribe(&self, asset: &AssetId) -> tokio::sync::broadcast::Receiver<Ticker> {
+        let mut tickers = self.tickers.lock().expect("Market data poisoned");
+        // An item with nothing going on isn't worth telling anyone about
+        tickers.latest.entry(asset.clone()).or_default();
+        tickers.subscribers.entry(asset.clone()).or_insert_with(|| tokio::sync::broadcast::channel(TICKER_BACKLOG).0).subscribe()
+    }
+    /// Follow the trade log, sending out prices as they change
+    ///
+    /// This only returns if the server refuses to stream to us
+    pub async fn run(&self) -> Result<()> {
+        self.mirror.follow_with(|state, outcome| self.update(state, outcome)).await
+    }
+    fn update(&self, state: &State, outcome: &ApplyOutcome) {
+        let mut tickers = self.tickers.lock().expect("Market data poisoned");
+        if let Some(asset) = &outcome.asset {
+            *tickers.volumes.entry(asset.clone()).or_default() += outcome.fills.iter().map(|fill| fill.count).sum::<u64>();
+        }
+        // Cancels and expiries can move the book without saying which item, so check everything watched
+        tickers.subscribers.retain(|_, sender| sender.receiver_count() > 0);
+        let watched: Vec<AssetId> = tickers.subscribers.keys().cloned().collect();
+        for asset in watched {
+            let ticker = tickers.build(state, &asset);
+            if tickers.latest.get(&asset) == Some(&ticker) { continue; }
+            tickers.latest.insert(asset.clone(), ticker.clone());
+            // Nobody listening right now isn't a problem
+            let _ = tickers.subscribers[&asset].send(ticker);
+        }
+    }
+}
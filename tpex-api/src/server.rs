@@ -3,44 +3,342 @@ mod tests;
 
 mod tokens;
 mod shared;
+mod store;
+mod webhooks;
+mod index;
+#[cfg(feature = "grpc")]
+mod grpc;
 
 use shared::*;
 
 use axum::Router;
 use clap::Parser;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::io::AsyncReadExt;
 use tpex::{Action, ActionLevel};
 use std::io::Write;
 
+/// The shortest candle the server keeps, which longer candles are built from
+const CANDLE_INTERVAL: chrono::TimeDelta = chrono::TimeDelta::minutes(1);
+/// How many actions apart the saved copies of past states are, which historical lookups and startup replay from
+const SNAPSHOT_INTERVAL: u64 = 1000;
+/// How often a standby asks the primary for new actions
+const FOLLOW_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// How many new actions a streaming client can fall behind by before it is cut off
+const SUBSCRIBER_BACKLOG: usize = 1024;
+/// The most actions that can be submitted in one batch
+const MAX_BATCH_ACTIONS: usize = 100;
+/// Response bodies smaller than this aren't worth compressing
+const COMPRESS_MIN_BYTES: usize = 1024;
+/// How often a new reserves report is published
+const RESERVES_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// The header each request's id is read from, or given in if the client didn't pick one
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// The id of the request being handled, so errors and recorded actions can be matched to the logs
+    static REQUEST_ID: String;
+}
+
 #[derive(clap::Parser)]
 struct Args {
     trades: std::path::PathBuf,
     db: String,
     endpoint: String,
     assets: Option<std::path::PathBuf>,
+    /// A PKCS#8 Ed25519 key to sign reserves reports with. Reports are unsigned without one
+    #[arg(long)]
+    reserves_key: Option<std::path::PathBuf>,
+    /// When appends to the trade log are forced to disk
+    #[arg(long, value_enum, default_value_t = store::Durability::Buffered)]
+    durability: store::Durability,
+    /// How often group commit syncs the trade log
+    #[arg(long, default_value_t = 10)]
+    group_commit_ms: u64,
+    /// The URL of a primary server to follow as a warm standby. Actions are refused until this server is promoted
+    #[arg(long, requires = "follow_token")]
+    follow: Option<reqwest::Url>,
+    /// The URL of a primary server to serve reads for. Actions are passed on to the primary, and this server can't be promoted
+    #[arg(long, requires = "follow_token", conflicts_with = "follow")]
+    replica: Option<reqwest::Url>,
+    /// The token to read the primary's trade log with
+    #[arg(long)]
+    follow_token: Option<tpex_api::Token>,
+    /// What kind of store the trade log is kept in
+    #[arg(long, value_enum, default_value_t = store::StoreKind::File)]
+    store: store::StoreKind,
+    /// Where to keep a copy of the state, so that startup only replays the actions after it
+    #[arg(long)]
+    snapshot: Option<std::path::PathBuf>,
+    /// A backup from /admin/backup to start from, instead of the snapshot
+    ///
+    /// The trade log must be one copied after the backup was taken. Unlike a snapshot, a backup that is missing or doesn't match the trade log stops startup
+    #[arg(long)]
+    restore: Option<std::path::PathBuf>,
+    /// A PEM certificate chain to serve the endpoint over TLS with
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<std::path::PathBuf>,
+    /// The PEM private key for the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<std::path::PathBuf>,
+    /// A Unix socket to serve on as well as the endpoint, for local clients and reverse proxies
+    #[arg(long)]
+    unix: Option<std::path::PathBuf>,
+    /// Log as JSON lines rather than plain text, for log collectors
+    #[arg(long)]
+    log_json: bool,
+}
+
+/// The server we copy actions from, when we are a standby or read replica
+struct Primary {
+    url: reqwest::Url,
+    /// Reads the primary's trade log with our own token
+    remote: tpex_api::Remote,
+    /// Passes on actions with the submitter's own token
+    client: reqwest::Client,
+    /// Whether we pass on actions rather than refusing them, and so can't be promoted
+    replica: bool
+}
+
+/// What is saved to the snapshot file: everything that is otherwise rebuilt by replaying the trade file
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ServerSnapshot {
+    state: tpex::Snapshot,
+    candles: tpex::analytics::CandleAggregator,
+    #[serde(default)]
+    events: tpex::analytics::EventLog
 }
 
 struct TPExState {
     state: tpex::State,
-    file: tokio::fs::File
+    log: Box<dyn store::TradeLogStore>,
+    durability: store::Durability,
+    candles: tpex::analytics::CandleAggregator,
+    events: tpex::analytics::EventLog,
+    /// What each action in the trade log involves, for searching it
+    index: index::ActionIndex,
+    /// Where newly applied actions are sent to be passed on to webhooks
+    notify: tokio::sync::mpsc::UnboundedSender<std::sync::Arc<webhooks::Notification>>,
+    /// Where every new action is sent for streaming clients, including those copied from a primary
+    subscribers: tokio::sync::broadcast::Sender<std::sync::Arc<webhooks::Notification>>,
+    /// Copies of past states, by the id of the next action they would apply
+    ///
+    /// These are filled in as historical lookups pass them, starting from the state before any actions
+    snapshots: std::collections::BTreeMap<u64, tpex::State>,
+    /// Where to save a copy of the state every SNAPSHOT_INTERVAL actions, if anywhere
+    snapshot_path: Option<std::path::PathBuf>
 }
 impl TPExState {
-    async fn apply(&mut self, action: Action) -> Result<u64, tpex::Error> {
-        self.state.apply(action, &mut self.file).await
+    async fn apply(&mut self, action: Action) -> Result<tpex::ApplyOutcome, tpex::Error> {
+        self.apply_at(action, chrono::Utc::now()).await
+    }
+    async fn apply_at(&mut self, action: Action, time: chrono::DateTime<chrono::Utc>) -> Result<tpex::ApplyOutcome, tpex::Error> {
+        let mut written = Vec::new();
+        let involved = self.state.get_involved(&action);
+        let assets = self.state.get_involved_assets(&action);
+        let outcome = self.state.apply_with_time(action.clone(), time, &mut written).await?;
+        let notification = notification(written, involved, assets, &outcome);
+        self.append(&notification.data, &notification.players, &outcome).await;
+        self.notify(time, &action, &outcome, notification, true);
+        self.candles.observe(time, &outcome);
+        self.events.observe(time, &action, &outcome);
+        self.save_snapshot().await;
+        Ok(outcome)
+    }
+    async fn apply_signed(&mut self, action: Action, signature: tpex::ActionSignature) -> Result<tpex::ApplyOutcome, tpex::Error> {
+        let mut written = Vec::new();
+        let involved = self.state.get_involved(&action);
+        let assets = self.state.get_involved_assets(&action);
+        let time = chrono::Utc::now();
+        let outcome = self.state.apply_signed(action.clone(), signature, time, &mut written).await?;
+        let notification = notification(written, involved, assets, &outcome);
+        self.append(&notification.data, &notification.players, &outcome).await;
+        self.notify(time, &action, &outcome, notification, true);
+        self.candles.observe(time, &outcome);
+        self.events.observe(time, &action, &outcome);
+        self.save_snapshot().await;
+        Ok(outcome)
+    }
+    /// Add a freshly applied action to the log, along with everyone it involved
+    async fn append(&mut self, data: &[u8], players: &std::collections::BTreeSet<tpex::PlayerId>, outcome: &tpex::ApplyOutcome) {
+        self.log.append(store::LogEntry { id: outcome.id, players, data }).await.expect("Could not write to log, must immediately stop!");
+        if self.durability == store::Durability::Fsync {
+            self.log.sync().await.expect("Could not sync log, must immediately stop!");
+        }
+    }
+    /// Index a freshly applied action, and pass it on to streaming clients, and to the webhooks if it's ours to send
+    ///
+    /// Actions copied from a primary aren't sent to webhooks, as the primary has already sent them
+    fn notify(&mut self, time: chrono::DateTime<chrono::Utc>, action: &Action, outcome: &tpex::ApplyOutcome, notification: webhooks::Notification, webhooks: bool) {
+        self.index.record(outcome.id, time, action, &notification.players, &notification.assets);
+        let notification = std::sync::Arc::new(notification);
+        if webhooks {
+            // The receiver only goes away when the server is shutting down
+            let _ = self.notify.send(notification.clone());
+        }
+        // Having no streaming clients isn't an error
+        let _ = self.subscribers.send(notification);
+    }
+    /// Apply actions copied from the primary's trade log, which must carry on from our last action
+    async fn apply_replicated(&mut self, data: &[u8]) -> Result<(), tpex::Error> {
+        for line in data.split_inclusive(|byte| *byte == b'\n') {
+            let record = serde_json::from_slice(line).expect("Corrupted trade log from primary");
+            let wrapped = tpex::migrate::upgrade(record)?;
+            let involved = self.state.get_involved(wrapped.get_action());
+            let assets = self.state.get_involved_assets(wrapped.get_action());
+            let mut applied = None;
+            // Replaying checks signatures and audits just as the primary did
+            self.state.replay_with(&mut &line[..], |time, action, outcome| applied = Some((time, action.clone(), outcome.clone()))).await?;
+            let (time, action, outcome) = applied.expect("Replicated action was not applied");
+            self.candles.observe(time, &outcome);
+            self.events.observe(time, &action, &outcome);
+            let notification = notification(line.to_vec(), involved, assets, &outcome);
+            self.append(&notification.data, &notification.players, &outcome).await;
+            self.notify(time, &action, &outcome, notification, false);
+            self.save_snapshot().await;
+        }
+        Ok(())
+    }
+    /// Save a copy of the state if we've reached the next snapshot, replacing the old copy only once the new one is written
+    async fn save_snapshot(&self) {
+        let Some(path) = self.snapshot_path.as_ref()
+        else { return; };
+        if !(self.state.get_next_id() - 1).is_multiple_of(SNAPSHOT_INTERVAL) {
+            return;
+        }
+        let snapshot = ServerSnapshot { state: self.state.snapshot(), candles: self.candles.clone(), events: self.events.clone() };
+        let data = serde_json::to_vec(&snapshot).expect("Unable to serialise snapshot");
+        let temp_path = path.with_extension("tmp");
+        if let Err(err) = async { tokio::fs::write(&temp_path, data).await?; tokio::fs::rename(&temp_path, path).await }.await {
+            let _ = writeln!(std::io::stderr(), "Could not save snapshot: {err}");
+        }
     }
     async fn get_lines(&mut self) -> Vec<u8> {
-        // Keeping everything in the log file means we can't have different versions of the same data
-        self.file.rewind().await.expect("Could not rewind trade file.");
-        let mut buf = Vec::new();
-        // This will seek to the end again, so pos is the same before and after get_lines
-        self.file.read_to_end(&mut buf).await.expect("Could not re-read trade file.");
-        buf
+        // Keeping everything in the log means we can't have different versions of the same data
+        self.log.read_all().await.expect("Could not re-read trade log.")
     }
 }
 
+/// Gather up who and what an applied action involved, along with anyone it traded against
+///
+/// The players and assets must be worked out before the action is applied, as applying it can remove what says who it was for
+fn notification(data: Vec<u8>, mut players: std::collections::BTreeSet<tpex::PlayerId>, mut assets: std::collections::BTreeSet<tpex::AssetId>, outcome: &tpex::ApplyOutcome) -> webhooks::Notification {
+    players.extend(outcome.fills.iter().map(|fill| fill.counterparty.clone()));
+    assets.extend(outcome.asset.clone());
+    webhooks::Notification { data, players, assets }
+}
+
+/// Perform every scheduled action that is due, cancelling any that can no longer be done
+async fn run_scheduled(state: &StateStruct) {
+    let mut tpex = state.tpex.write().await;
+    for target in tpex.state.get_due_scheduled(chrono::Utc::now()) {
+        if let Err(err) = tpex.apply(Action::RunScheduled { target }).await {
+            let _ = writeln!(std::io::stderr(), "Scheduled action {target} failed, cancelling: {err}");
+            if let Err(err) = tpex.apply(Action::CancelScheduled { target }).await {
+                let _ = writeln!(std::io::stderr(), "Could not cancel failed scheduled action {target}: {err}");
+            }
+        }
+    }
+}
+
+/// Sync the trade log if anything has been appended since the last sync, and let the actions waiting on it reply
+async fn group_commit(state: &StateStruct) {
+    let last_id = {
+        let mut tpex = state.tpex.write().await;
+        let last_id = tpex.state.get_next_id() - 1;
+        if last_id <= *state.synced.borrow() {
+            return;
+        }
+        tpex.log.sync().await.expect("Could not sync log, must immediately stop!");
+        last_id
+    };
+    state.synced.send_replace(last_id);
+}
+
+/// Copy any new actions from the primary, if we are still a standby
+async fn follow_primary(state: &StateStruct) {
+    let Some(primary) = state.primary.as_ref()
+    else { return; };
+    let next_id = state.tpex.read().await.state.get_next_id();
+    let data = match primary.remote.get_state(next_id).await {
+        Ok(data) => data,
+        Err(err) => {
+            let _ = writeln!(std::io::stderr(), "Could not fetch actions from primary: {err}");
+            return;
+        }
+    };
+    if data.is_empty() {
+        return;
+    }
+    let mut tpex = state.tpex.write().await;
+    // We could have been promoted while fetching
+    if !state.standby.load(std::sync::atomic::Ordering::SeqCst) || tpex.state.get_next_id() != next_id {
+        return;
+    }
+    tpex.apply_replicated(&data).await.expect("Standby has diverged from primary");
+}
+
+/// Throw away expired proposals, so they don't pile up
+async fn prune_proposals(state: &StateStruct) {
+    let mut tpex = state.tpex.write().await;
+    if !tpex.state.get_expired_proposals(chrono::Utc::now()).is_empty() {
+        if let Err(err) = tpex.apply(Action::PruneProposals { banker: tpex::PlayerId::the_bank() }).await {
+            let _ = writeln!(std::io::stderr(), "Could not prune expired proposals: {err}");
+        }
+    }
+}
+
+/// Read a saved snapshot, if there is one, checking that it is intact
+async fn read_snapshot(path: &std::path::Path) -> Result<Option<(tpex::State, tpex::analytics::CandleAggregator, tpex::analytics::EventLog)>, String> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("Could not read snapshot: {err}"))
+    };
+    let snapshot: ServerSnapshot = serde_json::from_slice(&data).map_err(|err| format!("Could not parse snapshot: {err}"))?;
+    let state = tpex::State::from_snapshot(snapshot.state).map_err(|err| format!("Ignoring snapshot: {err}"))?;
+    Ok(Some((state, snapshot.candles, snapshot.events)))
+}
+
+/// Read the saved snapshot, if there is one and it is intact
+async fn load_snapshot(path: Option<&std::path::PathBuf>) -> Option<(tpex::State, tpex::analytics::CandleAggregator, tpex::analytics::EventLog)> {
+    read_snapshot(path?).await.unwrap_or_else(|err| {
+        let _ = writeln!(std::io::stderr(), "{err}");
+        None
+    })
+}
+
+/// Build and sign a report of everything the exchange owes
+fn build_reserves(tpex: &tpex::State, key: Option<&ring::signature::Ed25519KeyPair>) -> SignedReservesReport {
+    use base64::prelude::*;
+    use ring::signature::KeyPair;
+
+    // Sign the exact text we hand out, so that checking it doesn't depend on how it is parsed
+    let report = serde_json::to_string(&tpex.get_reserves_report(chrono::Utc::now())).expect("Unable to serialise reserves report");
+    SignedReservesReport {
+        signature: key.map(|key| BASE64_STANDARD.encode(key.sign(report.as_bytes()))),
+        public_key: key.map(|key| BASE64_STANDARD.encode(key.public_key())),
+        report
+    }
+}
+
+/// Replace the published reserves report with an up to date one
+async fn publish_reserves(state: &StateStruct) {
+    let report = build_reserves(&state.tpex.read().await.state, state.reserves_key.as_ref());
+    *state.reserves.write().await = report;
+}
+
 struct StateStruct {
     tpex: tokio::sync::RwLock<TPExState>,
-    tokens: tokens::TokenHandler
+    tokens: tokens::TokenHandler,
+    webhooks: webhooks::WebhookRegistry,
+    /// The latest published reserves report
+    reserves: tokio::sync::RwLock<SignedReservesReport>,
+    reserves_key: Option<ring::signature::Ed25519KeyPair>,
+    /// Whether we are following a primary, and so refuse actions
+    standby: std::sync::atomic::AtomicBool,
+    primary: Option<Primary>,
+    /// The id of the last action known to be on disk, for group commit
+    synced: tokio::sync::watch::Sender<u64>
 }
 type State = std::sync::Arc<StateStruct>;
 
@@ -49,7 +347,20 @@ enum Error {
     TPEx(tpex::Error),
     UncontrolledUser,
     TokenTooLowLevel,
-    TokenInvalid
+    TokenInvalid,
+    MalformedAction,
+    NotIndexed,
+    Standby,
+    Replica,
+    PrimaryUnreachable,
+    InvalidUrl,
+    WebhookInvalid,
+    OutOfScope,
+    OutlivesToken,
+    TooManyActions,
+    DatabaseUnreachable,
+    /// An action in a batch failed, so none of the batch was applied
+    Batch { index: usize, error: Box<Error> }
 }
 impl From<tpex::Error> for Error {
     fn from(value: tpex::Error) -> Self {
@@ -58,12 +369,9 @@ impl From<tpex::Error> for Error {
 }
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        let (code,err) = match self {
-            Self::TPEx(err) => (409, ErrorInfo{error:err.to_string()}),
-            Self::UncontrolledUser => (403, ErrorInfo{error:"This action would act on behalf of a different user.".to_owned()}),
-            Self::TokenTooLowLevel => (403, ErrorInfo{error:"This action requires a higher permission level".to_owned()}),
-            Self::TokenInvalid => (409, ErrorInfo{error:"The given token does not exist".to_owned()})
-        };
+        let info = self.info();
+        let (code,error) = self.describe();
+        let err = ErrorInfo{error, request_id: REQUEST_ID.try_with(Clone::clone).ok(), ..info};
 
         let body = serde_json::to_vec(&err).expect("Unable to serialise error");
 
@@ -76,16 +384,62 @@ impl axum::response::IntoResponse for Error {
         .expect("Unable to create error response")
     }
 }
+impl Error {
+    /// The machine-readable parts of the error to send back
+    fn info(&self) -> ErrorInfo {
+        let code = match self {
+            Self::TPEx(_) => ErrorCode::Rejected,
+            Self::UncontrolledUser => ErrorCode::UncontrolledUser,
+            Self::TokenTooLowLevel => ErrorCode::TokenTooLowLevel,
+            Self::TokenInvalid => ErrorCode::TokenInvalid,
+            Self::MalformedAction => ErrorCode::MalformedAction,
+            Self::NotIndexed => ErrorCode::NotIndexed,
+            Self::Standby => ErrorCode::Standby,
+            Self::Replica => ErrorCode::Replica,
+            Self::PrimaryUnreachable => ErrorCode::PrimaryUnreachable,
+            Self::InvalidUrl => ErrorCode::InvalidUrl,
+            Self::WebhookInvalid => ErrorCode::WebhookInvalid,
+            Self::OutOfScope => ErrorCode::OutOfScope,
+            Self::OutlivesToken => ErrorCode::OutlivesToken,
+            Self::TooManyActions => ErrorCode::TooManyActions,
+            Self::DatabaseUnreachable => ErrorCode::DatabaseUnreachable,
+            Self::Batch { index, error } => return ErrorInfo { batch_index: Some(*index), ..error.info() }
+        };
+        let tpex = match self { Self::TPEx(err) => Some(err.clone()), _ => None };
+        ErrorInfo { code, tpex, ..Default::default() }
+    }
+    /// The status code and message to send back
+    fn describe(self) -> (u16, String) {
+        match self {
+            Self::TPEx(err) => (409, err.to_string()),
+            Self::UncontrolledUser => (403, "This action would act on behalf of a different user.".to_owned()),
+            Self::TokenTooLowLevel => (403, "This action requires a higher permission level".to_owned()),
+            Self::TokenInvalid => (409, "The given token does not exist".to_owned()),
+            Self::MalformedAction => (400, "The body is not a valid action".to_owned()),
+            Self::NotIndexed => (501, "The trade log is not kept in a store that can look actions up".to_owned()),
+            Self::Standby => (503, "This server is a standby, and will not take actions until it is promoted".to_owned()),
+            Self::Replica => (409, "This server is a read replica, and cannot be promoted".to_owned()),
+            Self::PrimaryUnreachable => (502, "The primary server could not be reached".to_owned()),
+            Self::InvalidUrl => (400, "The given URL is not a valid HTTP URL".to_owned()),
+            Self::WebhookInvalid => (409, "The given webhook does not exist".to_owned()),
+            Self::OutOfScope => (403, "This token is not allowed to do this".to_owned()),
+            Self::OutlivesToken => (403, "A token that expires cannot make a token that outlives it".to_owned()),
+            Self::TooManyActions => (400, format!("A batch can have at most {MAX_BATCH_ACTIONS} actions")),
+            Self::DatabaseUnreachable => (503, "The token database could not be reached".to_owned()),
+            Self::Batch { index, error } => {
+                let (code, err) = error.describe();
+                (code, format!("Action {index} of the batch failed, so none were applied: {err}"))
+            }
+        }
+    }
+}
 
-async fn state_patch(
-    axum::extract::State(state): axum::extract::State<State>,
-    token: TokenInfo,
-    axum::extract::Json(action): axum::extract::Json<tpex::Action>
-) -> Result<axum::response::Json<u64>, Error> {
+/// Check that a token can submit an action
+fn authorise(token: &TokenInfo, state: &tpex::State, action: &Action) -> Result<(), Error> {
     match token.level {
         TokenLevel::ReadOnly => return Err(Error::TokenTooLowLevel),
         TokenLevel::ProxyOne => {
-            let perms = state.tpex.read().await.state.perms(&action)?;
+            let perms = state.perms(action)?;
             if perms.player != token.user {
                 return Err(Error::UncontrolledUser);
             }
@@ -96,38 +450,679 @@ async fn state_patch(
         // Apply catches all banker perm mismatches, assuming that upstream has verified their action:
         TokenLevel::ProxyAll => ()
     }
-    let id = state.tpex.write().await.apply(action).await?;
-    Ok(axum::Json(id))
+    if token.scopes.is_some() {
+        let level = state.perms(action)?.level;
+        if !token.allows(Scope::for_action(action, level)) {
+            return Err(Error::OutOfScope);
+        }
+    }
+    Ok(())
+}
+
+/// Apply an action on behalf of a token, waiting until it is on disk if the durability policy says to
+///
+/// If an idempotency key is given and the token already used it, the first outcome is given back instead
+async fn submit(state: &State, token: &TokenInfo, action: Action, signature: Option<tpex::ActionSignature>, idempotency_key: Option<&str>) -> Result<tpex::ApplyOutcome, Error> {
+    authorise(token, &state.tpex.read().await.state, &action)?;
+    let mut tpex = state.tpex.write().await;
+    if state.standby.load(std::sync::atomic::Ordering::SeqCst) {
+        return Err(Error::Standby);
+    }
+    // Checked under the lock, so that a retry racing the first attempt can't apply it again
+    if let Some(key) = idempotency_key {
+        if let Some(outcome) = state.tokens.get_idempotent(&token.token, key).await.expect("Cannot access DB") {
+            return Ok(outcome);
+        }
+    }
+    let outcome = match signature {
+        Some(signature) => tpex.apply_signed(action, signature).await?,
+        None => tpex.apply(action).await?
+    };
+    if let Some(key) = idempotency_key {
+        state.tokens.record_idempotent(&token.token, key, &outcome).await.expect("Cannot access DB");
+    }
+    let durability = tpex.durability;
+    drop(tpex);
+    let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+    tracing::info!(action_id = outcome.id, user = %token.user, request_id, "Applied action");
+    state.tokens.record_action(&token.token, outcome.id, request_id.as_deref()).await.expect("Cannot access DB");
+    // Everything applied before the next sync waits for it together
+    if durability == store::Durability::Group {
+        state.synced.subscribe().wait_for(|synced| *synced >= outcome.id).await.expect("Group commit stopped");
+    }
+    Ok(outcome)
+}
+
+async fn state_patch(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    axum_extra::extract::OptionalQuery(args): axum_extra::extract::OptionalQuery<StatePatchArgs>,
+    headers: axum::http::HeaderMap,
+    // Taken as is, as a signature covers the exact bytes sent
+    body: axum::body::Bytes
+) -> Result<axum::response::Response, Error> {
+    let header = |name| headers.get(name).map(|value| value.to_str().map(str::to_owned).map_err(|_| tpex::Error::InvalidSignature)).transpose();
+    let signature = match (header(SIGNATURE_HEADER)?, header(PUBLIC_KEY_HEADER)?) {
+        // Signatures are over JSON text, so signed actions can't be sent as MessagePack
+        (Some(_), Some(_)) if is_msgpack(&headers) => return Err(tpex::Error::InvalidSignature.into()),
+        (Some(signature), Some(public_key)) => Some(tpex::ActionSignature { payload: String::from_utf8(body.to_vec()).map_err(|_| Error::MalformedAction)?, public_key, signature }),
+        (None, None) => None,
+        _ => return Err(tpex::Error::InvalidSignature.into())
+    };
+    // A signed body is a SignedAction, carrying the id it was signed for
+    let action: tpex::Action = match &signature {
+        Some(signature) => signature.signed_action().map_err(|_| Error::MalformedAction)?.action,
+        None => decode_body(&headers, &body)?
+    };
+    let idempotency_key = headers.get(IDEMPOTENCY_KEY_HEADER).map(|key| key.to_str().map_err(|_| Error::MalformedAction)).transpose()?;
+    let outcome = submit(&state, &token, action, signature, idempotency_key).await?;
+    // Older clients only know what to do with the id
+    Ok(if args.unwrap_or_default().outcome { axum::response::IntoResponse::into_response(axum::Json(outcome)) }
+    else { axum::response::IntoResponse::into_response(axum::Json(outcome.id)) })
+}
+
+/// Returns true if the request's Accept-Encoding allows gzip
+fn accepts_gzip(headers: &axum::http::HeaderMap) -> bool {
+    headers.get_all(axum::http::header::ACCEPT_ENCODING).iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        // A quality of zero means the coding is refused
+        let refused = parts.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+/// Returns true if the request's Accept asks for MessagePack
+fn accepts_msgpack(headers: &axum::http::HeaderMap) -> bool {
+    headers.get_all(axum::http::header::ACCEPT).iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|media| media.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE))
+}
+
+/// Returns true if the request's body is MessagePack rather than JSON
+fn is_msgpack(headers: &axum::http::HeaderMap) -> bool {
+    headers.get(axum::http::header::CONTENT_TYPE)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE))
+}
+
+/// Read a request body as JSON or MessagePack, going by its Content-Type
+fn decode_body<T: serde::de::DeserializeOwned>(headers: &axum::http::HeaderMap, body: &[u8]) -> Result<T, Error> {
+    if is_msgpack(headers) { rmp_serde::from_slice(body).map_err(|_| Error::MalformedAction) }
+    else { serde_json::from_slice(body).map_err(|_| Error::MalformedAction) }
+}
+
+/// Turn trade log lines into a MessagePack array of the actions on them
+fn lines_to_msgpack(data: &[u8]) -> Vec<u8> {
+    let actions: Vec<_> = data.split_inclusive(|byte| *byte == b'\n')
+        .map(|line| tpex::migrate::upgrade(serde_json::from_slice(line).expect("Corrupted trade log")).expect("Trade log record could not be upgraded"))
+        .collect();
+    rmp_serde::to_vec_named(&actions).expect("Unable to serialise actions")
+}
+
+/// Finish a response, gzipping the body if the client allows it and it's big enough to be worth it
+fn encode_body(headers: &axum::http::HeaderMap, mut response: axum::http::response::Builder, mut body: Vec<u8>) -> axum::response::Response {
+    response = response.header(axum::http::header::VARY, "Accept, Accept-Encoding");
+    if body.len() >= COMPRESS_MIN_BYTES && accepts_gzip(headers) {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&body).expect("Unable to compress response");
+        body = encoder.finish().expect("Unable to compress response");
+        response = response.header(axum::http::header::CONTENT_ENCODING, "gzip");
+    }
+    response.body(axum::body::Body::from(body)).expect("Unable to create response")
+}
+
+/// Returns true if the request's If-None-Match lists the given entity tag
+///
+/// Tags are compared weakly, as the same state can be sent compressed or not
+fn etag_matches(headers: &axum::http::HeaderMap, etag: &str) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    headers.get_all(axum::http::header::IF_NONE_MATCH).iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|tag| tag.trim() == "*" || strip(tag) == strip(etag))
+}
+
+/// Cut the requested lines out of the trade log, along with the id of the next line if a limit stopped the page early
+fn page_lines(data: &[u8], args: &StateGetArgs) -> (Vec<u8>, Option<u64>) {
+    let from = args.from.unwrap_or(1).max(1);
+    let last = match (args.to, args.limit) {
+        (Some(to), Some(limit)) => Some(to.min(from.saturating_add(limit).saturating_sub(1))),
+        (to, limit) => to.or(limit.map(|limit| from.saturating_add(limit).saturating_sub(1)))
+    };
+    // Line n holds action n
+    let mut lines = data.split_inclusive(|byte| *byte == b'\n').zip(1..).skip_while(|(_, id)| *id < from).peekable();
+    let mut body = Vec::new();
+    while let Some((line, _)) = lines.next_if(|(_, id)| last.is_none_or(|last| *id <= last)) {
+        body.extend_from_slice(line);
+    }
+    let next = lines.peek().map(|(_, next)| *next).filter(|next| args.to.is_none_or(|to| *next <= to));
+    (body, next)
+}
+
+/// Keep only the lines of a page that involve the requested player and item
+///
+/// Matches are only known for recent fills, so older actions that traded against a player's resting orders can be missed
+fn filter_lines(state: &tpex::State, data: &[u8], args: &StateGetArgs) -> Vec<u8> {
+    let filled: std::collections::HashSet<u64> = args.player.iter()
+        .flat_map(|player| state.get_fills(player, args.from.unwrap_or(1).saturating_sub(1)))
+        .map(|fill| fill.id)
+        .collect();
+    let mut ret = Vec::new();
+    for line in data.split_inclusive(|byte| *byte == b'\n') {
+        let record = serde_json::from_slice(line).expect("Corrupted trade log");
+        let wrapped = tpex::migrate::upgrade(record).expect("Trade log record could not be upgraded");
+        let action = wrapped.get_action();
+        let player_matches = args.player.as_ref().is_none_or(|player| filled.contains(&wrapped.get_id()) || state.get_involved(action).contains(player));
+        let asset_matches = args.asset.as_ref().is_none_or(|asset| state.get_involved_assets(action).contains(asset));
+        if player_matches && asset_matches {
+            ret.extend_from_slice(line);
+        }
+    }
+    ret
 }
 
 async fn state_get(
     axum::extract::State(state): axum::extract::State<State>,
     // must extract token to auth
     _token: TokenInfo,
+    headers: axum::http::HeaderMap,
     axum_extra::extract::OptionalQuery(args): axum_extra::extract::OptionalQuery<StateGetArgs>
 ) -> axum::response::Response {
-    let from = args.unwrap_or_default().from.unwrap_or(0).try_into().unwrap_or(usize::MAX);
-    let mut data = state.tpex.write().await.get_lines().await;
-    if from > 1 {
-        let idx =
-            data.iter()
-            .enumerate()
-            .filter(|(_, i)| **i == b'\n')
-            .map(|(idx,_)| idx)
-            .nth(from - 2)
-            .unwrap_or(usize::MAX);
-        if idx >= data.len() {
-            data = Vec::new();
-        }
-        else {
-            data.drain(0..=idx);
-        }
-    }
-    let body = axum::body::Body::from(data);
-    axum::response::Response::builder()
+    let args = args.unwrap_or_default();
+    let mut tpex = state.tpex.write().await;
+    // The log only ever grows, so nothing has changed until another action is applied
+    let etag = format!("W/\"{}\"", tpex.state.get_next_id());
+    if etag_matches(&headers, &etag) {
+        return axum::response::Response::builder()
+        .status(axum::http::StatusCode::NOT_MODIFIED)
+        .header(axum::http::header::ETAG, etag)
+        .header(axum::http::header::VARY, "Accept, Accept-Encoding")
+        .body(axum::body::Body::empty())
+        .expect("Unable to create state_get response");
+    }
+    let data = tpex.get_lines().await;
+    let (mut body, next) = page_lines(&data, &args);
+    if args.player.is_some() || args.asset.is_some() {
+        body = filter_lines(&tpex.state, &body, &args);
+    }
+    drop(tpex);
+    let content_type = if accepts_msgpack(&headers) {
+        body = lines_to_msgpack(&body);
+        MSGPACK_CONTENT_TYPE
+    } else { "text/plain" };
+    let mut response = axum::response::Response::builder().header("Content-Type", content_type).header(axum::http::header::ETAG, etag);
+    if let Some(next) = next {
+        response = response.header(NEXT_HEADER, next.to_string());
+    }
+    encode_body(&headers, response, body)
+}
+
+/// Find the id of the action on a trade log line
+fn line_id(line: &[u8]) -> u64 {
+    let record = serde_json::from_slice(line).expect("Corrupted trade log");
+    tpex::migrate::upgrade(record).expect("Trade log record could not be upgraded").get_id()
+}
+
+/// Turn a trade log line into an event, so that a reconnecting client can say where it got up to
+fn sse_event(line: &[u8]) -> axum::response::sse::Event {
+    axum::response::sse::Event::default()
+    .id(line_id(line).to_string())
+    .data(String::from_utf8_lossy(line.trim_ascii_end()))
+}
+
+/// Read the trade log from where the arguments say, and subscribe to the actions applied after it
+///
+/// The page limits are ignored, as the backlog runs up to the live actions
+async fn subscribe_lines(state: &State, args: &StateGetArgs) -> (Vec<u8>, tokio::sync::broadcast::Receiver<std::sync::Arc<webhooks::Notification>>) {
+    let args = StateGetArgs { from: args.from, to: None, limit: None, player: args.player.clone(), asset: args.asset.clone() };
+    // Subscribe before reading the log, so that nothing is missed in between
+    let mut tpex = state.tpex.write().await;
+    let receiver = tpex.subscribers.subscribe();
+    let data = tpex.get_lines().await;
+    let (mut backlog, _) = page_lines(&data, &args);
+    if args.player.is_some() || args.asset.is_some() {
+        backlog = filter_lines(&tpex.state, &backlog, &args);
+    }
+    (backlog, receiver)
+}
+
+/// Carry on a stream of trade log lines with the matching actions applied from now on, ending if the stream falls too far behind
+fn live_lines(receiver: tokio::sync::broadcast::Receiver<std::sync::Arc<webhooks::Notification>>, player: Option<tpex::PlayerId>, asset: Option<tpex::AssetId>) -> impl futures_util::Stream<Item = std::sync::Arc<webhooks::Notification>> {
+    futures_util::stream::unfold(receiver, move |mut receiver| {
+        let (player, asset) = (player.clone(), asset.clone());
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(notification) if notification.matches(player.as_ref(), asset.as_ref()) => return Some((notification, receiver)),
+                    Ok(_) => continue,
+                    Err(_) => return None
+                }
+            }
+        }
+    })
+}
+
+/// Stream the trade log as server-sent events, for clients that can't poll /state
+///
+/// The stream starts from the given action, or just after the one in Last-Event-ID, and carries on with new actions as they are applied.
+/// A client that falls too far behind is cut off, and has to reconnect to catch up
+async fn state_sse(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo,
+    headers: axum::http::HeaderMap,
+    axum_extra::extract::OptionalQuery(args): axum_extra::extract::OptionalQuery<StateGetArgs>
+) -> axum::response::sse::Sse<impl futures_util::Stream<Item = Result<axum::response::sse::Event, std::convert::Infallible>>> {
+    let mut args = args.unwrap_or_default();
+    if let Some(last) = headers.get("last-event-id").and_then(|last| last.to_str().ok()?.parse::<u64>().ok()) {
+        args.from = Some(last + 1);
+    }
+    let (backlog, receiver) = subscribe_lines(&state, &args).await;
+    let backlog: Vec<_> = backlog.split_inclusive(|byte| *byte == b'\n').map(|line| Ok(sse_event(line))).collect();
+    use futures_util::StreamExt;
+    let live = live_lines(receiver, args.player, args.asset).map(|notification| Ok(sse_event(&notification.data)));
+    axum::response::sse::Sse::new(futures_util::stream::iter(backlog).chain(live))
+    .keep_alive(axum::response::sse::KeepAlive::default())
+}
+
+/// Rebuild the state just after the given action, returning it along with the trade file it was built from
+async fn past_state(state: &State, id: u64) -> Result<(Vec<u8>, tpex::State), Error> {
+    // Only hold the lock long enough to find where to start from
+    let (lines, mut past) = {
+        let mut tpex = state.tpex.write().await;
+        if id >= tpex.state.get_next_id() {
+            return Err(tpex::Error::InvalidId { id }.into());
+        }
+        let past = tpex.snapshots.range(..=id + 1).next_back().map(|(_, snapshot)| snapshot.clone()).expect("No snapshot before the first action");
+        (tpex.get_lines().await, past)
+    };
+    // Save any snapshot we pass, so that later lookups near here are quicker
+    let snapshot_id = (id + 1) / SNAPSHOT_INTERVAL * SNAPSHOT_INTERVAL;
+    if snapshot_id > past.get_next_id() {
+        past.replay_until(&mut lines.as_slice(), snapshot_id - 1).await?;
+        state.tpex.write().await.snapshots.insert(snapshot_id, past.clone());
+    }
+    past.replay_until(&mut lines.as_slice(), id).await?;
+    Ok((lines, past))
+}
+
+async fn state_at(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<u64>
+) -> Result<axum::response::Response, Error> {
+    let past = past_state(&state, id).await?.1;
+    let (content_type, body) =
+        if accepts_msgpack(&headers) { (MSGPACK_CONTENT_TYPE, rmp_serde::to_vec_named(&past).expect("Unable to serialise state")) }
+        else { ("application/json", serde_json::to_vec(&past).expect("Unable to serialise state")) };
+    Ok(encode_body(&headers, axum::response::Response::builder().header("Content-Type", content_type), body))
+}
+
+async fn inspect_statement(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    axum::extract::Query(args): axum::extract::Query<StatementGetArgs>
+) -> Result<axum::response::Response, Error> {
+    // Statements are private
+    if args.player != token.user && token.level < TokenLevel::ProxyAll {
+        return Err(Error::UncontrolledUser);
+    }
+    let last_id = state.tpex.read().await.state.get_next_id() - 1;
+    let to = args.to.map_or(last_id, |to| to.min(last_id));
+    let from = args.from.unwrap_or(1).max(1);
+    if from > to {
+        return Err(tpex::Error::InvalidId { id: from }.into());
+    }
+    let (lines, mut past) = past_state(&state, from - 1).await?;
+    let mut statement = tpex::analytics::StatementBuilder::new(args.player);
+    past.replay_until_with(&mut lines.as_slice(), to, |time, action, outcome| statement.observe(time, action, outcome)).await?;
+
+    let (content_type, body) = match args.format.unwrap_or_default() {
+        StatementFormat::Json => ("application/json", serde_json::to_vec(statement.entries()).expect("Unable to serialise statement")),
+        StatementFormat::Csv => ("text/csv", statement.to_csv().into_bytes())
+    };
+    Ok(axum::response::Response::builder()
+    .header("Content-Type", content_type)
+    .body(axum::body::Body::from(body))
+    .expect("Unable to create statement response"))
+}
+
+async fn inspect_candles(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo,
+    axum::extract::Query(args): axum::extract::Query<CandlesGetArgs>
+) -> Result<axum::Json<Vec<tpex::analytics::Candle>>, Error> {
+    let tpex = state.tpex.read().await;
+    let candles = match args.interval_secs {
+        Some(interval_secs) => {
+            let interval = chrono::TimeDelta::try_seconds(interval_secs.try_into().map_err(|_| tpex::Error::InvalidInterval)?).ok_or(tpex::Error::InvalidInterval)?;
+            tpex.candles.rollup(&args.asset, interval)?
+        },
+        None => tpex.candles.get_candles(&args.asset)
+    };
+    Ok(axum::Json(candles))
+}
+
+/// Apply several actions in order, with nothing else applied in between
+///
+/// Either every action is applied or none are, in which case the error says which one failed
+async fn state_patch_batch(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes
+) -> Result<axum::response::Json<Vec<tpex::ApplyOutcome>>, Error> {
+    let actions: Vec<Action> = decode_body(&headers, &body)?;
+    if actions.len() > MAX_BATCH_ACTIONS {
+        return Err(Error::TooManyActions);
+    }
+    let mut tpex = state.tpex.write().await;
+    if state.standby.load(std::sync::atomic::Ordering::SeqCst) {
+        return Err(Error::Standby);
+    }
+    let time = chrono::Utc::now();
+    // Try the batch on a copy first, so that a failure part way through leaves nothing applied.
+    // Later actions are checked against the earlier ones, so they can cancel orders placed earlier in the batch
+    let mut trial = tpex.state.clone();
+    for (index, action) in actions.iter().enumerate() {
+        let result = async {
+            authorise(&token, &trial, action)?;
+            trial.apply_with_time(action.clone(), time, &mut tokio::io::sink()).await?;
+            Ok::<(), Error>(())
+        }.await;
+        result.map_err(|error| Error::Batch { index, error: Box::new(error) })?;
+    }
+    drop(trial);
+    let mut outcomes = Vec::new();
+    for action in actions {
+        outcomes.push(tpex.apply_at(action, time).await.expect("Batch action failed after working on a copy"));
+    }
+    let durability = tpex.durability;
+    drop(tpex);
+    let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+    for outcome in outcomes.iter() {
+        tracing::info!(action_id = outcome.id, user = %token.user, request_id, "Applied action");
+        state.tokens.record_action(&token.token, outcome.id, request_id.as_deref()).await.expect("Cannot access DB");
+    }
+    if let Some(last) = outcomes.last().filter(|_| durability == store::Durability::Group) {
+        state.synced.subscribe().wait_for(|synced| *synced >= last.id).await.expect("Group commit stopped");
+    }
+    Ok(axum::Json(outcomes))
+}
+
+/// Show what an action would do if it were applied now, without applying it
+async fn simulate_post(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes
+) -> Result<axum::response::Json<tpex::ApplyOutcome>, Error> {
+    let action: Action = decode_body(&headers, &body)?;
+    // Only copy the state while holding the lock, so that the simulation doesn't hold up real actions
+    let mut trial = {
+        let tpex = state.tpex.read().await;
+        authorise(&token, &tpex.state, &action)?;
+        tpex.state.clone()
+    };
+    let outcome = trial.apply_with_time(action, chrono::Utc::now(), &mut tokio::io::sink()).await?;
+    Ok(axum::Json(outcome))
+}
+
+/// Pass an action on to the primary, as a read replica, and catch up with it before replying
+async fn state_patch_replica(
+    axum::extract::State(state): axum::extract::State<State>,
+    uri: axum::http::Uri,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes
+) -> Result<axum::response::Response, Error> {
+    let primary = state.primary.as_ref().expect("Read replica has no primary");
+    let mut target = primary.url.clone();
+    // Send it to the same place on the primary
+    target.path_segments_mut().expect("Unable to nav to primary").extend(uri.path().split('/').filter(|segment| !segment.is_empty()));
+    target.set_query(uri.query());
+    let content_type = if is_msgpack(&headers) { MSGPACK_CONTENT_TYPE } else { "application/json" };
+    let mut request = primary.client.patch(target).header("Content-Type", content_type).body(body);
+    // The primary checks the submitter's token and signature itself
+    for name in ["Authorization", SIGNATURE_HEADER, PUBLIC_KEY_HEADER, IDEMPOTENCY_KEY_HEADER] {
+        if let Some(value) = headers.get(name) {
+            request = request.header(name, value.as_bytes());
+        }
+    }
+    let response = request.send().await.map_err(|_| Error::PrimaryUnreachable)?;
+    let status = response.status().as_u16();
+    let body = response.bytes().await.map_err(|_| Error::PrimaryUnreachable)?;
+    // So that the submitter can read what they just did
+    if (200..300).contains(&status) {
+        follow_primary(&state).await;
+    }
+    Ok(axum::response::Response::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .body(axum::body::Body::from(body))
+    .expect("Unable to create state_patch_replica response"))
+}
+
+async fn replication_promote(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo
+) -> Result<axum::Json<()>, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    if state.primary.as_ref().is_some_and(|primary| primary.replica) {
+        return Err(Error::Replica);
+    }
+    // Wait for any copying from the primary to finish, so nothing is applied after we stop following
+    let _tpex = state.tpex.write().await;
+    state.standby.store(false, std::sync::atomic::Ordering::SeqCst);
+    Ok(axum::Json(()))
+}
+
+async fn inspect_actions(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    axum::extract::Query(args): axum::extract::Query<ActionsGetArgs>
+) -> Result<axum::response::Response, Error> {
+    // A player's actions are as private as their statement
+    if args.player != token.user && token.level < TokenLevel::ProxyAll {
+        return Err(Error::UncontrolledUser);
+    }
+    let Some(data) = state.tpex.write().await.log.read_involving(&args.player).await.expect("Could not read trade log.")
+    else { return Err(Error::NotIndexed); };
+    Ok(axum::response::Response::builder()
     .header("Content-Type", "text/plain")
-    .body(body)
-    .expect("Unable to create state_get response")
+    .body(axum::body::Body::from(data))
+    .expect("Unable to create inspect_actions response"))
+}
+
+async fn inspect_orders(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo,
+    axum_extra::extract::OptionalQuery(args): axum_extra::extract::OptionalQuery<OrdersGetArgs>
+) -> axum::Json<std::collections::BTreeMap<u64, tpex::PendingOrder>> {
+    let player = args.and_then(|args| args.player);
+    let mut orders = state.tpex.read().await.state.get_orders();
+    orders.retain(|_, order| player.as_ref().is_none_or(|player| order.player == *player));
+    axum::Json(orders)
+}
+
+async fn inspect_withdrawals(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo
+) -> axum::Json<std::collections::BTreeMap<u64, tpex::PendingWithdrawal>> {
+    axum::Json(state.tpex.read().await.state.get_withdrawals())
+}
+
+async fn inspect_prices(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo,
+    axum::extract::Query(args): axum::extract::Query<PricesGetArgs>
+) -> axum::Json<Prices> {
+    let (buy, sell) = state.tpex.read().await.state.get_prices(&args.asset);
+    axum::Json(Prices { buy, sell })
+}
+
+async fn inspect_restricted(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo
+) -> axum::Json<std::collections::BTreeSet<tpex::AssetId>> {
+    axum::Json(state.tpex.read().await.state.get_restricted().cloned().collect())
+}
+
+/// Find every action matching all the given filters, as trade log lines
+async fn actions_get(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo,
+    headers: axum::http::HeaderMap,
+    axum_extra::extract::OptionalQuery(args): axum_extra::extract::OptionalQuery<ActionsSearchArgs>
+) -> axum::response::Response {
+    let args = args.unwrap_or_default();
+    let (data, ids) = {
+        let mut tpex = state.tpex.write().await;
+        let ids = tpex.index.search(&args);
+        (tpex.get_lines().await, ids)
+    };
+    // Line n holds action n
+    let lines: Vec<&[u8]> = data.split_inclusive(|byte| *byte == b'\n').collect();
+    let body = ids.into_iter().flat_map(|id| lines[(id - 1) as usize]).copied().collect();
+    encode_body(&headers, axum::response::Response::builder().header("Content-Type", "text/plain"), body)
+}
+
+/// Gather up everything waiting on the bankers
+fn build_banker_queue(tpex: &tpex::State, now: chrono::DateTime<chrono::Utc>) -> BankerQueue {
+    let restricted: std::collections::BTreeSet<_> = tpex.get_restricted().cloned().collect();
+    let withdrawals = tpex.get_withdrawals().into_values().map(|withdrawal| QueuedWithdrawal {
+        age_secs: (now - withdrawal.requested).num_seconds().max(0).try_into().unwrap_or_default(),
+        restricted: withdrawal.assets.keys().filter(|asset| restricted.contains(*asset)).cloned().collect(),
+        withdrawal
+    }).collect();
+    BankerQueue {
+        withdrawals,
+        authorisation_requests: tpex.get_authorisation_requests(),
+        proposals: tpex.get_proposals(),
+        restricted
+    }
+}
+
+async fn inspect_banker_queue(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo
+) -> Result<axum::Json<BankerQueue>, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    Ok(axum::Json(build_banker_queue(&state.tpex.read().await.state, chrono::Utc::now())))
+}
+
+async fn events_get(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo,
+    axum_extra::extract::OptionalQuery(args): axum_extra::extract::OptionalQuery<EventsGetArgs>
+) -> axum::Json<Vec<tpex::analytics::Event>> {
+    let from = args.and_then(|args| args.from).unwrap_or(1);
+    axum::Json(state.tpex.read().await.events.get_events(from))
+}
+
+async fn inspect_audit(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo
+) -> axum::Json<tpex::ItemisedAudit> {
+    axum::Json(state.tpex.read().await.state.get_itemised_audit())
+}
+
+/// Recalculate everything the exchange holds from scratch
+fn build_hard_audit(tpex: &tpex::State) -> HardAuditReport {
+    use tpex::Auditable;
+
+    let expected = tpex.soft_audit();
+    let (actual, mut discrepancies) = match tpex.try_hard_audit() {
+        Ok(actual) => (Some(actual), Vec::new()),
+        Err(err) => (None, vec![err])
+    };
+    if let Some(actual) = actual.as_ref().filter(|actual| **actual != expected) {
+        discrepancies.push(format!("Recalculated audit {actual:?} differs from running audit {expected:?}"));
+    }
+    HardAuditReport { expected, actual, discrepancies }
+}
+
+/// The process is up and answering requests
+async fn healthz() -> axum::Json<()> {
+    axum::Json(())
+}
+
+/// The server can take requests: the trade log has been replayed, which happens before we listen, and the token DB can be reached
+async fn readyz(
+    axum::extract::State(state): axum::extract::State<State>
+) -> Result<axum::Json<()>, Error> {
+    state.tokens.ping().await.map_err(|_| Error::DatabaseUnreachable)?;
+    Ok(axum::Json(()))
+}
+
+/// Run a hard audit on demand, reporting what is wrong rather than crashing
+async fn admin_hard_audit(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo
+) -> Result<axum::Json<HardAuditReport>, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    Ok(axum::Json(build_hard_audit(&state.tpex.read().await.state)))
+}
+
+/// Send a copy of everything the snapshot file holds, as of the latest action
+///
+/// This is taken while no actions can be applied, so it is consistent. The trade log needs backing up separately:
+/// it is only ever appended to, so any copy taken after this matches it
+async fn admin_backup(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    headers: axum::http::HeaderMap
+) -> Result<axum::response::Response, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    let snapshot = {
+        let tpex = state.tpex.read().await;
+        ServerSnapshot { state: tpex.state.snapshot(), candles: tpex.candles.clone(), events: tpex.events.clone() }
+    };
+    let response = axum::response::Response::builder()
+    .header("Content-Type", "application/json")
+    .header(axum::http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"tpex-backup-{}.json\"", snapshot.state.get_next_id() - 1))
+    .header(LOG_DIGEST_HEADER, snapshot.state.get_log_digest());
+    let body = serde_json::to_vec(&snapshot).expect("Unable to serialise backup");
+    Ok(encode_body(&headers, response, body))
+}
+
+async fn inspect_reserves(
+    axum::extract::State(state): axum::extract::State<State>,
+    // must extract token to auth
+    _token: TokenInfo
+) -> axum::Json<SignedReservesReport> {
+    axum::Json(state.reserves.read().await.clone())
 }
 
 async fn token_get(
@@ -148,8 +1143,28 @@ async fn token_post(
     if args.user != token.user && token.level < TokenLevel::ProxyAll {
         return Err(Error::UncontrolledUser)
     }
+    // A limited token can't be used to get around its limits
+    if let Some(scopes) = token.scopes.as_ref() {
+        if !args.scopes.as_ref().is_some_and(|new_scopes| new_scopes.is_subset(scopes)) {
+            return Err(Error::OutOfScope)
+        }
+    }
+    if let Some(expires) = token.expires {
+        let new_expires = args.ttl_secs.and_then(|ttl_secs| chrono::Utc::now().checked_add_signed(chrono::TimeDelta::seconds(ttl_secs.try_into().ok()?)));
+        if new_expires.is_none_or(|new_expires| new_expires > expires) {
+            return Err(Error::OutlivesToken)
+        }
+    }
 
-    Ok(axum::Json(state.tokens.create_token(args.level, args.user).await.expect("Cannot access DB")))
+    Ok(axum::Json(state.tokens.create_token(args.level, args.user, args.scopes, args.ttl_secs).await.expect("Cannot access DB")))
+}
+
+async fn token_rotate(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo
+) -> Result<axum::Json<Token>, Error> {
+    state.tokens.rotate_token(&token).await.expect("Cannot access DB")
+    .map_or(Err(Error::TokenInvalid), |token| Ok(axum::Json(token)))
 }
 
 async fn token_delete(
@@ -157,14 +1172,170 @@ async fn token_delete(
     token: TokenInfo,
     axum::extract::Json(args): axum::extract::Json<TokenDeleteArgs>
 ) -> Result<axum::Json<()>, Error> {
-    let target = args.token.unwrap_or(token.token);
-    // We only need perms to delete other tokens
-    if target != token.token && token.level < TokenLevel::ProxyAll {
+    let revoked = match args.id {
+        Some(id) => {
+            if token.level < TokenLevel::ProxyAll {
+                return Err(Error::TokenTooLowLevel);
+            }
+            state.tokens.delete_token_id(id).await
+        },
+        None => {
+            let target = args.token.unwrap_or(token.token);
+            // We only need perms to delete other tokens
+            if target != token.token && token.level < TokenLevel::ProxyAll {
+                return Err(Error::TokenTooLowLevel);
+            }
+            state.tokens.delete_token(&target).await
+        }
+    };
+    if !revoked.expect("Cannot access DB") {
+        return Err(Error::TokenInvalid);
+    }
+    Ok(axum::Json(()))
+}
+
+async fn token_submitter(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    axum::extract::Path(id): axum::extract::Path<u64>
+) -> Result<axum::Json<Option<ActionSubmitter>>, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    Ok(axum::Json(state.tokens.get_submitter(id).await.expect("Cannot access DB")))
+}
+
+async fn token_list(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo
+) -> Result<axum::Json<Vec<TokenUsage>>, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    Ok(axum::Json(state.tokens.list_tokens().await.expect("Cannot access DB")))
+}
+async fn webhooks_get(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo
+) -> Result<axum::Json<Vec<Webhook>>, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    Ok(axum::Json(state.webhooks.get_webhooks().await.expect("Cannot access DB")))
+}
+
+async fn webhooks_post(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    axum::extract::Json(args): axum::extract::Json<WebhookPostArgs>
+) -> Result<axum::Json<Webhook>, Error> {
+    if token.level < TokenLevel::ProxyAll {
+        return Err(Error::TokenTooLowLevel);
+    }
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    if !reqwest::Url::parse(&args.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+        return Err(Error::InvalidUrl);
+    }
+    Ok(axum::Json(state.webhooks.create_webhook(&token.user, args).await.expect("Cannot access DB")))
+}
+
+async fn webhooks_delete(
+    axum::extract::State(state): axum::extract::State<State>,
+    token: TokenInfo,
+    axum::extract::Path(id): axum::extract::Path<i64>
+) -> Result<axum::Json<()>, Error> {
+    if token.level < TokenLevel::ProxyAll {
         return Err(Error::TokenTooLowLevel);
     }
-    state.tokens.delete_token(&token.token).await
-    .map_or(Err(Error::TokenInvalid), |_| Ok(axum::Json(())))
+    if !token.allows(Scope::BankerAdmin) {
+        return Err(Error::OutOfScope);
+    }
+    if !state.webhooks.delete_webhook(id).await.expect("Cannot access DB") {
+        return Err(Error::WebhookInvalid);
+    }
+    Ok(axum::Json(()))
+}
+
+/// Build the TLS config for the endpoint from a PEM certificate chain and key
+fn load_tls(cert: &std::path::Path, key: &std::path::Path) -> tokio_rustls::TlsAcceptor {
+    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(cert).expect("Unable to read TLS certificate")
+        .collect::<Result<Vec<_>, _>>().expect("Unable to parse TLS certificate");
+    let key = PrivateKeyDer::from_pem_file(key).expect("Unable to read TLS key");
+    let mut config = rustls::ServerConfig::builder_with_provider(std::sync::Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions().expect("Unable to pick TLS versions")
+        .with_no_client_auth()
+        .with_single_cert(certs, key).expect("TLS key does not match certificate");
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config))
+}
+
+/// Serve a single connection that axum::serve can't take, such as one over TLS or a Unix socket
+///
+/// The peer's address is passed on where there is one, so token use can still be traced
+async fn serve_connection(io: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static, app: Router, remote: Option<std::net::SocketAddr>) {
+    use tower_service::Service;
+
+    let service = hyper::service::service_fn(move |mut request: hyper::Request<hyper::body::Incoming>| {
+        if let Some(remote) = remote {
+            request.extensions_mut().insert(axum::extract::ConnectInfo(remote));
+        }
+        app.clone().call(request)
+    });
+    // Clients hanging up part way through isn't our problem
+    let _ = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new())
+        .serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(io), service).await;
+}
+
+/// Gives each request a random id, unless the client sent its own
+#[derive(Clone, Copy)]
+struct MakeRequestId;
+impl tower_http::request_id::MakeRequestId for MakeRequestId {
+    fn make_request_id<B>(&mut self, _request: &axum::http::Request<B>) -> Option<tower_http::request_id::RequestId> {
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes).expect("Could not generate request id");
+        let id: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        Some(tower_http::request_id::RequestId::new(id.parse().expect("Request id is not a valid header")))
+    }
+}
+
+/// Make the request's id available to everything that handles it
+async fn scope_request_id(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let id = request.headers().get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    REQUEST_ID.scope(id, next.run(request)).await
 }
+
+/// Give every request an id, trace it under that id, and send the id back
+fn with_request_ids(app: Router) -> Router {
+    let header = axum::http::HeaderName::from_static(REQUEST_ID_HEADER);
+    let trace = tower_http::trace::TraceLayer::new_for_http()
+        .make_span_with(|request: &axum::http::Request<_>| {
+            let id = request.headers().get(REQUEST_ID_HEADER).and_then(|id| id.to_str().ok()).unwrap_or_default();
+            tracing::info_span!("request", method = %request.method(), uri = %request.uri(), request_id = id)
+        })
+        .on_response(tower_http::trace::DefaultOnResponse::new().level(tracing::Level::INFO));
+    // The last layer added is the first to see the request
+    app.layer(axum::middleware::from_fn(scope_request_id))
+        .layer(tower_http::request_id::PropagateRequestIdLayer::new(header.clone()))
+        .layer(trace)
+        .layer(tower_http::request_id::SetRequestIdLayer::new(header, MakeRequestId))
+}
+
 #[tokio::main]
 async fn main() {
     sqlx::any::install_default_drivers();
@@ -177,22 +1348,107 @@ async fn main() {
 
     let args = Args::parse();
 
-    let mut trade_file = tokio::fs::File::options().read(true).write(true).truncate(false).create(true).open(args.trades).await.expect("Unable to open trade list");
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()));
+    if args.log_json { logs.json().init() } else { logs.init() }
+
+    let mut trade_log = args.store.open(&args.trades).await.expect("Unable to open trade list");
+    let trade_file = trade_log.read_all().await.expect("Unable to read trade list");
     let mut tpex_state = tpex::State::new();
+    let mut asset_info = None;
     if let Some(asset_path) = args.assets {
         let mut assets = String::new();
         tokio::fs::File::open(asset_path).await.expect("Unable to open asset info")
         .read_to_string(&mut assets).await.expect("Unable to read asset list");
 
-        tpex_state.update_asset_info(serde_json::from_str(&assets).expect("Unable to parse asset info"))
+        asset_info = Some(serde_json::from_str(&assets).expect("Unable to parse asset info"));
+        tpex_state.update_asset_info(asset_info.clone().expect("Asset info disappeared"))
     }
-    tpex_state.replay(&mut trade_file).await.expect("Could not replay trades");
-
+    let initial_state = tpex_state.clone();
+    let mut snapshots: std::collections::BTreeMap<_, _> = [(initial_state.get_next_id(), initial_state.clone())].into_iter().collect();
+    let empty_candles = tpex::analytics::CandleAggregator::new(CANDLE_INTERVAL).expect("Invalid candle interval");
+    let mut candles = empty_candles.clone();
+    let mut events = tpex::analytics::EventLog::default();
+    // Carry on from the saved snapshot if we can, and fall back to replaying everything if it doesn't match the trade file.
+    // To restore from a backup, copy the trade log, then start with --restore pointing at the backup
+    let saved = match args.restore.as_ref() {
+        Some(path) => Some(read_snapshot(path).await.expect("Unable to load backup").expect("Backup not found")),
+        None => load_snapshot(args.snapshot.as_ref()).await
+    };
+    if let Some((mut saved_state, mut saved_candles, mut saved_events)) = saved {
+        // An empty trade log can't be checked against, and would otherwise carry on from the backup with none of its actions
+        if args.restore.is_some() && trade_file.is_empty() && saved_state.get_next_id() > 1 {
+            panic!("Backup is ahead of the trade log");
+        }
+        if let Some(asset_info) = asset_info {
+            saved_state.update_asset_info(asset_info);
+        }
+        let saved_copy = saved_state.clone();
+        let replayed = saved_state.replay_with(&mut trade_file.as_slice(), |time, action, outcome| {
+            saved_candles.observe(time, outcome);
+            saved_events.observe(time, action, outcome);
+        }).await;
+        match replayed {
+            Ok(()) => {
+                snapshots.insert(saved_copy.get_next_id(), saved_copy);
+                tpex_state = saved_state;
+                candles = saved_candles;
+                events = saved_events;
+            },
+            Err(err) if args.restore.is_some() => panic!("Backup does not match the trade log: {err}"),
+            Err(err) => {
+                let _ = writeln!(std::io::stderr(), "Ignoring snapshot: {err}");
+            }
+        }
+    }
+    let index = if tpex_state.get_next_id() == 1 {
+        candles = empty_candles;
+        events = Default::default();
+        let mut index = index::ActionIndex::default();
+        tpex_state.replay_involved(&mut trade_file.as_slice(), |time, action, outcome, players, assets| {
+            candles.observe(time, outcome);
+            events.observe(time, action, outcome);
+            index.record_applied(time, action, outcome, players, assets);
+        }).await.expect("Could not replay trades");
+        index
+    }
+    else {
+        // The snapshot doesn't say who the actions before it involved, so the index is rebuilt from the start
+        index::ActionIndex::build(initial_state, &trade_file).await.expect("Could not index trades")
+    };
     let token_handler = tokens::TokenHandler::new(&args.db).await.expect("Could not connect to DB");
+    let webhook_registry = webhooks::WebhookRegistry::new(token_handler.pool());
+    let (notify, notifications) = tokio::sync::mpsc::unbounded_channel();
+    let (subscribers, _) = tokio::sync::broadcast::channel(SUBSCRIBER_BACKLOG);
+
+    let reserves_key = match args.reserves_key {
+        Some(path) => {
+            let pkcs8 = tokio::fs::read(path).await.expect("Unable to read reserves key");
+            Some(ring::signature::Ed25519KeyPair::from_pkcs8(&pkcs8).expect("Unable to parse reserves key"))
+        },
+        None => None
+    };
+    let reserves = build_reserves(&tpex_state, reserves_key.as_ref());
+    let primary = match (args.follow.or(args.replica.clone()), args.follow_token) {
+        (Some(url), Some(token)) => Some(Primary {
+            remote: tpex_api::Remote::new(url.clone(), token),
+            client: reqwest::Client::new(),
+            replica: args.replica.is_some(),
+            url
+        }),
+        _ => None
+    };
+    let (synced, _) = tokio::sync::watch::channel(tpex_state.get_next_id() - 1);
 
     let state = StateStruct {
-        tpex: tokio::sync::RwLock::new(TPExState { state: tpex_state, file: trade_file }),
-        tokens: token_handler
+        tpex: tokio::sync::RwLock::new(TPExState { state: tpex_state, log: trade_log, durability: args.durability, candles, events, index, notify, subscribers, snapshots, snapshot_path: args.snapshot }),
+        tokens: token_handler,
+        webhooks: webhook_registry,
+        reserves: tokio::sync::RwLock::new(reserves),
+        reserves_key,
+        standby: primary.is_some().into(),
+        primary,
+        synced
     };
 
     let cors = tower_http::cors::CorsLayer::new()
@@ -200,18 +1456,152 @@ async fn main() {
         .allow_origin(tower_http::cors::Any)
         .allow_methods(tower_http::cors::Any);
 
+    let state = std::sync::Arc::new(state);
+
+    tokio::spawn({
+        let state = state.clone();
+        async move { webhooks::deliver(&state.webhooks, notifications).await }
+    });
+    // Materialise scheduled actions into the log once they're due, and clean up after expired proposals
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                // Standbys get these from the primary
+                if state.standby.load(std::sync::atomic::Ordering::SeqCst) {
+                    continue;
+                }
+                run_scheduled(&state).await;
+                prune_proposals(&state).await;
+            }
+        }
+    });
+    // Keep up with the primary until promoted
+    if state.primary.is_some() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FOLLOW_INTERVAL);
+            while state.standby.load(std::sync::atomic::Ordering::SeqCst) {
+                interval.tick().await;
+                follow_primary(&state).await;
+            }
+        });
+    }
+    // Sync the trade log for everything waiting on group commit
+    if args.durability == store::Durability::Group {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(args.group_commit_ms.max(1)));
+            loop {
+                interval.tick().await;
+                group_commit(&state).await;
+            }
+        });
+    }
+    // Keep the published reserves report fresh
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            let mut interval = tokio::time::interval(RESERVES_INTERVAL);
+            // The first tick is immediate, and we already have a fresh report
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                publish_reserves(&state).await;
+            }
+        }
+    });
+
+    // Read replicas pass actions on instead of applying them
+    let (patch_handler, batch_handler) =
+        if state.primary.as_ref().is_some_and(|primary| primary.replica) { (axum::routing::patch(state_patch_replica), axum::routing::patch(state_patch_replica)) }
+        else { (axum::routing::patch(state_patch), axum::routing::patch(state_patch_batch)) };
     let app = Router::new()
         .route("/state", axum::routing::get(state_get))
-        .route("/state", axum::routing::patch(state_patch))
+        .route("/state", patch_handler)
+        .route("/state/batch", batch_handler)
+        .route("/state/at/:id", axum::routing::get(state_at))
+        .route("/state/sse", axum::routing::get(state_sse))
+        .route("/events", axum::routing::get(events_get))
+        .route("/actions", axum::routing::get(actions_get))
+        .route("/simulate", axum::routing::post(simulate_post))
+
+        .route("/inspect/candles", axum::routing::get(inspect_candles))
+        .route("/inspect/statement", axum::routing::get(inspect_statement))
+        .route("/inspect/reserves", axum::routing::get(inspect_reserves))
+        .route("/inspect/audit", axum::routing::get(inspect_audit))
+        .route("/inspect/actions", axum::routing::get(inspect_actions))
+        .route("/inspect/orders", axum::routing::get(inspect_orders))
+        .route("/inspect/withdrawals", axum::routing::get(inspect_withdrawals))
+        .route("/inspect/prices", axum::routing::get(inspect_prices))
+        .route("/inspect/restricted", axum::routing::get(inspect_restricted))
+        .route("/inspect/banker-queue", axum::routing::get(inspect_banker_queue))
+
+        .route("/replication/promote", axum::routing::post(replication_promote))
+
+        .route("/healthz", axum::routing::get(healthz))
+        .route("/readyz", axum::routing::get(readyz))
+        .route("/admin/hard_audit", axum::routing::get(admin_hard_audit))
+        .route("/admin/backup", axum::routing::get(admin_backup))
 
         .route("/token", axum::routing::get(token_get))
         .route("/token", axum::routing::post(token_post))
         .route("/token", axum::routing::delete(token_delete))
+        .route("/token/rotate", axum::routing::post(token_rotate))
+        .route("/token/list", axum::routing::get(token_list))
+        .route("/token/submitter/:id", axum::routing::get(token_submitter))
+
+        .route("/webhooks", axum::routing::get(webhooks_get))
+        .route("/webhooks", axum::routing::post(webhooks_post))
+        .route("/webhooks/:id", axum::routing::delete(webhooks_delete))
 
-        .with_state(std::sync::Arc::new(state))
+        .with_state(state.clone());
+    #[cfg(feature = "grpc")]
+    let app = app.merge(grpc::router(state));
+    let app = with_request_ids(app.route_layer(cors));
 
-        .route_layer(cors);
+    if let Some(path) = args.unix {
+        // A socket left behind by an earlier run would stop us binding, but anything else there isn't ours to remove
+        use std::os::unix::fs::FileTypeExt;
+        if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(&path).expect("Could not remove old Unix socket");
+        }
+        let listener = tokio::net::UnixListener::bind(path).expect("Could not bind to Unix socket");
+        let app = app.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => { tokio::spawn(serve_connection(stream, app.clone(), None)); },
+                    Err(err) => { let _ = writeln!(std::io::stderr(), "Could not accept Unix connection: {err}"); }
+                }
+            }
+        });
+    }
 
     let listener = tokio::net::TcpListener::bind(args.endpoint).await.expect("Could not bind to endpoint");
-    axum::serve(listener, app).await.unwrap();
+    let Some((cert, key)) = args.tls_cert.zip(args.tls_key)
+    else {
+        // Connection info lets token use be traced back to where it came from
+        axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await.unwrap();
+        return;
+    };
+    let acceptor = load_tls(&cert, &key);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                let _ = writeln!(std::io::stderr(), "Could not accept connection: {err}");
+                continue;
+            }
+        };
+        let (acceptor, app) = (acceptor.clone(), app.clone());
+        // Do the handshake on its own task, so that a slow client doesn't hold up everyone else
+        tokio::spawn(async move {
+            if let Ok(stream) = acceptor.accept(stream).await {
+                serve_connection(stream, app, Some(remote)).await;
+            }
+        });
+    }
 }
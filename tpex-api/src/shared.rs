@@ -2,7 +2,7 @@ use std::{fmt::Display, str::FromStr};
 
 use num_traits::FromPrimitive;
 use serde::{de::Visitor, Deserialize, Serialize};
-use tpex::PlayerId;
+use tpex::{AssetId, PlayerId};
 use base64::prelude::*;
 
 #[repr(u8)]
@@ -35,13 +35,26 @@ impl<'de> Deserialize<'de> for TokenLevel {
 
             fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
             where E: serde::de::Error, {
-                TokenLevel::from_u64(v).ok_or(E::invalid_value(serde::de::Unexpected::Unsigned(v as u64), &Self))
+                TokenLevel::from_u64(v).ok_or(E::invalid_value(serde::de::Unexpected::Unsigned(v), &Self))
             }
         }
         deserializer.deserialize_u64(Inner)
     }
 }
 
+/// The header holding the base64 Ed25519 signature of a submitted action's body, which is then a tpex::SignedAction
+pub const SIGNATURE_HEADER: &str = "x-tpex-signature";
+/// The header holding the base64 public key a submitted action was signed with
+pub const PUBLIC_KEY_HEADER: &str = "x-tpex-public-key";
+/// The header holding the id to ask for next, when a page of the trade log stops before the end
+pub const NEXT_HEADER: &str = "x-tpex-next";
+/// The header holding the hash of the trade log that a backup was taken at, which the trade log restored alongside it must match
+pub const LOG_DIGEST_HEADER: &str = "x-tpex-log-digest";
+/// The header holding a client-chosen key for an action, so that retrying it gives back the first outcome instead of applying it twice
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// The media type to send or ask for instead of JSON, for smaller payloads that are quicker to parse
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct Token(pub [u8;16]);
 impl Token {
@@ -101,33 +114,348 @@ impl<'de> Deserialize<'de> for Token {
     }
 }
 
+/// Something a token can be limited to, within what its level already allows
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum Scope {
+    /// Reading anything
+    #[serde(rename = "read:state")]
+    ReadState,
+    /// Placing and cancelling orders
+    #[serde(rename = "write:orders")]
+    WriteOrders,
+    /// Moving coins and items to other players, directly or by escrow or loan
+    #[serde(rename = "write:transfers")]
+    WriteTransfers,
+    /// Taking items out of the exchange
+    #[serde(rename = "write:withdrawals")]
+    WriteWithdrawals,
+    /// Every other action a player takes for themselves
+    #[serde(rename = "write:account")]
+    WriteAccount,
+    /// Depositing and undepositing items for players
+    #[serde(rename = "banker:deposits")]
+    BankerDeposits,
+    /// Gathering players' withdrawals
+    #[serde(rename = "banker:withdrawals")]
+    BankerWithdrawals,
+    /// Every other banker action, and running the server
+    #[serde(rename = "banker:admin")]
+    BankerAdmin
+}
+impl Scope {
+    /// The scope needed to submit an action, which needs the given level
+    pub fn for_action(action: &tpex::Action, level: tpex::ActionLevel) -> Scope {
+        use tpex::Action;
+        match action {
+            Action::Schedule { action, .. } |
+            Action::Propose { action, .. } => Scope::for_action(action, level),
+            Action::BuyOrder { .. } |
+            Action::SellOrder { .. } |
+            Action::CancelOrder { .. } => Scope::WriteOrders,
+            Action::TransferCoins { .. } |
+            Action::TransferAsset { .. } |
+            Action::OfferEscrow { .. } |
+            Action::AcceptEscrow { .. } |
+            Action::CancelEscrow { .. } |
+            Action::OfferLoan { .. } |
+            Action::AcceptLoan { .. } |
+            Action::RepayLoan { .. } |
+            Action::LiquidateCollateral { .. } |
+            Action::CancelLoan { .. } => Scope::WriteTransfers,
+            Action::WithdrawalRequested { .. } |
+            Action::Expedited { .. } |
+            Action::RequestAuthorisation { .. } => Scope::WriteWithdrawals,
+            Action::Deposit { .. } |
+            Action::Undeposit { .. } => Scope::BankerDeposits,
+            Action::WithdrawalCompleted { .. } |
+            Action::ClaimWithdrawal { .. } |
+            Action::UnclaimWithdrawal { .. } |
+            Action::ExpireWithdrawals { .. } => Scope::BankerWithdrawals,
+            _ if level == tpex::ActionLevel::Banker => Scope::BankerAdmin,
+            _ => Scope::WriteAccount
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct TokenInfo {
     pub token: Token,
     pub user: PlayerId,
-    pub level: TokenLevel
+    pub level: TokenLevel,
+    /// What the token is limited to, if anything
+    #[serde(default)]
+    pub scopes: Option<std::collections::BTreeSet<Scope>>,
+    /// When the token stops working, if ever
+    #[serde(default)]
+    pub expires: Option<chrono::DateTime<chrono::Utc>>
+}
+impl TokenInfo {
+    /// Returns true if the token isn't kept out of the given scope
+    pub fn allows(&self, scope: Scope) -> bool { self.scopes.as_ref().is_none_or(|scopes| scopes.contains(&scope)) }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct TokenPostArgs {
     pub level: TokenLevel,
-    pub user: PlayerId
+    pub user: PlayerId,
+    /// What the new token is limited to. A limited token can only make tokens limited to the same or less
+    #[serde(default)]
+    pub scopes: Option<std::collections::BTreeSet<Scope>>,
+    /// How long the new token lasts, which is renewed each time it is rotated. A token that expires can only make tokens that expire no later
+    #[serde(default)]
+    pub ttl_secs: Option<u64>
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct TokenDeleteArgs {
-    pub token: Option<Token>
+    pub token: Option<Token>,
+    /// Revoke the token with this id from /token/list instead
+    #[serde(default)]
+    pub id: Option<i64>
+}
+
+/// Which token submitted an action
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ActionSubmitter {
+    pub action_id: u64,
+    /// The token's id from /token/list
+    pub token_id: i64,
+    /// The player the token belonged to
+    pub user: PlayerId,
+    /// The id of the request that applied it, to match against the server's logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>
+}
+
+/// How a token has been used, for finding stale or abused tokens
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TokenUsage {
+    /// Identifies the token without giving it away
+    pub id: i64,
+    pub user: PlayerId,
+    pub level: TokenLevel,
+    pub scopes: Option<std::collections::BTreeSet<Scope>>,
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+    pub revoked: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_used: Option<chrono::DateTime<chrono::Utc>>,
+    /// The address the token was last used from
+    pub last_ip: Option<String>,
+    /// How many requests the token has been used for
+    pub uses: u64,
+    /// How many actions have been applied with the token
+    pub actions: u64
 }
 
 #[derive(Default)]
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct StateGetArgs {
+    pub from: Option<u64>,
+    /// The last action to include. Defaults to the latest action
+    pub to: Option<u64>,
+    /// The most actions to include, counted before any filtering
+    pub limit: Option<u64>,
+    /// Only include actions involving this player
+    pub player: Option<PlayerId>,
+    /// Only include actions involving this item
+    pub asset: Option<AssetId>
+}
+
+#[derive(Default)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StatePatchArgs {
+    /// Reply with everything the action did, rather than just its id as older clients expect
+    #[serde(default)]
+    pub outcome: bool
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct WebhookPostArgs {
+    pub url: String,
+    /// Only send actions involving this player
+    pub player: Option<PlayerId>,
+    /// Only send actions involving this item
+    pub asset: Option<AssetId>
+}
+
+/// A URL that new actions are POSTed to
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Webhook {
+    pub id: i64,
+    /// The banker who registered the webhook
+    pub owner: PlayerId,
+    pub url: String,
+    pub player: Option<PlayerId>,
+    pub asset: Option<AssetId>
+}
+
+#[derive(Default)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct EventsGetArgs {
+    /// The first action to include events from. Defaults to the earliest remembered
     pub from: Option<u64>
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CandlesGetArgs {
+    pub asset: AssetId,
+    /// The length of each candle, which must be a whole number of minutes. Defaults to one minute
+    pub interval_secs: Option<u64>
+}
+
+#[derive(Default, Clone, Copy)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    #[default]
+    Json,
+    Csv
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StatementGetArgs {
+    pub player: PlayerId,
+    /// The first action to include. Defaults to the first action
+    pub from: Option<u64>,
+    /// The last action to include. Defaults to the latest action
+    pub to: Option<u64>,
+    pub format: Option<StatementFormat>
+}
+
+#[derive(Default)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct OrdersGetArgs {
+    /// Only include this player's orders
+    pub player: Option<PlayerId>
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PricesGetArgs {
+    pub asset: AssetId
+}
+
+/// How much is bid and offered for an item at each price
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Prices {
+    pub buy: std::collections::BTreeMap<tpex::Coins, u64>,
+    pub sell: std::collections::BTreeMap<tpex::Coins, u64>
+}
+
+#[derive(Default)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ActionsSearchArgs {
+    /// Only include actions involving this player
+    pub player: Option<PlayerId>,
+    /// Only include actions involving this item
+    pub asset: Option<AssetId>,
+    /// Only include actions of this type, such as "Undeposit"
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// Only include actions applied at or after this time
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only include actions applied before this time
+    pub to: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ActionsGetArgs {
+    pub player: PlayerId
+}
+
+/// A reserves report, and the bankers' signature over it
+#[derive(Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SignedReservesReport {
+    /// The report as JSON text, exactly as it was signed
+    pub report: String,
+    /// The base64 Ed25519 signature of the report, if the server has a signing key
+    pub signature: Option<String>,
+    /// The base64 public key to check the signature with
+    pub public_key: Option<String>
+}
+
+/// A withdrawal waiting for a banker, and how long it has waited
+#[derive(Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct QueuedWithdrawal {
+    #[serde(flatten)]
+    pub withdrawal: tpex::PendingWithdrawal,
+    /// How long ago the player asked for the withdrawal
+    pub age_secs: u64,
+    /// The restricted items in the withdrawal
+    pub restricted: std::collections::BTreeSet<AssetId>
+}
+
+/// Everything waiting on the bankers
+#[derive(Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BankerQueue {
+    /// Pending withdrawals, oldest first
+    pub withdrawals: Vec<QueuedWithdrawal>,
+    pub authorisation_requests: std::collections::BTreeMap<u64, tpex::AuthorisationRequest>,
+    /// Proposals waiting for a second banker
+    pub proposals: std::collections::BTreeMap<u64, tpex::Proposal>,
+    /// Items that can only be withdrawn with a banker's authorisation
+    pub restricted: std::collections::BTreeSet<AssetId>
+}
+
+/// The result of recalculating everything the exchange holds from scratch
+#[derive(Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HardAuditReport {
+    /// What the running audit says the exchange holds
+    pub expected: tpex::Audit,
+    /// What the trackers were recalculated to hold, if each was internally consistent
+    pub actual: Option<tpex::Audit>,
+    /// Everything found to be inconsistent. Empty if the audit passed
+    pub discrepancies: Vec<String>
+}
+
+/// What kind of failure a request hit, so clients can act on it without reading the message
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The exchange refused the action, for the reason in [`ErrorInfo::tpex`]
+    Rejected,
+    UncontrolledUser,
+    TokenTooLowLevel,
+    TokenInvalid,
+    MalformedAction,
+    NotIndexed,
+    Standby,
+    Replica,
+    PrimaryUnreachable,
+    InvalidUrl,
+    WebhookInvalid,
+    OutOfScope,
+    OutlivesToken,
+    TooManyActions,
+    DatabaseUnreachable,
+    /// A failure without a code, or with one this client is too old to know
+    #[default]
+    #[serde(other)]
+    Unknown
+}
+
 #[derive(Default, Debug)]
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct ErrorInfo {
-    pub error: String
+    pub error: String,
+    #[serde(default)]
+    pub code: ErrorCode,
+    /// Why the exchange refused the action, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tpex: Option<tpex::Error>,
+    /// Which action of a batch failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_index: Option<usize>,
+    /// The id of the failed request, to match against the server's logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>
 }
@@ -0,0 +1,144 @@
+use axum::async_trait;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// A newly applied action, to be added to the log
+pub struct LogEntry<'a> {
+    pub id: u64,
+    /// The players the action involved, for stores that can look actions up by player
+    pub players: &'a std::collections::BTreeSet<tpex::PlayerId>,
+    /// The action exactly as written by the state
+    pub data: &'a [u8]
+}
+
+/// Somewhere to keep the trade log, which is only ever added to
+#[async_trait]
+pub trait TradeLogStore: Send + Sync {
+    /// Add a newly applied action to the end of the log
+    async fn append(&mut self, entry: LogEntry<'_>) -> std::io::Result<()>;
+    /// Read back the whole log, as it would appear in a trade file
+    async fn read_all(&mut self) -> std::io::Result<Vec<u8>>;
+    /// Read back the actions involving a player, in order, or None if this store can't look them up
+    async fn read_involving(&mut self, _player: &tpex::PlayerId) -> std::io::Result<Option<Vec<u8>>> { Ok(None) }
+    /// Make sure everything appended so far would survive a power cut
+    async fn sync(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+/// When appends to the trade log are forced all the way to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Durability {
+    /// Leave it to the operating system, so a power cut can lose the latest actions
+    Buffered,
+    /// Sync after every action, before replying to it
+    Fsync,
+    /// Sync every few milliseconds, replying to each action once a sync has covered it
+    Group
+}
+
+/// Which kind of store to keep the trade log in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StoreKind {
+    /// A plain trade file, which can be read and edited by hand
+    File,
+    /// A SQLite database, with each action in its own row, indexed by the players it involves
+    Sqlite,
+    /// Nowhere: the log is lost when the server stops, which is only useful for testing
+    Memory
+}
+impl StoreKind {
+    /// Open a store of this kind at the given path
+    pub async fn open(self, path: &std::path::Path) -> std::io::Result<Box<dyn TradeLogStore>> {
+        Ok(match self {
+            StoreKind::File => Box::new(FileStore::new(path).await?),
+            StoreKind::Sqlite => Box::new(SqliteStore::new(path).await.map_err(std::io::Error::other)?),
+            StoreKind::Memory => Box::new(MemoryStore::default())
+        })
+    }
+}
+
+pub struct FileStore {
+    file: tokio::fs::File
+}
+impl FileStore {
+    pub async fn new(path: &std::path::Path) -> std::io::Result<FileStore> {
+        let file = tokio::fs::File::options().read(true).write(true).truncate(false).create(true).open(path).await?;
+        Ok(FileStore { file })
+    }
+}
+#[async_trait]
+impl TradeLogStore for FileStore {
+    async fn append(&mut self, entry: LogEntry<'_>) -> std::io::Result<()> {
+        self.file.seek(std::io::SeekFrom::End(0)).await?;
+        self.file.write_all(entry.data).await?;
+        self.file.flush().await
+    }
+    async fn read_all(&mut self) -> std::io::Result<Vec<u8>> {
+        self.file.rewind().await?;
+        let mut buf = Vec::new();
+        // This will seek to the end again, ready for the next append
+        self.file.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+    async fn sync(&mut self) -> std::io::Result<()> { self.file.sync_data().await }
+}
+
+/// Keeps the trade log in its own database
+///
+/// The query! macros can only check against the tokens' database, so the queries here are checked at runtime
+pub struct SqliteStore {
+    pool: sqlx::SqlitePool
+}
+impl SqliteStore {
+    pub async fn new(path: &std::path::Path) -> sqlx::Result<SqliteStore> {
+        let opt = sqlx::sqlite::SqliteConnectOptions::new().filename(path).create_if_missing(true);
+        let ret = SqliteStore {
+            pool: sqlx::SqlitePool::connect_with(opt).await?
+        };
+
+        sqlx::migrate!("../migrations/trade-log").run(&ret.pool).await?;
+
+        Ok(ret)
+    }
+}
+#[async_trait]
+impl TradeLogStore for SqliteStore {
+    async fn append(&mut self, entry: LogEntry<'_>) -> std::io::Result<()> {
+        // The action and its index go in together, so neither can be seen without the other
+        let id: i64 = entry.id.try_into().map_err(std::io::Error::other)?;
+        let mut transaction = self.pool.begin().await.map_err(std::io::Error::other)?;
+        sqlx::query(r#"INSERT INTO trade_log(action_id, data) VALUES (?, ?)"#).bind(id).bind(entry.data)
+        .execute(&mut *transaction).await.map_err(std::io::Error::other)?;
+        for player in entry.players {
+            #[allow(deprecated)]
+            let player = player.evil_deref();
+            sqlx::query(r#"INSERT OR IGNORE INTO trade_log_players(player, action_id) VALUES (?, ?)"#).bind(player).bind(id)
+            .execute(&mut *transaction).await.map_err(std::io::Error::other)?;
+        }
+        transaction.commit().await.map_err(std::io::Error::other)
+    }
+    async fn read_all(&mut self) -> std::io::Result<Vec<u8>> {
+        let rows: Vec<(Vec<u8>,)> = sqlx::query_as(r#"SELECT data FROM trade_log ORDER BY seq"#)
+        .fetch_all(&self.pool).await.map_err(std::io::Error::other)?;
+        Ok(rows.into_iter().flat_map(|(data,)| data).collect())
+    }
+    async fn read_involving(&mut self, player: &tpex::PlayerId) -> std::io::Result<Option<Vec<u8>>> {
+        #[allow(deprecated)]
+        let player = player.evil_deref();
+        let rows: Vec<(Vec<u8>,)> = sqlx::query_as(r#"SELECT data FROM trade_log_players JOIN trade_log USING (action_id) WHERE player = ? ORDER BY seq"#)
+        .bind(player)
+        .fetch_all(&self.pool).await.map_err(std::io::Error::other)?;
+        Ok(Some(rows.into_iter().flat_map(|(data,)| data).collect()))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Vec<u8>
+}
+#[async_trait]
+impl TradeLogStore for MemoryStore {
+    async fn append(&mut self, entry: LogEntry<'_>) -> std::io::Result<()> {
+        self.data.extend_from_slice(entry.data);
+        Ok(())
+    }
+    async fn read_all(&mut self) -> std::io::Result<Vec<u8>> { Ok(self.data.clone()) }
+}
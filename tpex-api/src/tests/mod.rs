@@ -7,3 +7,656 @@ async fn token_level_deser() {
         Err(e) => panic!("{e}")
     }
 }
+
+#[tokio::test]
+async fn trade_log_stores() {
+    use crate::store::{LogEntry, StoreKind};
+
+    let dir = std::env::temp_dir().join(format!("tpex-store-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Could not create test dir");
+    #[allow(deprecated)]
+    let (alice, bob) = (tpex::PlayerId::evil_constructor("alice".to_owned()), tpex::PlayerId::evil_constructor("bob".to_owned()));
+    let entries: [(u64, std::collections::BTreeSet<_>, &[u8]); 3] = [
+        (1, [alice.clone()].into(), b"{\"id\":1}\n"),
+        (2, [alice.clone(), bob.clone()].into(), b"{\"id\":2}\n"),
+        (3, [bob.clone()].into(), b"{\"id\":3}\n")
+    ];
+    for (kind, persistent, indexed) in [(StoreKind::File, true, false), (StoreKind::Sqlite, true, true), (StoreKind::Memory, false, false)] {
+        let path = dir.join(format!("{kind:?}"));
+        let mut store = kind.open(&path).await.expect("Could not open store");
+        assert_eq!(store.read_all().await.expect("Read failed"), b"");
+        for (id, players, data) in &entries[..2] {
+            store.append(LogEntry { id: *id, players, data }).await.expect("Append failed");
+        }
+        assert_eq!(store.read_all().await.expect("Read failed"), b"{\"id\":1}\n{\"id\":2}\n");
+        // Reading doesn't get in the way of appending
+        let (id, players, data) = &entries[2];
+        store.append(LogEntry { id: *id, players, data }).await.expect("Append failed");
+        drop(store);
+
+        let expected: &[u8] = if persistent { b"{\"id\":1}\n{\"id\":2}\n{\"id\":3}\n" } else { b"" };
+        let mut reopened = kind.open(&path).await.expect("Could not reopen store");
+        assert_eq!(reopened.read_all().await.expect("Read failed"), expected, "{kind:?}");
+        let involving = reopened.read_involving(&bob).await.expect("Read failed");
+        if indexed {
+            assert_eq!(involving.expect("Store not indexed"), b"{\"id\":2}\n{\"id\":3}\n");
+        }
+        else {
+            assert_eq!(involving, None);
+        }
+    }
+    std::fs::remove_dir_all(&dir).expect("Could not clean up test dir");
+}
+
+#[test]
+fn state_pages() {
+    use crate::{page_lines, StateGetArgs};
+
+    let data = b"1\n2\n3\n4\n5\n";
+    let page = |from, to, limit| page_lines(data, &StateGetArgs { from, to, limit, ..Default::default() });
+    assert_eq!(page(None, None, None), (data.to_vec(), None));
+    assert_eq!(page(Some(3), None, None), (b"3\n4\n5\n".to_vec(), None));
+    assert_eq!(page(Some(2), None, Some(2)), (b"2\n3\n".to_vec(), Some(4)));
+    assert_eq!(page(Some(4), None, Some(2)), (b"4\n5\n".to_vec(), None));
+    // A page that reaches the requested end doesn't point any further
+    assert_eq!(page(Some(1), Some(3), Some(2)), (b"1\n2\n".to_vec(), Some(3)));
+    assert_eq!(page(Some(3), Some(3), Some(2)), (b"3\n".to_vec(), None));
+    assert_eq!(page(Some(9), None, Some(2)), (Vec::new(), None));
+}
+
+#[test]
+fn gzip_negotiation() {
+    use std::io::Read;
+
+    let headers = |accept: &str| [(axum::http::header::ACCEPT_ENCODING, accept.parse().expect("Bad header"))].into_iter().collect::<axum::http::HeaderMap>();
+    assert!(crate::accepts_gzip(&headers("gzip")));
+    assert!(crate::accepts_gzip(&headers("br, GZIP;q=0.5")));
+    assert!(crate::accepts_gzip(&headers("*")));
+    assert!(!crate::accepts_gzip(&headers("gzip;q=0, br")));
+    assert!(!crate::accepts_gzip(&headers("identity")));
+    assert!(!crate::accepts_gzip(&axum::http::HeaderMap::new()));
+
+    // Small bodies go out as they are
+    let small = crate::encode_body(&headers("gzip"), axum::response::Response::builder(), b"1\n".to_vec());
+    assert_eq!(small.headers().get(axum::http::header::CONTENT_ENCODING), None);
+    let body = b"{\"id\":1}\n".repeat(1000);
+    let big = crate::encode_body(&headers("gzip"), axum::response::Response::builder(), body.clone());
+    assert_eq!(big.headers().get(axum::http::header::CONTENT_ENCODING).expect("Not compressed"), "gzip");
+    let compressed = futures_bytes(big);
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(compressed.as_slice()).read_to_end(&mut decompressed).expect("Bad gzip");
+    assert_eq!(decompressed, body);
+}
+
+fn futures_bytes(response: axum::response::Response) -> Vec<u8> {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().expect("Could not start runtime");
+    runtime.block_on(axum::body::to_bytes(response.into_body(), usize::MAX)).expect("Could not read body").to_vec()
+}
+
+#[test]
+fn etags() {
+    let headers = |tags: &str| [(axum::http::header::IF_NONE_MATCH, tags.parse().expect("Bad header"))].into_iter().collect::<axum::http::HeaderMap>();
+    assert!(crate::etag_matches(&headers("W/\"5\""), "W/\"5\""));
+    assert!(crate::etag_matches(&headers("\"4\", \"5\""), "W/\"5\""));
+    assert!(crate::etag_matches(&headers("*"), "W/\"5\""));
+    assert!(!crate::etag_matches(&headers("W/\"4\""), "W/\"5\""));
+    assert!(!crate::etag_matches(&axum::http::HeaderMap::new(), "W/\"5\""));
+}
+
+#[tokio::test]
+async fn state_filters() {
+    use crate::{filter_lines, StateGetArgs};
+    use tpex::{Action, Coins, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let (alice, bob) = (PlayerId::evil_constructor("alice".to_owned()), PlayerId::evil_constructor("bob".to_owned()));
+    let mut state = tpex::State::new();
+    let mut data = Vec::new();
+    for action in [
+        Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::SellOrder { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, coins_per: Coins::from_coins(1), display_count: None },
+        Action::Deposit { player: bob.clone(), asset: "cobblestone".to_owned(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::Deposit { player: bob.clone(), asset: DIAMOND_NAME.to_owned(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::BuyCoins { player: bob.clone(), n_diamonds: 1 },
+        Action::BuyOrder { player: bob.clone(), asset: DIAMOND_NAME.to_owned(), count: 2, coins_per: Coins::from_coins(1), display_count: None }
+    ] {
+        state.apply(action, &mut data).await.expect("Action failed");
+    }
+    let lines: Vec<&[u8]> = data.split_inclusive(|byte| *byte == b'\n').collect();
+    let filter = |player: Option<&PlayerId>, asset: Option<&str>| filter_lines(&state, &data, &StateGetArgs { player: player.cloned(), asset: asset.map(str::to_owned), ..Default::default() });
+
+    // Alice is only named by her own actions, but her order was matched by Bob's
+    assert_eq!(filter(Some(&alice), None), [lines[0], lines[1], lines[5]].concat());
+    assert_eq!(filter(None, Some("cobblestone")), lines[2]);
+    assert_eq!(filter(Some(&bob), Some(DIAMOND_NAME)), [lines[3], lines[4], lines[5]].concat());
+    assert_eq!(filter(Some(&alice), Some("cobblestone")), b"");
+}
+
+#[tokio::test]
+async fn webhook_registry() {
+    use crate::webhooks::{matches, Notification, WebhookRegistry};
+    use crate::WebhookPostArgs;
+
+    let path = std::env::temp_dir().join(format!("tpex-webhook-test-{}.db", std::process::id()));
+    let tokens = crate::tokens::TokenHandler::new(&format!("sqlite://{}", path.display())).await.expect("Could not open DB");
+    let registry = WebhookRegistry::new(tokens.pool());
+    #[allow(deprecated)]
+    let (alice, bob) = (tpex::PlayerId::evil_constructor("alice".to_owned()), tpex::PlayerId::evil_constructor("bob".to_owned()));
+
+    let everything = registry.create_webhook(&alice, WebhookPostArgs { url: "http://example.com/all".to_owned(), player: None, asset: None }).await.expect("Create failed");
+    let bobs = registry.create_webhook(&alice, WebhookPostArgs { url: "http://example.com/bob".to_owned(), player: Some(bob.clone()), asset: Some("cobblestone".to_owned()) }).await.expect("Create failed");
+    assert_eq!(registry.get_webhooks().await.expect("List failed"), [everything.clone(), bobs.clone()]);
+
+    let notification = |player: &tpex::PlayerId, asset: &str| Notification { data: Vec::new(), players: [player.clone()].into(), assets: [asset.to_owned()].into() };
+    assert!(matches(&everything, &notification(&alice, "stone")));
+    assert!(matches(&bobs, &notification(&bob, "cobblestone")));
+    assert!(!matches(&bobs, &notification(&alice, "cobblestone")));
+    assert!(!matches(&bobs, &notification(&bob, "stone")));
+
+    assert!(registry.delete_webhook(everything.id).await.expect("Delete failed"));
+    assert!(!registry.delete_webhook(everything.id).await.expect("Delete failed"));
+    assert_eq!(registry.get_webhooks().await.expect("List failed"), [bobs]);
+    std::fs::remove_file(&path).expect("Could not clean up test DB");
+}
+
+#[tokio::test]
+async fn own_cancellations_notified() {
+    use tpex::{Action, Coins, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let (notify, _notifications) = tokio::sync::mpsc::unbounded_channel();
+    let (subscribers, mut stream) = tokio::sync::broadcast::channel(16);
+    let mut tpex = crate::TPExState {
+        state: tpex::State::new(),
+        log: Box::new(crate::store::MemoryStore::default()),
+        durability: crate::store::Durability::Buffered,
+        candles: tpex::analytics::CandleAggregator::new(crate::CANDLE_INTERVAL).expect("Invalid candle interval"),
+        events: Default::default(),
+        index: Default::default(),
+        notify,
+        subscribers,
+        snapshots: Default::default(),
+        snapshot_path: None
+    };
+    tpex.apply(Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None }).await.expect("Deposit failed");
+    let order = tpex.apply(Action::SellOrder { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, coins_per: Coins::from_coins(1), display_count: None }).await.expect("Sell order failed").id;
+    let cancel = tpex.apply(Action::CancelOrder { target: order }).await.expect("Cancel failed").id;
+
+    // The order is gone once the cancel is applied, but it was still hers
+    let mut last = None;
+    while let Ok(notification) = stream.try_recv() { last = Some(notification); }
+    assert!(last.expect("Nothing was streamed").matches(Some(&alice), None));
+    let search = tpex.index.search(&crate::ActionsSearchArgs { player: Some(alice), ..Default::default() });
+    assert_eq!(search, [1, order, cancel]);
+}
+
+#[tokio::test]
+async fn token_scopes() {
+    use crate::{Scope, TokenLevel};
+    use tpex::{Action, ActionLevel, Coins, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let (alice, bob) = (PlayerId::evil_constructor("alice".to_owned()), PlayerId::evil_constructor("bob".to_owned()));
+    let order = Action::BuyOrder { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 1, coins_per: Coins::from_coins(1), display_count: None };
+    assert_eq!(Scope::for_action(&order, ActionLevel::Normal), Scope::WriteOrders);
+    assert_eq!(Scope::for_action(&Action::TransferCoins { payer: alice.clone(), payee: bob.clone(), count: Coins::from_coins(1) }, ActionLevel::Normal), Scope::WriteTransfers);
+    assert_eq!(Scope::for_action(&Action::Schedule { at: chrono::Utc::now(), action: Box::new(order) }, ActionLevel::Normal), Scope::WriteOrders);
+    assert_eq!(Scope::for_action(&Action::BuyCoins { player: alice.clone(), n_diamonds: 1 }, ActionLevel::Normal), Scope::WriteAccount);
+    assert_eq!(Scope::for_action(&Action::UpdateBankers { bankers: vec![alice.clone()], banker: alice.clone() }, ActionLevel::Banker), Scope::BankerAdmin);
+
+    let path = std::env::temp_dir().join(format!("tpex-scope-test-{}.db", std::process::id()));
+    let tokens = crate::tokens::TokenHandler::new(&format!("sqlite://{}", path.display())).await.expect("Could not open DB");
+    let unlimited = tokens.create_token(TokenLevel::ProxyOne, alice.clone(), None, None).await.expect("Create failed");
+    let market_maker = tokens.create_token(TokenLevel::ProxyOne, alice.clone(), Some([Scope::ReadState, Scope::WriteOrders].into()), None).await.expect("Create failed");
+    let unlimited = tokens.get_token(&unlimited).await.expect("Get failed");
+    let market_maker = tokens.get_token(&market_maker).await.expect("Get failed");
+    assert!(unlimited.allows(Scope::WriteTransfers));
+    assert!(market_maker.allows(Scope::WriteOrders));
+    assert!(!market_maker.allows(Scope::WriteTransfers));
+    std::fs::remove_file(&path).expect("Could not clean up test DB");
+}
+
+#[tokio::test]
+async fn token_lifecycle() {
+    use crate::TokenLevel;
+
+    #[allow(deprecated)]
+    let alice = tpex::PlayerId::evil_constructor("alice".to_owned());
+    let path = std::env::temp_dir().join(format!("tpex-token-test-{}.db", std::process::id()));
+    let tokens = crate::tokens::TokenHandler::new(&format!("sqlite://{}", path.display())).await.expect("Could not open DB");
+
+    let lasting = tokens.create_token(TokenLevel::ProxyOne, alice.clone(), None, None).await.expect("Create failed");
+    assert_eq!(tokens.get_token(&lasting).await.expect("Get failed").expires, None);
+    let expiring = tokens.create_token(TokenLevel::ProxyOne, alice.clone(), None, Some(3600)).await.expect("Create failed");
+    let expiring = tokens.get_token(&expiring).await.expect("Get failed");
+    assert!(expiring.expires.is_some_and(|expires| expires > chrono::Utc::now()));
+
+    // Rotating swaps the token for one that works the same, and stops the old one working
+    let rotated = tokens.rotate_token(&expiring).await.expect("Rotate failed").expect("Token was revoked");
+    let rotated = tokens.get_token(&rotated).await.expect("Get failed");
+    assert_eq!((&rotated.user, rotated.level, rotated.expires.is_some()), (&alice, TokenLevel::ProxyOne, true));
+    assert!(tokens.get_token(&expiring.token).await.is_err());
+    assert_eq!(tokens.rotate_token(&expiring).await.expect("Rotate failed"), None);
+
+    assert!(tokens.delete_token(&lasting).await.expect("Revoke failed"));
+    assert!(!tokens.delete_token(&lasting).await.expect("Revoke failed"));
+    assert!(tokens.get_token(&lasting).await.is_err());
+
+    tokens.record_use(&rotated.token, Some("127.0.0.1".to_owned())).await.expect("Record failed");
+    tokens.record_use(&rotated.token, None).await.expect("Record failed");
+    tokens.record_action(&rotated.token, 7, Some("abc")).await.expect("Record failed");
+    let usage = tokens.list_tokens().await.expect("List failed");
+    assert_eq!(usage.len(), 3);
+    assert!(usage[0].revoked.is_some() && usage[1].revoked.is_some() && usage[2].revoked.is_none());
+    assert_eq!((usage[2].uses, usage[2].actions, usage[2].last_ip.as_deref()), (2, 1, Some("127.0.0.1")));
+    assert!(usage[2].last_used.is_some() && usage[0].last_used.is_none());
+    let submitter = tokens.get_submitter(7).await.expect("Lookup failed").expect("Action not recorded");
+    assert_eq!((submitter.token_id, &submitter.user), (usage[2].id, &alice));
+    assert_eq!(submitter.request_id.as_deref(), Some("abc"));
+    assert_eq!(tokens.get_submitter(8).await.expect("Lookup failed"), None);
+    assert!(tokens.delete_token_id(usage[2].id).await.expect("Revoke failed"));
+    assert!(tokens.get_token(&rotated.token).await.is_err());
+    tokens.ping().await.expect("DB unreachable");
+    std::fs::remove_file(&path).expect("Could not clean up test DB");
+}
+
+#[test]
+fn hard_audit_report() {
+    let report = crate::build_hard_audit(&tpex::State::new());
+    assert_eq!(report.actual.as_ref(), Some(&report.expected));
+    assert!(report.discrepancies.is_empty());
+}
+
+#[tokio::test]
+async fn backup_restore() {
+    let path = std::env::temp_dir().join(format!("tpex-backup-test-{}.json", std::process::id()));
+    assert!(crate::read_snapshot(&path).await.expect("Missing backup errored").is_none());
+
+    let state = tpex::State::new();
+    let backup = crate::ServerSnapshot {
+        state: state.snapshot(),
+        candles: tpex::analytics::CandleAggregator::new(chrono::TimeDelta::minutes(1)).expect("Invalid candle interval"),
+        events: Default::default()
+    };
+    std::fs::write(&path, serde_json::to_vec(&backup).expect("Unable to serialise backup")).expect("Could not write backup");
+    let (restored, _, _) = crate::read_snapshot(&path).await.expect("Backup unreadable").expect("Backup not found");
+    assert_eq!(restored.get_next_id(), state.get_next_id());
+    assert_eq!(restored.snapshot().get_log_digest(), backup.state.get_log_digest());
+
+    std::fs::write(&path, b"not a backup").expect("Could not write backup");
+    assert!(crate::read_snapshot(&path).await.is_err());
+    std::fs::remove_file(&path).expect("Could not clean up test backup");
+}
+
+#[tokio::test]
+async fn manual_connections() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let app = axum::Router::new().route("/", axum::routing::get(|info: Option<axum::extract::ConnectInfo<std::net::SocketAddr>>| async move {
+        info.map(|info| info.0.to_string()).unwrap_or_default()
+    }));
+    for remote in [Some("127.0.0.1:1234".parse().expect("Invalid address")), None] {
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(crate::serve_connection(server, app.clone(), remote));
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.expect("Write failed");
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.expect("Read failed");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(response.ends_with("127.0.0.1:1234"), remote.is_some());
+    }
+}
+
+#[tokio::test]
+async fn request_ids() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let app = crate::with_request_ids(axum::Router::new().route("/", axum::routing::get(|| async { Err::<(), _>(crate::Error::Standby) })));
+    for (sent, expected) in [("X-Request-Id: mine\r\n", Some("mine")), ("", None)] {
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(crate::serve_connection(server, app.clone(), None));
+        client.write_all(format!("GET / HTTP/1.1\r\nHost: localhost\r\n{sent}Connection: close\r\n\r\n").as_bytes()).await.expect("Write failed");
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.expect("Read failed");
+        let (head, body) = response.split_once("\r\n\r\n").expect("No body");
+        let header = head.lines().find_map(|line| line.strip_prefix("x-request-id: ")).expect("No request id header").to_owned();
+        let info: crate::ErrorInfo = serde_json::from_str(body).expect("Invalid error body");
+        assert_eq!(info.request_id.as_ref(), Some(&header));
+        match expected {
+            Some(expected) => assert_eq!(header, expected),
+            None => assert_eq!(header.len(), 16)
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_errors() {
+    assert_eq!(tonic::Status::from(crate::Error::Standby).code(), tonic::Code::Unavailable);
+    assert_eq!(tonic::Status::from(crate::Error::MalformedAction).code(), tonic::Code::InvalidArgument);
+    assert_eq!(tonic::Status::from(crate::Error::from(tpex::Error::InvalidSignature)).code(), tonic::Code::FailedPrecondition);
+}
+
+#[tokio::test]
+async fn msgpack_negotiation() {
+    use tpex::{Action, PlayerId, DIAMOND_NAME};
+
+    let headers = |name, value: &str| [(name, value.parse().expect("Bad header"))].into_iter().collect::<axum::http::HeaderMap>();
+    assert!(crate::accepts_msgpack(&headers(axum::http::header::ACCEPT, "application/json;q=0.5, application/msgpack")));
+    assert!(!crate::accepts_msgpack(&headers(axum::http::header::ACCEPT, "application/json")));
+    assert!(!crate::accepts_msgpack(&axum::http::HeaderMap::new()));
+
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let action = Action::Deposit { player: alice, asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None };
+    let msgpack = headers(axum::http::header::CONTENT_TYPE, "application/msgpack");
+    let json = headers(axum::http::header::CONTENT_TYPE, "application/json");
+    let encoded = rmp_serde::to_vec_named(&action).expect("Unable to encode action");
+    assert_eq!(crate::decode_body::<Action>(&msgpack, &encoded).expect("Bad MessagePack"), action);
+    assert_eq!(crate::decode_body::<Action>(&json, &serde_json::to_vec(&action).expect("Unable to encode action")).expect("Bad JSON"), action);
+    assert!(crate::decode_body::<Action>(&json, &encoded).is_err());
+
+    // The trade log comes back as an array of the actions on it
+    let mut state = tpex::State::new();
+    let mut data = Vec::new();
+    state.apply(action.clone(), &mut data).await.expect("Action failed");
+    state.apply(action, &mut data).await.expect("Action failed");
+    let expected: Vec<_> = data.split_inclusive(|byte| *byte == b'\n')
+        .map(|line| tpex::migrate::upgrade(serde_json::from_slice(line).expect("Bad line")).expect("Could not upgrade"))
+        .collect();
+    let decoded: Vec<tpex::WrappedAction> = rmp_serde::from_slice(&crate::lines_to_msgpack(&data)).expect("Bad MessagePack");
+    assert_eq!(decoded, expected);
+    let state_msgpack = rmp_serde::to_vec_named(&state).expect("Unable to encode state");
+    assert!(!state_msgpack.is_empty());
+}
+
+#[test]
+fn prices_round_trip() {
+    use tpex::Coins;
+
+    let prices = crate::Prices {
+        buy: [(Coins::from_coins(1), 5), (Coins::from_millicoins(1500), 2)].into_iter().collect(),
+        sell: [(Coins::from_coins(3), 64)].into_iter().collect()
+    };
+    let json = serde_json::to_string(&prices).expect("Unable to serialise prices");
+    assert_eq!(serde_json::from_str::<crate::Prices>(&json).expect("Unable to parse prices"), prices);
+}
+
+#[tokio::test]
+async fn action_index() {
+    use crate::{index::ActionIndex, ActionsSearchArgs};
+    use tpex::{Action, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let (alice, bob) = (PlayerId::evil_constructor("alice".to_owned()), PlayerId::evil_constructor("bob".to_owned()));
+    let mut state = tpex::State::new();
+    let mut data = Vec::new();
+    for action in [
+        Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::Deposit { player: bob.clone(), asset: "cobblestone".to_owned(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::Undeposit { player: bob.clone(), asset: "cobblestone".to_owned(), count: 32, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::SellOrder { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, coins_per: tpex::Coins::from_coins(1), display_count: None },
+        Action::CancelOrder { target: 4 }
+    ] {
+        state.apply(action, &mut data).await.expect("Action failed");
+    }
+    let index = ActionIndex::build(tpex::State::new(), &data).await.expect("Unable to index trades");
+    let search = |args: ActionsSearchArgs| index.search(&args);
+
+    assert_eq!(search(Default::default()), [1, 2, 3, 4, 5]);
+    // The order is gone once it is cancelled, but the cancellation is still found as alice's, as it was when applied
+    assert_eq!(search(ActionsSearchArgs { player: Some(alice.clone()), ..Default::default() }), [1, 4, 5]);
+    assert_eq!(search(ActionsSearchArgs { player: Some(bob.clone()), ..Default::default() }), [2, 3]);
+    assert_eq!(search(ActionsSearchArgs { player: Some(PlayerId::the_bank()), kind: Some("Undeposit".to_owned()), ..Default::default() }), [3]);
+    assert_eq!(search(ActionsSearchArgs { asset: Some(DIAMOND_NAME.to_owned()), player: Some(bob), ..Default::default() }), Vec::<u64>::new());
+    assert_eq!(search(ActionsSearchArgs { kind: Some("Nonsense".to_owned()), ..Default::default() }), Vec::<u64>::new());
+    assert_eq!(search(ActionsSearchArgs { to: Some(chrono::DateTime::UNIX_EPOCH), ..Default::default() }), Vec::<u64>::new());
+    assert_eq!(search(ActionsSearchArgs { from: Some(chrono::DateTime::UNIX_EPOCH), asset: Some(DIAMOND_NAME.to_owned()), ..Default::default() }), [1, 4, 5]);
+}
+
+#[tokio::test]
+async fn banker_queue() {
+    use tpex::{Action, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let mut state = tpex::State::new();
+    let mut sink = tokio::io::sink();
+    for action in [
+        Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::BuyCoins { player: alice.clone(), n_diamonds: 1 },
+        Action::Deposit { player: alice.clone(), asset: "cobblestone".to_owned(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None },
+        Action::WithdrawalRequested { player: alice.clone(), assets: [("cobblestone".to_owned(), 16)].into_iter().collect() },
+        Action::UpdateRestricted { restricted_assets: vec!["cobblestone".to_owned()], banker: PlayerId::the_bank() }
+    ] {
+        state.apply(action, &mut sink).await.expect("Action failed");
+    }
+    let withdrawal = state.get_withdrawals().into_values().next().expect("No withdrawal");
+    let queue = crate::build_banker_queue(&state, withdrawal.requested + chrono::TimeDelta::minutes(5));
+    assert_eq!(queue.withdrawals.len(), 1);
+    assert_eq!(queue.withdrawals[0].age_secs, 300);
+    assert_eq!(queue.withdrawals[0].restricted, ["cobblestone".to_owned()].into());
+    assert_eq!(queue.restricted, ["cobblestone".to_owned()].into());
+    assert!(queue.authorisation_requests.is_empty() && queue.proposals.is_empty());
+}
+
+#[tokio::test]
+async fn stream_resumes() {
+    use tpex::{Action, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let mut state = tpex::State::new();
+    let mut log = Vec::new();
+    for count in [1, 2, 3] {
+        state.apply(Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    }
+    let lines: Vec<String> = String::from_utf8(log).expect("Invalid log").lines().map(str::to_owned).collect();
+    let event = |id: usize| format!("id: {}\ndata: {}\n\n", id + 1, lines[id]);
+    // The first connection drops after one action, the second skips ahead and is left open,
+    // and the third repeats what was missed before carrying on
+    let bodies = std::sync::Arc::new(std::sync::Mutex::new(vec![
+        (format!(":\n\n{}{}", event(1), event(2)), false),
+        (event(2), true),
+        (event(0), false)
+    ]));
+    let froms = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let app = axum::Router::new().route("/state/sse", axum::routing::get({
+        let (bodies, froms) = (bodies.clone(), froms.clone());
+        move |axum::extract::Query(args): axum::extract::Query<tpex_api::StateGetArgs>| async move {
+            use futures_util::StreamExt;
+
+            froms.lock().expect("Poisoned").push(args.from);
+            let (body, open) = bodies.lock().expect("Poisoned").pop().unwrap_or_default();
+            let chunks = futures_util::stream::iter([Ok::<_, std::convert::Infallible>(body)]).chain(futures_util::stream::pending());
+            axum::body::Body::from_stream(chunks.take(if open { usize::MAX } else { 1 }))
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Could not bind");
+    let endpoint = format!("http://{}/", listener.local_addr().expect("No address")).parse().expect("Invalid URL");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let remote = tpex_api::Remote::new(endpoint, tpex_api::Token([0; 16]));
+    let mut stream = remote.stream_actions(1);
+    for (id, line) in lines.iter().enumerate() {
+        let next = tokio::time::timeout(std::time::Duration::from_secs(5), stream.next_line()).await.expect("Stream stuck after skipping ahead");
+        assert_eq!(next.expect("Stream failed"), line.as_bytes());
+        assert_eq!(stream.next_id(), id as u64 + 2);
+    }
+    assert_eq!(*froms.lock().expect("Poisoned"), [Some(1), Some(2), Some(2)]);
+}
+
+#[tokio::test]
+async fn typed_errors() {
+    use axum::response::IntoResponse;
+
+    let overdrawn = tpex::Error::OverdrawnCoins { amount_overdrawn: tpex::Coins::from_coins(3) };
+    let response = crate::Error::Batch { index: 2, error: Box::new(crate::Error::TPEx(overdrawn.clone())) }.into_response();
+    assert_eq!(response.status(), 409);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.expect("Unreadable body");
+    let info: tpex_api::ErrorInfo = serde_json::from_slice(&body).expect("Invalid error body");
+    match tpex_api::Error::from(info) {
+        tpex_api::Error::Rejected(err, info) => {
+            assert_eq!(err, overdrawn);
+            assert_eq!((info.code, info.batch_index), (tpex_api::ErrorCode::Rejected, Some(2)));
+        }
+        other => panic!("Not a rejection: {other}")
+    }
+
+    let err = tpex_api::Error::from(serde_json::from_str::<tpex_api::ErrorInfo>(r#"{"error":"?","code":"from_the_future"}"#).expect("Invalid error body"));
+    assert_eq!(err.code(), Some(tpex_api::ErrorCode::Unknown));
+}
+
+#[tokio::test]
+async fn retried_actions() {
+    use tpex::{Action, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let action = Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None };
+    let outcome = tpex::State::new().apply(action.clone(), &mut tokio::io::sink()).await.expect("Deposit failed");
+
+    let path = std::env::temp_dir().join(format!("tpex-idempotency-test-{}.db", std::process::id()));
+    let tokens = crate::tokens::TokenHandler::new(&format!("sqlite://{}", path.display())).await.expect("Could not open DB");
+    let token = tokens.create_token(crate::TokenLevel::ProxyOne, alice, None, None).await.expect("Create failed");
+    assert_eq!(tokens.get_idempotent(&token, "key").await.expect("Lookup failed"), None);
+    tokens.record_idempotent(&token, "key", &outcome).await.expect("Record failed");
+    assert_eq!(tokens.get_idempotent(&token, "key").await.expect("Lookup failed"), Some(outcome.clone()));
+    std::fs::remove_file(&path).expect("Could not clean up test DB");
+
+    // The first attempt fails, and the retry has to carry the same key
+    let keys = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let app = axum::Router::new().route("/state", axum::routing::patch({
+        let (keys, outcome) = (keys.clone(), outcome.clone());
+        move |axum::extract::RawQuery(query): axum::extract::RawQuery, headers: axum::http::HeaderMap| async move {
+            // Without asking, the server only sends back the id
+            assert_eq!(query.as_deref(), Some("outcome=true"));
+            let mut keys = keys.lock().expect("Poisoned");
+            keys.push(headers.get(tpex_api::IDEMPOTENCY_KEY_HEADER).expect("No idempotency key").to_str().expect("Bad key").to_owned());
+            if keys.len() == 1 { Err(crate::Error::Standby) } else { Ok(axum::Json(outcome)) }
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Could not bind");
+    let endpoint = format!("http://{}/", listener.local_addr().expect("No address")).parse().expect("Invalid URL");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let policy = tpex_api::RetryPolicy { backoff: std::time::Duration::from_millis(1), ..Default::default() };
+    let remote = tpex_api::Remote::new(endpoint, tpex_api::Token([0; 16])).with_retries(policy);
+    assert_eq!(remote.apply(&action).await.expect("Retry failed"), outcome);
+    let keys = keys.lock().expect("Poisoned");
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys[0], keys[1]);
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn blocking_client() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+    let endpoint = format!("http://{}/", listener.local_addr().expect("No address")).parse().expect("Invalid URL");
+    listener.set_nonblocking(true).expect("Could not make listener async");
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().expect("Unable to start runtime");
+        let app = axum::Router::new().route("/inspect/restricted", axum::routing::get(|| async { axum::Json([tpex::DIAMOND_NAME]) }));
+        runtime.block_on(async { axum::serve(tokio::net::TcpListener::from_std(listener).expect("Bad listener"), app).await })
+    });
+    let remote = tpex_api::blocking::Remote::new(endpoint, tpex_api::Token([0; 16]));
+    assert_eq!(remote.get_restricted().expect("Request failed"), [tpex::DIAMOND_NAME.to_owned()].into());
+}
+
+#[tokio::test]
+async fn remote_builder() {
+    let app = axum::Router::new().route("/inspect/restricted", axum::routing::get(|headers: axum::http::HeaderMap| async move {
+        let header = |name| headers.get(name).and_then(|value: &axum::http::HeaderValue| value.to_str().ok()).unwrap_or_default().to_owned();
+        axum::Json([header("user-agent"), header("x-deployment"), header("authorization")])
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Could not bind");
+    let endpoint = format!("http://{}/", listener.local_addr().expect("No address")).parse().expect("Invalid URL");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let token = tpex_api::Token([0; 16]);
+    let remote = tpex_api::Remote::builder(endpoint, token)
+        .user_agent("tpex-test")
+        .header(axum::http::HeaderName::from_static("x-deployment"), axum::http::HeaderValue::from_static("staging"))
+        .timeout(std::time::Duration::from_secs(5))
+        .build().expect("Could not build remote");
+    let seen = remote.get_restricted().await.expect("Request failed");
+    assert_eq!(seen, ["tpex-test".to_owned(), "staging".to_owned(), format!("Bearer {token}")].into());
+}
+
+#[tokio::test]
+async fn order_quotes() {
+    use tpex::{Action, Coins, PlayerId, DIAMOND_NAME};
+    use tpex_api::{Quote, Refusal};
+
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let mut state = tpex::State::new();
+    state.apply(Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut tokio::io::sink()).await.expect("Deposit failed");
+    for (count, coins) in [(2, 10), (3, 12)] {
+        state.apply(Action::SellOrder { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count, coins_per: Coins::from_coins(coins), display_count: None }, &mut tokio::io::sink()).await.expect("Sell failed");
+    }
+
+    let diamond = DIAMOND_NAME.to_owned();
+    let quote = Quote::buy(&state, &diamond, 4).expect("Quote failed");
+    assert_eq!(quote, Quote { best_price: Coins::from_coins(10), limit_price: Coins::from_coins(12), total: Coins::from_coins(44) });
+    assert_eq!(quote.slippage_percent(), 20);
+    assert_eq!(Quote::buy(&state, &diamond, 6), Err(Refusal::NotEnoughOnBook { available: 5 }));
+    assert_eq!(Quote::sell(&state, &diamond, 1), Err(Refusal::NotEnoughOnBook { available: 0 }));
+}
+
+#[tokio::test]
+async fn market_data() {
+    use tpex::{Action, Coins, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let mut state = tpex::State::new();
+    let mut log = Vec::new();
+    state.apply(Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::SellOrder { player: alice, asset: DIAMOND_NAME.to_owned(), count: 2, coins_per: Coins::from_coins(10), display_count: None }, &mut log).await.expect("Sell failed");
+    let events: String = String::from_utf8(log).expect("Invalid log").lines().enumerate().map(|(id, line)| format!("id: {}\ndata: {line}\n\n", id + 1)).collect();
+    let app = axum::Router::new().route("/state/sse", axum::routing::get(move || async move { events }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Could not bind");
+    let endpoint = format!("http://{}/", listener.local_addr().expect("No address")).parse().expect("Invalid URL");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let market = std::sync::Arc::new(tpex_api::MarketData::new(tpex_api::Mirrored::new(endpoint, tpex_api::Token([0; 16]))));
+    let diamond = DIAMOND_NAME.to_owned();
+    let mut updates = market.subscribe(&diamond);
+    let follower = tokio::spawn({
+        let market = market.clone();
+        async move { market.run().await }
+    });
+    let ticker = tokio::time::timeout(std::time::Duration::from_secs(5), updates.recv()).await.expect("No update").expect("Channel closed");
+    assert_eq!(ticker, tpex_api::Ticker { bid: None, ask: Some((Coins::from_coins(10), 2)), last_price: None, volume: 0 });
+    assert_eq!(market.ticker(&diamond).await, ticker);
+    follower.abort();
+}
+
+#[tokio::test]
+async fn mirror_stream() {
+    use tpex::{Action, Coins, PlayerId, DIAMOND_NAME};
+
+    #[allow(deprecated)]
+    let (alice, bob) = (PlayerId::evil_constructor("alice".to_owned()), PlayerId::evil_constructor("bob".to_owned()));
+    let mut state = tpex::State::new();
+    let mut log = Vec::new();
+    state.apply(Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: bob.clone(), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: bob.clone(), n_diamonds: 5 }, &mut log).await.expect("Buy coins failed");
+    state.apply(Action::SellOrder { player: alice, asset: DIAMOND_NAME.to_owned(), count: 2, coins_per: Coins::from_coins(10), display_count: None }, &mut log).await.expect("Sell failed");
+    state.apply(Action::BuyOrder { player: bob, asset: DIAMOND_NAME.to_owned(), count: 1, coins_per: Coins::from_coins(10), display_count: None }, &mut log).await.expect("Buy failed");
+    let app = axum::Router::new().route("/state", axum::routing::get(move || async move { log }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Could not bind");
+    let endpoint = format!("http://{}/", listener.local_addr().expect("No address")).parse().expect("Invalid URL");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let mirror = tpex_api::Mirrored::new(endpoint, tpex_api::Token([0; 16]));
+    let mut outcomes = mirror.stream();
+    drop(mirror.sync().await);
+    let applied: Vec<(tpex::Action, tpex::ApplyOutcome)> = std::iter::from_fn(|| outcomes.try_recv().ok()).collect();
+    assert_eq!(applied.iter().map(|(_, outcome)| outcome.id).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+    assert!(matches!(applied[4], (Action::BuyOrder { count: 1, .. }, tpex::ApplyOutcome { ref fills, .. }) if fills.len() == 1));
+    drop(mirror.sync().await);
+    assert!(outcomes.try_recv().is_err());
+}
@@ -13,22 +13,47 @@ impl axum::extract::FromRequestParts<super::State> for TokenInfo {
 
     #[allow(clippy::type_complexity,clippy::type_repetition_in_bounds)]
     async fn from_request_parts(parts: &mut axum::http::request::Parts, state: &super::State) -> Result<Self, Self::Rejection> {
-            let Some(auth) : Option<Authorization<Bearer>> = parts.headers.typed_get()
-            else { return Err(StatusCode::UNAUTHORIZED); };
+            let address = parts.extensions.get::<axum::extract::ConnectInfo<std::net::SocketAddr>>().map(|info| info.0.ip().to_string());
+            // Everything read is fetched with GET
+            authenticate(state, &parts.headers, parts.method == axum::http::Method::GET, address).await
+        }
+}
 
-            let Ok(token) = auth.0.token().parse()
-            else { return Err(StatusCode::UNAUTHORIZED); };
+/// Find the token a request was made with, checking that it can still be used, and note down that it was
+pub async fn authenticate(state: &super::State, headers: &axum::http::HeaderMap, read: bool, address: Option<String>) -> Result<TokenInfo, StatusCode> {
+    let Some(auth) : Option<Authorization<Bearer>> = headers.typed_get()
+    else { return Err(StatusCode::UNAUTHORIZED); };
 
-            let Ok(token_info) = state.tokens.get_token(&token).await
-            else { return Err(StatusCode::UNAUTHORIZED); };
+    let Ok(token) = auth.0.token().parse()
+    else { return Err(StatusCode::UNAUTHORIZED); };
 
-            // If the token would need banker perms to make, check that the user is still at that level
-            if token_info.level > TokenLevel::ProxyOne && !state.tpex.read().await.state.is_banker(&token_info.user) {
-                return Err(StatusCode::UNAUTHORIZED)
-            }
+    // Revoked tokens aren't found
+    let Ok(token_info) = state.tokens.get_token(&token).await
+    else { return Err(StatusCode::UNAUTHORIZED); };
 
-            Ok(token_info)
-        }
+    if token_info.expires.is_some_and(|expires| expires <= chrono::Utc::now()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    // If the token would need banker perms to make, check that the user is still at that level
+    if token_info.level > TokenLevel::ProxyOne && !state.tpex.read().await.state.is_banker(&token_info.user) {
+        return Err(StatusCode::UNAUTHORIZED)
+    }
+
+    if read && !token_info.allows(Scope::ReadState) {
+        return Err(StatusCode::FORBIDDEN)
+    }
+
+    if state.tokens.record_use(&token_info.token, address).await.is_err() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    Ok(token_info)
+}
+
+/// Read a time stored in the token DB, where times too far off to represent just last as long as they can
+fn from_timestamp(secs: i64) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(secs, 0).unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC)
 }
 
 pub struct TokenHandler {
@@ -45,36 +70,148 @@ impl TokenHandler {
 
         Ok(ret)
     }
-    pub async fn create_token(&self, level: TokenLevel, user: PlayerId) -> sqlx::Result<Token> {
+    /// The database the tokens are kept in, for anything else that should be kept alongside them
+    pub fn pool(&self) -> sqlx::SqlitePool { self.pool.clone() }
+    /// Check that the DB can still be reached
+    pub async fn ping(&self) -> sqlx::Result<()> {
+        sqlx::query!(r#"SELECT 1 as "one""#).fetch_one(&self.pool).await?;
+        Ok(())
+    }
+    pub async fn create_token(&self, level: TokenLevel, user: PlayerId, scopes: Option<std::collections::BTreeSet<Scope>>, ttl_secs: Option<u64>) -> sqlx::Result<Token> {
+        let mut conn = self.pool.acquire().await?;
+        Self::insert_token(&mut conn, level, &user, scopes.as_ref(), ttl_secs).await
+    }
+    async fn insert_token(conn: &mut sqlx::SqliteConnection, level: TokenLevel, user: &PlayerId, scopes: Option<&std::collections::BTreeSet<Scope>>, ttl_secs: Option<u64>) -> sqlx::Result<Token> {
         let token = Token::generate();
 
         let slice = token.0.as_slice();
         let level = level as i64;
         #[allow(deprecated)]
         let user = user.evil_deref();
+        let scopes = scopes.map(|scopes| serde_json::to_string(scopes).expect("Unable to serialise scopes"));
+        let ttl_secs = ttl_secs.map(|ttl_secs| i64::try_from(ttl_secs).unwrap_or(i64::MAX));
+        let expires = ttl_secs.map(|ttl_secs| chrono::Utc::now().timestamp().saturating_add(ttl_secs));
 
-        sqlx::query!(r#"INSERT INTO tokens(token, level, user) VALUES (?, ?, ?)"#, slice, level, user)
-        .execute(&self.pool).await?;
+        sqlx::query!(r#"INSERT INTO tokens(token, level, user, scopes, expires, ttl_secs) VALUES (?, ?, ?, ?, ?, ?)"#, slice, level, user, scopes, expires, ttl_secs)
+        .execute(conn).await?;
 
         Ok(token)
     }
     pub async fn get_token(&self, token: &Token) -> sqlx::Result<TokenInfo> {
         let slice = token.0.as_slice();
         let query =
-            sqlx::query!(r#"SELECT token as "token: Vec<u8>", level, user FROM tokens WHERE token = ?"#, slice)
+            sqlx::query!(r#"SELECT token as "token: Vec<u8>", level, user, scopes, expires FROM tokens WHERE token = ? AND revoked IS NULL"#, slice)
             .fetch_one(&self.pool).await?;
 
         Ok(TokenInfo {
             token: Token(query.token.try_into().expect("Mismatched token length")),
             #[allow(deprecated)]
             user: tpex::PlayerId::evil_constructor(query.user),
-            level: TokenLevel::from_i64(query.level).expect("Invalid token level")
+            level: TokenLevel::from_i64(query.level).expect("Invalid token level"),
+            scopes: query.scopes.map(|scopes| serde_json::from_str(&scopes).expect("Invalid token scopes")),
+            expires: query.expires.map(from_timestamp)
         })
     }
-    pub async fn delete_token(&self, token: &Token) -> sqlx::Result<()> {
+    /// Note down that a token has been used, and where from if known
+    pub async fn record_use(&self, token: &Token, address: Option<String>) -> sqlx::Result<()> {
+        let now = chrono::Utc::now().timestamp();
         let slice = token.0.as_slice();
-        sqlx::query!(r#"DELETE FROM tokens WHERE token = ?"#, slice)
+        sqlx::query!(r#"UPDATE tokens SET last_used = ?, last_ip = COALESCE(?, last_ip), uses = uses + 1 WHERE token = ?"#, now, address, slice)
         .execute(&self.pool).await?;
         Ok(())
     }
+    /// Note down that an action has been applied with a token, and in which request, so that it can be traced back to it
+    pub async fn record_action(&self, token: &Token, action_id: u64, request_id: Option<&str>) -> sqlx::Result<()> {
+        let slice = token.0.as_slice();
+        let action_id = action_id as i64;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(r#"UPDATE tokens SET actions = actions + 1 WHERE token = ?"#, slice)
+        .execute(&mut *tx).await?;
+        sqlx::query!(r#"INSERT INTO token_actions(action_id, token_id, user, request_id) SELECT ?, rowid, user, ? FROM tokens WHERE token = ?"#, action_id, request_id, slice)
+        .execute(&mut *tx).await?;
+        tx.commit().await
+    }
+    /// Find the outcome of an action a token already applied under the given idempotency key
+    pub async fn get_idempotent(&self, token: &Token, key: &str) -> sqlx::Result<Option<tpex::ApplyOutcome>> {
+        let slice = token.0.as_slice();
+        let outcome =
+            sqlx::query_scalar!(r#"SELECT outcome FROM idempotency_keys WHERE key = ? AND token_id = (SELECT rowid FROM tokens WHERE token = ?)"#, key, slice)
+            .fetch_optional(&self.pool).await?;
+        Ok(outcome.map(|outcome| serde_json::from_str(&outcome).expect("Invalid stored outcome")))
+    }
+    /// Note down the outcome of an action applied under an idempotency key, so a retry gets it back
+    pub async fn record_idempotent(&self, token: &Token, key: &str, outcome: &tpex::ApplyOutcome) -> sqlx::Result<()> {
+        let slice = token.0.as_slice();
+        let outcome = serde_json::to_string(outcome).expect("Unable to serialise outcome");
+        sqlx::query!(r#"INSERT INTO idempotency_keys(token_id, key, outcome) SELECT rowid, ?, ? FROM tokens WHERE token = ?"#, key, outcome, slice)
+        .execute(&self.pool).await?;
+        Ok(())
+    }
+    /// Find the token that submitted an action, if it came through the API
+    pub async fn get_submitter(&self, action_id: u64) -> sqlx::Result<Option<ActionSubmitter>> {
+        let id = action_id as i64;
+        let query = sqlx::query!(r#"SELECT token_id, user, request_id FROM token_actions WHERE action_id = ?"#, id)
+        .fetch_optional(&self.pool).await?;
+        Ok(query.map(|query| ActionSubmitter {
+            action_id,
+            token_id: query.token_id,
+            #[allow(deprecated)]
+            user: tpex::PlayerId::evil_constructor(query.user),
+            request_id: query.request_id
+        }))
+    }
+    /// List every token ever made, including revoked ones
+    pub async fn list_tokens(&self) -> sqlx::Result<Vec<TokenUsage>> {
+        let query = sqlx::query!(r#"SELECT rowid as "rowid!", level, user, scopes, expires, revoked, last_used, last_ip, uses, actions FROM tokens ORDER BY rowid"#)
+        .fetch_all(&self.pool).await?;
+        Ok(query.into_iter().map(|row| TokenUsage {
+            id: row.rowid,
+            #[allow(deprecated)]
+            user: tpex::PlayerId::evil_constructor(row.user),
+            level: TokenLevel::from_i64(row.level).expect("Invalid token level"),
+            scopes: row.scopes.map(|scopes| serde_json::from_str(&scopes).expect("Invalid token scopes")),
+            expires: row.expires.map(from_timestamp),
+            revoked: row.revoked.map(from_timestamp),
+            last_used: row.last_used.map(from_timestamp),
+            last_ip: row.last_ip,
+            uses: row.uses as u64,
+            actions: row.actions as u64
+        }).collect())
+    }
+    /// Revoke a token, returning false if it was already revoked or never existed
+    ///
+    /// Revoked tokens are kept, so that there's a record of them
+    pub async fn delete_token(&self, token: &Token) -> sqlx::Result<bool> {
+        let mut conn = self.pool.acquire().await?;
+        Self::revoke_token(&mut conn, token).await
+    }
+    /// Revoke a token by its id from list_tokens, returning false if it was already revoked or never existed
+    pub async fn delete_token_id(&self, id: i64) -> sqlx::Result<bool> {
+        let now = chrono::Utc::now().timestamp();
+        let result = sqlx::query!(r#"UPDATE tokens SET revoked = ? WHERE rowid = ? AND revoked IS NULL"#, now, id)
+        .execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+    async fn revoke_token(conn: &mut sqlx::SqliteConnection, token: &Token) -> sqlx::Result<bool> {
+        let slice = token.0.as_slice();
+        let now = chrono::Utc::now().timestamp();
+        let result = sqlx::query!(r#"UPDATE tokens SET revoked = ? WHERE token = ? AND revoked IS NULL"#, now, slice)
+        .execute(conn).await?;
+        Ok(result.rows_affected() > 0)
+    }
+    /// Swap a token for a new one with the same permissions and a renewed expiry, revoking the old one
+    ///
+    /// Returns None if the old token was revoked first, in which case no new token is made
+    pub async fn rotate_token(&self, old: &TokenInfo) -> sqlx::Result<Option<Token>> {
+        let mut tx = self.pool.begin().await?;
+        let slice = old.token.0.as_slice();
+        let ttl_secs = sqlx::query_scalar!(r#"SELECT ttl_secs FROM tokens WHERE token = ?"#, slice)
+        .fetch_one(&mut *tx).await?;
+        if !Self::revoke_token(&mut tx, &old.token).await? {
+            return Ok(None);
+        }
+        let token = Self::insert_token(&mut tx, old.level, &old.user, old.scopes.as_ref(), ttl_secs.map(|ttl_secs| ttl_secs as u64)).await?;
+        tx.commit().await?;
+        Ok(Some(token))
+    }
 }
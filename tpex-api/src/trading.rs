@@ -0,0 +1,119 @@
+//! Order helpers that check the mirrored order book before placing anything
+
+use tpex::{Action, ApplyOutcome, AssetId, Coins, PlayerId, State};
+
+use crate::{Error, Mirrored, Result};
+
+/// Why an order helper didn't place an order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Refusal {
+    /// There isn't enough on the book to fill the whole amount
+    NotEnoughOnBook { available: u64 },
+    /// Buying would cost more than allowed
+    OverBudget { total: Coins, max_total: Coins },
+    /// Selling would bring in less than allowed
+    UnderMinimum { total: Coins, min_total: Coins },
+    /// The worst price taken would be further from the best price than allowed
+    TooMuchSlippage { slippage_percent: u64, max_slippage_percent: u64 }
+}
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::NotEnoughOnBook { available } => write!(f, "Only {available} are available on the order book."),
+            Refusal::OverBudget { total, max_total } => write!(f, "This would cost {total}, which is more than {max_total}."),
+            Refusal::UnderMinimum { total, min_total } => write!(f, "This would bring in {total}, which is less than {min_total}."),
+            Refusal::TooMuchSlippage { slippage_percent, max_slippage_percent } =>
+                write!(f, "The price would move {slippage_percent}% from the best price, which is more than {max_slippage_percent}%.")
+        }
+    }
+}
+
+/// What taking an amount from the order book would come to right now
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Quote {
+    /// The best price on the book
+    pub best_price: Coins,
+    /// The worst price that has to be taken to fill the whole amount, which the order is placed at
+    pub limit_price: Coins,
+    /// The coins the whole amount comes to at the prices on the book
+    pub total: Coins
+}
+impl Quote {
+    /// Quote buying from the sell orders, cheapest first
+    pub fn buy(state: &State, asset: &AssetId, count: u64) -> core::result::Result<Quote, Refusal> {
+        let (_, sell) = state.get_prices(asset);
+        Self::take(sell.into_iter(), count)
+    }
+    /// Quote selling to the buy orders, dearest first
+    pub fn sell(state: &State, asset: &AssetId, count: u64) -> core::result::Result<Quote, Refusal> {
+        let (buy, _) = state.get_prices(asset);
+        Self::take(buy.into_iter().rev(), count)
+    }
+    /// How far the limit price is from the best price, as a percentage of the best price rounded up
+    pub fn slippage_percent(&self) -> u64 {
+        let best = self.best_price.millicoins() as u128;
+        let moved = self.limit_price.millicoins().abs_diff(self.best_price.millicoins()) as u128;
+        if best == 0 { return 0; }
+        (moved * 100).div_ceil(best).try_into().unwrap_or(u64::MAX)
+    }
+    fn take(mut levels: impl Iterator<Item = (Coins, u64)>, count: u64) -> core::result::Result<Quote, Refusal> {
+        let mut quote: Option<Quote> = None;
+        let mut left = count;
+        let mut available = 0;
+        while left > 0 {
+            let Some((coins_per, amount)) = levels.next()
+            else { return Err(Refusal::NotEnoughOnBook { available }); };
+            let taken = amount.min(left);
+            left -= taken;
+            available += amount;
+            // A book too big to add up can't be bought from anyway
+            let cost = coins_per.checked_mul(taken).map_err(|_| Refusal::NotEnoughOnBook { available })?;
+            let quote = quote.get_or_insert(Quote { best_price: coins_per, limit_price: coins_per, total: Coins::default() });
+            quote.limit_price = coins_per;
+            quote.total.checked_add_assign(cost).map_err(|_| Refusal::NotEnoughOnBook { available })?;
+        }
+        Ok(quote.unwrap_or_default())
+    }
+}
+impl Mirrored {
+    /// Buy at the prices on the book, unless the whole amount would cost more than max_total
+    ///
+    /// The order is placed at the quoted limit price, so it rests on the book rather than paying more if the book moves first
+    pub async fn buy_with_max_cost(&self, player: PlayerId, asset: AssetId, count: u64, max_total: Coins) -> Result<ApplyOutcome> {
+        let quote = Quote::buy(&*self.sync().await, &asset, count).map_err(Error::Refused)?;
+        if quote.total > max_total {
+            return Err(Error::Refused(Refusal::OverBudget { total: quote.total, max_total }));
+        }
+        self.apply(Action::BuyOrder { player, asset, count, coins_per: quote.limit_price, display_count: None }).await
+    }
+    /// Buy at the prices on the book, unless the worst price taken is more than max_slippage_percent above the best
+    pub async fn buy_with_max_slippage(&self, player: PlayerId, asset: AssetId, count: u64, max_slippage_percent: u64) -> Result<ApplyOutcome> {
+        let quote = Quote::buy(&*self.sync().await, &asset, count).map_err(Error::Refused)?;
+        check_slippage(&quote, max_slippage_percent).map_err(Error::Refused)?;
+        self.apply(Action::BuyOrder { player, asset, count, coins_per: quote.limit_price, display_count: None }).await
+    }
+    /// Sell at the prices on the book, unless the whole amount would bring in less than min_total
+    ///
+    /// The order is placed at the quoted limit price, so it rests on the book rather than taking less if the book moves first
+    pub async fn sell_with_min_proceeds(&self, player: PlayerId, asset: AssetId, count: u64, min_total: Coins) -> Result<ApplyOutcome> {
+        let quote = Quote::sell(&*self.sync().await, &asset, count).map_err(Error::Refused)?;
+        if quote.total < min_total {
+            return Err(Error::Refused(Refusal::UnderMinimum { total: quote.total, min_total }));
+        }
+        self.apply(Action::SellOrder { player, asset, count, coins_per: quote.limit_price, display_count: None }).await
+    }
+    /// Sell at the prices on the book, unless the worst price taken is more than max_slippage_percent below the best
+    pub async fn sell_with_max_slippage(&self, player: PlayerId, asset: AssetId, count: u64, max_slippage_percent: u64) -> Result<ApplyOutcome> {
+        let quote = Quote::sell(&*self.sync().await, &asset, count).map_err(Error::Refused)?;
+        check_slippage(&quote, max_slippage_percent).map_err(Error::Refused)?;
+        self.apply(Action::SellOrder { player, asset, count, coins_per: quote.limit_price, display_count: None }).await
+    }
+}
+
+fn check_slippage(quote: &Quote, max_slippage_percent: u64) -> core::result::Result<(), Refusal> {
+    let slippage_percent = quote.slippage_percent();
+    if slippage_percent > max_slippage_percent {
+        return Err(Refusal::TooMuchSlippage { slippage_percent, max_slippage_percent });
+    }
+    Ok(())
+}
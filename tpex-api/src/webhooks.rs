@@ -0,0 +1,106 @@
+use std::io::Write;
+
+use tpex::{AssetId, PlayerId};
+use crate::shared::*;
+
+/// How many times an action is sent to a webhook before giving up on it
+const DELIVERY_ATTEMPTS: u32 = 5;
+/// How long to wait before resending an action, which doubles after each failure
+const FIRST_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// A newly applied action, along with who and what it involved
+pub struct Notification {
+    /// The action's line in the trade log
+    pub data: Vec<u8>,
+    pub players: std::collections::BTreeSet<PlayerId>,
+    pub assets: std::collections::BTreeSet<AssetId>
+}
+
+impl Notification {
+    /// Returns true if the action involved the given player and item, where given
+    pub fn matches(&self, player: Option<&PlayerId>, asset: Option<&AssetId>) -> bool {
+        player.is_none_or(|player| self.players.contains(player)) &&
+        asset.is_none_or(|asset| self.assets.contains(asset))
+    }
+}
+
+/// Returns true if the webhook wants to be sent the action
+pub fn matches(webhook: &Webhook, notification: &Notification) -> bool {
+    notification.matches(webhook.player.as_ref(), webhook.asset.as_ref())
+}
+
+/// The registered webhooks, kept in the same database as the tokens
+pub struct WebhookRegistry {
+    pool: sqlx::SqlitePool
+}
+impl WebhookRegistry {
+    pub fn new(pool: sqlx::SqlitePool) -> WebhookRegistry { WebhookRegistry { pool } }
+    pub async fn create_webhook(&self, owner: &PlayerId, args: WebhookPostArgs) -> sqlx::Result<Webhook> {
+        #[allow(deprecated)]
+        let (owner_str, player) = (owner.evil_deref().as_str(), args.player.as_ref().map(|player| player.evil_deref().as_str()));
+        let id = sqlx::query!(r#"INSERT INTO webhooks(owner, url, player, asset) VALUES (?, ?, ?, ?)"#, owner_str, args.url, player, args.asset)
+        .execute(&self.pool).await?
+        .last_insert_rowid();
+        Ok(Webhook { id, owner: owner.clone(), url: args.url, player: args.player, asset: args.asset })
+    }
+    pub async fn get_webhooks(&self) -> sqlx::Result<Vec<Webhook>> {
+        let query = sqlx::query!(r#"SELECT id as "id!", owner, url, player, asset FROM webhooks ORDER BY id"#)
+        .fetch_all(&self.pool).await?;
+        #[allow(deprecated)]
+        Ok(query.into_iter().map(|row| Webhook {
+            id: row.id,
+            owner: PlayerId::evil_constructor(row.owner),
+            url: row.url,
+            player: row.player.map(PlayerId::evil_constructor),
+            asset: row.asset
+        }).collect())
+    }
+    /// Remove a webhook, returning false if there was no such webhook
+    pub async fn delete_webhook(&self, id: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query!(r#"DELETE FROM webhooks WHERE id = ?"#, id)
+        .execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Send each new action to the webhooks that want it, for as long as actions are being applied
+///
+/// Each webhook is sent its actions independently, so they can arrive out of order when one has to be retried
+pub async fn deliver(registry: &WebhookRegistry, mut notifications: tokio::sync::mpsc::UnboundedReceiver<std::sync::Arc<Notification>>) {
+    let client = reqwest::Client::new();
+    while let Some(notification) = notifications.recv().await {
+        let webhooks = match registry.get_webhooks().await {
+            Ok(webhooks) => webhooks,
+            Err(err) => {
+                let _ = writeln!(std::io::stderr(), "Could not read webhooks: {err}");
+                continue;
+            }
+        };
+        let body = notification.data.trim_ascii_end().to_vec();
+        for webhook in webhooks.into_iter().filter(|webhook| matches(webhook, &notification)) {
+            tokio::spawn(send(client.clone(), webhook.url, body.clone()));
+        }
+    }
+}
+
+/// POST an action to a webhook, backing off between attempts
+async fn send(client: reqwest::Client, url: String, body: Vec<u8>) {
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let result = client.post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send().await
+            .and_then(reqwest::Response::error_for_status);
+        match result {
+            Ok(_) => return,
+            Err(err) if attempt == DELIVERY_ATTEMPTS => {
+                let _ = writeln!(std::io::stderr(), "Giving up on webhook {url}: {err}");
+            },
+            Err(_) => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Python bindings for the exchange state, for backtesting and auditing the trade log in notebooks
+//!
+//! Actions, players' holdings and everything else structured cross over as the same JSON the API uses, converted to and from Python objects
+
+#[cfg(test)]
+mod tests;
+
+use pyo3::prelude::*;
+use tpex::{Auditable, PlayerId};
+
+pyo3::create_exception!(tpex_py, TPExError, pyo3::exceptions::PyException);
+
+/// Turn anything the API would send as JSON into the matching Python object
+fn to_py(py: Python<'_>, value: &impl serde::Serialize) -> PyResult<PyObject> {
+    let json = serde_json::to_string(value).expect("Unable to serialise value");
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+/// Turn a Python object into anything the API would take as JSON
+fn from_py<T: serde::de::DeserializeOwned>(value: &Bound<'_, PyAny>) -> PyResult<T> {
+    let json: String = value.py().import("json")?.call_method1("dumps", (value,))?.extract()?;
+    serde_json::from_str(&json).map_err(|err| pyo3::exceptions::PyValueError::new_err(err.to_string()))
+}
+
+fn player(name: String) -> PlayerId {
+    serde_json::from_value(serde_json::Value::String(name)).expect("Player ids are strings")
+}
+
+fn tpex_err(err: tpex::Error) -> PyErr { TPExError::new_err(err.to_string()) }
+
+#[pyclass(name = "State")]
+struct PyState {
+    state: tpex::State,
+    // The state reads and writes through tokio's IO traits, though everything is in memory
+    runtime: tokio::runtime::Runtime
+}
+
+#[pymethods]
+impl PyState {
+    #[new]
+    fn new() -> PyState {
+        PyState {
+            state: tpex::State::new(),
+            runtime: tokio::runtime::Builder::new_current_thread().build().expect("Unable to start runtime")
+        }
+    }
+    /// Apply every action in a trade log, or the part of it after what has already been applied
+    fn replay(&mut self, log: &[u8]) -> PyResult<()> {
+        let PyState { state, runtime } = self;
+        runtime.block_on(state.replay(&mut &*log)).map_err(tpex_err)
+    }
+    /// Apply an action given as a dict, returning the outcome and the trade log line it was written as
+    #[pyo3(signature = (action, time = None))]
+    fn apply(&mut self, py: Python<'_>, action: &Bound<'_, PyAny>, time: Option<chrono::DateTime<chrono::Utc>>) -> PyResult<(PyObject, String)> {
+        let action: tpex::Action = from_py(action)?;
+        let mut line = Vec::new();
+        let PyState { state, runtime } = self;
+        let outcome = runtime.block_on(state.apply_with_time(action, time.unwrap_or_else(chrono::Utc::now), &mut line)).map_err(tpex_err)?;
+        Ok((to_py(py, &outcome)?, String::from_utf8(line).expect("Trade log line is not UTF-8")))
+    }
+    #[getter]
+    fn next_id(&self) -> u64 { self.state.get_next_id() }
+    fn get_bal(&self, py: Python<'_>, player_id: String) -> PyResult<PyObject> { to_py(py, &self.state.get_bal(&player(player_id))) }
+    fn get_assets(&self, py: Python<'_>, player_id: String) -> PyResult<PyObject> { to_py(py, &self.state.get_assets(&player(player_id))) }
+    fn get_bals(&self, py: Python<'_>) -> PyResult<PyObject> { to_py(py, &self.state.get_bals()) }
+    fn get_orders(&self, py: Python<'_>) -> PyResult<PyObject> { to_py(py, &self.state.get_orders()) }
+    fn get_withdrawals(&self, py: Python<'_>) -> PyResult<PyObject> { to_py(py, &self.state.get_withdrawals()) }
+    /// The (buy, sell) price levels for an asset, each mapping price to amount
+    fn get_prices(&self, py: Python<'_>, asset: String) -> PyResult<PyObject> { to_py(py, &self.state.get_prices(&asset)) }
+    /// The best (buy, sell) price and amount for an asset
+    fn get_bbo(&self, py: Python<'_>, asset: String) -> PyResult<PyObject> { to_py(py, &self.state.get_bbo(&asset)) }
+    fn get_last_price(&self, py: Python<'_>, asset: String) -> PyResult<PyObject> { to_py(py, &self.state.get_last_price(&asset)) }
+    fn is_banker(&self, player_id: String) -> bool { self.state.is_banker(&player(player_id)) }
+    /// Recalculate everything from scratch, raising if anything is inconsistent
+    fn hard_audit(&self, py: Python<'_>) -> PyResult<PyObject> {
+        to_py(py, &self.state.try_hard_audit().map_err(TPExError::new_err)?)
+    }
+}
+
+#[pymodule]
+fn tpex_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyState>()?;
+    m.add("TPExError", m.py().get_type::<TPExError>())?;
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use pyo3::prelude::*;
+use tpex::{Action, PlayerId, DIAMOND_NAME};
+
+use crate::{PyState, TPExError};
+
+#[test]
+fn apply_and_replay() {
+    #[allow(deprecated)]
+    let alice = PlayerId::evil_constructor("alice".to_owned());
+    let deposit = Action::Deposit { player: alice.clone(), asset: DIAMOND_NAME.to_owned(), count: 3, banker: PlayerId::the_bank(), note: None, reference: None };
+    let sell = Action::SellOrder { player: alice, asset: DIAMOND_NAME.to_owned(), count: 4, coins_per: tpex::Coins::from_coins(1), display_count: None };
+    Python::with_gil(|py| {
+        let mut state = PyState::new();
+        let (_, line) = state.apply(py, crate::to_py(py, &deposit).expect("Unconvertible action").bind(py), None).expect("Deposit failed");
+        assert_eq!(state.next_id(), 2);
+        let err = state.apply(py, crate::to_py(py, &sell).expect("Unconvertible action").bind(py), None).expect_err("Overdrawn sell applied");
+        assert!(err.is_instance_of::<TPExError>(py));
+
+        let mut replayed = PyState::new();
+        replayed.replay(line.as_bytes()).expect("Replay failed");
+        let assets = replayed.get_assets(py, "alice".to_owned()).expect("Unconvertible assets");
+        assert_eq!(assets.bind(py).get_item(DIAMOND_NAME).expect("No diamonds").extract::<u64>().expect("Not a count"), 3);
+        replayed.hard_audit(py).expect("Audit failed");
+    });
+}
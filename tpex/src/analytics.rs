@@ -0,0 +1,387 @@
+//! Summaries of trading, built up from applied actions for charts and dashboards
+
+use serde::{Deserialize, Serialize};
+
+use super::{Action, ApplyOutcome, AssetId, Coins, Error, PlayerId, DIAMOND_NAME};
+
+/// The most events remembered, after which the oldest are forgotten
+pub const MAX_EVENTS: usize = 10000;
+
+/// The trading in an asset over one interval
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Candle {
+    /// The start of the interval
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub open: Coins,
+    pub high: Coins,
+    pub low: Coins,
+    pub close: Coins,
+    /// The number of items traded
+    pub volume: u64
+}
+impl Candle {
+    /// Fold a later candle into this one
+    fn merge(&mut self, later: &Candle) {
+        self.high = self.high.max(later.high);
+        self.low = self.low.min(later.low);
+        self.close = later.close;
+        self.volume += later.volume;
+    }
+}
+
+/// Builds per-asset candles from applied actions, live or during a replay
+///
+/// Intervals with no trading have no candle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandleAggregator {
+    #[serde(with = "interval_millis")]
+    interval: chrono::TimeDelta,
+    candles: std::collections::HashMap<AssetId, std::collections::BTreeMap<chrono::DateTime<chrono::Utc>, Candle>>
+}
+/// Candle lengths are saved as a number of milliseconds
+mod interval_millis {
+    pub fn serialize<S: serde::Serializer>(interval: &chrono::TimeDelta, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(interval.num_milliseconds())
+    }
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<chrono::TimeDelta, D::Error> {
+        <i64 as serde::Deserialize>::deserialize(deserializer).map(chrono::TimeDelta::milliseconds)
+    }
+}
+impl CandleAggregator {
+    /// Create an aggregator with the given candle length, which must be a positive number of milliseconds
+    pub fn new(interval: chrono::TimeDelta) -> Result<CandleAggregator, Error> {
+        if interval.num_milliseconds() <= 0 {
+            return Err(Error::InvalidInterval);
+        }
+        Ok(CandleAggregator { interval, candles: Default::default() })
+    }
+    /// The length of each candle
+    pub fn interval(&self) -> chrono::TimeDelta { self.interval }
+    /// Note down the matches made by an action applied at the given time
+    pub fn observe(&mut self, time: chrono::DateTime<chrono::Utc>, outcome: &ApplyOutcome) {
+        let Some(asset) = outcome.asset.as_ref()
+        else { return; };
+        for fill in outcome.fills.iter() {
+            self.record(asset, time, fill.coins_per, fill.count);
+        }
+    }
+    /// Note down a single match
+    pub fn record(&mut self, asset: &AssetId, time: chrono::DateTime<chrono::Utc>, coins_per: Coins, count: u64) {
+        let start = Self::bucket(time, self.interval);
+        let candle = Candle { start, open: coins_per, high: coins_per, low: coins_per, close: coins_per, volume: count };
+        match self.candles.entry(asset.clone()).or_default().entry(start) {
+            std::collections::btree_map::Entry::Occupied(mut entry) => entry.get_mut().merge(&candle),
+            std::collections::btree_map::Entry::Vacant(entry) => { entry.insert(candle); }
+        }
+    }
+    /// List an asset's candles, oldest first
+    pub fn get_candles(&self, asset: &AssetId) -> Vec<Candle> {
+        self.candles.get(asset).map(|candles| candles.values().cloned().collect()).unwrap_or_default()
+    }
+    /// List an asset's candles combined into longer ones, oldest first
+    ///
+    /// The interval must be a multiple of this aggregator's interval
+    pub fn rollup(&self, asset: &AssetId, interval: chrono::TimeDelta) -> Result<Vec<Candle>, Error> {
+        let (base, target) = (self.interval.num_milliseconds(), interval.num_milliseconds());
+        if target <= 0 || !(target as u64).is_multiple_of(base as u64) {
+            return Err(Error::InvalidInterval);
+        }
+        let mut ret: Vec<Candle> = Vec::new();
+        for candle in self.candles.get(asset).into_iter().flat_map(|candles| candles.values()) {
+            let start = Self::bucket(candle.start, interval);
+            match ret.last_mut() {
+                Some(last) if last.start == start => last.merge(candle),
+                _ => ret.push(Candle { start, ..candle.clone() })
+            }
+        }
+        Ok(ret)
+    }
+    /// The start of the interval a time falls in
+    fn bucket(time: chrono::DateTime<chrono::Utc>, interval: chrono::TimeDelta) -> chrono::DateTime<chrono::Utc> {
+        let interval = interval.num_milliseconds();
+        let millis = time.timestamp_millis();
+        chrono::DateTime::from_timestamp_millis(millis - millis.rem_euclid(interval)).expect("Candle start out of range")
+    }
+}
+
+/// What caused a statement entry
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum StatementKind {
+    Deposit,
+    Undeposit,
+    /// Diamonds turned into coins
+    BuyCoins,
+    /// Coins turned into diamonds
+    SellCoins,
+    TransferIn,
+    TransferOut,
+    /// A match where the player was the buyer
+    Bought,
+    /// A match where the player was the seller
+    Sold,
+    Fee,
+    Withdrawal,
+    /// An earlier entry being undone by a banker
+    Reversal
+}
+
+/// One movement in and out of a player's account
+///
+/// Each entry balances what came in against what went out, so a match shows both the items and the coins
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct StatementEntry {
+    /// The id of the action that caused the movement
+    pub id: u64,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub kind: StatementKind,
+    /// The other player involved, if any
+    pub counterparty: Option<PlayerId>,
+    /// The item moved, if any
+    pub asset: Option<AssetId>,
+    pub items_in: u64,
+    pub items_out: u64,
+    pub coins_in: Coins,
+    pub coins_out: Coins
+}
+impl StatementEntry {
+    fn new(id: u64, time: chrono::DateTime<chrono::Utc>, kind: StatementKind) -> StatementEntry {
+        StatementEntry { id, time, kind, counterparty: None, asset: None, items_in: 0, items_out: 0, coins_in: Coins::default(), coins_out: Coins::default() }
+    }
+    /// The entry that exactly undoes this one
+    fn reversed(&self, id: u64, time: chrono::DateTime<chrono::Utc>) -> StatementEntry {
+        StatementEntry {
+            id, time,
+            kind: StatementKind::Reversal,
+            counterparty: self.counterparty.clone(),
+            asset: self.asset.clone(),
+            items_in: self.items_out,
+            items_out: self.items_in,
+            coins_in: self.coins_out,
+            coins_out: self.coins_in
+        }
+    }
+}
+
+/// Builds a statement of one player's account from applied actions, usually during a replay
+///
+/// Refunds from cancelled orders and expired withdrawals aren't shown, as only the coins and items entering and leaving the exchange and changing hands are
+#[derive(Debug, Clone)]
+pub struct StatementBuilder {
+    player: PlayerId,
+    entries: Vec<StatementEntry>,
+    /// The ids of the player's withdrawals, so that expediting them can be charged to them
+    withdrawals: std::collections::HashSet<u64>
+}
+impl StatementBuilder {
+    /// Start an empty statement for a player
+    pub fn new(player: PlayerId) -> StatementBuilder {
+        StatementBuilder { player, entries: Vec::new(), withdrawals: Default::default() }
+    }
+    /// Note down how an action applied at the given time moved the player's coins and items
+    pub fn observe(&mut self, time: chrono::DateTime<chrono::Utc>, action: &Action, outcome: &ApplyOutcome) {
+        let id = outcome.id;
+        let mut new_entries = Vec::new();
+        match action {
+            Action::Deposit { player, asset, count, .. } if player == &self.player =>
+                new_entries.push(StatementEntry { asset: Some(asset.clone()), items_in: *count, ..StatementEntry::new(id, time, StatementKind::Deposit) }),
+            Action::Undeposit { player, asset, count, .. } if player == &self.player =>
+                new_entries.push(StatementEntry { asset: Some(asset.clone()), items_out: *count, ..StatementEntry::new(id, time, StatementKind::Undeposit) }),
+            Action::BuyCoins { player, n_diamonds } if player == &self.player =>
+                new_entries.push(StatementEntry {
+                    asset: Some(DIAMOND_NAME.to_owned()), items_out: *n_diamonds,
+                    coins_in: Coins::from_diamonds(*n_diamonds).expect("BuyCoins overflow"),
+                    ..StatementEntry::new(id, time, StatementKind::BuyCoins)
+                }),
+            Action::SellCoins { player, n_diamonds } if player == &self.player =>
+                new_entries.push(StatementEntry {
+                    asset: Some(DIAMOND_NAME.to_owned()), items_in: *n_diamonds,
+                    coins_out: Coins::from_diamonds(*n_diamonds).expect("SellCoins overflow"),
+                    ..StatementEntry::new(id, time, StatementKind::SellCoins)
+                }),
+            Action::TransferCoins { payer, payee, count } => {
+                if payer == &self.player {
+                    new_entries.push(StatementEntry { counterparty: Some(payee.clone()), coins_out: *count, ..StatementEntry::new(id, time, StatementKind::TransferOut) });
+                }
+                if payee == &self.player {
+                    new_entries.push(StatementEntry { counterparty: Some(payer.clone()), coins_in: *count, ..StatementEntry::new(id, time, StatementKind::TransferIn) });
+                }
+            },
+            Action::TransferAsset { payer, payee, asset, count } => {
+                if payer == &self.player {
+                    new_entries.push(StatementEntry { counterparty: Some(payee.clone()), asset: Some(asset.clone()), items_out: *count, ..StatementEntry::new(id, time, StatementKind::TransferOut) });
+                }
+                if payee == &self.player {
+                    new_entries.push(StatementEntry { counterparty: Some(payer.clone()), asset: Some(asset.clone()), items_in: *count, ..StatementEntry::new(id, time, StatementKind::TransferIn) });
+                }
+            },
+            Action::BuyOrder { player, asset, .. } | Action::SellOrder { player, asset, .. } => {
+                let is_buy = matches!(action, Action::BuyOrder { .. });
+                for fill in outcome.fills.iter() {
+                    // The player can be on either side of the match, or even both
+                    let sides = [(player == &self.player, is_buy, &fill.counterparty), (fill.counterparty == self.player, !is_buy, player)];
+                    for (_, bought, counterparty) in sides.into_iter().filter(|(involved, _, _)| *involved) {
+                        let coins = fill.coins_per.checked_mul(fill.count).expect("Fill coins overflow");
+                        let mut entry = StatementEntry { counterparty: Some(counterparty.clone()), asset: Some(asset.clone()), ..StatementEntry::new(id, time, StatementKind::Sold) };
+                        if bought {
+                            entry.kind = StatementKind::Bought;
+                            entry.items_in = fill.count;
+                            entry.coins_out = coins;
+                        }
+                        else {
+                            entry.items_out = fill.count;
+                            entry.coins_in = coins;
+                        }
+                        new_entries.push(entry);
+                    }
+                }
+            },
+            Action::WithdrawalRequested { player, assets } if player == &self.player => {
+                self.withdrawals.insert(id);
+                let mut assets: Vec<_> = assets.iter().filter(|(_, count)| **count > 0).collect();
+                assets.sort();
+                for (asset, count) in assets {
+                    new_entries.push(StatementEntry { asset: Some(asset.clone()), items_out: *count, ..StatementEntry::new(id, time, StatementKind::Withdrawal) });
+                }
+            },
+            Action::Reverse { target, .. } => {
+                let reversed: Vec<_> = self.entries.iter().filter(|entry| entry.id == *target).map(|entry| entry.reversed(id, time)).collect();
+                new_entries.extend(reversed);
+            },
+            _ => ()
+        }
+        // Only the player's own actions charge them fees
+        let charged = match action {
+            Action::WithdrawalRequested { player, .. } => player == &self.player,
+            Action::Expedited { target } => self.withdrawals.contains(target),
+            _ => false
+        };
+        if charged && !outcome.fees_paid.is_zero() {
+            new_entries.push(StatementEntry { coins_out: outcome.fees_paid, ..StatementEntry::new(id, time, StatementKind::Fee) });
+        }
+        self.entries.extend(new_entries);
+    }
+    /// The entries so far, oldest first
+    pub fn entries(&self) -> &[StatementEntry] { &self.entries }
+    /// The entries so far as CSV, with a header row
+    ///
+    /// Coins are written as decimals, rather than in the usual display format, so that spreadsheets can add them up
+    pub fn to_csv(&self) -> String {
+        fn coins(coins: Coins) -> String { format!("{}.{:03}", coins.millicoins() / 1000, coins.millicoins() % 1000) }
+        fn quote(field: &str) -> String {
+            if field.contains([',', '"', '\n']) { format!("\"{}\"", field.replace('"', "\"\"")) } else { field.to_owned() }
+        }
+        let mut ret = "id,time,kind,counterparty,asset,items_in,items_out,coins_in,coins_out\n".to_owned();
+        for entry in self.entries.iter() {
+            ret += &[
+                entry.id.to_string(),
+                entry.time.to_rfc3339(),
+                format!("{:?}", entry.kind),
+                quote(&entry.counterparty.as_ref().map(ToString::to_string).unwrap_or_default()),
+                quote(entry.asset.as_deref().unwrap_or_default()),
+                entry.items_in.to_string(),
+                entry.items_out.to_string(),
+                coins(entry.coins_in),
+                coins(entry.coins_out)
+            ].join(",");
+            ret.push('\n');
+        }
+        ret
+    }
+}
+
+/// Something that happened to an order, withdrawal or proposal
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum EventKind {
+    /// An incoming order was matched against one resting on the book
+    ///
+    /// The incoming order has the id of the event's action
+    OrderFilled {
+        resting_order: u64,
+        asset: AssetId,
+        buyer: PlayerId,
+        seller: PlayerId,
+        count: u64,
+        coins_per: Coins
+    },
+    /// An order was taken off the book, by its owner or as a side effect of another action
+    OrderCancelled { order_id: u64 },
+    /// A banker has completed a withdrawal, so its items are ready to be handed over
+    WithdrawalReady { withdrawal_id: u64 },
+    /// A proposal was agreed to, and its action applied
+    ProposalPassed { proposal_id: u64 }
+}
+
+/// An event, along with the action that caused it
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: u64,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub kind: EventKind
+}
+
+/// Turns applied actions into events, so that clients don't need to work out fills and cancellations themselves
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct EventLog {
+    /// The most recent events, oldest first
+    events: std::collections::VecDeque<Event>,
+    /// Scheduled and proposed actions by id, as running them only names them
+    ///
+    /// Pruned proposals are never run, so stay here
+    deferred: std::collections::HashMap<u64, Action>
+}
+impl EventLog {
+    /// Note down the events caused by an action applied at the given time
+    pub fn observe(&mut self, time: chrono::DateTime<chrono::Utc>, action: &Action, outcome: &ApplyOutcome) {
+        let id = outcome.id;
+        let mut kinds = Vec::new();
+        let run = match action {
+            Action::RunScheduled { target } => self.deferred.remove(target),
+            Action::Agree { proposal_id, .. } => {
+                kinds.push(EventKind::ProposalPassed { proposal_id: *proposal_id });
+                self.deferred.remove(proposal_id)
+            },
+            Action::CancelScheduled { target: dropped } |
+            Action::Disagree { proposal_id: dropped, .. } |
+            Action::RetractProposal { proposal_id: dropped, .. } => {
+                self.deferred.remove(dropped);
+                None
+            },
+            _ => None
+        };
+        // The outcome of running an action is the outcome of the action itself
+        match run.as_ref().unwrap_or(action) {
+            Action::Schedule { action, .. } |
+            Action::Propose { action, .. } => { self.deferred.insert(id, (**action).clone()); },
+            Action::BuyOrder { player, asset, .. } | Action::SellOrder { player, asset, .. } => {
+                let is_buy = matches!(run.as_ref().unwrap_or(action), Action::BuyOrder { .. });
+                for fill in outcome.fills.iter() {
+                    let (buyer, seller) = if is_buy { (player, &fill.counterparty) } else { (&fill.counterparty, player) };
+                    kinds.push(EventKind::OrderFilled {
+                        resting_order: fill.order_id,
+                        asset: asset.clone(),
+                        buyer: buyer.clone(),
+                        seller: seller.clone(),
+                        count: fill.count,
+                        coins_per: fill.coins_per
+                    });
+                }
+            },
+            Action::CancelOrder { target } => kinds.push(EventKind::OrderCancelled { order_id: *target }),
+            Action::WithdrawalCompleted { target, .. } => kinds.push(EventKind::WithdrawalReady { withdrawal_id: *target }),
+            _ => ()
+        }
+        kinds.extend(outcome.orders_cancelled.iter().map(|order_id| EventKind::OrderCancelled { order_id: *order_id }));
+        for kind in kinds {
+            if self.events.len() >= MAX_EVENTS {
+                self.events.pop_front();
+            }
+            self.events.push_back(Event { id, time, kind });
+        }
+    }
+    /// List the remembered events caused by actions from the given id onwards, oldest first
+    pub fn get_events(&self, from_id: u64) -> Vec<Event> {
+        // Events are recorded in id order, so we can skip straight to the new ones
+        let start = self.events.partition_point(|event| event.id < from_id);
+        self.events.range(start..).cloned().collect()
+    }
+}
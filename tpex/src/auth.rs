@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+use super::{AssetId, Error, PlayerId};
+
+/// A player asking the bankers to let them withdraw a restricted item
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct AuthorisationRequest {
+    pub id: u64,
+    pub player: PlayerId,
+    pub asset: AssetId,
+    /// How many more of the item the player wants to be able to withdraw
+    pub count: u64,
+    /// Why the player wants the item, for the bankers to judge
+    pub reason: String
+}
+
+/// The point after which an authorisation no longer counts
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum AuthExpiry {
+    /// The authorisation can be used by actions up to and including this id
+    Id(u64),
+    /// The authorisation can be used until this time
+    Time(chrono::DateTime<chrono::Utc>)
+}
+impl AuthExpiry {
+    /// Returns true if an action with the given id and time is too late to use the authorisation
+    pub fn has_passed(&self, id: u64, time: chrono::DateTime<chrono::Utc>) -> bool {
+        match self {
+            AuthExpiry::Id(last_id) => id > *last_id,
+            AuthExpiry::Time(last_time) => time > *last_time
+        }
+    }
+}
+
+/// How many of a restricted item a player can still withdraw
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Authorisation {
+    pub count: u64,
+    pub expiry: Option<AuthExpiry>
+}
+impl Authorisation {
+    /// The count that can be used by an action with the given id and time
+    pub fn usable(&self, id: u64, time: chrono::DateTime<chrono::Utc>) -> u64 {
+        if self.expiry.as_ref().is_some_and(|expiry| expiry.has_passed(id, time)) { 0 } else { self.count }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AuthTracker {
+    /// How many of each restricted item each player can still withdraw
+    authorisations: std::collections::HashMap<PlayerId, std::collections::HashMap<AssetId, Authorisation>>,
+    requests: std::collections::BTreeMap<u64, AuthorisationRequest>,
+    /// Players who can authorise a specific item without being bankers
+    delegates: std::collections::HashMap<AssetId, PlayerId>
+}
+impl AuthTracker {
+    /// Get how many of an item a player can still withdraw, ignoring expiry
+    pub fn get_authorisation(&self, player: &PlayerId, asset: &AssetId) -> Option<Authorisation> {
+        self.authorisations.get(player).and_then(|x| x.get(asset)).cloned()
+    }
+    /// Set how many of an item a player can withdraw, and until when
+    pub fn authorise(&mut self, player: PlayerId, asset: AssetId, new_count: u64, expiry: Option<AuthExpiry>) {
+        self.authorisations.entry(player).or_default().insert(asset, Authorisation { count: new_count, expiry });
+    }
+    /// Check that a player is authorised to withdraw the given amount of an item, by an action with the given id and time
+    pub fn check_withdrawal(&self, player: &PlayerId, asset: &AssetId, count: u64, id: u64, time: chrono::DateTime<chrono::Utc>) -> Result<(), Error> {
+        // Check if they are authorised to withdraw any amount of these items
+        let Some(auth_amount) = self.get_authorisation(player, asset).map(|auth| auth.usable(id, time))
+        else { return Err(Error::UnauthorisedWithdrawal{ asset: asset.clone(), amount_overdrawn: None}); };
+        // Check if they are authorised to withdraw at least this many items
+        if auth_amount < count {
+            return Err(Error::UnauthorisedWithdrawal{ asset: asset.clone(), amount_overdrawn: Some(count - auth_amount)});
+        }
+        Ok(())
+    }
+    /// Use up some of a player's authorisation, which must have been checked first
+    pub fn commit_withdrawal(&mut self, player: &PlayerId, asset: &AssetId, count: u64) {
+        // TODO: Clean up after ourselves
+        self.authorisations.get_mut(player).expect("Asset player disappeared after check")
+                            .get_mut(asset).expect("Asset auth disappeared after check").count -= count;
+    }
+    /// Get the player who can authorise an item on the bank's behalf, if any
+    pub fn get_delegate(&self, asset: &AssetId) -> Option<&PlayerId> { self.delegates.get(asset) }
+    /// List all items with a delegated authoriser
+    pub fn get_delegates(&self) -> std::collections::HashMap<AssetId, PlayerId> { self.delegates.clone() }
+    /// Change who can authorise an item on the bank's behalf
+    pub fn set_delegate(&mut self, asset: AssetId, delegate: Option<PlayerId>) {
+        match delegate {
+            Some(delegate) => { self.delegates.insert(asset, delegate); },
+            None => { self.delegates.remove(&asset); }
+        }
+    }
+    /// Get a request
+    pub fn get_request(&self, id: u64) -> Result<AuthorisationRequest, Error> { self.requests.get(&id).cloned().ok_or(Error::InvalidId { id }) }
+    /// List all requests waiting for a banker
+    pub fn get_requests(&self) -> std::collections::BTreeMap<u64, AuthorisationRequest> { self.requests.clone() }
+    /// Get the request the bankers should look at next
+    pub fn get_next_request(&self) -> Option<AuthorisationRequest> { self.requests.first_key_value().map(|(_, request)| request.clone()) }
+    /// Start tracking a request
+    pub fn track_request(&mut self, request: AuthorisationRequest) {
+        self.requests.insert(request.id, request);
+    }
+    /// Stop tracking a request, so that it can be approved or denied
+    pub fn remove_request(&mut self, id: u64) -> Result<AuthorisationRequest, Error> {
+        self.requests.remove(&id).ok_or(Error::InvalidId { id })
+    }
+    /// Hand one player's authorisations and requests over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        if let Some(authorisations) = self.authorisations.remove(from) {
+            let target = self.authorisations.entry(to.clone()).or_default();
+            for (asset, auth) in authorisations {
+                match target.entry(asset) {
+                    std::collections::hash_map::Entry::Occupied(mut entry) => {
+                        // Keep the later expiry, as the bankers meant both players to have their authorisations
+                        let entry = entry.get_mut();
+                        entry.count = entry.count.saturating_add(auth.count);
+                        entry.expiry = match (entry.expiry.take(), auth.expiry) {
+                            (Some(AuthExpiry::Id(a)), Some(AuthExpiry::Id(b))) => Some(AuthExpiry::Id(a.max(b))),
+                            (Some(AuthExpiry::Time(a)), Some(AuthExpiry::Time(b))) => Some(AuthExpiry::Time(a.max(b))),
+                            // Mismatched kinds can't be compared, so don't let either run out
+                            _ => None
+                        };
+                    },
+                    std::collections::hash_map::Entry::Vacant(entry) => { entry.insert(auth); }
+                }
+            }
+        }
+        for request in self.requests.values_mut() {
+            if &request.player == from {
+                request.player = to.clone();
+            }
+        }
+        for delegate in self.delegates.values_mut().filter(|delegate| *delegate == from) {
+            *delegate = to.clone();
+        }
+    }
+}
@@ -1,14 +1,19 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::Coins;
 
 use super::{AssetId, Audit, Auditable, Error, PlayerId};
 
 
-#[derive(Default, Debug, Serialize, Clone)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct BalanceTracker {
     balances: std::collections::HashMap<PlayerId, Coins>,
     assets: std::collections::HashMap<PlayerId, std::collections::HashMap<AssetId, u64>>,
+    /// The players holding each asset, so that finding them doesn't need every player to be checked
+    holders: std::collections::HashMap<AssetId, std::collections::BTreeSet<PlayerId>>,
+    /// The players whose coin balance has changed since this was last taken
+    #[serde(skip)]
+    changed: std::collections::HashSet<PlayerId>,
 
     current_audit: Audit
 }
@@ -21,8 +26,18 @@ impl BalanceTracker {
     pub fn get_assets(&self, player: &PlayerId) -> std::collections::HashMap<AssetId, u64> {
         self.assets.get(player).map_or_else(Default::default, Clone::clone)
     }
+    /// Get everyone holding an asset, and how many they hold
+    pub fn get_holders(&self, asset: &AssetId) -> std::collections::HashMap<PlayerId, u64> {
+        self.holders.get(asset).into_iter().flatten()
+        .map(|player| (player.clone(), self.assets[player][asset]))
+        .collect()
+    }
+    /// Get every player's assets
+    pub fn get_all_assets(&self) -> std::collections::HashMap<PlayerId, std::collections::HashMap<AssetId, u64>> { self.assets.clone() }
     /// Get all balances
     pub fn get_bals(&self) -> std::collections::HashMap<PlayerId, Coins> { self.balances.clone() }
+    /// Get and forget the players whose coin balance has changed since this was last called
+    pub fn take_changed(&mut self) -> std::collections::HashSet<PlayerId> { std::mem::take(&mut self.changed) }
 
     /// Check if a player can afford to give up assets
     pub fn check_asset_removal(&self, player: &PlayerId, asset: &str, count: u64) -> Result<(), Error> {
@@ -63,11 +78,11 @@ impl BalanceTracker {
             if assets.is_empty() {
                 self.assets.remove(player);
             }
+            self.remove_holder(player, asset);
         }
         self.current_audit.sub_asset(asset.clone(), count);
         Ok(())
     }
-    #[allow(dead_code)]
     /// Check if a player can afford to pay
     pub fn check_coin_removal(&self, player: &PlayerId, count: Coins) -> Result<(), Error> {
         // If the player doesn't have an account, they definitely cannot withdraw
@@ -93,6 +108,7 @@ impl BalanceTracker {
 
         // Take away their coins
         tgt.checked_sub_assign(count).expect("Coin removal underflow");
+        self.changed.insert(player.clone());
 
         // If it's zero, clean up
         if tgt.is_zero() {
@@ -102,23 +118,84 @@ impl BalanceTracker {
         self.current_audit.sub_coins(count);
         Ok(())
     }
+    /// Decreases a player's coins and assets, but only if they can afford all of them
+    pub fn commit_multi_removal(&mut self, player: &PlayerId, coins: Coins, assets: &std::collections::HashMap<AssetId, u64>) -> Result<(), Error> {
+        // Check everything first, so that we don't take half of it
+        if !coins.is_zero() {
+            self.check_coin_removal(player, coins)?;
+        }
+        for (asset, count) in assets.iter().filter(|(_, count)| **count > 0) {
+            self.check_asset_removal(player, asset, *count)?;
+        }
+        // Now take it all
+        if !coins.is_zero() {
+            self.commit_coin_removal(player, coins).expect("Coins disappeared after check");
+        }
+        for (asset, count) in assets.iter().filter(|(_, count)| **count > 0) {
+            self.commit_asset_removal(player, asset, *count).expect("Assets disappeared after check");
+        }
+        Ok(())
+    }
+    /// Increases a player's coins and assets
+    pub fn commit_multi_add(&mut self, player: &PlayerId, coins: Coins, assets: &std::collections::HashMap<AssetId, u64>) {
+        // Don't leave empty entries lying around
+        if !coins.is_zero() {
+            self.commit_coin_add(player, coins);
+        }
+        for (asset, count) in assets.iter().filter(|(_, count)| **count > 0) {
+            self.commit_asset_add(player, asset, *count);
+        }
+    }
+    /// Move everything one player has into another player's account
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        // The totals don't change, so the audit doesn't either
+        if let Some(coins) = self.balances.remove(from) {
+            self.balances.entry(to.clone()).or_default().checked_add_assign(coins).expect("Player balance overflow");
+            self.changed.extend([from.clone(), to.clone()]);
+        }
+        if let Some(assets) = self.assets.remove(from) {
+            for asset in assets.keys() {
+                self.remove_holder(from, asset);
+                self.holders.entry(asset.clone()).or_default().insert(to.clone());
+            }
+            let target = self.assets.entry(to.clone()).or_default();
+            for (asset, count) in assets {
+                *target.entry(asset).or_default() += count;
+            }
+        }
+    }
+    /// Stop listing a player as holding an asset
+    fn remove_holder(&mut self, player: &PlayerId, asset: &AssetId) {
+        let std::collections::hash_map::Entry::Occupied(mut entry) = self.holders.entry(asset.clone())
+        else { return; };
+        entry.get_mut().remove(player);
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+    }
     /// Increases a player's asset count
     pub fn commit_asset_add(&mut self, player: &PlayerId, asset: &AssetId, count: u64) {
+        // Don't list players as holding nothing
+        if count == 0 {
+            return;
+        }
         *self.assets.entry(player.clone()).or_default().entry(asset.clone()).or_default() += count;
+        self.holders.entry(asset.clone()).or_default().insert(player.clone());
         self.current_audit.add_asset(asset.clone(), count);
     }
     /// Increases a player's coin count
     pub fn commit_coin_add(&mut self, player: &PlayerId, count: Coins) {
         self.balances.entry(player.clone()).or_default().checked_add_assign(count).expect("Player balance overflow");
+        self.changed.insert(player.clone());
         self.current_audit.add_coins(count);
     }
 }
 impl Auditable for BalanceTracker {
     fn soft_audit(&self) -> Audit { self.current_audit.clone() }
 
-    fn hard_audit(&self) -> Audit {
+    fn try_hard_audit(&self) -> Result<Audit, String> {
         if self.current_audit.coins != self.balances.values().fold(Coins::default(), |acc, i| acc.checked_add(*i).expect("Audit balance overflow")) {
-            panic!("Coins inconsistent in balance");
+            return Err("Coins inconsistent in balance".to_owned());
         }
         let mut recalced_assets: std::collections::HashMap<AssetId, u64> = std::collections::HashMap::new();
         for  player_assets in self.assets.values() {
@@ -127,8 +204,17 @@ impl Auditable for BalanceTracker {
             }
         }
         if self.current_audit.assets != recalced_assets {
-            panic!("Assets inconsistent in balance");
+            return Err("Assets inconsistent in balance".to_owned());
+        }
+        let mut recalced_holders: std::collections::HashMap<AssetId, std::collections::BTreeSet<PlayerId>> = std::collections::HashMap::new();
+        for (player, player_assets) in self.assets.iter() {
+            for asset in player_assets.keys() {
+                recalced_holders.entry(asset.clone()).or_default().insert(player.clone());
+            }
+        }
+        if self.holders != recalced_holders {
+            return Err("Holders inconsistent in balance".to_owned());
         }
-        self.soft_audit()
+        Ok(self.soft_audit())
     }
 }
@@ -19,6 +19,7 @@ impl Coins {
         .map(Coins::from_millicoins)
         .ok_or(Error::Overflow)
     }
+    pub const fn millicoins(&self) -> u64 { self.milli }
     pub const fn is_zero(&self) -> bool { self.milli == 0 }
 
     pub fn checked_add(&self, other: Coins) -> Result<Coins> {
@@ -62,10 +63,10 @@ impl Display for Coins {
         if frac == 0 {
             write!(f, "c")
         }
-        else if frac % 100 == 0 {
+        else if frac.is_multiple_of(100) {
             write!(f, ".{}c", frac/100)
         }
-        else if frac % 10 == 0 {
+        else if frac.is_multiple_of(10) {
             write!(f, ".{:02}c", frac/10)
         }
         else {
@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Coins;
+
+use super::{AssetId, Audit, Auditable, Error, PlayerId};
+
+/// The coins and assets that make up one side of an escrowed trade
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct EscrowBundle {
+    pub coins: Coins,
+    pub assets: std::collections::HashMap<AssetId, u64>
+}
+impl From<EscrowBundle> for Audit {
+    fn from(value: EscrowBundle) -> Self {
+        let mut ret = Audit { coins: value.coins, ..Default::default() };
+        // Go through add_asset so that zero counts don't end up in the audit
+        value.assets.into_iter().for_each(|(asset, count)| ret.add_asset(asset, count));
+        ret
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PendingEscrow {
+    pub id: u64,
+    /// The player who offered the trade, whose side is locked away
+    pub player: PlayerId,
+    /// The only player who can accept the trade
+    pub counterparty: PlayerId,
+    /// What the player will give the counterparty
+    pub give: EscrowBundle,
+    /// What the counterparty must give the player
+    pub want: EscrowBundle,
+    /// The time after which the trade can no longer be accepted
+    pub expiry: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct EscrowTracker {
+    pending: std::collections::BTreeMap<u64, PendingEscrow>,
+
+    current_audit: Audit
+}
+impl EscrowTracker {
+    /// Get an escrowed trade
+    pub fn get_escrow(&self, id: u64) -> Result<PendingEscrow, Error> { self.pending.get(&id).cloned().ok_or(Error::InvalidId { id }) }
+    /// List all escrowed trades
+    pub fn get_escrows(&self) -> std::collections::BTreeMap<u64, PendingEscrow> { self.pending.clone() }
+    /// Start tracking a trade, whose given side has already been taken from the player
+    pub fn track_escrow(&mut self, escrow: PendingEscrow) {
+        // We are now responsible for what was given
+        self.current_audit += escrow.give.clone().into();
+        self.pending.insert(escrow.id, escrow);
+    }
+    /// Hand one player's side of every trade over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        for escrow in self.pending.values_mut() {
+            if &escrow.player == from {
+                escrow.player = to.clone();
+            }
+            if &escrow.counterparty == from {
+                escrow.counterparty = to.clone();
+            }
+        }
+    }
+    /// Stop tracking a trade, so that its given side can be handed out
+    pub fn remove_escrow(&mut self, id: u64) -> Result<PendingEscrow, Error> {
+        let Some(res) = self.pending.remove(&id)
+        else { return Err(Error::InvalidId { id }); };
+        // We are no longer responsible for what was given
+        self.current_audit.sub_coins(res.give.coins);
+        for (asset, count) in res.give.assets.iter() {
+            self.current_audit.sub_asset(asset.clone(), *count);
+        }
+        Ok(res)
+    }
+}
+impl Auditable for EscrowTracker {
+    fn soft_audit(&self) -> Audit { self.current_audit.clone() }
+
+    fn try_hard_audit(&self) -> Result<Audit, String> {
+        let mut new_audit = Audit::default();
+        for escrow in self.pending.values() {
+            new_audit += escrow.give.clone().into();
+        }
+        if new_audit != self.current_audit {
+            return Err("Recalculated escrow audit differs from soft audit".to_owned());
+        }
+        Ok(new_audit)
+    }
+}
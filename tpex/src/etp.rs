@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+use super::{AssetId, Error, PlayerId};
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct EtpInfo {
+    /// The player who creates units, and holds the basket backing them
+    pub issuer: PlayerId,
+    /// The assets backing a single unit
+    pub basket: std::collections::HashMap<AssetId, u64>,
+    /// The number of units in existence
+    pub issued: u64,
+    /// The most units that can exist at once, set by the bankers
+    pub cap: Option<u64>,
+    /// If set, only these players (and the issuer) can be given units
+    pub allowlist: Option<std::collections::HashSet<PlayerId>>
+}
+impl EtpInfo {
+    /// Returns true if the player is allowed to be given units
+    pub fn can_receive(&self, player: &PlayerId) -> bool {
+        self.allowlist.as_ref().is_none_or(|allowlist| player == &self.issuer || allowlist.contains(player))
+    }
+}
+impl EtpInfo {
+    /// The assets backing the given number of units
+    pub fn basket_for(&self, count: u64) -> Result<std::collections::HashMap<AssetId, u64>, Error> {
+        self.basket.iter()
+        .map(|(asset, count_per)| Ok((asset.clone(), count_per.checked_mul(count).ok_or(Error::Overflow)?)))
+        .collect()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct EtpTracker {
+    products: std::collections::HashMap<AssetId, EtpInfo>
+}
+impl EtpTracker {
+    /// Get info about a product
+    pub fn get_etp(&self, product: &AssetId) -> Result<EtpInfo, Error> {
+        self.products.get(product).cloned().ok_or_else(|| Error::NotAnEtp { product: product.clone() })
+    }
+    /// List all products
+    pub fn get_etps(&self) -> std::collections::HashMap<AssetId, EtpInfo> { self.products.clone() }
+    /// Returns true if the given asset is a product
+    pub fn is_etp(&self, product: &AssetId) -> bool { self.products.contains_key(product) }
+    /// Create a product, or change the basket of one with no units
+    pub fn define(&mut self, product: AssetId, issuer: PlayerId, basket: std::collections::HashMap<AssetId, u64>) -> Result<(), Error> {
+        match self.products.entry(product) {
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                // Someone else has already taken the name
+                if entry.get().issuer != issuer {
+                    return Err(Error::EtpExists { product: entry.key().clone() });
+                }
+                // Changing the basket would change what existing units are worth
+                if entry.get().issued > 0 {
+                    return Err(Error::EtpUnitsOutstanding { product: entry.key().clone() });
+                }
+                entry.get_mut().basket = basket;
+            },
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(EtpInfo { issuer, basket, issued: 0, cap: None, allowlist: None });
+            }
+        }
+        Ok(())
+    }
+    /// Change the most units of a product that can exist at once
+    pub fn set_cap(&mut self, product: &AssetId, cap: Option<u64>) -> Result<(), Error> {
+        let Some(info) = self.products.get_mut(product)
+        else { return Err(Error::NotAnEtp { product: product.clone() }); };
+        info.cap = cap;
+        Ok(())
+    }
+    /// Change who is allowed to be given units
+    pub fn set_allowlist(&mut self, product: &AssetId, allowlist: Option<std::collections::HashSet<PlayerId>>) -> Result<(), Error> {
+        let Some(info) = self.products.get_mut(product)
+        else { return Err(Error::NotAnEtp { product: product.clone() }); };
+        info.allowlist = allowlist;
+        Ok(())
+    }
+    /// Check that new units can be created
+    pub fn check_issue(&self, product: &AssetId, count: u64) -> Result<(), Error> {
+        let info = self.products.get(product).ok_or_else(|| Error::NotAnEtp { product: product.clone() })?;
+        let new_issued = info.issued.checked_add(count).ok_or(Error::Overflow)?;
+        match info.cap {
+            Some(cap) if new_issued > cap => Err(Error::EtpCapReached { product: product.clone(), cap }),
+            _ => Ok(())
+        }
+    }
+    /// Note down that new units have been created
+    pub fn issue(&mut self, product: &AssetId, count: u64) -> Result<(), Error> {
+        self.check_issue(product, count)?;
+        let info = self.products.get_mut(product).expect("Product disappeared after check");
+        info.issued += count;
+        Ok(())
+    }
+    /// Change the size of each unit, given the new supply and basket
+    pub fn rescale(&mut self, product: &AssetId, issued: u64, basket: std::collections::HashMap<AssetId, u64>, cap: Option<u64>) {
+        let info = self.products.get_mut(product).expect("Rescaled non-existent product");
+        info.issued = issued;
+        info.basket = basket;
+        info.cap = cap;
+    }
+    /// Hand one player's products, and places on allowlists, over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        for info in self.products.values_mut() {
+            if &info.issuer == from {
+                info.issuer = to.clone();
+            }
+            if let Some(allowlist) = info.allowlist.as_mut() {
+                if allowlist.remove(from) {
+                    allowlist.insert(to.clone());
+                }
+            }
+        }
+    }
+    /// Note down that units have been destroyed
+    pub fn remove(&mut self, product: &AssetId, count: u64) {
+        let info = self.products.get_mut(product).expect("Removed units of non-existent product");
+        info.issued = info.issued.checked_sub(count).expect("Removed more units than were issued");
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Coins;
+
+use super::{Audit, Auditable};
+
+/// Where the bank's fee income came from
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+pub enum FeeSource {
+    /// The flat and per stack fees for a completed withdrawal
+    Withdrawal,
+    /// The extra fee for expediting a withdrawal
+    Expedite
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct FeeTracker {
+    income: std::collections::BTreeMap<FeeSource, Coins>,
+    total: Coins
+}
+impl FeeTracker {
+    /// Get the fee income so far, broken down by source
+    pub fn get_report(&self) -> std::collections::BTreeMap<FeeSource, Coins> { self.income.clone() }
+    /// Note down income that has been paid to the bank
+    pub fn record(&mut self, source: FeeSource, coins: Coins) {
+        if coins.is_zero() {
+            return;
+        }
+        self.income.entry(source).or_default().checked_add_assign(coins).expect("Fee income overflow");
+        self.total.checked_add_assign(coins).expect("Fee income overflow");
+    }
+}
+impl Auditable for FeeTracker {
+    // The fees themselves are in the bank's balance, so we hold nothing
+    fn soft_audit(&self) -> Audit { Audit::default() }
+
+    fn try_hard_audit(&self) -> Result<Audit, String> {
+        let mut recalc = Coins::default();
+        for coins in self.income.values() {
+            recalc.checked_add_assign(*coins).expect("Fee income overflow");
+        }
+        if recalc != self.total {
+            return Err("Fee income breakdown differs from total".to_owned());
+        }
+        Ok(Audit::default())
+    }
+}
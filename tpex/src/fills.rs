@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Coins;
+
+use super::{AssetId, OrderType, PlayerId};
+
+/// The most fills remembered for each player, after which the oldest are forgotten
+pub const MAX_FILLS_PER_PLAYER: usize = 1000;
+
+/// One side of a match, from the point of view of one player
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PlayerFill {
+    /// The id of the action that caused the match
+    pub id: u64,
+    pub asset: AssetId,
+    /// Whether the player bought or sold
+    pub side: OrderType,
+    pub count: u64,
+    pub coins_per: Coins,
+    /// The player on the other side of the match
+    pub counterparty: PlayerId,
+    /// The fee the player paid on the match, which is currently always zero as trading is free
+    pub fee: Coins
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct FillTracker {
+    /// Each player's most recent fills, oldest first
+    fills: std::collections::HashMap<PlayerId, std::collections::VecDeque<PlayerFill>>
+}
+impl FillTracker {
+    /// Note down a fill for a player, forgetting their oldest if they have too many
+    pub fn record(&mut self, player: &PlayerId, fill: PlayerFill) {
+        let fills = self.fills.entry(player.clone()).or_default();
+        if fills.len() >= MAX_FILLS_PER_PLAYER {
+            fills.pop_front();
+        }
+        fills.push_back(fill);
+    }
+    /// List a player's remembered fills from actions after the given id, oldest first
+    pub fn get_fills(&self, player: &PlayerId, since_id: u64) -> Vec<PlayerFill> {
+        let Some(fills) = self.fills.get(player)
+        else { return Vec::new(); };
+        // Fills are recorded in id order, so we can skip straight to the new ones
+        let start = fills.partition_point(|fill| fill.id <= since_id);
+        fills.range(start..).cloned().collect()
+    }
+    /// Hand one player's fill history over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        for fill in self.fills.values_mut().flatten().filter(|fill| &fill.counterparty == from) {
+            fill.counterparty = to.clone();
+        }
+        if let Some(fills) = self.fills.remove(from) {
+            let target = self.fills.entry(to.clone()).or_default();
+            // Keep the merged history in id order, and within the cap
+            let mut merged: Vec<_> = target.drain(..).chain(fills).collect();
+            merged.sort_by_key(|fill| fill.id);
+            let excess = merged.len().saturating_sub(MAX_FILLS_PER_PLAYER);
+            target.extend(merged.into_iter().skip(excess));
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Coins;
+
+use super::PlayerId;
+
+/// A player's coin balance just after an action changed it
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct BalanceCheckpoint {
+    /// The id of the action that changed the balance
+    pub id: u64,
+    pub balance: Coins
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct BalanceHistoryTracker {
+    /// Each player's balance after every action that changed it, oldest first
+    checkpoints: std::collections::HashMap<PlayerId, Vec<BalanceCheckpoint>>
+}
+impl BalanceHistoryTracker {
+    /// Note down a player's new balance
+    pub fn record(&mut self, player: &PlayerId, id: u64, balance: Coins) {
+        let checkpoints = self.checkpoints.entry(player.clone()).or_default();
+        match checkpoints.last_mut() {
+            // An action can change a balance more than once, so only keep where it ended up
+            Some(last) if last.id == id => last.balance = balance,
+            // Coming back to the same balance isn't worth a point on the chart
+            Some(last) if last.balance == balance => (),
+            _ => checkpoints.push(BalanceCheckpoint { id, balance })
+        }
+    }
+    /// List a player's balance changes from actions between the given ids inclusive, oldest first
+    ///
+    /// The last change before the range is also included if there was one, so that the balance at the start is known
+    pub fn get_history(&self, player: &PlayerId, from_id: u64, to_id: u64) -> Vec<BalanceCheckpoint> {
+        let Some(checkpoints) = self.checkpoints.get(player)
+        else { return Vec::new(); };
+        // Checkpoints are recorded in id order, so we can skip straight to the range
+        let start = checkpoints.partition_point(|checkpoint| checkpoint.id < from_id).saturating_sub(1);
+        let end = checkpoints.partition_point(|checkpoint| checkpoint.id <= to_id);
+        checkpoints.get(start..end).map(<[_]>::to_vec).unwrap_or_default()
+    }
+}
@@ -1,4 +1,4 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::Coins;
 
@@ -11,7 +11,7 @@ use super::{AssetId, Audit, Auditable, PlayerId, Error};
 //     count: u64
 // }
 
-#[derive(Default, Debug, Serialize, Clone)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct InvestmentTracker {
     // These three tables must be kept consistent
     asset_investments: std::collections::HashMap<AssetId, std::collections::HashMap<PlayerId, u64>>,
@@ -85,6 +85,24 @@ impl InvestmentTracker {
 
         Ok(())
     }
+    /// Move one player's investments to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        // The totals don't change, so the audit doesn't either
+        if let Some(investments) = self.player_investments.remove(from) {
+            for (asset, count) in investments {
+                *self.player_investments.entry(to.clone()).or_default().entry(asset.clone()).or_default() += count;
+                let asset_investments = self.asset_investments.get_mut(&asset).expect("Investment table corruption: player_investments found but asset missing");
+                asset_investments.remove(from);
+                *asset_investments.entry(to.clone()).or_default() += count;
+            }
+        }
+        if let Some(confirmed) = self.investment_confirmed.remove(from) {
+            let target = self.investment_confirmed.entry(to.clone()).or_default();
+            for (asset, count) in confirmed {
+                *target.entry(asset).or_default() += count;
+            }
+        }
+    }
     #[allow(dead_code)]
     pub fn try_mark_busy(&mut self, asset: &AssetId, count: u64) -> Result<(), Error> {
         let amount_invested = self.amount_invested.get(asset).cloned().unwrap_or_default();
@@ -114,7 +132,7 @@ impl InvestmentTracker {
 impl Auditable for InvestmentTracker {
     fn soft_audit(&self) -> Audit { self.current_audit.clone() }
 
-    fn hard_audit(&self) -> Audit {
+    fn try_hard_audit(&self) -> Result<Audit, String> {
         // Check the tables are consistent
         let asset_recalc: std::collections::HashMap<AssetId, u64> = self.asset_investments.iter()
             .map(|(asset, tab)| (asset.clone(),tab.values().sum())).collect();
@@ -126,7 +144,7 @@ impl Auditable for InvestmentTracker {
                 a
             });
         if player_recalc != asset_recalc {
-            panic!("Investment table inconsistent: player does not match asset");
+            return Err("Investment table inconsistent: player does not match asset".to_owned());
         }
         // Doesn't matter which one, they're the same
         let mut total_invested = player_recalc;
@@ -144,7 +162,7 @@ impl Auditable for InvestmentTracker {
                 *target = res;
             }
             else {
-                panic!("Investment table inconsistent: lent out non-existent asset");
+                return Err("Investment table inconsistent: lent out non-existent asset".to_owned());
             }
         }
         // Finally, filter out the empty assets
@@ -152,8 +170,8 @@ impl Auditable for InvestmentTracker {
         let new_audit = Audit{coins: Coins::default(), assets: total_invested};
         // Check to see if this matches our info
         if new_audit != self.current_audit {
-            panic!("Investment table inconsistent: recalculated audit differed from soft result");
+            return Err("Investment table inconsistent: recalculated audit differed from soft result".to_owned());
         }
-        new_audit
+        Ok(new_audit)
     }
 }
@@ -1,24 +1,51 @@
 use std::{collections::HashSet, ops::{Add, AddAssign}};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 
 // We use a base coins, which represent 1/1000 of a diamond
 use serde::{Deserialize, Serialize, ser::SerializeMap};
 
-use self::{order::PendingOrder, withdrawal::PendingWithdrawal};
+use self::{auth::Authorisation, escrow::PendingEscrow, etp::EtpInfo, loan::PendingLoan};
 
+pub mod analytics;
+mod auth;
 mod balance;
+mod escrow;
+mod etp;
+mod fees;
+mod fills;
+mod history;
 mod investment;
+mod loan;
+mod log;
+pub mod migrate;
 mod order;
+mod proposal;
+mod reserves;
+mod signing;
+mod stats;
 mod withdrawal;
 mod coins;
 #[cfg(test)]
 mod tests;
 
-pub use order::OrderType;
+pub use order::{OrderType, Fill, OrderLimits, SelfTradePolicy, DepthLevel, PriceLevel, PendingOrder};
+pub use withdrawal::PendingWithdrawal;
 pub use coins::Coins;
+pub use escrow::EscrowBundle;
+pub use fees::FeeSource;
+pub use auth::{AuthExpiry, AuthorisationRequest};
+pub use proposal::{ApprovalPolicy, Proposal};
+pub use stats::MarketStats;
+pub use fills::PlayerFill;
+pub use history::BalanceCheckpoint;
+pub use reserves::{ReservesReport, ReserveTotals, EtpBacking};
+pub use signing::{ActionSignature, SignedAction};
+pub use log::{LogFormat, convert_log, BINARY_HEADER};
 
 pub const DIAMOND_NAME: &str = "diamond";
+/// How many actions back a Reverse can reach, so that the actions kept for reversing don't pile up forever
+pub const REVERSAL_WINDOW: u64 = 10_000;
 const INITIAL_BANK_PRICES: UpdateBankPrices = UpdateBankPrices {
     withdraw_flat: Coins::from_millicoins(1000),
     withdraw_per_stack: Coins::from_millicoins(20),
@@ -82,6 +109,7 @@ pub struct AutoConversion {
     pub n_to: u64
 }
 
+// Fields added to an action after it first shipped are optional, defaulted and left out when unset, so older logs still parse
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum Action {
     /// Deleted transaction, for when someone does a bad
@@ -89,12 +117,26 @@ pub enum Action {
         reason: String,
         banker: PlayerId
     },
+    /// Undo the balance changes of an earlier deposit, undeposit, transfer or coin conversion
+    ///
+    /// Only the last REVERSAL_WINDOW actions can be reversed
+    Reverse {
+        target: u64,
+        reason: String,
+        banker: PlayerId
+    },
     /// Player deposited assets
     Deposit {
         player: PlayerId,
         asset: AssetId,
         count: u64,
         banker: PlayerId,
+        /// Free text from the banker, for resolving disputes
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+        /// Where the deposit can be checked, e.g. chest coordinates or a screenshot URL
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
     },
     /// Player asked to expedite withdrawal
     Expedited {
@@ -110,6 +152,29 @@ pub enum Action {
         target: u64,
         banker: PlayerId,
     },
+    /// A banker says they are gathering the items for a withdrawal, so that no other banker does
+    ClaimWithdrawal {
+        target: u64,
+        banker: PlayerId,
+    },
+    /// Release a banker's claim on a withdrawal, so that any banker can complete it
+    UnclaimWithdrawal {
+        target: u64,
+        banker: PlayerId,
+    },
+    /// Change how long withdrawals can wait before they can be expired
+    ///
+    /// A ttl_secs of None lets withdrawals wait forever
+    UpdateWithdrawalTtl {
+        ttl_secs: Option<u64>,
+        banker: PlayerId,
+    },
+    /// Cancel and refund every unclaimed withdrawal that has been waiting longer than the ttl
+    ///
+    /// Any restricted item authorisations used by the withdrawals are not given back
+    ExpireWithdrawals {
+        banker: PlayerId,
+    },
     /// The player got coins for giving diamonds
     BuyCoins {
         player: PlayerId,
@@ -128,6 +193,9 @@ pub enum Action {
         asset: AssetId,
         count: u64,
         coins_per: Coins,
+        /// If given, only this many will be shown on the order book at once (an iceberg order)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        display_count: Option<u64>,
     },
     /// Player offers to sell assets at a price, and locks away assets until cancelled
     ///
@@ -137,6 +205,9 @@ pub enum Action {
         asset: AssetId,
         count: u64,
         coins_per: Coins,
+        /// If given, only this many will be shown on the order book at once (an iceberg order)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        display_count: Option<u64>,
     },
     /// Updates the list of assets that require prior authorisation from an admin
     UpdateRestricted {
@@ -148,7 +219,44 @@ pub enum Action {
         authorisee: PlayerId,
         banker: PlayerId,
         asset: AssetId,
-        new_count: u64
+        new_count: u64,
+        /// If given, the authorisation can't be used after this point
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expiry: Option<AuthExpiry>,
+    },
+    /// A player asks the bankers to let them withdraw count more of a restricted item
+    RequestAuthorisation {
+        player: PlayerId,
+        asset: AssetId,
+        count: u64,
+        reason: String
+    },
+    /// A banker grants a request, adding its count to what the player can withdraw
+    ///
+    /// The player's authorisation for the item then expires at the given point, if any
+    ApproveAuthorisation {
+        target: u64,
+        banker: PlayerId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expiry: Option<AuthExpiry>,
+    },
+    /// A banker turns down a request
+    DenyAuthorisation {
+        target: u64,
+        banker: PlayerId,
+    },
+    /// Let a player authorise, and approve or deny requests for, one restricted item without being a banker
+    ///
+    /// A delegate of None takes the power back
+    DelegateAuthoriser {
+        asset: AssetId,
+        delegate: Option<PlayerId>,
+        banker: PlayerId,
+    },
+    /// Adds or updates the info of the given items, so that they can be deposited and charged for properly
+    UpdateAssetInfo {
+        asset_info: std::collections::HashMap<AssetId, AssetInfo>,
+        banker: PlayerId,
     },
     /// Changes the fees
     UpdateBankPrices {
@@ -188,6 +296,61 @@ pub enum Action {
         asset: AssetId,
         count: u64
     },
+    /// Changes what happens when a player's order would match their own
+    UpdateSelfTradePolicy {
+        policy: SelfTradePolicy,
+        banker: PlayerId,
+    },
+    /// Limit how much a player can have on the order book at once
+    ///
+    /// A player of None changes the limits for everyone without their own limits
+    UpdateOrderLimits {
+        player: Option<PlayerId>,
+        limits: OrderLimits,
+        banker: PlayerId,
+    },
+    /// Stop a player from performing any actions, for compromised or disputed accounts
+    ///
+    /// Bankers can still act on the account, e.g. to fix balances
+    FreezeAccount {
+        player: PlayerId,
+        reason: String,
+        banker: PlayerId,
+    },
+    /// Let a frozen player perform actions again
+    UnfreezeAccount {
+        player: PlayerId,
+        banker: PlayerId,
+    },
+    /// Move everything a player has to a new id, for when they change their name
+    ///
+    /// The old id is recorded as an alias, and can no longer perform actions.
+    /// Scheduled actions are not rewritten, so will most likely fail
+    MigrateAccount {
+        from: PlayerId,
+        to: PlayerId,
+        banker: PlayerId,
+    },
+    /// Stop all new orders for an asset, for when something has gone wrong with it
+    ///
+    /// Orders can still be cancelled
+    HaltTrading {
+        asset: AssetId,
+        banker: PlayerId,
+    },
+    /// Allow new orders for a halted asset again
+    ResumeTrading {
+        asset: AssetId,
+        banker: PlayerId,
+    },
+    /// Reject orders for an asset priced more than the given percentage away from its last traded price
+    ///
+    /// A max_deviation_percent of None removes the band
+    UpdatePriceBand {
+        asset: AssetId,
+        max_deviation_percent: Option<u64>,
+        banker: PlayerId,
+    },
     /// Cancel the remaining assets and coins in a buy or sell order
     CancelOrder {
         target: u64
@@ -226,47 +389,171 @@ pub enum Action {
     //     to: AssetId,
     //     count: u64
     // },
+    /// Lock away coins and assets to swap with a specific player, who can accept until the expiry
+    OfferEscrow {
+        player: PlayerId,
+        counterparty: PlayerId,
+        give: EscrowBundle,
+        want: EscrowBundle,
+        expiry: Option<chrono::DateTime<chrono::Utc>>
+    },
+    /// The counterparty agrees to an escrowed trade, swapping both sides at once
+    AcceptEscrow {
+        target: u64
+    },
+    /// The offering player takes back an escrowed trade that hasn't been accepted
+    CancelEscrow {
+        target: u64
+    },
+    /// Lock away coins to lend to a player, who must lock away collateral to take them
+    OfferLoan {
+        lender: PlayerId,
+        borrower: PlayerId,
+        principal: Coins,
+        repayment: Coins,
+        collateral: std::collections::HashMap<AssetId, u64>,
+        due: chrono::DateTime<chrono::Utc>
+    },
+    /// The borrower locks away the collateral and takes the principal
+    AcceptLoan {
+        target: u64
+    },
+    /// The borrower pays the lender back, and gets their collateral back
+    RepayLoan {
+        target: u64
+    },
+    /// The lender takes the collateral of a loan that wasn't repaid in time
+    LiquidateCollateral {
+        target: u64
+    },
+    /// The lender takes back a loan that hasn't been accepted
+    CancelLoan {
+        target: u64
+    },
+    /// Declare a new exchange traded product, backed by the given basket of assets per unit
+    ///
+    /// The basket can only be changed while no units exist
+    DefineEtp {
+        issuer: PlayerId,
+        product: AssetId,
+        basket: std::collections::HashMap<AssetId, u64>
+    },
+    /// The issuer creates new units of a product in their own account
+    IssueEtp {
+        product: AssetId,
+        count: u64
+    },
+    /// The issuer destroys units of a product from their own account
+    RemoveEtp {
+        product: AssetId,
+        count: u64
+    },
+    /// Limit how many units of a product can exist at once
+    ///
+    /// A cap of None removes the limit. Lowering the cap below the current supply only stops new units
+    UpdateEtpCap {
+        product: AssetId,
+        cap: Option<u64>,
+        banker: PlayerId,
+    },
+    /// The issuer changes the size of a product's units, giving ratio.0 new units for every ratio.1 old ones
+    ///
+    /// Every holding and the basket must divide exactly, and no units can be tied up in orders or trades
+    SplitEtp {
+        product: AssetId,
+        ratio: (u64, u64)
+    },
+    /// The issuer restricts who can be given units of a product, for private placements
+    ///
+    /// An allowlist of None lets anyone hold units. Players already holding units keep them
+    UpdateEtpAllowlist {
+        product: AssetId,
+        allowlist: Option<Vec<PlayerId>>
+    },
+    /// A player swaps the basket for new units, which the issuer keeps as backing
+    CreateUnits {
+        player: PlayerId,
+        product: AssetId,
+        count: u64
+    },
+    /// A player swaps units back for the basket, which is taken from the issuer
+    RedeemUnits {
+        player: PlayerId,
+        product: AssetId,
+        count: u64
+    },
+    /// Perform an action at a later time, with the permissions it would need now
+    ///
+    /// The server runs it with RunScheduled once it is due
+    Schedule {
+        at: chrono::DateTime<chrono::Utc>,
+        action: Box<Action>
+    },
+    /// Perform a scheduled action that is now due
+    RunScheduled {
+        target: u64
+    },
+    /// Stop a scheduled action from being performed
+    CancelScheduled {
+        target: u64
+    },
+    /// A banker proposes a banker action, which happens once another banker agrees to it
+    ///
+    /// This is needed for the actions picked out by the approval policy
+    Propose {
+        action: Box<Action>,
+        /// A short summary for the other bankers, which older logs don't have
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        /// Why the action is being proposed, which older logs don't have
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+    },
+    /// Another banker agrees to a proposal, performing its action
+    Agree {
+        proposal_id: u64,
+        banker: PlayerId,
+    },
+    /// Another banker disagrees with a proposal, throwing it away
+    Disagree {
+        proposal_id: u64,
+        banker: PlayerId,
+    },
+    /// The proposer takes back a proposal before anyone agrees to it
+    RetractProposal {
+        proposal_id: u64,
+        player: PlayerId,
+    },
+    /// Change which banker actions need a second banker to agree to them
+    UpdateApprovalPolicy {
+        policy: ApprovalPolicy,
+        banker: PlayerId,
+    },
+    /// Throw away every proposal that has been waiting longer than the policy's ttl
+    PruneProposals {
+        banker: PlayerId,
+    },
+    /// Change the Ed25519 key the player signs their actions with, or stop them signing with None
+    SetSigningKey {
+        player: PlayerId,
+        /// The base64 public key
+        public_key: Option<String>,
+    },
     /// Used to correct typos
     Undeposit {
         player: PlayerId,
         asset: AssetId,
         count: u64,
-        banker: PlayerId
+        banker: PlayerId,
+        /// Free text from the banker, for resolving disputes
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+        /// Where the removal can be checked, e.g. chest coordinates or a screenshot URL
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reference: Option<String>,
     },
 }
-impl Action {
-    fn adjust_audit(&self, mut audit: Audit) -> Option<Audit> {
-        match self {
-            Action::Deposit { asset, count, .. } => {
-                audit.add_asset(asset.clone(), *count);
-                Some(audit)
-            },
-            Action::Undeposit { asset, count, .. } => {
-                audit.sub_asset(asset.clone(), *count);
-                Some(audit.clone())
-            }
-            Action::WithdrawalCompleted{..} => {
-                // We don't know what the withdrawal is just from the id
-                //
-                // TODO: find a way to track this nicely
-                None
-            },
-            Action::BuyCoins { n_diamonds,.. } => {
-                audit.add_coins(Coins::from_diamonds(*n_diamonds).expect("BuyCoins overflow in audit"));
-                audit.sub_asset(DIAMOND_NAME.to_owned(), *n_diamonds);
-                Some(audit)
-            },
-            Action::SellCoins { n_diamonds, .. } => {
-                audit.sub_coins(Coins::from_diamonds(*n_diamonds).expect("SellCoins overflow in audit"));
-                audit.add_asset(DIAMOND_NAME.to_owned(), *n_diamonds);
-                Some(audit)
-            },
-            _ => Some(audit)
-        }
-    }
-}
-
-#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize)]
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Audit {
     pub coins: Coins,
     pub assets: std::collections::HashMap<AssetId, u64>
@@ -313,28 +600,159 @@ impl AddAssign for Audit {
     }
 }
 
+/// The total coins and assets we hold, broken down by where they are held
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ItemisedAudit {
+    /// What players can freely use, in their balances
+    pub free: Audit,
+    /// What is locked away in everything other than balances
+    pub locked: Audit,
+    pub escrow: Audit,
+    pub fees: Audit,
+    pub investment: Audit,
+    pub loan: Audit,
+    pub order: Audit,
+    pub withdrawal: Audit,
+    /// The basket needed to back each exchange traded product's outstanding units
+    ///
+    /// This is part of the issuers' holdings, so is already counted in the rest of the audit
+    pub etp_backing: std::collections::HashMap<AssetId, Audit>
+}
+
+/// How an action changes the total coins and assets we hold
+#[derive(Default, Debug, Clone)]
+struct AuditDelta {
+    added: Audit,
+    removed: Audit
+}
+impl AuditDelta {
+    /// Swap what is added and removed, for undoing an action
+    fn reversed(self) -> AuditDelta { AuditDelta { added: self.removed, removed: self.added } }
+    /// Work out what the audit should be after the change
+    fn apply(self, mut audit: Audit) -> Audit {
+        audit += self.added;
+        audit.sub_coins(self.removed.coins);
+        for (asset, count) in self.removed.assets {
+            audit.sub_asset(asset, count);
+        }
+        audit
+    }
+}
+
 pub trait Auditable {
     // Check internal counters, will be called after every action
     fn soft_audit(&self) -> Audit;
-    // Verify internal counters, will be called rarely. Should panic if inconsistencies found
-    fn hard_audit(&self) -> Audit;
+    // Recalculate internal counters from scratch, will be called rarely. Returns what is inconsistent, if anything
+    fn try_hard_audit(&self) -> std::result::Result<Audit, String>;
+    // Verify internal counters, will be called rarely. Panics if inconsistencies found
+    fn hard_audit(&self) -> Audit {
+        self.try_hard_audit().unwrap_or_else(|err| panic!("{err}"))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct WrappedAction {
+    // The schema version the action was written with
+    version: u32,
     // The id of the action, which should equal the line number of the trades list
     id: u64,
     // The time this action was performed
     time: chrono::DateTime<chrono::Utc>,
     // The action itself
     action: Action,
+    // The submitter's signature over the action, if they signed it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<ActionSignature>,
+}
+impl WrappedAction {
+    /// Get the id of the action
+    pub fn get_id(&self) -> u64 { self.id }
+    /// Get the action itself
+    pub fn get_action(&self) -> &Action { &self.action }
+    /// Get when the action was applied
+    pub fn get_time(&self) -> chrono::DateTime<chrono::Utc> { self.time }
+}
+
+/// A saved copy of the state, so that loading it only needs the actions after it replayed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    // The schema version of the actions the state was built from
+    version: u32,
+    state: State
+}
+impl Snapshot {
+    /// Get the id of the next action the saved state would apply
+    pub fn get_next_id(&self) -> u64 { self.state.next_id }
+    /// Get the hash chained over every action the saved state has applied, which the trade file must match
+    pub fn get_log_digest(&self) -> &str { &self.state.log_digest }
+}
+
+/// What happened when an action was applied
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ApplyOutcome {
+    /// The id of the applied action
+    pub id: u64,
+    /// The orders on the book that were instantly matched
+    pub fills: Vec<Fill>,
+    /// The fees taken from the player
+    pub fees_paid: Coins,
+    /// The amount of an order that was left on the book
+    pub amount_rested: u64,
+    /// The orders that were cancelled as a side effect
+    pub orders_cancelled: Vec<u64>,
+    /// The asset traded, if an order was placed
+    pub asset: Option<AssetId>
+}
+
+/// How the units of an exchange traded product are spread out
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct EtpSupply {
+    /// The number of units in existence
+    pub issued: u64,
+    /// The units held by anyone other than the issuer, which must be backed
+    pub outstanding: u64,
+    /// The units held by the issuer themselves
+    pub held_by_issuer: u64
+}
+
+/// Who holds an asset
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct AssetDistribution {
+    /// The total amount held by the exchange, including what is locked away in orders, trades, and withdrawals
+    pub total: u64,
+    /// Each player's balance plus what they have locked in sell orders, largest first
+    pub holders: Vec<(PlayerId, u64)>
+}
+
+/// A banker's annotations on a deposit or undeposit
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct ActionNote {
+    /// The player whose assets changed
+    pub player: PlayerId,
+    /// The banker who performed the action
+    pub banker: PlayerId,
+    pub note: Option<String>,
+    pub reference: Option<String>
+}
+
+/// An action waiting to be performed
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ScheduledAction {
+    pub id: u64,
+    /// The time from which it can be performed
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub action: Action,
+    /// Whether a second banker agreed to it being scheduled, so it doesn't need approving again when it is run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub approved: bool
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct AssetInfo {
-    stack_size: u64
+    /// The most of this item that fits in one inventory slot, which withdrawal fees are charged per
+    pub stack_size: u64
 }
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum Error {
     OverdrawnAsset {
         asset: AssetId,
@@ -354,7 +772,43 @@ pub enum Error {
     AlreadyDone,
     IsNotABanker{player: PlayerId},
     CoinStringMangled,
-    CoinStringTooPrecise
+    CoinStringTooPrecise,
+    InvalidDisplayCount,
+    SelfTrade{order: u64},
+    TradingHalted{asset: AssetId},
+    OutsidePriceBand{asset: AssetId, last_price: Coins, max_deviation_percent: u64},
+    EscrowExpired{id: u64},
+    LoanOverdue{id: u64},
+    LoanNotDue{id: u64, due: chrono::DateTime<chrono::Utc>},
+    LoanNotAccepted{id: u64},
+    NotAnEtp{product: AssetId},
+    EtpExists{product: AssetId},
+    EtpUnitsOutstanding{product: AssetId},
+    EtpUnderbacked{product: AssetId, asset: AssetId, amount_short: u64},
+    EtpCapReached{product: AssetId, cap: u64},
+    EtpUnitsLocked{product: AssetId},
+    InvalidSplit{product: AssetId},
+    TransferRestricted{product: AssetId, player: PlayerId},
+    NotReversible{id: u64},
+    CannotSchedule,
+    AccountFrozen{player: PlayerId, reason: String},
+    WithdrawalClaimed{id: u64, banker: PlayerId},
+    InvalidAssetInfo{asset: AssetId},
+    TooManyOrders{max_open_orders: u64},
+    TooManyBuyCoins{max_buy_coins: Coins},
+    AccountMigrated{player: PlayerId, to: PlayerId},
+    NotDue{id: u64, at: chrono::DateTime<chrono::Utc>},
+    NotRestricted{asset: AssetId},
+    NeedsApproval,
+    NotProposable,
+    OwnProposal{id: u64},
+    ProposalExpired{id: u64},
+    NotProposer{id: u64},
+    InvalidInterval,
+    InvalidSignature,
+    InvalidSigningKey,
+    UnsupportedVersion{version: Option<u64>},
+    SnapshotMismatch
 }
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -402,6 +856,117 @@ impl std::fmt::Display for Error {
             Error::CoinStringTooPrecise => {
             write!(f, "Too much precision was given for the coins: the system can only handle 3 decimal places.")
             },
+            Error::InvalidDisplayCount => {
+                write!(f, "An order must display at least one item at a time.")
+            },
+            Error::SelfTrade { order } => {
+                write!(f, "This order would match your own order {order}.")
+            },
+            Error::TradingHalted { asset } => {
+                write!(f, "Trading of {asset} has been halted by the bankers.")
+            },
+            Error::OutsidePriceBand { asset, last_price, max_deviation_percent } => {
+                write!(f, "Orders for {asset} must be priced within {max_deviation_percent}% of the last traded price of {last_price}.")
+            },
+            Error::EscrowExpired { id } => {
+                write!(f, "The escrowed trade {id} has expired.")
+            },
+            Error::LoanOverdue { id } => {
+                write!(f, "The loan {id} is already past its due date.")
+            },
+            Error::LoanNotDue { id, due } => {
+                write!(f, "The loan {id} is not due until {due}.")
+            },
+            Error::LoanNotAccepted { id } => {
+                write!(f, "The loan {id} has not been accepted.")
+            },
+            Error::NotAnEtp { product } => {
+                write!(f, "The item \"{product}\" is not an exchange traded product.")
+            },
+            Error::EtpExists { product } => {
+                write!(f, "The name \"{product}\" is already taken.")
+            },
+            Error::EtpUnitsOutstanding { product } => {
+                write!(f, "The basket of {product} cannot change while units of it exist.")
+            },
+            Error::EtpUnderbacked { product, asset, amount_short } => {
+                write!(f, "The issuer of {product} needs {amount_short} more {asset} to back its units.")
+            },
+            Error::EtpCapReached { product, cap } => {
+                write!(f, "No more than {cap} units of {product} can exist at once.")
+            },
+            Error::EtpUnitsLocked { product } => {
+                write!(f, "Some units of {product} are tied up in orders or trades.")
+            },
+            Error::InvalidSplit { product } => {
+                write!(f, "The holdings and basket of {product} cannot be split exactly by that ratio.")
+            },
+            Error::TransferRestricted { product, player } => {
+                write!(f, "{player} is not allowed to hold {product}.")
+            },
+            Error::NotReversible { id } => {
+                write!(f, "The action {id} cannot be reversed, has already been reversed, or is too old to reverse.")
+            },
+            Error::AccountFrozen { player, reason } => {
+                write!(f, "The account {player} has been frozen by the bankers: {reason}")
+            },
+            Error::WithdrawalClaimed { id, banker } => {
+                write!(f, "The withdrawal {id} is being handled by {banker}.")
+            },
+            Error::InvalidAssetInfo { asset } => {
+                write!(f, "The info given for {asset} is invalid: stack sizes must be at least 1.")
+            },
+            Error::TooManyOrders { max_open_orders } => {
+                write!(f, "Players can only have {max_open_orders} orders open at once.")
+            },
+            Error::TooManyBuyCoins { max_buy_coins } => {
+                write!(f, "Players can only have {max_buy_coins} locked away in buy orders at once.")
+            },
+            Error::AccountMigrated { player, to } => {
+                write!(f, "The account {player} has been moved to {to}.")
+            },
+            Error::CannotSchedule => {
+                write!(f, "Scheduling actions cannot themselves be scheduled.")
+            },
+            Error::NotDue { id, at } => {
+                write!(f, "The scheduled action {id} is not due until {at}.")
+            },
+            Error::NotRestricted { asset } => {
+                write!(f, "The item {asset} does not need authorisation to withdraw.")
+            },
+            Error::NeedsApproval => {
+                write!(f, "This action must be proposed, and agreed to by another banker.")
+            },
+            Error::NotProposable => {
+                write!(f, "Only banker actions can be proposed.")
+            },
+            Error::OwnProposal { id } => {
+                write!(f, "Bankers cannot vote on their own proposal {id}.")
+            },
+            Error::ProposalExpired { id } => {
+                write!(f, "The proposal {id} has expired.")
+            },
+            Error::InvalidInterval => {
+                write!(f, "The interval must be a positive multiple of the base interval.")
+            },
+            Error::NotProposer { id } => {
+                write!(f, "Only the proposer can retract the proposal {id}.")
+            },
+            Error::InvalidSignature => {
+                write!(f, "The signature is invalid, or was not made with the acting player's signing key.")
+            },
+            Error::InvalidSigningKey => {
+                write!(f, "Signing keys must be base64 Ed25519 public keys.")
+            },
+            Error::UnsupportedVersion { version: Some(version) } => {
+                write!(f, "The trade file has an action with schema version {version}, which this version of TPEx cannot read.")
+            },
+            Error::UnsupportedVersion { version: None } => {
+                write!(f, "The trade file has an action with an invalid schema version.")
+            },
+            Error::SnapshotMismatch => {
+                write!(f, "The snapshot is damaged, or does not match the start of the trade file.")
+            },
         }
 
     }
@@ -409,7 +974,7 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {}
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct UpdateBankPrices {
     withdraw_flat: Coins,
     withdraw_per_stack: Coins,
@@ -417,22 +982,59 @@ struct UpdateBankPrices {
     investment_share: f64
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct State {
     next_id: u64,
     asset_info: std::collections::HashMap<AssetId, AssetInfo>,
     fees: UpdateBankPrices,
+    withdrawal_ttl_secs: Option<u64>,
 
+    #[serde(rename = "restricted")]
     restricted_assets: std::collections::HashSet<AssetId>,
-    authorisations: std::collections::HashMap<PlayerId, std::collections::HashMap<AssetId, u64>>,
+    #[serde(rename = "halted")]
+    halted_assets: std::collections::HashSet<AssetId>,
+    price_bands: std::collections::HashMap<AssetId, u64>,
     investables: std::collections::HashSet<AssetId>,
 
     earnings: std::collections::HashMap<PlayerId, Coins>,
     bankers: std::collections::HashSet<PlayerId>,
+    /// Players who cannot perform actions, and why
+    frozen: std::collections::HashMap<PlayerId, String>,
+    /// Old player ids, and the ids their accounts were moved to
+    migrated: std::collections::HashMap<PlayerId, PlayerId>,
+    self_trade_policy: SelfTradePolicy,
+    default_order_limits: OrderLimits,
+    order_limits: std::collections::HashMap<PlayerId, OrderLimits>,
+    /// Actions that can still be reversed, by id
+    reversible: std::collections::BTreeMap<u64, Action>,
+    scheduled: std::collections::BTreeMap<u64, ScheduledAction>,
+    approval_policy: ApprovalPolicy,
+    /// Annotations on deposits and undeposits, by id
+    action_notes: std::collections::BTreeMap<u64, ActionNote>,
+    /// How new actions are written to the trade file
+    #[serde(skip)]
+    log_format: LogFormat,
+    /// Whether the binary header is already in the trade file, so that it is only written once
+    #[serde(skip)]
+    log_header_written: bool,
+    /// A hash chained over every action applied, so that a snapshot can be matched up with its trade file
+    log_digest: String,
+    /// The total coins and assets we hold, kept up to date as actions are applied so that it needn't be summed from the trackers
+    audit: Audit,
 
+    auth: auth::AuthTracker,
     balance: balance::BalanceTracker,
+    balance_history: history::BalanceHistoryTracker,
+    escrow: escrow::EscrowTracker,
+    etp: etp::EtpTracker,
+    fees_earned: fees::FeeTracker,
+    signing: signing::SigningKeyTracker,
     investment: investment::InvestmentTracker,
+    loan: loan::LoanTracker,
     order: order::OrderTracker,
+    fills: fills::FillTracker,
+    proposal: proposal::ProposalTracker,
+    stats: stats::StatsTracker,
     withdrawal: withdrawal::WithdrawalTracker
 }
 impl Default for State {
@@ -441,16 +1043,41 @@ impl Default for State {
         State {
             asset_info,
             fees: INITIAL_BANK_PRICES,
+            withdrawal_ttl_secs: None,
             restricted_assets: Default::default(),
-            authorisations: Default::default(),
+            halted_assets: Default::default(),
+            price_bands: Default::default(),
             earnings: Default::default(),
             // Start on ID 1 for nice mapping to line numbers
             next_id: 1,
             bankers: [PlayerId::the_bank()].into_iter().collect(),
+            frozen: Default::default(),
+            migrated: Default::default(),
             investables: Default::default(),
+            self_trade_policy: Default::default(),
+            default_order_limits: Default::default(),
+            order_limits: Default::default(),
+            reversible: Default::default(),
+            scheduled: Default::default(),
+            approval_policy: Default::default(),
+            action_notes: Default::default(),
+            audit: Default::default(),
+            log_format: Default::default(),
+            log_header_written: false,
+            log_digest: Default::default(),
+            auth: Default::default(),
             balance: Default::default(),
+            balance_history: Default::default(),
+            escrow: Default::default(),
+            etp: Default::default(),
+            fees_earned: Default::default(),
+            signing: Default::default(),
             investment: Default::default(),
+            loan: Default::default(),
             order: Default::default(),
+            fills: Default::default(),
+            proposal: Default::default(),
+            stats: Default::default(),
             withdrawal: Default::default(),
         }
     }
@@ -462,8 +1089,22 @@ impl State {
     pub fn update_asset_info(&mut self, asset_info: std::collections::HashMap<AssetId, AssetInfo>) {
         self.asset_info.extend(asset_info);
     }
+    /// Choose how new actions are written to the trade file, which can only be done before any have been
+    ///
+    /// Replaying a trade file picks its format automatically
+    pub fn set_log_format(&mut self, format: LogFormat) -> Result<()> {
+        if self.next_id != 1 {
+            return Err(Error::AlreadyDone);
+        }
+        self.log_format = format;
+        Ok(())
+    }
+    /// Get how new actions are written to the trade file
+    pub fn get_log_format(&self) -> LogFormat { self.log_format }
     /// Get the next line
     pub fn get_next_id(&self) -> u64 { self.next_id }
+    /// Get the hash chained over every action applied so far
+    pub fn get_log_digest(&self) -> &str { &self.log_digest }
     /// Get a player's balance
     pub fn get_bal(&self, player: &PlayerId) -> Coins { self.balance.get_bal(player) }
     /// Get all balances
@@ -472,32 +1113,197 @@ impl State {
     pub fn get_assets(&self, player: &PlayerId) -> std::collections::HashMap<AssetId, u64> { self.balance.get_assets(player) }
     /// Calculate the withdrawal fees
     pub fn calc_withdrawal_fee(&self, assets: &std::collections::HashMap<AssetId, u64>) -> Result<Coins> {
-        let mut total_fee = self.fees.withdraw_flat;
-        for (asset, count) in assets {
-            total_fee.checked_add_assign(
-                self.fees.withdraw_per_stack.checked_mul(
-                    count.div_ceil(self.asset_info.get(asset).ok_or(Error::UnknownAsset{asset:asset.clone()})?.stack_size)
-                )?
-            )?
-        }
-        Ok(total_fee)
+        withdrawal::calc_withdrawal_fee(self.fees.withdraw_flat, self.fees.withdraw_per_stack, &self.asset_info, assets)
     }
+    /// Get the fee income the bank has received so far, broken down by source
+    pub fn get_fee_report(&self) -> std::collections::BTreeMap<FeeSource, Coins> { self.fees_earned.get_report() }
+    /// Get the fees a banker has earned by completing withdrawals
+    pub fn get_earnings(&self, banker: &PlayerId) -> Coins { self.earnings.get(banker).cloned().unwrap_or_default() }
     /// Get the expedite fee
     pub fn expedite_fee(&self) -> Coins { self.fees.expedited }
     /// List all withdrawals
     pub fn get_withdrawals(&self) -> std::collections::BTreeMap<u64, PendingWithdrawal> { self.withdrawal.get_withdrawals() }
     /// Get the withdrawal the bankers should examine next
     pub fn get_next_withdrawal(&self) -> Option<PendingWithdrawal> { self.withdrawal.get_next_withdrawal() }
+    /// Get the withdrawal the given banker should examine next, skipping those claimed by other bankers
+    pub fn get_next_withdrawal_for(&self, banker: &PlayerId) -> Option<PendingWithdrawal> { self.withdrawal.get_next_withdrawal_for(banker) }
+    /// List all withdrawals that no banker has claimed
+    pub fn get_unclaimed_withdrawals(&self) -> std::collections::BTreeMap<u64, PendingWithdrawal> { self.withdrawal.get_unclaimed_withdrawals() }
     /// List all orders
     pub fn get_orders(&self) -> std::collections::BTreeMap<u64, PendingOrder> { self.order.get_all() }
     /// Get a specific order
     pub fn get_order(&self, id: u64) -> Result<PendingOrder> { self.order.get_order(id) }
     /// Prices for an asset, returns (price, amount) in (buy, sell)
+    ///
+    /// Only the visible amount of iceberg orders is included
     pub fn get_prices(&self, asset: &AssetId) -> (std::collections::BTreeMap<Coins, u64>, std::collections::BTreeMap<Coins, u64>) { self.order.get_prices(asset) }
+    /// The best visible buy and sell prices for an asset, and the amount at each, returns (buy, sell)
+    pub fn get_bbo(&self, asset: &AssetId) -> (Option<PriceLevel>, Option<PriceLevel>) { self.order.get_bbo(asset) }
+    /// The best max_levels visible price levels for an asset on each side, best first, returns (buy, sell)
+    ///
+    /// Each level includes the running totals from the best price
+    pub fn get_depth(&self, asset: &AssetId, max_levels: usize) -> Result<(Vec<DepthLevel>, Vec<DepthLevel>)> { self.order.get_depth(asset, max_levels) }
+    /// List all escrowed trades
+    pub fn get_escrows(&self) -> std::collections::BTreeMap<u64, PendingEscrow> { self.escrow.get_escrows() }
+    /// Get a specific escrowed trade
+    pub fn get_escrow(&self, id: u64) -> Result<PendingEscrow> { self.escrow.get_escrow(id) }
+    /// List all loans, offered or accepted
+    pub fn get_loans(&self) -> std::collections::BTreeMap<u64, PendingLoan> { self.loan.get_loans() }
+    /// Get a specific loan
+    pub fn get_loan(&self, id: u64) -> Result<PendingLoan> { self.loan.get_loan(id) }
+    /// Get how much of an asset the exchange holds, and who holds it
+    pub fn get_asset_distribution(&self, asset: &AssetId) -> AssetDistribution {
+        let total = self.soft_audit().assets.get(asset).copied().unwrap_or(0);
+        let mut holders = self.balance.get_holders(asset);
+        for (player, count) in self.order.get_sellers(asset) {
+            *holders.entry(player).or_default() += count;
+        }
+        let mut holders: Vec<_> = holders.into_iter().collect();
+        holders.sort_by(|(a_player, a_count), (b_player, b_count)| b_count.cmp(a_count).then_with(|| a_player.cmp(b_player)));
+        AssetDistribution { total, holders }
+    }
+    /// List all exchange traded products
+    pub fn get_etps(&self) -> std::collections::HashMap<AssetId, EtpInfo> { self.etp.get_etps() }
+    /// Get info about an exchange traded product
+    pub fn get_etp(&self, product: &AssetId) -> Result<EtpInfo> { self.etp.get_etp(product) }
+    /// Get how many units of a product exist, and who holds them
+    pub fn get_etp_supply(&self, product: &AssetId) -> Result<EtpSupply> {
+        let info = self.etp.get_etp(product)?;
+        let held_by_issuer = self.balance.get_assets(&info.issuer).get(product).copied().unwrap_or(0);
+        Ok(EtpSupply { issued: info.issued, outstanding: info.issued - held_by_issuer, held_by_issuer })
+    }
+    /// Check that the issuer of a product holds enough of its basket to back every unit they don't hold themselves
+    pub fn check_etp_backing(&self, product: &AssetId) -> Result<()> {
+        let info = self.etp.get_etp(product)?;
+        let issuer_assets = self.balance.get_assets(&info.issuer);
+        let outstanding = self.get_etp_supply(product)?.outstanding;
+        for (asset, count_per) in info.basket {
+            let required = count_per.checked_mul(outstanding).ok_or(Error::Overflow)?;
+            let held = issuer_assets.get(&asset).copied().unwrap_or(0);
+            if held < required {
+                return Err(Error::EtpUnderbacked { product: product.clone(), asset, amount_short: required - held });
+            }
+        }
+        Ok(())
+    }
+    /// Break the audit down by where the coins and assets are held
+    pub fn get_itemised_audit(&self) -> ItemisedAudit {
+        let escrow = self.escrow.soft_audit();
+        let fees = self.fees_earned.soft_audit();
+        let investment = self.investment.soft_audit();
+        let loan = self.loan.soft_audit();
+        let order = self.order.soft_audit();
+        let withdrawal = self.withdrawal.soft_audit();
+        let locked = escrow.clone() + fees.clone() + investment.clone() + loan.clone() + order.clone() + withdrawal.clone();
+        let etp_backing = self.etp.get_etps().into_iter().map(|(product, info)| {
+            let outstanding = self.get_etp_supply(&product).expect("ETP disappeared while listing").outstanding;
+            let mut backing = Audit::default();
+            for (asset, count_per) in info.basket {
+                backing.add_asset(asset, count_per.saturating_mul(outstanding));
+            }
+            (product, backing)
+        }).collect();
+        ItemisedAudit { free: self.balance.soft_audit(), locked, escrow, fees, investment, loan, order, withdrawal, etp_backing }
+    }
+    /// Build a report of everything the exchange owes, as of the latest action
+    pub fn get_reserves_report(&self, time: chrono::DateTime<chrono::Utc>) -> ReservesReport {
+        let liabilities = self.hard_audit();
+        let mut locked = liabilities.clone();
+        let mut claims: std::collections::BTreeMap<PlayerId, ReserveTotals> = std::collections::BTreeMap::new();
+        for (player, coins) in self.balance.get_bals() {
+            locked.sub_coins(coins);
+            claims.entry(player).or_default().coins = coins;
+        }
+        for (player, assets) in self.balance.get_all_assets() {
+            for (asset, count) in assets.iter() {
+                locked.sub_asset(asset.clone(), *count);
+            }
+            claims.entry(player).or_default().assets = assets.into_iter().collect();
+        }
+        let etps = self.etp.get_etps().into_iter().map(|(product, info)| {
+            let outstanding = self.get_etp_supply(&product).expect("ETP disappeared while listing").outstanding;
+            let issuer_assets = self.balance.get_assets(&info.issuer);
+            let required = info.basket.iter().map(|(asset, count_per)| (asset.clone(), count_per.saturating_mul(outstanding))).collect();
+            let held = info.basket.keys().map(|asset| (asset.clone(), issuer_assets.get(asset).copied().unwrap_or(0))).collect();
+            (product, EtpBacking { issuer: info.issuer, outstanding, required, held })
+        }).collect();
+        ReservesReport { id: self.next_id - 1, time, liabilities: liabilities.into(), claims, locked: locked.into(), etps }
+    }
+    /// List the annotated deposits and undeposits for a player, by id
+    pub fn get_action_notes(&self, player: &PlayerId) -> std::collections::BTreeMap<u64, ActionNote> {
+        self.action_notes.iter().filter(|(_, note)| &note.player == player).map(|(id, note)| (*id, note.clone())).collect()
+    }
+    /// Get which banker actions need a second banker to agree to them
+    pub fn get_approval_policy(&self) -> ApprovalPolicy { self.approval_policy.clone() }
+    /// Get the base64 Ed25519 key a player signs their actions with, if they have one
+    pub fn get_signing_key(&self, player: &PlayerId) -> Option<String> { self.signing.get_key(player).cloned() }
+    /// List all proposals waiting for a second banker
+    pub fn get_proposals(&self) -> std::collections::BTreeMap<u64, Proposal> { self.proposal.get_proposals() }
+    /// Get a specific proposal
+    pub fn get_proposal(&self, id: u64) -> Result<Proposal> { self.proposal.get_proposal(id) }
+    /// List the ids of proposals that have expired by the given time, but have not been pruned
+    pub fn get_expired_proposals(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<u64> {
+        match self.proposal_cutoff(now) {
+            Ok(Some(cutoff)) => self.proposal.get_created_before(cutoff),
+            _ => Vec::new()
+        }
+    }
+    /// The time before which proposals must have been made to have expired
+    fn proposal_cutoff(&self, now: chrono::DateTime<chrono::Utc>) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let Some(ttl_secs) = self.approval_policy.proposal_ttl_secs
+        else { return Ok(None); };
+        Ok(Some(now - chrono::Duration::seconds(ttl_secs.try_into().map_err(|_| Error::Overflow)?)))
+    }
+    /// List all actions waiting to be performed
+    pub fn get_scheduled(&self) -> std::collections::BTreeMap<u64, ScheduledAction> { self.scheduled.clone() }
+    /// List the ids of scheduled actions that are due by the given time, oldest first
+    pub fn get_due_scheduled(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<u64> {
+        let mut due: Vec<_> = self.scheduled.values().filter(|scheduled| scheduled.at <= now).collect();
+        due.sort_by_key(|scheduled| (scheduled.at, scheduled.id));
+        due.into_iter().map(|scheduled| scheduled.id).collect()
+    }
     /// Returns true if the given item is currently restricted
     pub fn is_restricted(&self, asset: &AssetId) -> bool { self.restricted_assets.contains(asset) }
     /// Lists all restricted items
     pub fn get_restricted(&self) -> impl Iterator<Item = &AssetId> { self.restricted_assets.iter() }
+    /// Get how many of a restricted item a player can still withdraw, and until when
+    pub fn get_authorisation(&self, player: &PlayerId, asset: &AssetId) -> Option<Authorisation> { self.auth.get_authorisation(player, asset) }
+    /// List all authorisation requests waiting for a banker
+    pub fn get_authorisation_requests(&self) -> std::collections::BTreeMap<u64, AuthorisationRequest> { self.auth.get_requests() }
+    /// Get the player who can authorise an item on the bank's behalf, if any
+    pub fn get_delegated_authoriser(&self, asset: &AssetId) -> Option<PlayerId> { self.auth.get_delegate(asset).cloned() }
+    /// List all items with a delegated authoriser
+    pub fn get_delegated_authorisers(&self) -> std::collections::HashMap<AssetId, PlayerId> { self.auth.get_delegates() }
+    /// Get the authorisation request the bankers should look at next
+    pub fn get_next_authorisation_request(&self) -> Option<AuthorisationRequest> { self.auth.get_next_request() }
+    /// Returns true if trading of the given item is currently halted
+    pub fn is_halted(&self, asset: &AssetId) -> bool { self.halted_assets.contains(asset) }
+    /// Lists all items with halted trading
+    pub fn get_halted(&self) -> impl Iterator<Item = &AssetId> { self.halted_assets.iter() }
+    /// Get the price of the most recent match for an asset
+    pub fn get_last_price(&self, asset: &AssetId) -> Option<Coins> { self.order.get_last_price(asset) }
+    /// List a player's recent matches from actions after the given id, oldest first
+    ///
+    /// Only a limited number of each player's most recent fills are remembered
+    pub fn get_fills(&self, player: &PlayerId, since_id: u64) -> Vec<PlayerFill> { self.fills.get_fills(player, since_id) }
+    /// List a player's coin balance after each action between the given ids inclusive that changed it, oldest first
+    ///
+    /// The last change before from_id is included too, to give the starting balance
+    pub fn get_balance_history(&self, player: &PlayerId, from_id: u64, to_id: u64) -> Vec<BalanceCheckpoint> { self.balance_history.get_history(player, from_id, to_id) }
+    /// Summarise the last day of trading in an asset, up to the given time
+    pub fn get_market_stats(&self, asset: &AssetId, now: chrono::DateTime<chrono::Utc>) -> MarketStats {
+        MarketStats { last_price: self.get_last_price(asset), ..self.stats.get_stats(asset, now) }
+    }
+    /// Get the maximum percentage an order's price can be from the last traded price, if any
+    pub fn get_price_band(&self, asset: &AssetId) -> Option<u64> { self.price_bands.get(asset).cloned() }
+    /// Get the order limits that apply to a player
+    pub fn get_order_limits(&self, player: &PlayerId) -> OrderLimits { self.order_limits.get(player).unwrap_or(&self.default_order_limits).clone() }
+    /// Get the reason a player's account was frozen, if it is
+    pub fn get_frozen_reason(&self, player: &PlayerId) -> Option<String> { self.frozen.get(player).cloned() }
+    /// Lists all frozen accounts, and why they were frozen
+    pub fn get_frozen(&self) -> std::collections::HashMap<PlayerId, String> { self.frozen.clone() }
+    /// Get the id a player's account was moved to, if it was
+    pub fn get_migrated(&self, player: &PlayerId) -> Option<PlayerId> { self.migrated.get(player).cloned() }
     /// Gets a list of all bankers
     pub fn get_bankers(&self) -> HashSet<PlayerId> { self.bankers.clone() }
     /// Returns true if the given player is an banker
@@ -506,18 +1312,196 @@ impl State {
     pub fn asset_info(&self, asset: &AssetId) -> Result<AssetInfo> {
         self.asset_info.get(asset).cloned().ok_or_else(|| Error::UnknownAsset { asset: asset.clone() })
     }
+    /// List the players an action involves: the player acting, and any it names
+    ///
+    /// Players matched against by an order aren't known until it is applied, so must be taken from its outcome
+    pub fn get_involved(&self, action: &Action) -> std::collections::BTreeSet<PlayerId> {
+        let mut ret: std::collections::BTreeSet<PlayerId> = self.perms(action).map(|perms| perms.player).into_iter().collect();
+        match action {
+            Action::Deleted { banker, .. } |
+            Action::Reverse { banker, .. } |
+            Action::WithdrawalCompleted { banker, .. } |
+            Action::ClaimWithdrawal { banker, .. } |
+            Action::UnclaimWithdrawal { banker, .. } |
+            Action::UpdateWithdrawalTtl { banker, .. } |
+            Action::ExpireWithdrawals { banker } |
+            Action::UpdateRestricted { banker, .. } |
+            Action::ApproveAuthorisation { banker, .. } |
+            Action::DenyAuthorisation { banker, .. } |
+            Action::UpdateAssetInfo { banker, .. } |
+            Action::UpdateBankPrices { banker, .. } |
+            Action::UpdateSelfTradePolicy { banker, .. } |
+            Action::HaltTrading { banker, .. } |
+            Action::ResumeTrading { banker, .. } |
+            Action::UpdatePriceBand { banker, .. } |
+            Action::UpdateInvestables { banker, .. } |
+            Action::UpdateEtpCap { banker, .. } |
+            Action::Agree { banker, .. } |
+            Action::Disagree { banker, .. } |
+            Action::UpdateApprovalPolicy { banker, .. } |
+            Action::PruneProposals { banker } => { ret.insert(banker.clone()); },
+            Action::Deposit { player, banker, .. } |
+            Action::Undeposit { player, banker, .. } |
+            Action::FreezeAccount { player, banker, .. } |
+            Action::UnfreezeAccount { player, banker } |
+            Action::AuthoriseRestricted { authorisee: player, banker, .. } => { ret.extend([player.clone(), banker.clone()]); },
+            Action::WithdrawalRequested { player, .. } |
+            Action::BuyCoins { player, .. } |
+            Action::SellCoins { player, .. } |
+            Action::BuyOrder { player, .. } |
+            Action::SellOrder { player, .. } |
+            Action::RequestAuthorisation { player, .. } |
+            Action::Invest { player, .. } |
+            Action::Uninvest { player, .. } |
+            Action::DefineEtp { issuer: player, .. } |
+            Action::CreateUnits { player, .. } |
+            Action::RedeemUnits { player, .. } |
+            Action::RetractProposal { player, .. } |
+            Action::SetSigningKey { player, .. } => { ret.insert(player.clone()); },
+            Action::TransferCoins { payer, payee, .. } |
+            Action::TransferAsset { payer, payee, .. } |
+            Action::OfferEscrow { player: payer, counterparty: payee, .. } |
+            Action::OfferLoan { lender: payer, borrower: payee, .. } => { ret.extend([payer.clone(), payee.clone()]); },
+            Action::DelegateAuthoriser { delegate, banker, .. } => { ret.extend(delegate.iter().cloned().chain([banker.clone()])); },
+            Action::UpdateOrderLimits { player, banker, .. } => { ret.extend(player.iter().cloned().chain([banker.clone()])); },
+            Action::MigrateAccount { from, to, banker } => { ret.extend([from.clone(), to.clone(), banker.clone()]); },
+            Action::UpdateBankers { bankers, banker } => { ret.extend(bankers.iter().cloned().chain([banker.clone()])); },
+            Action::UpdateEtpAllowlist { allowlist, .. } => { ret.extend(allowlist.iter().flatten().cloned()); },
+            Action::Schedule { action, .. } |
+            Action::Propose { action, .. } => { ret.extend(self.get_involved(action)); },
+            // These only name other actions, so the acting player is all we know about
+            Action::Expedited { .. } |
+            Action::CancelOrder { .. } |
+            Action::AcceptEscrow { .. } |
+            Action::CancelEscrow { .. } |
+            Action::AcceptLoan { .. } |
+            Action::RepayLoan { .. } |
+            Action::LiquidateCollateral { .. } |
+            Action::CancelLoan { .. } |
+            Action::IssueEtp { .. } |
+            Action::RemoveEtp { .. } |
+            Action::SplitEtp { .. } |
+            Action::RunScheduled { .. } |
+            Action::CancelScheduled { .. } => ()
+        }
+        ret
+    }
+    /// List the items an action names, or that the order it cancels is for
+    ///
+    /// Like players, the item an order matches in isn't known until it is applied, so must be taken from its outcome
+    pub fn get_involved_assets(&self, action: &Action) -> std::collections::BTreeSet<AssetId> {
+        let mut ret = std::collections::BTreeSet::new();
+        match action {
+            Action::Deposit { asset, .. } |
+            Action::Undeposit { asset, .. } |
+            Action::BuyOrder { asset, .. } |
+            Action::SellOrder { asset, .. } |
+            Action::AuthoriseRestricted { asset, .. } |
+            Action::RequestAuthorisation { asset, .. } |
+            Action::DelegateAuthoriser { asset, .. } |
+            Action::TransferAsset { asset, .. } |
+            Action::HaltTrading { asset, .. } |
+            Action::ResumeTrading { asset, .. } |
+            Action::UpdatePriceBand { asset, .. } |
+            Action::Invest { asset, .. } |
+            Action::Uninvest { asset, .. } |
+            Action::IssueEtp { product: asset, .. } |
+            Action::RemoveEtp { product: asset, .. } |
+            Action::UpdateEtpCap { product: asset, .. } |
+            Action::SplitEtp { product: asset, .. } |
+            Action::UpdateEtpAllowlist { product: asset, .. } |
+            Action::CreateUnits { product: asset, .. } |
+            Action::RedeemUnits { product: asset, .. } => { ret.insert(asset.clone()); },
+            Action::WithdrawalRequested { assets, .. } |
+            Action::OfferLoan { collateral: assets, .. } => { ret.extend(assets.keys().cloned()); },
+            Action::UpdateRestricted { restricted_assets: assets, .. } |
+            Action::UpdateInvestables { assets, .. } => { ret.extend(assets.iter().cloned()); },
+            Action::UpdateAssetInfo { asset_info, .. } => { ret.extend(asset_info.keys().cloned()); },
+            Action::OfferEscrow { give, want, .. } => { ret.extend(give.assets.keys().chain(want.assets.keys()).cloned()); },
+            Action::DefineEtp { product, basket, .. } => { ret.extend(basket.keys().cloned().chain([product.clone()])); },
+            Action::BuyCoins { .. } |
+            Action::SellCoins { .. } => { ret.insert(DIAMOND_NAME.to_owned()); },
+            Action::CancelOrder { target } => { ret.extend(self.get_order(*target).map(|order| order.asset)); },
+            Action::Schedule { action, .. } |
+            Action::Propose { action, .. } => { ret.extend(self.get_involved_assets(action)); },
+            Action::Deleted { .. } |
+            Action::Reverse { .. } |
+            Action::Expedited { .. } |
+            Action::WithdrawalCompleted { .. } |
+            Action::ClaimWithdrawal { .. } |
+            Action::UnclaimWithdrawal { .. } |
+            Action::UpdateWithdrawalTtl { .. } |
+            Action::ExpireWithdrawals { .. } |
+            Action::ApproveAuthorisation { .. } |
+            Action::DenyAuthorisation { .. } |
+            Action::UpdateBankPrices { .. } |
+            Action::TransferCoins { .. } |
+            Action::UpdateSelfTradePolicy { .. } |
+            Action::UpdateOrderLimits { .. } |
+            Action::FreezeAccount { .. } |
+            Action::UnfreezeAccount { .. } |
+            Action::MigrateAccount { .. } |
+            Action::UpdateBankers { .. } |
+            Action::AcceptEscrow { .. } |
+            Action::CancelEscrow { .. } |
+            Action::AcceptLoan { .. } |
+            Action::RepayLoan { .. } |
+            Action::LiquidateCollateral { .. } |
+            Action::CancelLoan { .. } |
+            Action::RunScheduled { .. } |
+            Action::CancelScheduled { .. } |
+            Action::Agree { .. } |
+            Action::Disagree { .. } |
+            Action::RetractProposal { .. } |
+            Action::UpdateApprovalPolicy { .. } |
+            Action::PruneProposals { .. } |
+            Action::SetSigningKey { .. } => ()
+        }
+        ret
+    }
     /// Get the required permissions for a given action
     pub fn perms(&self, action: &Action) -> Result<ActionPermissions> {
         match action {
+            // Delegates only need to be themselves for their own item
+            Action::AuthoriseRestricted { banker, asset, .. }
+            if self.auth.get_delegate(asset) == Some(banker)
+                => Ok(ActionPermissions{level: ActionLevel::Normal, player: banker.clone()}),
+            Action::ApproveAuthorisation { target, banker, .. } |
+            Action::DenyAuthorisation { target, banker }
+            if self.auth.get_request(*target).is_ok_and(|request| self.auth.get_delegate(&request.asset) == Some(banker))
+                => Ok(ActionPermissions{level: ActionLevel::Normal, player: banker.clone()}),
+
             Action::AuthoriseRestricted { banker, .. } |
+            Action::ApproveAuthorisation { banker, .. } |
+            Action::DenyAuthorisation { banker, .. } |
+            Action::DelegateAuthoriser { banker, .. } |
+            Action::Agree { banker, .. } |
+            Action::Disagree { banker, .. } |
+            Action::UpdateApprovalPolicy { banker, .. } |
+            Action::PruneProposals { banker, .. } |
             Action::Deleted { banker, .. } |
+            Action::Reverse { banker, .. } |
             Action::Deposit { banker, .. } |
             Action::UpdateBankPrices { banker, .. } |
+            Action::UpdateAssetInfo { banker, .. } |
             Action::UpdateBankers { banker, .. } |
+            Action::UpdateSelfTradePolicy { banker, .. } |
+            Action::UpdateOrderLimits { banker, .. } |
+            Action::FreezeAccount { banker, .. } |
+            Action::UnfreezeAccount { banker, .. } |
+            Action::MigrateAccount { banker, .. } |
+            Action::HaltTrading { banker, .. } |
+            Action::ResumeTrading { banker, .. } |
+            Action::UpdatePriceBand { banker, .. } |
+            Action::UpdateEtpCap { banker, .. } |
             // Action::UpdateConvertables { banker, .. } |
             Action::UpdateInvestables { banker, .. } |
             Action::UpdateRestricted { banker, .. } |
             Action::WithdrawalCompleted { banker, .. } |
+            Action::ClaimWithdrawal { banker, .. } |
+            Action::UpdateWithdrawalTtl { banker, .. } |
+            Action::ExpireWithdrawals { banker, .. } |
+            Action::UnclaimWithdrawal { banker, .. } |
             Action::Undeposit { banker, .. }
                 => Ok(ActionPermissions{level: ActionLevel::Banker, player: banker.clone()}),
 
@@ -530,42 +1514,272 @@ impl State {
             Action::TransferAsset { payer: player, .. } |
             Action::TransferCoins { payer: player, .. } |
             Action::Uninvest { player, .. } |
-            Action::WithdrawalRequested { player, .. }
+            Action::WithdrawalRequested { player, .. } |
+            Action::OfferEscrow { player, .. } |
+            Action::OfferLoan { lender: player, .. } |
+            Action::DefineEtp { issuer: player, .. } |
+            Action::CreateUnits { player, .. } |
+            Action::RedeemUnits { player, .. } |
+            Action::RequestAuthorisation { player, .. } |
+            Action::RetractProposal { player, .. } |
+            Action::SetSigningKey { player, .. }
                 => Ok(ActionPermissions{level: ActionLevel::Normal, player: player.clone()}),
 
             Action::Expedited { target } =>
                 Ok(ActionPermissions{level: ActionLevel::Normal, player: self.withdrawal.get_withdrawal(*target)?.player.clone()}),
             Action::CancelOrder { target } =>
-                Ok(ActionPermissions{level: ActionLevel::Normal, player: self.order.get_order(*target)?.player.clone()})
+                Ok(ActionPermissions{level: ActionLevel::Normal, player: self.order.get_order(*target)?.player.clone()}),
+            Action::AcceptEscrow { target } =>
+                Ok(ActionPermissions{level: ActionLevel::Normal, player: self.escrow.get_escrow(*target)?.counterparty.clone()}),
+            Action::CancelEscrow { target } =>
+                Ok(ActionPermissions{level: ActionLevel::Normal, player: self.escrow.get_escrow(*target)?.player.clone()}),
+            Action::AcceptLoan { target } |
+            Action::RepayLoan { target } =>
+                Ok(ActionPermissions{level: ActionLevel::Normal, player: self.loan.get_loan(*target)?.borrower.clone()}),
+            Action::LiquidateCollateral { target } |
+            Action::CancelLoan { target } =>
+                Ok(ActionPermissions{level: ActionLevel::Normal, player: self.loan.get_loan(*target)?.lender.clone()}),
+            // Scheduled and proposed actions need the same permissions as the action itself
+            Action::Schedule { action, .. } |
+            Action::Propose { action, .. } => self.perms(action),
+            Action::RunScheduled { target } |
+            Action::CancelScheduled { target } =>
+                self.perms(&self.scheduled.get(target).ok_or(Error::InvalidId { id: *target })?.action),
+            Action::IssueEtp { product, .. } |
+            Action::RemoveEtp { product, .. } |
+            Action::SplitEtp { product, .. } |
+            Action::UpdateEtpAllowlist { product, .. } =>
+                Ok(ActionPermissions{level: ActionLevel::Normal, player: self.etp.get_etp(product)?.issuer})
 
 
         }
     }
+    /// Cancel an order and refund whatever is left in it
+    fn cancel_order(&mut self, target: u64) -> Result<()> {
+        match self.order.cancel(target)? {
+            order::CancelResult::BuyOrder { player, refund_coins } => {
+                self.balance.commit_coin_add(&player, refund_coins);
+            },
+            order::CancelResult::SellOrder { player, refunded_asset, refund_count } => {
+                self.balance.commit_asset_add(&player, &refunded_asset, refund_count);
+            }
+        }
+        Ok(())
+    }
+    /// Returns true if the asset is a real item or a product, and so can be held
+    fn is_tradeable(&self, asset: &AssetId) -> bool { self.asset_info.contains_key(asset) || self.etp.is_etp(asset) }
+    /// Check that a player is allowed to be given an asset, which only matters for restricted products
+    fn check_recipient(&self, player: &PlayerId, asset: &AssetId) -> Result<()> {
+        match self.etp.get_etp(asset) {
+            Ok(info) if !info.can_receive(player) => Err(Error::TransferRestricted { product: asset.clone(), player: player.clone() }),
+            _ => Ok(())
+        }
+    }
+    /// Check that a player is allowed to be given every asset in a list
+    fn check_recipient_multi(&self, player: &PlayerId, assets: &std::collections::HashMap<AssetId, u64>) -> Result<()> {
+        assets.iter().filter(|(_, count)| **count > 0).try_for_each(|(asset, _)| self.check_recipient(player, asset))
+    }
+    /// Note down matches for the market stats and both players' histories
+    fn record_fills(&mut self, id: u64, time: chrono::DateTime<chrono::Utc>, player: &PlayerId, asset: &AssetId, side: OrderType, fills: &[Fill]) {
+        let other_side = match side { OrderType::Buy => OrderType::Sell, OrderType::Sell => OrderType::Buy };
+        for fill in fills {
+            self.stats.record(asset, stats::TradePrint { time, coins_per: fill.coins_per, count: fill.count });
+            let player_fill = PlayerFill {
+                id,
+                asset: asset.clone(),
+                side: side.clone(),
+                count: fill.count,
+                coins_per: fill.coins_per,
+                counterparty: fill.counterparty.clone(),
+                fee: Coins::default()
+            };
+            self.fills.record(&fill.counterparty, PlayerFill { side: other_side.clone(), counterparty: player.clone(), ..player_fill.clone() });
+            self.fills.record(player, player_fill);
+        }
+    }
+    /// Check that placing an order won't take a player over their order limits
+    fn check_order_limits(&self, player: &PlayerId, new_buy_coins: Coins) -> Result<()> {
+        let limits = self.get_order_limits(player);
+        let (n_orders, mut buy_coins) = self.order.get_usage(player)?;
+        // We assume the order will rest, as we can't know how much will match until it's placed
+        if let Some(max_open_orders) = limits.max_open_orders {
+            if n_orders >= max_open_orders {
+                return Err(Error::TooManyOrders { max_open_orders });
+            }
+        }
+        if let Some(max_buy_coins) = limits.max_buy_coins {
+            buy_coins.checked_add_assign(new_buy_coins)?;
+            if buy_coins > max_buy_coins {
+                return Err(Error::TooManyBuyCoins { max_buy_coins });
+            }
+        }
+        Ok(())
+    }
+    /// Check if an incoming order is allowed under the self trade policy
+    fn check_self_trade(&self, player: &PlayerId, asset: &AssetId, count: u64, coins_per: Coins, order_type: &OrderType) -> Result<()> {
+        if self.self_trade_policy != SelfTradePolicy::RejectIncoming {
+            return Ok(());
+        }
+        match self.order.get_matches(asset, count, coins_per, order_type).into_iter().find(|order| &order.player == player) {
+            Some(order) => Err(Error::SelfTrade { order: order.id }),
+            None => Ok(())
+        }
+    }
+    /// Cancel the player's orders that an incoming order would cross, if the self trade policy wants that
+    fn cancel_self_trades(&mut self, player: &PlayerId, asset: &AssetId, coins_per: Coins, order_type: &OrderType) -> Vec<u64> {
+        if self.self_trade_policy != SelfTradePolicy::CancelResting {
+            return Vec::new();
+        }
+        let targets = self.order.get_crossing(player, asset, coins_per, order_type);
+        for target in targets.iter() {
+            self.cancel_order(*target).expect("Could not cancel crossing order");
+        }
+        targets
+    }
+    /// Check an order's price is close enough to the last traded price
+    fn check_price_band(&self, asset: &AssetId, coins_per: Coins) -> Result<()> {
+        let (Some(max_deviation_percent), Some(last_price)) = (self.get_price_band(asset), self.get_last_price(asset))
+        else { return Ok(()); };
+        // Work in u128 so that the percentages can't overflow
+        let last = last_price.millicoins() as u128;
+        let price = coins_per.millicoins() as u128;
+        if price.abs_diff(last) * 100 > last * max_deviation_percent as u128 {
+            return Err(Error::OutsidePriceBand { asset: asset.clone(), last_price, max_deviation_percent });
+        }
+        Ok(())
+    }
+    /// Check that whoever is performing an action is allowed to
+    fn check_perms(&self, action: &Action) -> Result<()> {
+        let perms = self.perms(action)?;
+        // Cancelling a scheduled action moves nothing, so a failed one can be cleared out even once its owner can't act
+        if matches!(action, Action::CancelScheduled { .. }) {
+            return Ok(());
+        }
+        match perms {
+            ActionPermissions { level: ActionLevel::Banker, player } => {
+                if !self.is_banker(&player) {
+                    return Err(Error::IsNotABanker { player });
+                }
+            },
+            ActionPermissions { level: ActionLevel::Normal, player } => {
+                if let Some(reason) = self.frozen.get(&player) {
+                    return Err(Error::AccountFrozen { player, reason: reason.clone() });
+                }
+                if let Some(to) = self.migrated.get(&player) {
+                    return Err(Error::AccountMigrated { player, to: to.clone() });
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Returns true if a banker action needs a second banker to agree to it first
+    fn needs_approval(&self, action: &Action) -> bool {
+        let policy = &self.approval_policy;
+        match action {
+            Action::Undeposit { count, .. } => policy.undeposit_over.is_some_and(|max| *count > max),
+            // Reversing a deposit is just an undeposit by another name
+            Action::Reverse { target, .. } => match self.reversible.get(target) {
+                Some(Action::Deposit { count, .. }) => policy.undeposit_over.is_some_and(|max| *count > max),
+                _ => false
+            },
+            Action::UpdateBankPrices { .. } => policy.bank_prices,
+            Action::UpdateBankers { .. } => policy.bankers,
+            // Otherwise a single banker could just turn the policy off
+            Action::UpdateApprovalPolicy { .. } => *policy != ApprovalPolicy::default(),
+            // Otherwise it could be run later without anyone agreeing to it
+            Action::Schedule { action, .. } => self.needs_approval(action),
+            _ => false
+        }
+    }
     // Atomic (but not parallelisable!).
     // This means the function will change significant things (i.e. more than just creating empty lists) IF AND ONLY IF it fully succeeds.
     // As such, we don't have to worry about giving it bad actions
-    fn apply_inner(&mut self, id: u64, action: Action) -> Result<()> {
+    fn apply_inner(&mut self, id: u64, time: chrono::DateTime<chrono::Utc>, action: Action) -> Result<ApplyOutcome> {
         // Blanket check perms
         //
         // TODO: optimise
-        if let ActionPermissions { level: ActionLevel::Banker, player } = self.perms(&action)? {
-            if !self.is_banker(&player) {
-                return Err(Error::IsNotABanker { player });
-            }
+        self.check_perms(&action)?;
+        if self.needs_approval(&action) {
+            return Err(Error::NeedsApproval);
+        }
+        let delta = self.audit_delta(&action);
+        let outcome = self.apply_checked(id, time, action);
+        // A failed action changes nothing, but clear out the changed list either way
+        let changed = self.balance.take_changed();
+        let outcome = outcome?;
+        self.audit = match delta {
+            Some(delta) => delta.apply(std::mem::take(&mut self.audit)),
+            // We couldn't work out the change, so recalculate
+            None => self.sum_tracker_audits()
+        };
+        for player in changed {
+            self.balance_history.record(&player, id, self.balance.get_bal(&player));
         }
+        Ok(outcome)
+    }
+    /// Apply an action whose permissions have already been checked, atomically like apply_inner
+    fn apply_checked(&mut self, id: u64, time: chrono::DateTime<chrono::Utc>, action: Action) -> Result<ApplyOutcome> {
+        // Remember the simple balance movements, so that they can be reversed later
+        let reversible = matches!(action,
+            Action::Deposit{..} | Action::Undeposit{..} |
+            Action::TransferCoins{..} | Action::TransferAsset{..} |
+            Action::BuyCoins{..} | Action::SellCoins{..}
+        ).then(|| action.clone());
 
+        let mut outcome = ApplyOutcome { id, ..Default::default() };
         match action {
             Action::Deleted{..} => Ok(()),
-            Action::Deposit { player, asset, count, .. } => {
+            Action::Reverse { target, .. } => {
+                let Some(reversed) = self.reversible.get(&target)
+                else {
+                    return Err(if target >= id { Error::InvalidId { id: target } } else { Error::NotReversible { id: target } });
+                };
+                // Each arm checks and takes first, so a failure leaves nothing half done
+                match reversed.clone() {
+                    Action::Deposit { player, asset, count, .. } => {
+                        self.balance.commit_asset_removal(&player, &asset, count)?;
+                    },
+                    Action::Undeposit { player, asset, count, .. } => {
+                        self.balance.commit_asset_add(&player, &asset, count);
+                    },
+                    Action::TransferCoins { payer, payee, count } => {
+                        self.balance.commit_coin_removal(&payee, count)?;
+                        self.balance.commit_coin_add(&payer, count);
+                    },
+                    Action::TransferAsset { payer, payee, asset, count } => {
+                        self.balance.commit_asset_removal(&payee, &asset, count)?;
+                        self.balance.commit_asset_add(&payer, &asset, count);
+                    },
+                    Action::BuyCoins { player, n_diamonds } => {
+                        self.balance.commit_coin_removal(&player, Coins::from_diamonds(n_diamonds).expect("Reversed BuyCoins overflow"))?;
+                        self.balance.commit_asset_add(&player, &DIAMOND_NAME.to_owned(), n_diamonds);
+                    },
+                    Action::SellCoins { player, n_diamonds } => {
+                        self.balance.commit_asset_removal(&player, &DIAMOND_NAME.to_owned(), n_diamonds)?;
+                        self.balance.commit_coin_add(&player, Coins::from_diamonds(n_diamonds).expect("Reversed SellCoins overflow"));
+                    },
+                    _ => unreachable!("Unsupported action was marked as reversible")
+                }
+                self.reversible.remove(&target);
+                Ok(())
+            },
+            Action::Deposit { player, asset, count, banker, note, reference } => {
                 if !self.asset_info.contains_key(&asset) {
                     return Err(Error::UnknownAsset { asset });
                 }
                 self.balance.commit_asset_add(&player, &asset, count);
+                if note.is_some() || reference.is_some() {
+                    self.action_notes.insert(id, ActionNote { player, banker, note, reference });
+                }
 
                 Ok(())
             },
-            Action::Undeposit { player, asset, count, .. } => {
-                self.balance.commit_asset_removal(&player, &asset, count)
+            Action::Undeposit { player, asset, count, banker, note, reference } => {
+                self.balance.commit_asset_removal(&player, &asset, count)?;
+                if note.is_some() || reference.is_some() {
+                    self.action_notes.insert(id, ActionNote { player, banker, note, reference });
+                }
+                Ok(())
             },
             Action::WithdrawalRequested { player, assets} => {
                 let total_fee = self.calc_withdrawal_fee(&assets)?;
@@ -581,13 +1795,7 @@ impl State {
                     // Check if restricted
                     if is_restricted {
                         // If it is restricted, we have to check before we take their assets
-                        // Check if they are authorised to withdraw any amount of these items
-                        let Some(auth_amount) = self.authorisations.get(&player).and_then(|x| x.get(&asset))
-                        else { return Err(Error::UnauthorisedWithdrawal{ asset: asset.clone(), amount_overdrawn: None}); };
-                        // Check if they are authorised to withdraw at least this many items
-                        if *auth_amount < count {
-                            return Err(Error::UnauthorisedWithdrawal{ asset: asset.clone(), amount_overdrawn: Some(count - *auth_amount)});
-                        }
+                        self.auth.check_withdrawal(&player, &asset, count, id, time)?;
                     }
                     tracked_assets.insert(asset, count);
                 }
@@ -600,21 +1808,36 @@ impl State {
                     self.balance.commit_asset_removal(&player, asset, *count).expect("Assets disappeared after check");
                     // Remove allowance if restricted
                     if self.is_restricted(asset) {
-                        // TODO: Clean up after ourselves
-                        *self.authorisations.get_mut(&player).expect("Asset player disappeared after check")
-                                            .get_mut(asset).expect("Asset auth disappeared after check") -= count;
+                        self.auth.commit_withdrawal(&player, asset, *count);
                     }
                 }
 
                 // Register the withdrawal. This cannot fail, so we don't have to worry about atomicity
-                self.withdrawal.track_withdrawal(id, player, tracked_assets, total_fee);
+                self.withdrawal.track_withdrawal(id, player, tracked_assets, total_fee, time);
+                outcome.fees_paid = total_fee;
                 Ok(())
             },
-            Action::SellOrder { player, asset, count, coins_per } => {
+            Action::SellOrder { player, asset, count, coins_per, display_count } => {
+                // An iceberg order that shows nothing would never refill
+                if display_count == Some(0) {
+                    return Err(Error::InvalidDisplayCount);
+                }
+                if self.is_halted(&asset) {
+                    return Err(Error::TradingHalted { asset });
+                }
+                self.check_price_band(&asset, coins_per)?;
+                self.check_self_trade(&player, &asset, count, coins_per, &OrderType::Sell)?;
+                self.check_order_limits(&player, Coins::default())?;
+                // The allowlist might have changed since the buyers placed their orders
+                for buyer in self.order.get_matches(&asset, count, coins_per, &OrderType::Sell) {
+                    self.check_recipient(&buyer.player, &asset)?;
+                }
                 // Check and take their assets first
                 self.balance.commit_asset_removal(&player, &asset, count)?;
+                // Clear their own buy orders out of the way if needs be
+                outcome.orders_cancelled = self.cancel_self_trades(&player, &asset, coins_per, &OrderType::Sell);
                 // Do the matching and listing
-                let res = self.order.handle_sell(id, &player, &asset, count, coins_per);
+                let res = self.order.handle_sell(id, &player, &asset, count, coins_per, display_count);
                 // Transfer the assets
                 for (buyer, count) in res.assets_instant_matched {
                     self.balance.commit_asset_add(&buyer, &asset, count);
@@ -622,13 +1845,30 @@ impl State {
                 // Transfer the money
                 self.balance.commit_coin_add(&player, res.coins_instant_earned);
 
+                outcome.amount_rested = count - res.fills.iter().map(|fill| fill.count).sum::<u64>();
+                outcome.fills = res.fills;
+                self.record_fills(id, time, &player, &asset, OrderType::Sell, &outcome.fills);
+                outcome.asset = Some(asset);
                 Ok(())
             },
-            Action::BuyOrder { player, asset, count, coins_per } => {
+            Action::BuyOrder { player, asset, count, coins_per, display_count } => {
+                // An iceberg order that shows nothing would never refill
+                if display_count == Some(0) {
+                    return Err(Error::InvalidDisplayCount);
+                }
+                if self.is_halted(&asset) {
+                    return Err(Error::TradingHalted { asset });
+                }
+                self.check_price_band(&asset, coins_per)?;
+                self.check_self_trade(&player, &asset, count, coins_per, &OrderType::Buy)?;
+                self.check_order_limits(&player, coins_per.checked_mul(count)?)?;
+                self.check_recipient(&player, &asset)?;
                 // Check and take their money first
                 self.balance.commit_coin_removal(&player, coins_per.checked_mul(count)?)?;
+                // Clear their own sell orders out of the way if needs be
+                outcome.orders_cancelled = self.cancel_self_trades(&player, &asset, coins_per, &OrderType::Buy);
                 // Do the matching and listing
-                let res = self.order.handle_buy(id, &player, &asset, count, coins_per);
+                let res = self.order.handle_buy(id, &player, &asset, count, coins_per, display_count);
                 // Transfer the money
                 self.balance.commit_coin_add(&player, res.coins_refunded);
                 // Pay the sellers
@@ -640,28 +1880,59 @@ impl State {
                     self.balance.commit_asset_add(&player, &asset, res.assets_instant_matched);
                 }
 
+                outcome.amount_rested = count - res.assets_instant_matched;
+                outcome.fills = res.fills;
+                self.record_fills(id, time, &player, &asset, OrderType::Buy, &outcome.fills);
+                outcome.asset = Some(asset);
                 Ok(())
             },
             Action::WithdrawalCompleted { target, banker } => {
+                // Don't let a banker complete a withdrawal that another banker is gathering
+                if let Some(assignee) = self.withdrawal.get_withdrawal(target)?.assignee {
+                    if assignee != banker {
+                        return Err(Error::WithdrawalClaimed { id: target, banker: assignee });
+                    }
+                }
                 // Try to take out the pending transaction
                 let res = self.withdrawal.complete(target)?;
                 // Mark who delivered
                 self.earnings.entry(banker).or_default().checked_add_assign(res.total_fee).expect("Withdrawal earnings overflow");
                 // Add the profit
                 self.balance.commit_coin_add(&PlayerId::the_bank(), res.total_fee);
+                self.fees_earned.record(FeeSource::Expedite, res.expedite_fee);
+                self.fees_earned.record(FeeSource::Withdrawal, res.total_fee.checked_sub(res.expedite_fee).expect("Expedite fee larger than total fee"));
                 Ok(())
             },
-            Action::CancelOrder { target } => {
-                match self.order.cancel(target)? {
-                    order::CancelResult::BuyOrder { player, refund_coins } => {
-                        self.balance.commit_coin_add(&player, refund_coins);
-                    },
-                    order::CancelResult::SellOrder { player, refunded_asset, refund_count } => {
-                        self.balance.commit_asset_add(&player, &refunded_asset, refund_count);
-                    }
+            Action::ClaimWithdrawal { target, banker } => {
+                match self.withdrawal.get_withdrawal(target)?.assignee {
+                    Some(assignee) if assignee == banker => return Err(Error::AlreadyDone),
+                    Some(assignee) => return Err(Error::WithdrawalClaimed { id: target, banker: assignee }),
+                    None => ()
                 }
+                self.withdrawal.set_assignee(target, Some(banker))
+            },
+            // Any banker can do this, in case the claiming banker disappears
+            Action::UnclaimWithdrawal { target, .. } => {
+                if self.withdrawal.get_withdrawal(target)?.assignee.is_none() {
+                    return Err(Error::AlreadyDone);
+                }
+                self.withdrawal.set_assignee(target, None)
+            },
+            Action::UpdateWithdrawalTtl { ttl_secs, .. } => {
+                self.withdrawal_ttl_secs = ttl_secs;
                 Ok(())
             },
+            Action::ExpireWithdrawals { .. } => {
+                let Some(ttl_secs) = self.withdrawal_ttl_secs
+                else { return Err(Error::AlreadyDone); };
+                let cutoff = time - chrono::Duration::seconds(ttl_secs.try_into().map_err(|_| Error::Overflow)?);
+                for target in self.withdrawal.get_requested_before(cutoff) {
+                    let withdrawal = self.withdrawal.cancel(target).expect("Withdrawal disappeared after check");
+                    self.balance.commit_multi_add(&withdrawal.player, withdrawal.total_fee, &withdrawal.assets);
+                }
+                Ok(())
+            },
+            Action::CancelOrder { target } => self.cancel_order(target),
             Action::BuyCoins { player, n_diamonds } => {
                 // Check and take diamonds from payer...
                 self.balance.commit_asset_removal(&player,&DIAMOND_NAME.to_owned(), n_diamonds)?;
@@ -687,12 +1958,52 @@ impl State {
                 self.restricted_assets = std::collections::HashSet::from_iter(restricted_assets);
                 Ok(())
             },
-            Action::AuthoriseRestricted { authorisee, asset, new_count, .. } => {
+            Action::AuthoriseRestricted { authorisee, asset, new_count, expiry, .. } => {
                 // Check it's a valid asset (not necessarily authorisable to enable pre-authorisation)
                 if !self.asset_info.contains_key(&asset) {
                     return Err(Error::UnknownAsset { asset });
                 }
-                self.authorisations.entry(authorisee).or_default().insert(asset, new_count);
+                self.auth.authorise(authorisee, asset, new_count, expiry);
+                Ok(())
+            },
+            Action::RequestAuthorisation { player, asset, count, reason } => {
+                // Only restricted items need authorising
+                if !self.is_restricted(&asset) {
+                    return Err(Error::NotRestricted { asset });
+                }
+                self.auth.track_request(AuthorisationRequest { id, player, asset, count, reason });
+                Ok(())
+            },
+            Action::ApproveAuthorisation { target, expiry, .. } => {
+                let request = self.auth.get_request(target)?;
+                // Anything left over from an expired authorisation shouldn't come back to life
+                let current = self.auth.get_authorisation(&request.player, &request.asset).map(|auth| auth.usable(id, time)).unwrap_or(0);
+                let new_count = current.checked_add(request.count).ok_or(Error::Overflow)?;
+                self.auth.remove_request(target).expect("Authorisation request disappeared after check");
+                self.auth.authorise(request.player, request.asset, new_count, expiry);
+                Ok(())
+            },
+            Action::DenyAuthorisation { target, .. } => {
+                self.auth.remove_request(target)?;
+                Ok(())
+            },
+            Action::DelegateAuthoriser { asset, delegate, .. } => {
+                if !self.asset_info.contains_key(&asset) {
+                    return Err(Error::UnknownAsset { asset });
+                }
+                self.auth.set_delegate(asset, delegate);
+                Ok(())
+            },
+            Action::UpdateAssetInfo { asset_info, .. } => {
+                // A stack size of 0 would make the fees divide by zero
+                if let Some(asset) = asset_info.iter().find(|(_, info)| info.stack_size == 0).map(|(asset, _)| asset) {
+                    return Err(Error::InvalidAssetInfo { asset: asset.clone() });
+                }
+                // A product can't share a name with a real item
+                if let Some(asset) = asset_info.keys().find(|asset| self.etp.is_etp(asset)) {
+                    return Err(Error::EtpExists { product: asset.clone() });
+                }
+                self.update_asset_info(asset_info);
                 Ok(())
             },
             Action::UpdateBankPrices { withdraw_flat, withdraw_per_stack, expedited, investment_share , ..} => {
@@ -707,6 +2018,7 @@ impl State {
                 Ok(())
             },
             Action::TransferAsset { payer, payee, asset, count } => {
+                self.check_recipient(&payee, &asset)?;
                 // Check and take assets from payer...
                 self.balance.commit_asset_removal(&payer, &asset, count)?;
                 // ... and give it to payee
@@ -715,7 +2027,7 @@ impl State {
             },
             Action::Expedited { target, .. } => {
                 // Find the withdrawal
-                let withdrawal = self.withdrawal.get_withdrawal(id)?;
+                let withdrawal = self.withdrawal.get_withdrawal(target)?;
                 // If the withdrawal is already expedited, this should not be attempted
                 if withdrawal.expedited {
                     return Err(Error::AlreadyDone)
@@ -725,12 +2037,101 @@ impl State {
                 self.balance.commit_coin_removal(&withdrawal.player, fee)?;
                 // Expediting should always work here
                 self.withdrawal.expedite(target, fee).expect("Withdrawal exists and is normal but cannot be expedited");
+                outcome.fees_paid = fee;
                 Ok(())
             },
             Action::UpdateBankers { bankers, .. } => {
                 self.bankers = std::collections::HashSet::from_iter(bankers);
                 Ok(())
             },
+            Action::UpdateSelfTradePolicy { policy, .. } => {
+                self.self_trade_policy = policy;
+                Ok(())
+            },
+            Action::UpdateOrderLimits { player, limits, .. } => {
+                match player {
+                    Some(player) => { self.order_limits.insert(player, limits); },
+                    None => { self.default_order_limits = limits; }
+                }
+                Ok(())
+            },
+            Action::FreezeAccount { player, reason, .. } => {
+                self.frozen.insert(player, reason);
+                Ok(())
+            },
+            Action::UnfreezeAccount { player, .. } => {
+                if self.frozen.remove(&player).is_none() {
+                    return Err(Error::AlreadyDone);
+                }
+                Ok(())
+            },
+            Action::MigrateAccount { from, to, .. } => {
+                if from == to {
+                    return Err(Error::AlreadyDone);
+                }
+                // An account can only be moved once, or the first move would be overwritten
+                if let Some(new_from) = self.migrated.get(&from) {
+                    return Err(Error::AccountMigrated { player: from, to: new_from.clone() });
+                }
+                // Don't move things into an account that is itself gone
+                if let Some(new_to) = self.migrated.get(&to) {
+                    return Err(Error::AccountMigrated { player: to, to: new_to.clone() });
+                }
+                self.balance.migrate(&from, &to);
+                self.escrow.migrate(&from, &to);
+                self.etp.migrate(&from, &to);
+                self.investment.migrate(&from, &to);
+                self.loan.migrate(&from, &to);
+                self.order.migrate(&from, &to);
+                self.withdrawal.migrate(&from, &to);
+                self.proposal.migrate(&from, &to);
+                self.fills.migrate(&from, &to);
+                self.auth.migrate(&from, &to);
+                if let Some(earnings) = self.earnings.remove(&from) {
+                    self.earnings.entry(to.clone()).or_default().checked_add_assign(earnings).expect("Earnings overflow");
+                }
+                if self.bankers.remove(&from) {
+                    self.bankers.insert(to.clone());
+                }
+                if let Some(limits) = self.order_limits.remove(&from) {
+                    self.order_limits.entry(to.clone()).or_insert(limits);
+                }
+                // A freeze follows the account, so it can't be dodged
+                if let Some(reason) = self.frozen.remove(&from) {
+                    self.frozen.insert(to.clone(), reason);
+                }
+                // Anything that pointed at the old id now points at the new one
+                for target in self.migrated.values_mut().filter(|target| **target == from) {
+                    *target = to.clone();
+                }
+                self.migrated.insert(from, to);
+                Ok(())
+            },
+            Action::HaltTrading { asset, .. } => {
+                if !self.asset_info.contains_key(&asset) {
+                    return Err(Error::UnknownAsset { asset });
+                }
+                if !self.halted_assets.insert(asset) {
+                    return Err(Error::AlreadyDone);
+                }
+                Ok(())
+            },
+            Action::ResumeTrading { asset, .. } => {
+                if !self.halted_assets.remove(&asset) {
+                    return Err(Error::AlreadyDone);
+                }
+                Ok(())
+            },
+            Action::UpdatePriceBand { asset, max_deviation_percent, .. } => {
+                if !self.asset_info.contains_key(&asset) {
+                    return Err(Error::UnknownAsset { asset });
+                }
+                match max_deviation_percent {
+                    Some(max_deviation_percent) => { self.price_bands.insert(asset, max_deviation_percent); },
+                    None => { self.price_bands.remove(&asset); }
+                }
+                Ok(())
+            },
             Action::UpdateInvestables { assets, .. } => {
                 // Check they're valid assets
                 if let Some(asset) =
@@ -757,6 +2158,284 @@ impl State {
                 self.investment.try_remove_investment(&player, &asset, count)?;
                 Ok(())
             },
+            Action::OfferEscrow { player, counterparty, give, want, expiry } => {
+                // Check they're asking for real assets or products
+                if let Some(asset) = want.assets.keys().find(|id| !self.is_tradeable(id)) {
+                    return Err(Error::UnknownAsset { asset: asset.clone() });
+                }
+                // Check both sides are allowed to end up with what they'd be given
+                self.check_recipient_multi(&counterparty, &give.assets)?;
+                self.check_recipient_multi(&player, &want.assets)?;
+                // Check and take what they're giving
+                self.balance.commit_multi_removal(&player, give.coins, &give.assets)?;
+                // Lock it away until the counterparty decides
+                self.escrow.track_escrow(PendingEscrow { id, player, counterparty, give, want, expiry });
+                Ok(())
+            },
+            Action::AcceptEscrow { target } => {
+                let escrow = self.escrow.get_escrow(target)?;
+                if escrow.expiry.is_some_and(|expiry| time > expiry) {
+                    return Err(Error::EscrowExpired { id: target });
+                }
+                // The allowlist might have changed since the offer
+                self.check_recipient_multi(&escrow.counterparty, &escrow.give.assets)?;
+                self.check_recipient_multi(&escrow.player, &escrow.want.assets)?;
+                // Check and take what the counterparty is giving...
+                self.balance.commit_multi_removal(&escrow.counterparty, escrow.want.coins, &escrow.want.assets)?;
+                // ... hand it to the player ...
+                self.balance.commit_multi_add(&escrow.player, escrow.want.coins, &escrow.want.assets);
+                // ... and release the player's side to the counterparty
+                self.escrow.remove_escrow(target).expect("Escrow disappeared after check");
+                self.balance.commit_multi_add(&escrow.counterparty, escrow.give.coins, &escrow.give.assets);
+                Ok(())
+            },
+            Action::CancelEscrow { target } => {
+                let escrow = self.escrow.remove_escrow(target)?;
+                self.balance.commit_multi_add(&escrow.player, escrow.give.coins, &escrow.give.assets);
+                Ok(())
+            },
+            Action::OfferLoan { lender, borrower, principal, repayment, collateral, due } => {
+                // Check they're asking for real assets or products
+                if let Some(asset) = collateral.keys().find(|id| !self.is_tradeable(id)) {
+                    return Err(Error::UnknownAsset { asset: asset.clone() });
+                }
+                // The lender must be able to take the collateral if it comes to it
+                self.check_recipient_multi(&lender, &collateral)?;
+                // Check and take the principal
+                self.balance.commit_coin_removal(&lender, principal)?;
+                self.loan.track_offer(PendingLoan { id, lender, borrower, principal, repayment, collateral, due, accepted: false });
+                Ok(())
+            },
+            Action::AcceptLoan { target } => {
+                let loan = self.loan.get_loan(target)?;
+                if loan.accepted {
+                    return Err(Error::AlreadyDone);
+                }
+                if time > loan.due {
+                    return Err(Error::LoanOverdue { id: target });
+                }
+                // Check and take the collateral...
+                self.balance.commit_multi_removal(&loan.borrower, Coins::default(), &loan.collateral)?;
+                // ... and hand over the principal
+                self.loan.accept(target).expect("Loan disappeared after check");
+                self.balance.commit_coin_add(&loan.borrower, loan.principal);
+                Ok(())
+            },
+            Action::RepayLoan { target } => {
+                let loan = self.loan.get_loan(target)?;
+                if !loan.accepted {
+                    return Err(Error::LoanNotAccepted { id: target });
+                }
+                // Check and take the repayment...
+                self.balance.commit_coin_removal(&loan.borrower, loan.repayment)?;
+                // ... pay the lender ...
+                self.balance.commit_coin_add(&loan.lender, loan.repayment);
+                // ... and give back the collateral
+                self.loan.remove_active(target).expect("Loan disappeared after check");
+                self.balance.commit_multi_add(&loan.borrower, Coins::default(), &loan.collateral);
+                Ok(())
+            },
+            Action::LiquidateCollateral { target } => {
+                let loan = self.loan.get_loan(target)?;
+                if time <= loan.due {
+                    return Err(Error::LoanNotDue { id: target, due: loan.due });
+                }
+                let loan = self.loan.remove_active(target)?;
+                self.balance.commit_multi_add(&loan.lender, Coins::default(), &loan.collateral);
+                Ok(())
+            },
+            Action::CancelLoan { target } => {
+                let loan = self.loan.remove_offer(target)?;
+                self.balance.commit_coin_add(&loan.lender, loan.principal);
+                Ok(())
+            },
+            Action::DefineEtp { issuer, product, basket } => {
+                // A product can't share a name with a real item
+                if self.asset_info.contains_key(&product) {
+                    return Err(Error::EtpExists { product });
+                }
+                // Check the basket is made of real items
+                if let Some(asset) = basket.keys().find(|id| !self.asset_info.contains_key(*id)) {
+                    return Err(Error::UnknownAsset { asset: asset.clone() });
+                }
+                self.etp.define(product, issuer, basket)
+            },
+            Action::IssueEtp { product, count } => {
+                let issuer = self.etp.get_etp(&product)?.issuer;
+                self.etp.issue(&product, count)?;
+                self.balance.commit_asset_add(&issuer, &product, count);
+                Ok(())
+            },
+            Action::RemoveEtp { product, count } => {
+                let issuer = self.etp.get_etp(&product)?.issuer;
+                // Check and take the units
+                self.balance.commit_asset_removal(&issuer, &product, count)?;
+                self.etp.remove(&product, count);
+                Ok(())
+            },
+            Action::UpdateEtpCap { product, cap, .. } => self.etp.set_cap(&product, cap),
+            Action::Schedule { at, action } => {
+                if matches!(*action, Action::Schedule{..} | Action::RunScheduled{..} | Action::CancelScheduled{..}) {
+                    return Err(Error::CannotSchedule);
+                }
+                self.scheduled.insert(id, ScheduledAction { id, at, action: *action, approved: false });
+                Ok(())
+            },
+            Action::RunScheduled { target } => {
+                let scheduled = self.scheduled.get(&target).ok_or(Error::InvalidId { id: target })?.clone();
+                if time < scheduled.at {
+                    return Err(Error::NotDue { id: target, at: scheduled.at });
+                }
+                // Check it as if it were being applied now, but leave the audit to the RunScheduled, which already counts it
+                self.check_perms(&scheduled.action)?;
+                if !scheduled.approved && self.needs_approval(&scheduled.action) {
+                    return Err(Error::NeedsApproval);
+                }
+                // This is atomic, so we only have to clean up if it works
+                outcome = self.apply_checked(id, time, scheduled.action)?;
+                self.scheduled.remove(&target);
+                Ok(())
+            },
+            Action::CancelScheduled { target } => {
+                self.scheduled.remove(&target).ok_or(Error::InvalidId { id: target })?;
+                Ok(())
+            },
+            Action::Propose { action, title, description } => {
+                // Only banker actions need a second banker
+                let ActionPermissions { level: ActionLevel::Banker, player: proposer } = self.perms(&action)?
+                else { return Err(Error::NotProposable); };
+                if matches!(*action, Action::Agree{..} | Action::Disagree{..}) {
+                    return Err(Error::NotProposable);
+                }
+                self.proposal.track(Proposal { id, proposer, action: *action, created: time, title, description });
+                Ok(())
+            },
+            Action::Agree { proposal_id, banker } => {
+                let proposal = self.proposal.get_proposal(proposal_id)?;
+                if proposal.proposer == banker {
+                    return Err(Error::OwnProposal { id: proposal_id });
+                }
+                // Expired proposals fail even before they are pruned
+                if self.proposal_cutoff(time)?.is_some_and(|cutoff| proposal.created < cutoff) {
+                    return Err(Error::ProposalExpired { id: proposal_id });
+                }
+                // The proposer must still be allowed to do it, but the approval policy has now been satisfied
+                self.check_perms(&proposal.action)?;
+                // This is atomic, so we only have to clean up if it works
+                outcome = self.apply_checked(id, time, proposal.action)?;
+                // The agreement covers running it, as well as scheduling it
+                if let Some(scheduled) = self.scheduled.get_mut(&id) {
+                    scheduled.approved = true;
+                }
+                self.proposal.remove(proposal_id).expect("Proposal disappeared after check");
+                Ok(())
+            },
+            Action::Disagree { proposal_id, banker } => {
+                if self.proposal.get_proposal(proposal_id)?.proposer == banker {
+                    return Err(Error::OwnProposal { id: proposal_id });
+                }
+                self.proposal.remove(proposal_id)?;
+                Ok(())
+            },
+            Action::RetractProposal { proposal_id, player } => {
+                if self.proposal.get_proposal(proposal_id)?.proposer != player {
+                    return Err(Error::NotProposer { id: proposal_id });
+                }
+                self.proposal.remove(proposal_id).expect("Proposal disappeared after check");
+                Ok(())
+            },
+            Action::UpdateApprovalPolicy { policy, .. } => {
+                self.approval_policy = policy;
+                Ok(())
+            },
+            Action::SetSigningKey { player, public_key } => {
+                if let Some(public_key) = public_key.as_ref() {
+                    signing::check_public_key(public_key)?;
+                }
+                self.signing.set_key(player, public_key);
+                Ok(())
+            },
+            Action::PruneProposals { .. } => {
+                let Some(cutoff) = self.proposal_cutoff(time)?
+                else { return Err(Error::AlreadyDone); };
+                for target in self.proposal.get_created_before(cutoff) {
+                    self.proposal.remove(target).expect("Proposal disappeared after check");
+                }
+                Ok(())
+            },
+            Action::UpdateEtpAllowlist { product, allowlist } => self.etp.set_allowlist(&product, allowlist.map(|allowlist| allowlist.into_iter().collect())),
+            Action::SplitEtp { product, ratio: (new_units, old_units) } => {
+                let info = self.etp.get_etp(&product)?;
+                if new_units == 0 || old_units == 0 {
+                    return Err(Error::InvalidSplit { product });
+                }
+                // Prices on the book would be for the old unit size, so there can't be any orders
+                let (buys, sells) = self.order.get_prices(&product);
+                if !buys.is_empty() || !sells.is_empty() {
+                    return Err(Error::EtpUnitsLocked { product });
+                }
+                // Every unit must be sitting in someone's balance, so that we can change all of them
+                let holders = self.balance.get_holders(&product);
+                if holders.values().sum::<u64>() != info.issued {
+                    return Err(Error::EtpUnitsLocked { product });
+                }
+                // Work in u128 so that the ratio can't overflow
+                let rescale = |count: u64, mul: u64, div: u64| -> Result<u64> {
+                    let scaled = count as u128 * mul as u128;
+                    if !scaled.is_multiple_of(div as u128) {
+                        return Err(Error::InvalidSplit { product: product.clone() });
+                    }
+                    (scaled / div as u128).try_into().map_err(|_| Error::Overflow)
+                };
+                // Check everything divides exactly
+                let new_holdings = holders.into_iter()
+                    .map(|(player, count)| Ok((player, count, rescale(count, new_units, old_units)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                let new_basket = info.basket.iter()
+                    .map(|(asset, count)| Ok((asset.clone(), rescale(*count, old_units, new_units)?)))
+                    .collect::<Result<std::collections::HashMap<_, _>>>()?;
+                let new_issued = rescale(info.issued, new_units, old_units)?;
+                // A cap doesn't need to divide exactly, so round it down
+                let new_cap = info.cap.map(|cap| u64::try_from(cap as u128 * new_units as u128 / old_units as u128).unwrap_or(u64::MAX));
+                // Now swap everyone's units
+                for (player, old_count, new_count) in new_holdings {
+                    self.balance.commit_asset_removal(&player, &product, old_count).expect("Units disappeared after check");
+                    self.balance.commit_asset_add(&player, &product, new_count);
+                }
+                self.etp.rescale(&product, new_issued, new_basket, new_cap);
+                // The last price was for the old unit size
+                self.order.clear_last_price(&product);
+                self.stats.clear(&product);
+                Ok(())
+            },
+            Action::CreateUnits { player, product, count } => {
+                let info = self.etp.get_etp(&product)?;
+                let basket = info.basket_for(count)?;
+                self.etp.check_issue(&product, count)?;
+                self.check_recipient(&player, &product)?;
+                // Check and take the basket...
+                self.balance.commit_multi_removal(&player, Coins::default(), &basket)?;
+                // ... hand it to the issuer as backing ...
+                self.balance.commit_multi_add(&info.issuer, Coins::default(), &basket);
+                // ... and create the units
+                self.etp.issue(&product, count).expect("Could not issue units after check");
+                self.balance.commit_asset_add(&player, &product, count);
+                Ok(())
+            },
+            Action::RedeemUnits { player, product, count } => {
+                let info = self.etp.get_etp(&product)?;
+                let basket = info.basket_for(count)?;
+                // Check they have the units
+                self.balance.check_asset_removal(&player, &product, count)?;
+                // Check and take the basket from the issuer...
+                self.balance.commit_multi_removal(&info.issuer, Coins::default(), &basket)?;
+                // ... hand it to the player ...
+                self.balance.commit_multi_add(&player, Coins::default(), &basket);
+                // ... and destroy the units
+                self.balance.commit_asset_removal(&player, &product, count).expect("Units disappeared after check");
+                self.etp.remove(&product, count);
+                Ok(())
+            },
             /*
             Action::InstantConvert { from, to, count, player } => {
                 // BUG: will fail audit
@@ -790,66 +2469,247 @@ impl State {
                 self.convertables = convertables.into_iter().collect();
                 Ok(())
             } */
+        }?;
+        if let Some(action) = reversible {
+            self.reversible.insert(id, action);
+        }
+        // Forget anything the next action could no longer reach
+        let cutoff = (id + 1).saturating_sub(REVERSAL_WINDOW);
+        while let Some(entry) = self.reversible.first_entry() {
+            if *entry.key() >= cutoff { break; }
+            entry.remove();
         }
+        Ok(outcome)
+    }
+    /// Work out how an action will change the audit, which must be done before the action is applied
+    ///
+    /// Returns None if the action refers to something that doesn't exist, in which case it will fail anyway
+    fn audit_delta(&self, action: &Action) -> Option<AuditDelta> {
+        let mut delta = AuditDelta::default();
+        match action {
+            Action::Deposit { asset, count, .. } => {
+                delta.added.add_asset(asset.clone(), *count);
+            },
+            Action::Undeposit { asset, count, .. } => {
+                delta.removed.add_asset(asset.clone(), *count);
+            },
+            Action::WithdrawalCompleted { target, .. } => {
+                // The fee stays with us, but the items leave
+                for (asset, count) in self.withdrawal.get_withdrawal(*target).ok()?.assets {
+                    delta.removed.add_asset(asset, count);
+                }
+            },
+            Action::BuyCoins { n_diamonds,.. } => {
+                delta.added.add_coins(Coins::from_diamonds(*n_diamonds).ok()?);
+                delta.removed.add_asset(DIAMOND_NAME.to_owned(), *n_diamonds);
+            },
+            Action::SellCoins { n_diamonds, .. } => {
+                delta.removed.add_coins(Coins::from_diamonds(*n_diamonds).ok()?);
+                delta.added.add_asset(DIAMOND_NAME.to_owned(), *n_diamonds);
+            },
+            // Units are only backed by the issuer's word, so they appear from nowhere
+            Action::IssueEtp { product, count } |
+            Action::CreateUnits { product, count, .. } => {
+                delta.added.add_asset(product.clone(), *count);
+            },
+            Action::RemoveEtp { product, count } |
+            Action::RedeemUnits { product, count, .. } => {
+                delta.removed.add_asset(product.clone(), *count);
+            },
+            Action::SplitEtp { product, ratio: (new_units, old_units) } => {
+                let issued = self.etp.get_etp(product).ok()?.issued;
+                let new_issued: u64 = (issued as u128 * *new_units as u128).checked_div(*old_units as u128)?.try_into().ok()?;
+                if new_issued > issued {
+                    delta.added.add_asset(product.clone(), new_issued - issued);
+                }
+                else {
+                    delta.removed.add_asset(product.clone(), issued - new_issued);
+                }
+            },
+            Action::Reverse { target, .. } => {
+                return self.audit_delta(self.reversible.get(target)?).map(AuditDelta::reversed);
+            },
+            Action::RunScheduled { target } => {
+                return self.audit_delta(&self.scheduled.get(target)?.action);
+            },
+            Action::Agree { proposal_id, .. } => {
+                return self.audit_delta(&self.proposal.get_proposal(*proposal_id).ok()?.action);
+            },
+            _ => ()
+        }
+        Some(delta)
     }
     /// Load in the transactions from a trade file. Because of numbering, we must do this first; we cannot append
     pub async fn replay(&mut self, trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin)) -> Result<()> {
-        let trade_file_reader = tokio::io::BufReader::new(trade_file);
-        let mut trade_file_lines = trade_file_reader.lines();
-        let mut last_audit = self.hard_audit();
-        while let Some(line) = trade_file_lines.next_line().await.expect("Could not read line from trade list") {
-            let wrapped_action: WrappedAction = serde_json::from_str(&line).expect("Corrupted trade file");
+        self.replay_with(trade_file, |_, _, _| ()).await
+    }
+    /// Load in the transactions from a trade file, stopping after the action with the given id
+    ///
+    /// Actions the state has already applied are skipped, so that replay can carry on from an earlier copy of the state
+    pub async fn replay_until(&mut self, trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin), last_id: u64) -> Result<()> {
+        self.replay_until_with(trade_file, last_id, |_, _, _| ()).await
+    }
+    /// Load in the transactions from a trade file like replay_until, telling the callback when each new one was applied, what it was, and how it went
+    pub async fn replay_until_with(
+        &mut self,
+        trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+        last_id: u64,
+        mut on_apply: impl FnMut(chrono::DateTime<chrono::Utc>, &Action, &ApplyOutcome)
+    ) -> Result<()> {
+        self.replay_observed(trade_file, Some(last_id), |_, _| (), |time, action, outcome, ()| on_apply(time, action, outcome)).await
+    }
+    /// Load in the transactions from a trade file, telling the callback when each one was applied, what it was, and how it went
+    pub async fn replay_with(
+        &mut self,
+        trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+        mut on_apply: impl FnMut(chrono::DateTime<chrono::Utc>, &Action, &ApplyOutcome)
+    ) -> Result<()> {
+        self.replay_observed(trade_file, None, |_, _| (), |time, action, outcome, ()| on_apply(time, action, outcome)).await
+    }
+    /// Load in the transactions from a trade file like replay_with, also telling the callback which players and items each one involved
+    ///
+    /// These are worked out before each action is applied, as they are when it is first applied, since afterwards the orders it filled or cancelled are gone
+    pub async fn replay_involved(
+        &mut self,
+        trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+        mut on_apply: impl FnMut(chrono::DateTime<chrono::Utc>, &Action, &ApplyOutcome, std::collections::BTreeSet<PlayerId>, std::collections::BTreeSet<AssetId>)
+    ) -> Result<()> {
+        self.replay_observed(
+            trade_file,
+            None,
+            |state, action| (state.get_involved(action), state.get_involved_assets(action)),
+            |time, action, outcome, (players, assets)| on_apply(time, action, outcome, players, assets)
+        ).await
+    }
+    /// Load in the transactions from a trade file, up to the given id if there is one, looking at the state before each one is applied, and telling the callback what was seen once it has been
+    async fn replay_observed<T>(
+        &mut self,
+        trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+        last_id: Option<u64>,
+        mut before_apply: impl FnMut(&State, &Action) -> T,
+        mut on_apply: impl FnMut(chrono::DateTime<chrono::Utc>, &Action, &ApplyOutcome, T)
+    ) -> Result<()> {
+        let mut reader = self.open_log(trade_file).await?;
+        while last_id.is_none_or(|last_id| self.next_id <= last_id) {
+            let Some((wrapped_action, record)) = reader.next().await?
+            else { return last_id.map_or(Ok(()), |id| Err(Error::InvalidId { id })); };
             if wrapped_action.id != self.next_id {
-                panic!("Trade file ID mismatch: action {} found on line {}: {}", wrapped_action.id, self.next_id, line);
-            }
-            self.apply_inner(self.next_id, wrapped_action.action.clone())?;
-            if let Some(new_audit) = wrapped_action.action.adjust_audit(last_audit) {
-                let post = self.hard_audit();
-                if new_audit != post {
-                    panic!("Failed audit on {line}: expected {new_audit:?} vs actual {post:?}");
-                }
-                last_audit = new_audit;
-            }
-            else {
-                // The state has changed, adjust the audit
-                last_audit = self.hard_audit();
+                panic!("Trade file ID mismatch: action {} found on line {}: {}", wrapped_action.id, self.next_id, log::describe(&wrapped_action));
             }
+            self.check_signature(self.next_id, &wrapped_action.action, wrapped_action.signature.as_ref())?;
+            let seen = before_apply(self, &wrapped_action.action);
+            let outcome = self.apply_inner(self.next_id, wrapped_action.time, wrapped_action.action.clone())?;
+            self.check_audit(&wrapped_action);
+            on_apply(wrapped_action.time, &wrapped_action.action, &outcome, seen);
+            self.log_digest = log::chain_digest(&self.log_digest, &record);
             self.next_id += 1;
         }
         Ok(())
     }
+    /// Start reading a trade file, carrying on in its format when new actions are written
+    ///
+    /// The trade file can either be the rest of the log, or all of it. If it is all of it,
+    /// the actions the state has already applied are skipped, checking that they are the ones it applied
+    async fn open_log<R: tokio::io::AsyncRead + std::marker::Unpin>(&mut self, trade_file: R) -> Result<log::LogReader<R>> {
+        let mut reader = log::LogReader::new(trade_file).await;
+        self.log_format = reader.format();
+        self.log_header_written = reader.format() == LogFormat::Binary;
+        let Some(first) = reader.next().await?
+        else { return Ok(reader); };
+        if first.0.id != 1 || self.next_id == 1 {
+            reader.push_back(first);
+            return Ok(reader);
+        }
+        let mut digest = log::chain_digest("", &first.1);
+        for _ in 2..self.next_id {
+            let Some((_, record)) = reader.next().await?
+            else { return Err(Error::SnapshotMismatch); };
+            digest = log::chain_digest(&digest, &record);
+        }
+        if digest != self.log_digest {
+            return Err(Error::SnapshotMismatch);
+        }
+        Ok(reader)
+    }
+    /// Take a copy of the state that can be saved, and later carry on from with the same trade file
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { version: migrate::CURRENT_VERSION, state: self.clone() }
+    }
+    /// Carry on from a saved copy of the state, checking that it is intact
+    ///
+    /// Replaying its trade file then skips the actions it has already applied
+    pub fn from_snapshot(snapshot: Snapshot) -> Result<State> {
+        if snapshot.version != migrate::CURRENT_VERSION {
+            return Err(Error::UnsupportedVersion { version: Some(snapshot.version.into()) });
+        }
+        let state = snapshot.state;
+        if state.try_hard_audit().as_ref() != Ok(&state.audit) {
+            return Err(Error::SnapshotMismatch);
+        }
+        Ok(state)
+    }
+    /// Check that a signature, if there is one, covers the action at the given id and was made with the acting player's key
+    fn check_signature(&self, id: u64, action: &Action, signature: Option<&ActionSignature>) -> Result<()> {
+        let Some(signature) = signature
+        else { return Ok(()); };
+        signature.verify(id, action)?;
+        if self.signing.get_key(&self.perms(action)?.player) != Some(&signature.public_key) {
+            return Err(Error::InvalidSignature);
+        }
+        Ok(())
+    }
+    /// Make sure the running audit matches what the trackers actually hold, after the given action was applied
+    fn check_audit(&self, wrapped_action: &WrappedAction) {
+        let post = self.hard_audit();
+        if self.audit != post {
+            panic!("Failed audit on {}: expected {:?} vs actual {post:?}", log::describe(wrapped_action), self.audit);
+        }
+    }
+    /// Add up what each tracker thinks it holds
+    fn sum_tracker_audits(&self) -> Audit {
+        self.balance.soft_audit() + self.escrow.soft_audit() + self.fees_earned.soft_audit() + self.investment.soft_audit() + self.loan.soft_audit() + self.order.soft_audit() + self.withdrawal.soft_audit()
+    }
     /// Atomically try to apply an action, and if successful, write to given stream
-    pub async fn apply(&mut self, action: Action, out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin)) -> Result<u64> {
+    pub async fn apply(&mut self, action: Action, out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin)) -> Result<ApplyOutcome> {
+        self.apply_with_time(action, chrono::offset::Utc::now(), out).await
+    }
+    /// Atomically try to apply an action as if it happened at the given time, and if successful, write to given stream
+    pub async fn apply_with_time(&mut self, action: Action, time: chrono::DateTime<chrono::Utc>, out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin)) -> Result<ApplyOutcome> {
+        self.apply_wrapped(action, time, None, out).await
+    }
+    /// Atomically try to apply an action signed by the acting player as if it happened at the given time, and if successful, write it to given stream with its signature
+    pub async fn apply_signed(&mut self, action: Action, signature: ActionSignature, time: chrono::DateTime<chrono::Utc>, out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin)) -> Result<ApplyOutcome> {
+        self.apply_wrapped(action, time, Some(signature), out).await
+    }
+    async fn apply_wrapped(&mut self, action: Action, time: chrono::DateTime<chrono::Utc>, signature: Option<ActionSignature>, out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin)) -> Result<ApplyOutcome> {
         let id = self.next_id;
+        self.check_signature(id, &action, signature.as_ref())?;
         let wrapped_action = WrappedAction {
+            version: migrate::CURRENT_VERSION,
             id,
-            time: chrono::offset::Utc::now(),
+            time,
             action: action.clone(),
+            signature
         };
-        let mut line = serde_json::to_string(&wrapped_action).expect("Cannot serialise action");
-        let pre = self.soft_audit();
-        self.apply_inner(self.next_id, wrapped_action.action)?;
-        // We can soft audit, as the last one was checked as required
-        if let Some(expected) = action.adjust_audit(pre) {
-            let post = self.hard_audit();
-            if expected != post {
-                panic!("Failed audit on {line}: expected {expected:?} vs actual {post:?}");
-            }
-        }
-        line.push('\n');
+        let record = log::encode(&wrapped_action, self.log_format);
+        let outcome = self.apply_inner(self.next_id, time, action)?;
+        self.check_audit(&wrapped_action);
+        self.log_digest = log::chain_digest(&self.log_digest, &record);
         self.next_id += 1;
-        out.write_all(line.as_bytes()).await.expect("Could not write to log, must immediately stop!");
+        if self.log_format == LogFormat::Binary && !self.log_header_written {
+            out.write_all(log::BINARY_HEADER).await.expect("Could not write to log, must immediately stop!");
+            self.log_header_written = true;
+        }
+        out.write_all(&record).await.expect("Could not write to log, must immediately stop!");
         out.flush().await.expect("Could not flush to log, must immediately stop!");
-        Ok(id)
+        Ok(outcome)
     }
 }
 impl Auditable for State {
-    fn soft_audit(&self) -> Audit {
-        self.balance.soft_audit() + self.investment.soft_audit() + self.order.soft_audit() + self.withdrawal.soft_audit()
-    }
+    fn soft_audit(&self) -> Audit { self.audit.clone() }
 
-    fn hard_audit(&self) -> Audit {
-        self.balance.hard_audit() + self.investment.hard_audit() + self.order.hard_audit() + self.withdrawal.hard_audit()
+    fn try_hard_audit(&self) -> std::result::Result<Audit, String> {
+        Ok(self.balance.try_hard_audit()? + self.escrow.try_hard_audit()? + self.fees_earned.try_hard_audit()? + self.investment.try_hard_audit()? + self.loan.try_hard_audit()? + self.order.try_hard_audit()? + self.withdrawal.try_hard_audit()?)
     }
 }
 
@@ -858,14 +2718,40 @@ impl Serialize for State {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> where S: serde::Serializer {
         let mut map = serializer.serialize_map(None)?;
         map.serialize_entry("next_id", &self.next_id)?;
+        map.serialize_entry("log_digest", &self.log_digest)?;
+        map.serialize_entry("audit", &self.audit)?;
+        map.serialize_entry("asset_info", &self.asset_info)?;
+        map.serialize_entry("earnings", &self.earnings)?;
+        map.serialize_entry("withdrawal", &self.withdrawal)?;
         map.serialize_entry("balance", &self.balance)?;
+        map.serialize_entry("balance_history", &self.balance_history)?;
         map.serialize_entry("order", &self.order)?;
         map.serialize_entry("investment", &self.investment)?;
-        map.serialize_entry("authorisations", &self.authorisations)?;
+        map.serialize_entry("escrow", &self.escrow)?;
+        map.serialize_entry("loan", &self.loan)?;
+        map.serialize_entry("etp", &self.etp)?;
+        map.serialize_entry("auth", &self.auth)?;
         map.serialize_entry("restricted", &self.restricted_assets)?;
+        map.serialize_entry("halted", &self.halted_assets)?;
+        map.serialize_entry("price_bands", &self.price_bands)?;
         map.serialize_entry("investables", &self.investables)?;
         map.serialize_entry("bankers", &self.bankers)?;
+        map.serialize_entry("frozen", &self.frozen)?;
+        map.serialize_entry("migrated", &self.migrated)?;
         map.serialize_entry("fees", &self.fees)?;
+        map.serialize_entry("withdrawal_ttl_secs", &self.withdrawal_ttl_secs)?;
+        map.serialize_entry("fees_earned", &self.fees_earned)?;
+        map.serialize_entry("signing", &self.signing)?;
+        map.serialize_entry("self_trade_policy", &self.self_trade_policy)?;
+        map.serialize_entry("default_order_limits", &self.default_order_limits)?;
+        map.serialize_entry("order_limits", &self.order_limits)?;
+        map.serialize_entry("reversible", &self.reversible)?;
+        map.serialize_entry("scheduled", &self.scheduled)?;
+        map.serialize_entry("approval_policy", &self.approval_policy)?;
+        map.serialize_entry("proposal", &self.proposal)?;
+        map.serialize_entry("stats", &self.stats)?;
+        map.serialize_entry("fills", &self.fills)?;
+        map.serialize_entry("action_notes", &self.action_notes)?;
         map.end()
     }
 }
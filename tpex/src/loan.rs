@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Coins;
+
+use super::{AssetId, Audit, Auditable, Error, PlayerId};
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PendingLoan {
+    pub id: u64,
+    pub lender: PlayerId,
+    pub borrower: PlayerId,
+    /// The coins given to the borrower
+    pub principal: Coins,
+    /// The coins the borrower must pay back
+    pub repayment: Coins,
+    /// The assets the lender gets if the loan isn't repaid in time
+    pub collateral: std::collections::HashMap<AssetId, u64>,
+    /// The time after which the lender can take the collateral
+    pub due: chrono::DateTime<chrono::Utc>,
+    /// Whether the borrower has taken the principal and locked away the collateral
+    pub accepted: bool
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct LoanTracker {
+    loans: std::collections::BTreeMap<u64, PendingLoan>,
+
+    current_audit: Audit
+}
+impl LoanTracker {
+    /// Get a loan
+    pub fn get_loan(&self, id: u64) -> Result<PendingLoan, Error> { self.loans.get(&id).cloned().ok_or(Error::InvalidId { id }) }
+    /// List all loans, offered or accepted
+    pub fn get_loans(&self) -> std::collections::BTreeMap<u64, PendingLoan> { self.loans.clone() }
+    /// Track a new loan offer, whose principal has already been taken from the lender
+    pub fn track_offer(&mut self, loan: PendingLoan) {
+        // We are responsible for the principal until the borrower takes it
+        self.current_audit.add_coins(loan.principal);
+        self.loans.insert(loan.id, loan);
+    }
+    /// Mark a loan as accepted, once the collateral has been taken from the borrower
+    pub fn accept(&mut self, id: u64) -> Result<PendingLoan, Error> {
+        let Some(loan) = self.loans.get_mut(&id)
+        else { return Err(Error::InvalidId { id }); };
+        if loan.accepted {
+            return Err(Error::AlreadyDone);
+        }
+        loan.accepted = true;
+        // The principal is handed over, and the collateral locked away
+        self.current_audit.sub_coins(loan.principal);
+        for (asset, count) in loan.collateral.iter() {
+            self.current_audit.add_asset(asset.clone(), *count);
+        }
+        Ok(loan.clone())
+    }
+    /// Hand one player's side of every loan over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        for loan in self.loans.values_mut() {
+            if &loan.lender == from {
+                loan.lender = to.clone();
+            }
+            if &loan.borrower == from {
+                loan.borrower = to.clone();
+            }
+        }
+    }
+    /// Remove a loan that has not been accepted, so the principal can be refunded
+    pub fn remove_offer(&mut self, id: u64) -> Result<PendingLoan, Error> {
+        let std::collections::btree_map::Entry::Occupied(entry) = self.loans.entry(id)
+        else { return Err(Error::InvalidId { id }); };
+        if entry.get().accepted {
+            return Err(Error::AlreadyDone);
+        }
+        let loan = entry.remove();
+        self.current_audit.sub_coins(loan.principal);
+        Ok(loan)
+    }
+    /// Remove an accepted loan, so the collateral can be handed out
+    pub fn remove_active(&mut self, id: u64) -> Result<PendingLoan, Error> {
+        let std::collections::btree_map::Entry::Occupied(entry) = self.loans.entry(id)
+        else { return Err(Error::InvalidId { id }); };
+        if !entry.get().accepted {
+            return Err(Error::LoanNotAccepted { id });
+        }
+        let loan = entry.remove();
+        for (asset, count) in loan.collateral.iter() {
+            self.current_audit.sub_asset(asset.clone(), *count);
+        }
+        Ok(loan)
+    }
+}
+impl Auditable for LoanTracker {
+    fn soft_audit(&self) -> Audit { self.current_audit.clone() }
+
+    fn try_hard_audit(&self) -> Result<Audit, String> {
+        let mut new_audit = Audit::default();
+        for loan in self.loans.values() {
+            if loan.accepted {
+                for (asset, count) in loan.collateral.iter() {
+                    new_audit.add_asset(asset.clone(), *count);
+                }
+            }
+            else {
+                new_audit.add_coins(loan.principal);
+            }
+        }
+        if new_audit != self.current_audit {
+            return Err("Recalculated loan audit differs from soft audit".to_owned());
+        }
+        Ok(new_audit)
+    }
+}
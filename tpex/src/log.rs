@@ -0,0 +1,144 @@
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+use super::{migrate, Result, WrappedAction};
+
+/// The start of a binary trade file: a magic string, then the format version
+pub const BINARY_HEADER: &[u8] = b"TPEXBIN\x01";
+
+/// How actions are written to a trade file
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum LogFormat {
+    /// One JSON object per line
+    #[default]
+    JsonLines,
+    /// A header, then length-prefixed CBOR records, which are smaller and quicker to read
+    Binary
+}
+
+/// Encode an action as it should appear in a trade file
+pub(crate) fn encode(wrapped_action: &WrappedAction, format: LogFormat) -> Vec<u8> {
+    match format {
+        LogFormat::JsonLines => {
+            let mut line = serde_json::to_vec(wrapped_action).expect("Cannot serialise action");
+            line.push(b'\n');
+            line
+        },
+        LogFormat::Binary => {
+            let mut record = Vec::new();
+            ciborium::into_writer(wrapped_action, &mut record).expect("Cannot serialise action");
+            let len: u32 = record.len().try_into().expect("Action too large for binary log");
+            let mut ret = len.to_be_bytes().to_vec();
+            ret.extend(record);
+            ret
+        }
+    }
+}
+
+/// Chain the hash of every action applied so far with the next one's record, exactly as it is in the trade file
+///
+/// Hashing the bytes as written, rather than reserialising them, keeps the digest the same however maps are ordered
+pub(crate) fn chain_digest(digest: &str, record: &[u8]) -> String {
+    use base64::prelude::*;
+
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(digest.as_bytes());
+    context.update(record);
+    BASE64_STANDARD.encode(context.finish())
+}
+
+/// Show an action as a JSON line, for error messages
+pub(crate) fn describe(wrapped_action: &WrappedAction) -> String {
+    serde_json::to_string(wrapped_action).expect("Cannot serialise action")
+}
+
+/// Reads actions from a trade file in either format, working out which from the start of the file
+pub(crate) struct LogReader<R> {
+    reader: tokio::io::BufReader<R>,
+    format: LogFormat,
+    /// An action that was read and then put back
+    pending: Option<(WrappedAction, Vec<u8>)>
+}
+impl<R: tokio::io::AsyncRead + std::marker::Unpin> LogReader<R> {
+    pub async fn new(trade_file: R) -> LogReader<R> {
+        let mut reader = tokio::io::BufReader::new(trade_file);
+        // JSON lines start with a brace, so can never be mistaken for the header
+        let format =
+            if reader.fill_buf().await.expect("Could not read from trade list").starts_with(BINARY_HEADER) {
+                reader.consume(BINARY_HEADER.len());
+                LogFormat::Binary
+            }
+            else { LogFormat::JsonLines };
+        LogReader { reader, format, pending: None }
+    }
+    pub fn format(&self) -> LogFormat { self.format }
+    /// Put an action back, so that it is read again next
+    pub fn push_back(&mut self, record: (WrappedAction, Vec<u8>)) { self.pending = Some(record); }
+    /// Read the next action, upgraded to the current version, along with its record exactly as it is in the trade file
+    pub async fn next(&mut self) -> Result<Option<(WrappedAction, Vec<u8>)>> {
+        if let Some(record) = self.pending.take() {
+            return Ok(Some(record));
+        }
+        let mut raw = Vec::new();
+        // Where the record starts, after any length prefix
+        let start = match self.format {
+            LogFormat::JsonLines => {
+                if self.reader.read_until(b'\n', &mut raw).await.expect("Could not read line from trade list") == 0 {
+                    return Ok(None);
+                }
+                0
+            },
+            LogFormat::Binary => {
+                if self.reader.fill_buf().await.expect("Could not read from trade list").is_empty() {
+                    return Ok(None);
+                }
+                let len = self.reader.read_u32().await.expect("Corrupted trade file");
+                raw.extend(len.to_be_bytes());
+                raw.resize(4 + len as usize, 0);
+                self.reader.read_exact(&mut raw[4..]).await.expect("Corrupted trade file");
+                4
+            }
+        };
+        let record = &raw[start..];
+        // Almost every record is already current, so only older ones are parsed a second time to be upgraded
+        let current = match self.format {
+            LogFormat::JsonLines => serde_json::from_slice::<WrappedAction>(record).ok(),
+            LogFormat::Binary => ciborium::from_reader::<WrappedAction, _>(record).ok()
+        };
+        let wrapped_action = match current {
+            Some(wrapped_action) if wrapped_action.version == migrate::CURRENT_VERSION => wrapped_action,
+            _ => {
+                let value: serde_json::Value = match self.format {
+                    LogFormat::JsonLines => serde_json::from_slice(record).expect("Corrupted trade file"),
+                    LogFormat::Binary => ciborium::from_reader(record).expect("Corrupted trade file")
+                };
+                migrate::upgrade(value)?
+            }
+        };
+        Ok(Some((wrapped_action, raw)))
+    }
+}
+
+/// Copy every action in a trade file of either format to a new trade file in the given format
+pub async fn convert_log(
+    trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+    out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin),
+    format: LogFormat
+) -> Result<()> {
+    copy_log(LogReader::new(trade_file).await, out, format).await
+}
+
+/// Write every remaining action from a reader to a new trade file in the given format
+pub(crate) async fn copy_log(
+    mut reader: LogReader<impl tokio::io::AsyncRead + std::marker::Unpin>,
+    out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin),
+    format: LogFormat
+) -> Result<()> {
+    if format == LogFormat::Binary {
+        out.write_all(BINARY_HEADER).await.expect("Could not write to log");
+    }
+    while let Some((wrapped_action, _)) = reader.next().await? {
+        out.write_all(&encode(&wrapped_action, format)).await.expect("Could not write to log");
+    }
+    out.flush().await.expect("Could not flush to log");
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use super::{Coins, Error, Result, WrappedAction};
+
+/// The schema version written with every new action
+pub const CURRENT_VERSION: u32 = 2;
+/// The version of actions written before versions were recorded
+pub const LEGACY_VERSION: u32 = 1;
+
+/// Rewrites a record from one version to the next, indexed by the version it starts at, minus the legacy version
+type Migration = fn(&mut serde_json::Map<String, serde_json::Value>);
+const MIGRATIONS: [Migration; (CURRENT_VERSION - LEGACY_VERSION) as usize] = [
+    v1_to_v2
+];
+
+/// The fields of legacy actions that held coins, by action
+const LEGACY_COIN_FIELDS: &[(&str, &[&str])] = &[
+    ("BuyOrder", &["coins_per"]),
+    ("SellOrder", &["coins_per"]),
+    ("TransferCoins", &["count"]),
+    ("UpdateBankPrices", &["withdraw_flat", "withdraw_per_stack", "expedited"]),
+];
+
+/// The legacy `src/trade.rs` schema wrote coins as a bare number of millicoins, where they are now Coins strings
+///
+/// Every field added to actions since then is optional, so nothing else needs filling in
+fn v1_to_v2(record: &mut serde_json::Map<String, serde_json::Value>) {
+    let Some(action) = record.get_mut("action").and_then(serde_json::Value::as_object_mut)
+    else { return; };
+    for (name, fields) in LEGACY_COIN_FIELDS {
+        let Some(action) = action.get_mut(*name).and_then(serde_json::Value::as_object_mut)
+        else { continue; };
+        for field in *fields {
+            if let Some(millicoins) = action.get(*field).and_then(serde_json::Value::as_u64) {
+                action.insert((*field).to_owned(), Coins::from_millicoins(millicoins).to_string().into());
+            }
+        }
+    }
+}
+
+/// Get the schema version of a record from a trade file
+pub fn get_version(record: &serde_json::Value) -> Result<u32> {
+    match record.get("version") {
+        None => Ok(LEGACY_VERSION),
+        Some(version) => {
+            let Some(version) = version.as_u64()
+            else { return Err(Error::UnsupportedVersion { version: None }); };
+            match u32::try_from(version) {
+                Ok(version) if (LEGACY_VERSION..=CURRENT_VERSION).contains(&version) => Ok(version),
+                _ => Err(Error::UnsupportedVersion { version: Some(version) })
+            }
+        }
+    }
+}
+
+/// Bring a record from a trade file of any supported version up to the current one
+pub fn upgrade(mut record: serde_json::Value) -> Result<WrappedAction> {
+    let version = get_version(&record)?;
+    let fields = record.as_object_mut().expect("Corrupted trade file");
+    for migration in &MIGRATIONS[(version - LEGACY_VERSION) as usize..] {
+        migration(fields);
+    }
+    fields.insert("version".to_owned(), CURRENT_VERSION.into());
+    Ok(serde_json::from_value(record).expect("Corrupted trade file"))
+}
+
+/// Rewrite a trade file so every action is at the current version, keeping its format
+pub async fn migrate_log(
+    trade_file: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+    out: &mut (impl tokio::io::AsyncWrite + std::marker::Unpin)
+) -> Result<()> {
+    let reader = super::log::LogReader::new(trade_file).await;
+    let format = reader.format();
+    super::log::copy_log(reader, out, format).await
+}
@@ -1,10 +1,10 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::Coins;
 
 use super::{AssetId, Audit, Auditable, Error, PlayerId};
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum OrderType {
     Buy,
     Sell
@@ -18,35 +18,124 @@ impl std::fmt::Display for OrderType {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct PendingOrder {
     pub id: u64,
     pub coins_per: Coins,
     pub player: PlayerId,
+    /// The amount currently visible on the order book
     pub amount_remaining: u64,
+    /// The amount held back to refill the visible amount, for iceberg orders
+    #[serde(default)]
+    pub amount_hidden: u64,
+    /// The most that will be shown on the order book at once, if this is an iceberg order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_count: Option<u64>,
     pub asset: AssetId,
     pub order_type: OrderType
 }
+impl PendingOrder {
+    fn new(id: u64, player: &PlayerId, asset: &AssetId, count: u64, coins_per: Coins, display_count: Option<u64>, order_type: OrderType) -> PendingOrder {
+        let amount_remaining = display_count.map_or(count, |display_count| display_count.min(count));
+        PendingOrder {
+            id,
+            coins_per,
+            player: player.clone(),
+            amount_remaining,
+            amount_hidden: count - amount_remaining,
+            display_count,
+            asset: asset.clone(),
+            order_type
+        }
+    }
+    /// The total amount left in the order, including any hidden amount
+    pub fn amount_total(&self) -> u64 { self.amount_remaining + self.amount_hidden }
+    /// Take some of the order, but not all of it, refilling the visible amount from the hidden amount as needed
+    ///
+    /// Returns true if the visible amount was refilled
+    fn take(&mut self, count: u64) -> bool {
+        if count < self.amount_remaining {
+            self.amount_remaining -= count;
+            return false;
+        }
+        let display_count = self.display_count.expect("Refilled an order that cannot be refilled");
+        // The hidden amount is shown in tranches of display_count, so find the tranche we stopped in
+        let taken_hidden = count - self.amount_remaining;
+        let tranche_start = (taken_hidden / display_count) * display_count;
+        let tranche_size = display_count.min(self.amount_hidden - tranche_start);
+        self.amount_remaining = tranche_size - (taken_hidden - tranche_start);
+        self.amount_hidden -= tranche_start + tranche_size;
+        true
+    }
+}
+
+/// What to do when a player's order would match their own order on the book
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum SelfTradePolicy {
+    /// Let the orders match as normal
+    #[default]
+    Allow,
+    /// Cancel the player's orders on the book that the new order would cross
+    CancelResting,
+    /// Refuse to place the new order
+    RejectIncoming
+}
+
+/// The most a player can have on the order book at once
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct OrderLimits {
+    /// The most buy and sell orders they can have open
+    pub max_open_orders: Option<u64>,
+    /// The most coins they can have locked away in buy orders
+    pub max_buy_coins: Option<Coins>
+}
+
+/// A match between an incoming order and an order on the book
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    /// The id of the order that was on the book
+    pub order_id: u64,
+    /// The player who placed the order on the book
+    pub counterparty: PlayerId,
+    pub count: u64,
+    pub coins_per: Coins
+}
+
+/// A price on the order book, and the visible amount at it
+pub type PriceLevel = (Coins, u64);
+
+/// One price level of the order book, with totals from the best price up to and including it
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct DepthLevel {
+    pub coins_per: Coins,
+    /// The visible amount at this price
+    pub count: u64,
+    pub cumulative_count: u64,
+    /// The coins needed to take every visible order up to and including this price
+    pub cumulative_notional: Coins
+}
 
 #[derive(Default)]
 pub struct BuyData {
     pub coins_refunded: Coins,
     pub assets_instant_matched: u64,
     /// Maps sellers to the amount they're owed
-    pub sellers: std::collections::HashMap<PlayerId, Coins>
+    pub sellers: std::collections::HashMap<PlayerId, Coins>,
+    pub fills: Vec<Fill>
 }
 
 #[derive(Default)]
 pub struct SellData {
     pub coins_instant_earned: Coins,
-    pub assets_instant_matched: std::collections::HashMap<PlayerId, u64>
+    pub assets_instant_matched: std::collections::HashMap<PlayerId, u64>,
+    pub fills: Vec<Fill>
 }
 pub enum CancelResult {
     BuyOrder{player: PlayerId, refund_coins: Coins},
     SellOrder{player: PlayerId, refunded_asset: AssetId, refund_count: u64}
 }
 
-#[derive(Debug, Default, Serialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct OrderTracker {
     orders: std::collections::BTreeMap<u64, PendingOrder>,
 
@@ -54,6 +143,12 @@ pub struct OrderTracker {
     best_buy: std::collections::HashMap<AssetId, std::collections::BTreeMap<Coins, std::collections::VecDeque<u64>>>,
     /// XXX: this contains cancelled orders, skip over them
     best_sell: std::collections::HashMap<AssetId, std::collections::BTreeMap<Coins, std::collections::VecDeque<u64>>>,
+    /// The visible amount at each buy price, kept up to date so that reading the book is cheap
+    buy_levels: std::collections::HashMap<AssetId, std::collections::BTreeMap<Coins, u64>>,
+    /// The visible amount at each sell price, kept up to date so that reading the book is cheap
+    sell_levels: std::collections::HashMap<AssetId, std::collections::BTreeMap<Coins, u64>>,
+    /// The price of the most recent match for each asset
+    last_price: std::collections::HashMap<AssetId, Coins>,
 
     current_audit: Audit
 }
@@ -65,39 +160,65 @@ struct MatchResult<T> {
 impl OrderTracker {
     pub fn get_order(&self, id: u64) -> Result<PendingOrder, Error> { self.orders.get(&id).cloned().ok_or(Error::InvalidId { id }) }
     pub fn get_all(&self) -> std::collections::BTreeMap<u64, PendingOrder> { self.orders.clone() }
+    /// The price of the most recent match for an asset
+    pub fn get_last_price(&self, asset: &AssetId) -> Option<Coins> { self.last_price.get(asset).cloned() }
+    /// Forget the price of the most recent match for an asset, for when its units change size
+    pub fn clear_last_price(&mut self, asset: &AssetId) { self.last_price.remove(asset); }
     /// Prices for an asset, returns (price, amount) in (buy, sell)
     pub fn get_prices(&self, asset: &AssetId) -> (std::collections::BTreeMap<Coins, u64>, std::collections::BTreeMap<Coins, u64>) {
-        let buy_levels = self.best_buy
+        (self.buy_levels.get(asset).cloned().unwrap_or_default(), self.sell_levels.get(asset).cloned().unwrap_or_default())
+    }
+    /// The best visible buy and sell prices for an asset, and the amount at each, returns (buy, sell)
+    pub fn get_bbo(&self, asset: &AssetId) -> (Option<PriceLevel>, Option<PriceLevel>) {
+        (
+            // Best buy order is the highest
+            self.buy_levels.get(asset).and_then(|levels| levels.last_key_value()).map(|(coins_per, count)| (*coins_per, *count)),
+            // Best sell order is the lowest
+            self.sell_levels.get(asset).and_then(|levels| levels.first_key_value()).map(|(coins_per, count)| (*coins_per, *count))
+        )
+    }
+    /// The best max_levels price levels for an asset on each side, best first, returns (buy, sell)
+    pub fn get_depth(&self, asset: &AssetId, max_levels: usize) -> Result<(Vec<DepthLevel>, Vec<DepthLevel>), Error> {
+        let buy_levels = self.buy_levels
             .get(asset)
-            .iter()
-            .flat_map(|x| x.iter())
-            .filter_map(|(level, orders)| {
-                orders
-                    .iter()
-                    .cloned()
-                    .filter_map(|id| self.orders.get(&id).map(|x| x.amount_remaining))
-                    // We have None here iff there are no non-canceled orders
-                    .reduce(|a,b| a+b)
-                    .map(|amount| (*level, amount))
-            })
-            .collect();
-
-        let sell_levels = self.best_sell
+            .into_iter()
+            .flat_map(|x| x.iter().rev())
+            .map(|(level, amount)| (*level, *amount))
+            .take(max_levels);
+        let sell_levels = self.sell_levels
             .get(asset)
-            .iter()
+            .into_iter()
             .flat_map(|x| x.iter())
-            .filter_map(|(level, orders)| {
-                orders
-                    .iter()
-                    .cloned()
-                    .filter_map(|id| self.orders.get(&id).map(|x| x.amount_remaining))
-                    // We have None here iff there are no non-canceled orders
-                    .reduce(|a,b| a+b)
-                    .map(|amount| (*level, amount))
-            })
-            .collect();
-
-        (buy_levels, sell_levels)
+            .map(|(level, amount)| (*level, *amount))
+            .take(max_levels);
+        Ok((Self::accumulate(buy_levels)?, Self::accumulate(sell_levels)?))
+    }
+    /// Put an order's visible amount on its price level
+    fn show(&mut self, order: &PendingOrder) {
+        let target = match order.order_type { OrderType::Buy => &mut self.buy_levels, OrderType::Sell => &mut self.sell_levels };
+        *target.entry(order.asset.clone()).or_default().entry(order.coins_per).or_default() += order.amount_remaining;
+    }
+    /// Take an order's visible amount off its price level, cleaning up empty levels
+    fn hide(&mut self, order: &PendingOrder) {
+        let target = match order.order_type { OrderType::Buy => &mut self.buy_levels, OrderType::Sell => &mut self.sell_levels };
+        let std::collections::hash_map::Entry::Occupied(mut asset_class) = target.entry(order.asset.clone())
+        else { panic!("Hid order from non-existent asset class"); };
+        let std::collections::btree_map::Entry::Occupied(mut level) = asset_class.get_mut().entry(order.coins_per)
+        else { panic!("Hid order from non-existent price level"); };
+        *level.get_mut() -= order.amount_remaining;
+        if *level.get() == 0 { level.remove(); }
+        if asset_class.get().is_empty() { asset_class.remove(); }
+    }
+    /// Add running totals to price levels, which must be best first
+    fn accumulate(levels: impl Iterator<Item = (Coins, u64)>) -> Result<Vec<DepthLevel>, Error> {
+        let mut cumulative_count = 0;
+        let mut cumulative_notional = Coins::default();
+        levels.map(|(coins_per, count)| {
+            cumulative_count += count;
+            cumulative_notional.checked_add_assign(coins_per.checked_mul(count)?)?;
+            Ok(DepthLevel { coins_per, count, cumulative_count, cumulative_notional })
+        })
+        .collect()
     }
 
     /// Generic function to match buy and sell orders, investments, etc
@@ -159,6 +280,27 @@ impl OrderTracker {
             // ... write out ids within each price point ...
             .flat_map(|(_price, ids)| ids.iter().cloned())
     }
+    /// Iterate over the orders that an incoming order of the given type could match
+    fn iterate_opposing<'a>(&'a self, asset: &'a AssetId, limit: Coins, order_type: &OrderType) -> impl Iterator<Item = u64> + 'a {
+        match order_type {
+            OrderType::Buy => itertools::Either::Left(self.iterate_best_sell(asset, limit)),
+            OrderType::Sell => itertools::Either::Right(self.iterate_best_buy(asset, limit))
+        }
+    }
+    /// Get the orders on the book that an incoming order would be matched against, in matching order
+    pub fn get_matches(&self, asset: &AssetId, count: u64, coins_per: Coins, order_type: &OrderType) -> Vec<PendingOrder> {
+        let iter = self.iterate_opposing(asset, coins_per, order_type)
+            // We don't care about canceled orders here
+            .filter_map(|id| self.orders.get(&id))
+            .map(|order| (order.amount_total(), order.clone()));
+        Self::do_match(count, iter).1.into_iter().map(|res| res.data).collect()
+    }
+    /// Get the ids of a player's orders that an incoming order at this price would cross
+    pub fn get_crossing(&self, player: &PlayerId, asset: &AssetId, coins_per: Coins, order_type: &OrderType) -> Vec<u64> {
+        self.iterate_opposing(asset, coins_per, order_type)
+            .filter(|id| self.orders.get(id).is_some_and(|order| &order.player == player))
+            .collect()
+    }
     fn remove_best(&mut self, asset: AssetId, order_type: OrderType) -> Option<PendingOrder> {
         let target = match order_type { OrderType::Buy => &mut self.best_buy, OrderType::Sell => &mut self.best_sell };
 
@@ -178,19 +320,31 @@ impl OrderTracker {
         // Clean up
         if best_level.get().is_empty() { best_level.remove(); }
         if asset_class.get().is_empty() { asset_class.remove(); }
+        if let Some(order) = ret.as_ref() { self.hide(order); }
 
         ret
     }
+    /// Send a refilled order to the back of its price level, as it has used up its priority
+    fn requeue(&mut self, order: &PendingOrder) {
+        let target = match order.order_type { OrderType::Buy => &mut self.best_buy, OrderType::Sell => &mut self.best_sell };
+        let level = target
+            .get_mut(&order.asset)
+            .and_then(|levels| levels.get_mut(&order.coins_per))
+            .expect("Refilled order has no price level");
+        level.retain(|id| *id != order.id);
+        level.push_back(order.id);
+    }
 
     #[must_use]
-    pub fn handle_buy(&mut self, id: u64, player: &PlayerId, asset: &AssetId, count: u64, coins_per: Coins) -> BuyData {
+    pub fn handle_buy(&mut self, id: u64, player: &PlayerId, asset: &AssetId, count: u64, coins_per: Coins, display_count: Option<u64>) -> BuyData {
         let mut ret = BuyData::default();
 
         // Match the orders
         let iter = self.iterate_best_sell(asset, coins_per)
             .map(|idx| {
                 match self.orders.get(&idx) {
-                    Some(order) => (order.amount_remaining, Some(order.clone())),
+                    // Hidden amounts can be matched, they just aren't shown
+                    Some(order) => (order.amount_total(), Some(order.clone())),
                     None => (0, None)
                 }
             });
@@ -208,8 +362,15 @@ impl OrderTracker {
                 }
                 else {
                     let order_ref = self.orders.get_mut(&match_res.data.expect("Partial canceled order").id).expect("Cannot get mut order");
-                    order_ref.amount_remaining = match_res.order_remaining;
-                    order_ref.clone()
+                    let order_before = order_ref.clone();
+                    let refilled = order_ref.take(match_res.order_taken);
+                    let order_val = order_ref.clone();
+                    self.hide(&order_before);
+                    self.show(&order_val);
+                    if refilled {
+                        self.requeue(&order_val);
+                    }
+                    order_val
                 }
             };
             // Give the assets ...
@@ -219,14 +380,22 @@ impl OrderTracker {
                 coins_per.checked_sub(order.coins_per).expect("Refund difference underflow")
                 .checked_mul(match_res.order_taken).expect("Matched coins overflow")
             ).expect("Refund accumulator overflow");
+            // ... note down the fill ...
+            ret.fills.push(Fill{ order_id: order.id, counterparty: order.player.clone(), count: match_res.order_taken, coins_per: order.coins_per });
             // ... and track the seller
             ret.sellers.entry(order.player).or_default().checked_add_assign(order.coins_per.checked_mul(match_res.order_taken).expect("Coins earnt overflow")).expect("Seller balance overflow");
         }
 
+        if let Some(last) = ret.fills.last() {
+            self.last_price.insert(asset.clone(), last.coins_per);
+        }
+
         // If needs be, list the remaining amount
         if amount_remaining > 0 {
             self.best_buy.entry(asset.clone()).or_default().entry(coins_per).or_default().push_back(id);
-            self.orders.insert(id, PendingOrder{ id, coins_per, player: player.clone(), amount_remaining, asset: asset.clone(), order_type: OrderType::Buy });
+            let order = PendingOrder::new(id, player, asset, amount_remaining, coins_per, display_count, OrderType::Buy);
+            self.show(&order);
+            self.orders.insert(id, order);
             // We are responsible for the coins bound up in the buy order
             self.current_audit.add_coins(coins_per.checked_mul(amount_remaining).expect("Buy order remaining coins overflow"));
         }
@@ -237,14 +406,15 @@ impl OrderTracker {
     }
 
     #[must_use]
-    pub fn handle_sell(&mut self, id:u64, player: &PlayerId, asset: &AssetId, count: u64, coins_per: Coins) -> SellData {
+    pub fn handle_sell(&mut self, id:u64, player: &PlayerId, asset: &AssetId, count: u64, coins_per: Coins, display_count: Option<u64>) -> SellData {
         let mut ret = SellData::default();
 
         // Then match the orders
         let iter = self.iterate_best_buy(asset, coins_per)
             .map(|idx| {
                 match self.orders.get(&idx) {
-                    Some(order) => (order.amount_remaining, Some(order.clone())),
+                    // Hidden amounts can be matched, they just aren't shown
+                    Some(order) => (order.amount_total(), Some(order.clone())),
                     None => (0, None)
                 }
             });
@@ -262,22 +432,37 @@ impl OrderTracker {
                 }
                 else {
                     let order_ref = self.orders.get_mut(&match_res.data.expect("Partial canceled order").id).expect("Cannot get mut order");
-                    order_ref.amount_remaining = match_res.order_remaining;
-                    order_ref.clone()
+                    let order_before = order_ref.clone();
+                    let refilled = order_ref.take(match_res.order_taken);
+                    let order_val = order_ref.clone();
+                    self.hide(&order_before);
+                    self.show(&order_val);
+                    if refilled {
+                        self.requeue(&order_val);
+                    }
+                    order_val
                 }
             };
             // Give the money ...
             ret.coins_instant_earned.checked_add_assign(
                 order.coins_per.checked_mul(match_res.order_taken).expect("Sell order instant earned increment overflow")
             ).expect("Sell order instant earned overflow");
+            // ... note down the fill ...
+            ret.fills.push(Fill{ order_id: order.id, counterparty: order.player.clone(), count: match_res.order_taken, coins_per: order.coins_per });
             // ... give the assets ...
             *ret.assets_instant_matched.entry(order.player).or_default() += match_res.order_taken;
         }
 
+        if let Some(last) = ret.fills.last() {
+            self.last_price.insert(asset.clone(), last.coins_per);
+        }
+
         // If needs be, list the remaining amount
         if amount_remaining > 0 {
             self.best_sell.entry(asset.clone()).or_default().entry(coins_per).or_default().push_back(id);
-            self.orders.insert(id, PendingOrder{ id, coins_per, player: player.clone(), amount_remaining, asset: asset.clone(), order_type: OrderType::Sell });
+            let order = PendingOrder::new(id, player, asset, amount_remaining, coins_per, display_count, OrderType::Sell);
+            self.show(&order);
+            self.orders.insert(id, order);
         }
 
         // We are no longer responsible for the earnt coins
@@ -287,12 +472,41 @@ impl OrderTracker {
 
         ret
     }
+    /// Count a player's open orders, and the coins locked in their buy orders
+    pub fn get_usage(&self, player: &PlayerId) -> Result<(u64, Coins), Error> {
+        let mut n_orders = 0;
+        let mut buy_coins = Coins::default();
+        for order in self.orders.values().filter(|order| &order.player == player) {
+            n_orders += 1;
+            if order.order_type == OrderType::Buy {
+                buy_coins.checked_add_assign(order.coins_per.checked_mul(order.amount_total())?)?;
+            }
+        }
+        Ok((n_orders, buy_coins))
+    }
+    /// Get everyone with an asset locked in sell orders, and how many they have locked
+    pub fn get_sellers(&self, asset: &AssetId) -> std::collections::HashMap<PlayerId, u64> {
+        let mut ret: std::collections::HashMap<PlayerId, u64> = std::collections::HashMap::new();
+        for id in self.best_sell.get(asset).into_iter().flat_map(|levels| levels.values()).flatten() {
+            if let Some(order) = self.orders.get(id) {
+                *ret.entry(order.player.clone()).or_default() += order.amount_total();
+            }
+        }
+        ret
+    }
+    /// Hand one player's orders over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        self.orders.values_mut()
+        .filter(|order| &order.player == from)
+        .for_each(|order| order.player = to.clone());
+    }
     pub fn cancel(&mut self, target_id: u64) -> Result<CancelResult, Error> {
         if let Some(found) = self.orders.remove(&target_id) {
+            self.hide(&found);
             match found.order_type {
                 // If we found it as a buy...
                 OrderType::Buy => {
-                    let refund_coins = found.coins_per.checked_mul(found.amount_remaining).expect("Order cancel refund overflow");
+                    let refund_coins = found.coins_per.checked_mul(found.amount_total()).expect("Order cancel refund overflow");
                     // ... we are no longer responsible for the refunded coins ...
                     self.current_audit.sub_coins(refund_coins);
                     // ... and refund the money ...
@@ -301,9 +515,9 @@ impl OrderTracker {
                 // If we found it as a sell...
                 OrderType::Sell => {
                     // ... we are no longer responsible for the refunded assets ...
-                    self.current_audit.sub_asset(found.asset.clone(), found.amount_remaining);
+                    self.current_audit.sub_asset(found.asset.clone(), found.amount_total());
                     // ... and refund the assets
-                    Ok(CancelResult::SellOrder { player: found.player, refunded_asset: found.asset, refund_count: found.amount_remaining })
+                    Ok(CancelResult::SellOrder { refund_count: found.amount_total(), player: found.player, refunded_asset: found.asset })
                 }
             }
         }
@@ -316,19 +530,26 @@ impl OrderTracker {
 impl Auditable for OrderTracker {
     fn soft_audit(&self) -> Audit { self.current_audit.clone() }
 
-    fn hard_audit(&self) -> Audit {
+    fn try_hard_audit(&self) -> Result<Audit, String> {
         let mut new_audit = Audit::default();
+        let mut buy_levels: std::collections::HashMap<AssetId, std::collections::BTreeMap<Coins, u64>> = Default::default();
+        let mut sell_levels: std::collections::HashMap<AssetId, std::collections::BTreeMap<Coins, u64>> = Default::default();
         for order in self.orders.values() {
+            let target = match order.order_type { OrderType::Buy => &mut buy_levels, OrderType::Sell => &mut sell_levels };
+            *target.entry(order.asset.clone()).or_default().entry(order.coins_per).or_default() += order.amount_remaining;
             match order.order_type {
                 // A buy order has taken coins from someone's account
-                OrderType::Buy => new_audit.add_coins(order.coins_per.checked_mul(order.amount_remaining).expect("Hard audit coin increment overflow")),
+                OrderType::Buy => new_audit.add_coins(order.coins_per.checked_mul(order.amount_total()).expect("Hard audit coin increment overflow")),
                 // A buy order has taken assets from someone's account
-                OrderType::Sell => new_audit.add_asset(order.asset.clone(), order.amount_remaining),
+                OrderType::Sell => new_audit.add_asset(order.asset.clone(), order.amount_total()),
             }
         }
+        if buy_levels != self.buy_levels || sell_levels != self.sell_levels {
+            return Err(format!("Order tracker has inconsistent price levels: hard {:?} {:?} vs soft {:?} {:?}", buy_levels, sell_levels, self.buy_levels, self.sell_levels));
+        }
         if new_audit != self.current_audit {
-            panic!("Order tracker has inconsistent audit: hard {:?} vs soft {:?} for all {:?}", new_audit, self.current_audit, self.orders);
+            return Err(format!("Order tracker has inconsistent audit: hard {:?} vs soft {:?} for all {:?}", new_audit, self.current_audit, self.orders));
         }
-        new_audit
+        Ok(new_audit)
     }
 }
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Error, PlayerId};
+
+/// Which banker actions need a second banker to agree before they happen
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ApprovalPolicy {
+    /// Removing more than this many items at once, by undepositing or reversing a deposit
+    pub undeposit_over: Option<u64>,
+    /// Changing the bank's fees
+    pub bank_prices: bool,
+    /// Changing who the bankers are
+    pub bankers: bool,
+    /// How long a proposal can wait for a second banker before it fails, if at all
+    pub proposal_ttl_secs: Option<u64>
+}
+
+/// A banker action waiting for a second banker
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Proposal {
+    pub id: u64,
+    /// The banker who proposed the action, who can't also agree to it
+    pub proposer: PlayerId,
+    pub action: Action,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub title: Option<String>,
+    pub description: Option<String>
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ProposalTracker {
+    proposals: std::collections::BTreeMap<u64, Proposal>
+}
+impl ProposalTracker {
+    /// Get a proposal
+    pub fn get_proposal(&self, id: u64) -> Result<Proposal, Error> { self.proposals.get(&id).cloned().ok_or(Error::InvalidId { id }) }
+    /// List all proposals waiting for a second banker
+    pub fn get_proposals(&self) -> std::collections::BTreeMap<u64, Proposal> { self.proposals.clone() }
+    /// List the ids of proposals made before the given time
+    pub fn get_created_before(&self, cutoff: chrono::DateTime<chrono::Utc>) -> Vec<u64> {
+        self.proposals.values().filter(|proposal| proposal.created < cutoff).map(|proposal| proposal.id).collect()
+    }
+    /// Start tracking a proposal
+    pub fn track(&mut self, proposal: Proposal) {
+        self.proposals.insert(proposal.id, proposal);
+    }
+    /// Stop tracking a proposal, once it has been agreed or disagreed to
+    pub fn remove(&mut self, id: u64) -> Result<Proposal, Error> {
+        self.proposals.remove(&id).ok_or(Error::InvalidId { id })
+    }
+    /// Hand one player's proposals over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        for proposal in self.proposals.values_mut() {
+            if &proposal.proposer == from {
+                proposal.proposer = to.clone();
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Coins;
+
+use super::{AssetId, Audit, PlayerId};
+
+/// Coins and items, kept in a fixed order so that a report always serialises the same way
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ReserveTotals {
+    pub coins: Coins,
+    pub assets: std::collections::BTreeMap<AssetId, u64>
+}
+impl From<Audit> for ReserveTotals {
+    fn from(value: Audit) -> Self {
+        ReserveTotals { coins: value.coins, assets: value.assets.into_iter().collect() }
+    }
+}
+
+/// How well an exchange traded product is backed by its issuer's holdings
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct EtpBacking {
+    pub issuer: PlayerId,
+    /// The units held by anyone other than the issuer
+    pub outstanding: u64,
+    /// The basket needed to back the outstanding units
+    pub required: std::collections::BTreeMap<AssetId, u64>,
+    /// How much of the basket the issuer actually holds
+    pub held: std::collections::BTreeMap<AssetId, u64>
+}
+impl EtpBacking {
+    /// Returns true if the issuer holds the whole required basket
+    pub fn is_backed(&self) -> bool {
+        self.required.iter().all(|(asset, required)| self.held.get(asset).copied().unwrap_or(0) >= *required)
+    }
+}
+
+/// Everything the exchange owes its players at a point in time, for the bankers to publish
+///
+/// The liabilities are what the bankers must have in storage, and are split into what players hold in their balances and what is locked away in orders, withdrawals, and the like
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ReservesReport {
+    /// The id of the last action included
+    pub id: u64,
+    pub time: chrono::DateTime<chrono::Utc>,
+    /// Everything held on players' behalf
+    pub liabilities: ReserveTotals,
+    /// Each player's balance
+    pub claims: std::collections::BTreeMap<PlayerId, ReserveTotals>,
+    /// The liabilities not in any player's balance
+    pub locked: ReserveTotals,
+    /// The backing of each exchange traded product, which is counted in the issuer's claims
+    pub etps: std::collections::BTreeMap<AssetId, EtpBacking>
+}
@@ -0,0 +1,71 @@
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Error, PlayerId};
+
+/// What a player signs: an action, along with the id they expect it to be given
+///
+/// Signed actions are published in the trade log, so without the id anyone could submit one again
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SignedAction {
+    pub id: u64,
+    pub action: Action
+}
+
+/// A player's Ed25519 signature over the exact text of an action they submitted
+///
+/// The text is kept as well as the action, as re-serialising the action might not give the same bytes
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ActionSignature {
+    /// The SignedAction's JSON, exactly as it was signed
+    pub payload: String,
+    /// The base64 public key the action was signed with
+    pub public_key: String,
+    /// The base64 signature
+    pub signature: String
+}
+impl ActionSignature {
+    /// Get the action that was signed, and the id it was signed for
+    pub fn signed_action(&self) -> Result<SignedAction, Error> {
+        serde_json::from_str(&self.payload).map_err(|_| Error::InvalidSignature)
+    }
+    /// Check that the signature is valid, and that it covers the given action being given the given id
+    pub fn verify(&self, id: u64, action: &Action) -> Result<(), Error> {
+        let signed_action = self.signed_action()?;
+        if signed_action.id != id || &signed_action.action != action {
+            return Err(Error::InvalidSignature);
+        }
+        let public_key = BASE64_STANDARD.decode(&self.public_key).map_err(|_| Error::InvalidSignature)?;
+        let signature = BASE64_STANDARD.decode(&self.signature).map_err(|_| Error::InvalidSignature)?;
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+            .verify(self.payload.as_bytes(), &signature)
+            .map_err(|_| Error::InvalidSignature)
+    }
+}
+
+/// Check that a public key is a base64 Ed25519 key
+pub fn check_public_key(public_key: &str) -> Result<(), Error> {
+    match BASE64_STANDARD.decode(public_key) {
+        Ok(bytes) if bytes.len() == ring::signature::ED25519_PUBLIC_KEY_LEN => Ok(()),
+        _ => Err(Error::InvalidSigningKey)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SigningKeyTracker {
+    /// The key each player signs their actions with
+    ///
+    /// These don't follow migrated accounts, as a key belongs to whoever holds it rather than to the account
+    keys: std::collections::HashMap<PlayerId, String>
+}
+impl SigningKeyTracker {
+    /// Get the key a player signs their actions with
+    pub fn get_key(&self, player: &PlayerId) -> Option<&String> { self.keys.get(player) }
+    /// Change the key a player signs their actions with
+    pub fn set_key(&mut self, player: PlayerId, public_key: Option<String>) {
+        match public_key {
+            Some(public_key) => { self.keys.insert(player, public_key); },
+            None => { self.keys.remove(&player); }
+        }
+    }
+}
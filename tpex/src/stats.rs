@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Coins;
+
+use super::AssetId;
+
+/// How far back the market stats look
+pub const STATS_WINDOW: chrono::TimeDelta = chrono::TimeDelta::hours(24);
+
+/// A single match between two orders
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TradePrint {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub coins_per: Coins,
+    pub count: u64
+}
+
+/// A summary of recent trading in an asset
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct MarketStats {
+    /// The price of the most recent match, however long ago it was
+    pub last_price: Option<Coins>,
+    /// The number of items traded in the window
+    pub volume: u64,
+    /// The coins that changed hands in the window
+    pub notional: Coins,
+    pub high: Option<Coins>,
+    pub low: Option<Coins>,
+    /// The average price in the window, weighted by volume
+    pub vwap: Option<Coins>
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct StatsTracker {
+    /// Matches within the window of the latest match, oldest first
+    prints: std::collections::HashMap<AssetId, std::collections::VecDeque<TradePrint>>
+}
+impl StatsTracker {
+    /// Note down a match, and forget any that have fallen out of the window
+    pub fn record(&mut self, asset: &AssetId, print: TradePrint) {
+        let cutoff = print.time - STATS_WINDOW;
+        let prints = self.prints.entry(asset.clone()).or_default();
+        while prints.front().is_some_and(|old| old.time <= cutoff) {
+            prints.pop_front();
+        }
+        prints.push_back(print);
+    }
+    /// Forget every match for an asset, for when its units change size
+    pub fn clear(&mut self, asset: &AssetId) { self.prints.remove(asset); }
+    /// Summarise the matches in the window ending at the given time
+    pub fn get_stats(&self, asset: &AssetId, now: chrono::DateTime<chrono::Utc>) -> MarketStats {
+        let cutoff = now - STATS_WINDOW;
+        let mut ret = MarketStats::default();
+        // Work in u128 so that the notional can't overflow before we check it
+        let mut notional: u128 = 0;
+        for print in self.prints.get(asset).into_iter().flatten().filter(|print| print.time > cutoff && print.time <= now) {
+            ret.volume += print.count;
+            notional += print.coins_per.millicoins() as u128 * print.count as u128;
+            ret.high = ret.high.max(Some(print.coins_per));
+            ret.low = Some(ret.low.map_or(print.coins_per, |low| low.min(print.coins_per)));
+        }
+        if ret.volume > 0 {
+            ret.notional = Coins::from_millicoins(notional.try_into().unwrap_or(u64::MAX));
+            ret.vwap = Some(Coins::from_millicoins((notional / ret.volume as u128) as u64));
+        }
+        ret
+    }
+}
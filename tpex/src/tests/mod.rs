@@ -89,7 +89,9 @@ async fn deposit_undeposit() {
         player: player(1),
         asset: item.clone(),
         count: 16384,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("Deposit failed");
     assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), Some(16384));
     assert_eq!(state.hard_audit(), Audit{coins: Coins::default(), assets: [(item.clone(), 16384)].into_iter().collect()});
@@ -97,7 +99,9 @@ async fn deposit_undeposit() {
         player: player(1),
         asset: item.clone(),
         count: 16384,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("Undeposit failed");
     assert_eq!(state.hard_audit(), Audit::default());
 }
@@ -111,7 +115,9 @@ async fn invalid_deposit() {
         player: player(1),
         asset: "costelbone".to_owned(),
         count: 49,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect_err("Costlebone deposited");
 }
 
@@ -126,21 +132,27 @@ async fn undeposit() {
         player: player(1),
         asset: item.clone(),
         count: 49,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("Deposit failed");
     assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), Some(49));
     state.apply(Action::Undeposit {
         player: player(1),
         asset: item.clone(),
         count: 48,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("First undeposit failed");
     assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), Some(1));
     state.apply(Action::Undeposit {
         player: player(1),
         asset: item.clone(),
         count: 1,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("Second undeposit failed");
     assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), None);
     assert_eq!(state.hard_audit(), Audit::default());
@@ -150,7 +162,7 @@ fn pretty_orders(state: &State) -> impl Display {
     struct OrderInfo(BTreeMap<u64, PendingOrder>);
     impl std::fmt::Display for OrderInfo {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            for PendingOrder { id, coins_per, player, amount_remaining, asset, order_type } in self.0.values() {
+            for PendingOrder { id, coins_per, player, amount_remaining, asset, order_type, .. } in self.0.values() {
                 let t = match order_type {
                     OrderType::Buy => 'B',
                     OrderType::Sell => 'S',
@@ -174,19 +186,25 @@ async fn matching() {
         player: player(1),
         asset: item.clone(),
         count: 64,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("Deposit 1 failed");
     state.apply(Action::Deposit {
         player: player(2),
         asset: item.clone(),
         count: 128,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("Deposit 2 failed");
     state.apply(Action::Deposit {
         player: player(3),
         asset: DIAMOND_NAME.to_owned(),
         count: 64,
-        banker: PlayerId::the_bank()
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
     }, &mut sink).await.expect("Deposit 3 failed");
     state.apply(Action::BuyCoins {
         player: player(3),
@@ -199,38 +217,44 @@ async fn matching() {
         player: player(1),
         asset: item.clone(),
         count: 64,
-        coins_per: Coins::from_millicoins(1000)
+        coins_per: Coins::from_millicoins(1000),
+        display_count: None
     }, &mut sink).await.expect_err("Bought with insufficient coins");
     state.apply(Action::SellOrder {
         player: player(1),
         asset: item.clone(),
         count: 32,
-        coins_per: Coins::from_coins(1)
+        coins_per: Coins::from_coins(1),
+        display_count: None
     }, &mut sink).await.expect("Sell order 1 failed");
     state.apply(Action::SellOrder {
         player: player(1),
         asset: item.clone(),
         count: 16,
-        coins_per: Coins::from_coins(3)
+        coins_per: Coins::from_coins(3),
+        display_count: None
     }, &mut sink).await.expect("Sell order 2 failed");
     state.apply(Action::SellOrder {
         player: player(2),
         asset: item.clone(),
         count: 16,
-        coins_per: Coins::from_coins(2)
+        coins_per: Coins::from_coins(2),
+        display_count: None
     }, &mut sink).await.expect("Sell order 3 failed");
     state.apply(Action::SellOrder {
         player: player(2),
         asset: item.clone(),
         count: 16,
-        coins_per: Coins::from_coins(2)
+        coins_per: Coins::from_coins(2),
+        display_count: None
     }, &mut sink).await.expect("Sell order 4 failed");
     let cancel_me = state.apply(Action::SellOrder {
         player: player(2),
         asset: item.clone(),
         count: 16,
-        coins_per: Coins::from_coins(1)
-    }, &mut sink).await.expect("Sell order 5 failed");
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Sell order 5 failed").id;
     state.apply(Action::CancelOrder {
         target: cancel_me
     }, &mut sink).await.expect("Cancel sell order 5 failed");
@@ -238,23 +262,31 @@ async fn matching() {
         player: player(2),
         asset: item.clone(),
         count: 16,
-        coins_per: Coins::from_coins(10)
+        coins_per: Coins::from_coins(10),
+        display_count: None
     }, &mut sink).await.expect("Sell order 6 failed");
     state.apply(Action::SellOrder {
         player: player(2),
         asset: item.clone(),
         count: 16,
-        coins_per: Coins::from_coins(1)
+        coins_per: Coins::from_coins(1),
+        display_count: None
     }, &mut sink).await.expect("Sell order 7 failed");
     println!("Initial orders:\n{}", pretty_orders(&state));
 
-    state.apply(Action::BuyOrder {
+    let outcome = state.apply(Action::BuyOrder {
         player: player(3),
         asset: item.clone(),
         count: 40,
-        coins_per: Coins::from_coins(4)
+        coins_per: Coins::from_coins(4),
+        display_count: None
     }, &mut sink).await.expect("Buy order 1 failed");
     println!("Post buy 1:\n{}", pretty_orders(&state));
+    assert_eq!(outcome.fills, vec![
+        Fill { order_id: 5, counterparty: player(1), count: 32, coins_per: Coins::from_coins(1) },
+        Fill { order_id: 12, counterparty: player(2), count: 8, coins_per: Coins::from_coins(1) },
+    ]);
+    assert_eq!(outcome.amount_rested, 0);
 
     for (p, bal) in [(player(1), 32), (player(2), 8), (player(3), 63960)] {
         assert_eq!(state.get_bal(&p), Coins::from_coins(bal));
@@ -265,7 +297,8 @@ async fn matching() {
         player: player(3),
         asset: item.clone(),
         count: 80,
-        coins_per: Coins::from_coins(4)
+        coins_per: Coins::from_coins(4),
+        display_count: None
     }, &mut sink).await.expect("Buy order 2 failed");
     println!("Post buy 2:\n{}", pretty_orders(&state));
 
@@ -278,7 +311,8 @@ async fn matching() {
         player: player(2),
         asset: item.clone(),
         count: 24,
-        coins_per: Coins::from_coins(4)
+        coins_per: Coins::from_coins(4),
+        display_count: None
     }, &mut sink).await.expect("Sell order 8 failed");
     println!("Post sell 8:\n{}", pretty_orders(&state));
 
@@ -288,3 +322,1736 @@ async fn matching() {
     assert_eq!(state.get_assets(&player(3)), [(item.clone(), 120)].into_iter().collect());
 
 }
+
+#[tokio::test]
+async fn iceberg() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit {
+        player: player(1),
+        asset: item.clone(),
+        count: 100,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::Deposit {
+        player: player(2),
+        asset: item.clone(),
+        count: 10,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 2 failed");
+    state.apply(Action::Deposit {
+        player: player(3),
+        asset: DIAMOND_NAME.to_owned(),
+        count: 1,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 3 failed");
+    state.apply(Action::BuyCoins {
+        player: player(3),
+        n_diamonds: 1
+    }, &mut sink).await.expect("Buy coins failed");
+
+    state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 100,
+        coins_per: Coins::from_coins(1),
+        display_count: Some(0)
+    }, &mut sink).await.expect_err("Iceberg order displaying nothing was placed");
+    let iceberg = state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 100,
+        coins_per: Coins::from_coins(1),
+        display_count: Some(10)
+    }, &mut sink).await.expect("Iceberg sell order failed").id;
+    state.apply(Action::SellOrder {
+        player: player(2),
+        asset: item.clone(),
+        count: 10,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Normal sell order failed");
+    assert_eq!(state.get_prices(&item).1, [(Coins::from_coins(1), 20)].into_iter().collect());
+
+    // Eat through the first tranche and half of the second
+    state.apply(Action::BuyOrder {
+        player: player(3),
+        asset: item.clone(),
+        count: 15,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Buy order 1 failed");
+    let order = state.get_order(iceberg).expect("Iceberg order disappeared");
+    assert_eq!((order.amount_remaining, order.amount_hidden), (5, 80));
+    assert_eq!(state.get_prices(&item).1, [(Coins::from_coins(1), 15)].into_iter().collect());
+    println!("Post buy 1:\n{}", pretty_orders(&state));
+
+    // The refill sent the iceberg to the back of the queue, so player 2 should be next
+    state.apply(Action::BuyOrder {
+        player: player(3),
+        asset: item.clone(),
+        count: 10,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Buy order 2 failed");
+    assert_eq!(state.get_bal(&player(2)), Coins::from_coins(10));
+    assert_eq!(state.get_order(iceberg).expect("Iceberg order disappeared").amount_total(), 85);
+
+    // Cancelling should refund the hidden amount too
+    state.apply(Action::CancelOrder { target: iceberg }, &mut sink).await.expect("Cancel iceberg failed");
+    assert_eq!(state.get_assets(&player(1)), [(item.clone(), 85)].into_iter().collect());
+    assert_eq!(state.get_bal(&player(1)), Coins::from_coins(15));
+
+    // Plain orders are written just as they were before icebergs
+    let plain = Action::SellOrder { player: player(1), asset: item.clone(), count: 1, coins_per: Coins::from_coins(1), display_count: None };
+    let json = serde_json::to_string(&plain).expect("Serialise failed");
+    assert!(!json.contains("display_count"));
+    assert_eq!(serde_json::from_str::<Action>(&json).expect("Deserialise failed"), plain);
+}
+
+#[tokio::test]
+async fn self_trade() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit {
+        player: player(1),
+        asset: item.clone(),
+        count: 64,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::Deposit {
+        player: player(1),
+        asset: DIAMOND_NAME.to_owned(),
+        count: 1,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 2 failed");
+    state.apply(Action::BuyCoins {
+        player: player(1),
+        n_diamonds: 1
+    }, &mut sink).await.expect("Buy coins failed");
+    let resting = state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 32,
+        coins_per: Coins::from_coins(2),
+        display_count: None
+    }, &mut sink).await.expect("Sell order failed").id;
+
+    // Players shouldn't be able to set this
+    state.apply(Action::UpdateSelfTradePolicy {
+        policy: SelfTradePolicy::RejectIncoming,
+        banker: player(1)
+    }, &mut sink).await.expect_err("Non-banker changed self trade policy");
+    state.apply(Action::UpdateSelfTradePolicy {
+        policy: SelfTradePolicy::RejectIncoming,
+        banker: PlayerId::the_bank()
+    }, &mut sink).await.expect("Policy update 1 failed");
+    assert_eq!(state.apply(Action::BuyOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 8,
+        coins_per: Coins::from_coins(2),
+        display_count: None
+    }, &mut sink).await, Err(Error::SelfTrade { order: resting }));
+    // Not crossing is fine
+    state.apply(Action::BuyOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 8,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Non-crossing buy order failed");
+
+    state.apply(Action::UpdateSelfTradePolicy {
+        policy: SelfTradePolicy::CancelResting,
+        banker: PlayerId::the_bank()
+    }, &mut sink).await.expect("Policy update 2 failed");
+    let outcome = state.apply(Action::BuyOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 8,
+        coins_per: Coins::from_coins(2),
+        display_count: None
+    }, &mut sink).await.expect("Crossing buy order failed");
+    assert_eq!(outcome.orders_cancelled, vec![resting]);
+    assert!(outcome.fills.is_empty());
+    assert_eq!(outcome.amount_rested, 8);
+    assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), Some(64));
+    assert_eq!(state.get_bal(&player(1)), Coins::from_coins(1000 - 8 - 16));
+}
+
+#[tokio::test]
+async fn halt_trading() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit {
+        player: player(1),
+        asset: item.clone(),
+        count: 64,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit failed");
+    let resting = state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 32,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Sell order 1 failed").id;
+
+    state.apply(Action::HaltTrading { asset: item.clone(), banker: PlayerId::the_bank() }, &mut sink).await.expect("Halt failed");
+    state.apply(Action::HaltTrading { asset: item.clone(), banker: PlayerId::the_bank() }, &mut sink).await.expect_err("Double halt succeeded");
+    assert!(state.is_halted(&item));
+    assert_eq!(state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 32,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await, Err(Error::TradingHalted { asset: item.clone() }));
+    // Cancelling should still work
+    state.apply(Action::CancelOrder { target: resting }, &mut sink).await.expect("Cancel while halted failed");
+
+    state.apply(Action::ResumeTrading { asset: item.clone(), banker: PlayerId::the_bank() }, &mut sink).await.expect("Resume failed");
+    state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 32,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Sell order 2 failed");
+}
+
+#[tokio::test]
+async fn price_band() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit {
+        player: player(1),
+        asset: item.clone(),
+        count: 64,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::Deposit {
+        player: player(2),
+        asset: DIAMOND_NAME.to_owned(),
+        count: 1,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 2 failed");
+    state.apply(Action::BuyCoins {
+        player: player(2),
+        n_diamonds: 1
+    }, &mut sink).await.expect("Buy coins failed");
+    state.apply(Action::UpdatePriceBand {
+        asset: item.clone(),
+        max_deviation_percent: Some(10),
+        banker: PlayerId::the_bank()
+    }, &mut sink).await.expect("Price band update failed");
+
+    // With no trades yet, anything goes
+    state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 16,
+        coins_per: Coins::from_coins(10),
+        display_count: None
+    }, &mut sink).await.expect("Sell order 1 failed");
+    assert_eq!(state.get_last_price(&item), None);
+    state.apply(Action::BuyOrder {
+        player: player(2),
+        asset: item.clone(),
+        count: 8,
+        coins_per: Coins::from_coins(10),
+        display_count: None
+    }, &mut sink).await.expect("Buy order 1 failed");
+    assert_eq!(state.get_last_price(&item), Some(Coins::from_coins(10)));
+
+    // 11c is exactly on the edge of the band
+    state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 16,
+        coins_per: Coins::from_coins(11),
+        display_count: None
+    }, &mut sink).await.expect("Sell order 2 failed");
+    assert_eq!(state.apply(Action::SellOrder {
+        player: player(1),
+        asset: item.clone(),
+        count: 16,
+        coins_per: Coins::from_millicoins(11_001),
+        display_count: None
+    }, &mut sink).await, Err(Error::OutsidePriceBand { asset: item.clone(), last_price: Coins::from_coins(10), max_deviation_percent: 10 }));
+    state.apply(Action::BuyOrder {
+        player: player(2),
+        asset: item.clone(),
+        count: 8,
+        coins_per: Coins::from_millicoins(8_999),
+        display_count: None
+    }, &mut sink).await.expect_err("Buy order outside of band succeeded");
+
+    state.apply(Action::UpdatePriceBand {
+        asset: item.clone(),
+        max_deviation_percent: None,
+        banker: PlayerId::the_bank()
+    }, &mut sink).await.expect("Price band removal failed");
+    state.apply(Action::BuyOrder {
+        player: player(2),
+        asset: item.clone(),
+        count: 8,
+        coins_per: Coins::from_coins(1),
+        display_count: None
+    }, &mut sink).await.expect("Buy order after band removal failed");
+}
+
+#[tokio::test]
+async fn escrow() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit {
+        player: player(1),
+        asset: item.clone(),
+        count: 64,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::Deposit {
+        player: player(2),
+        asset: DIAMOND_NAME.to_owned(),
+        count: 1,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 2 failed");
+    state.apply(Action::BuyCoins {
+        player: player(2),
+        n_diamonds: 1
+    }, &mut sink).await.expect("Buy coins failed");
+
+    let give = EscrowBundle { coins: Coins::default(), assets: [(item.clone(), 32)].into_iter().collect() };
+    let want = EscrowBundle { coins: Coins::from_coins(50), assets: Default::default() };
+
+    state.apply(Action::OfferEscrow {
+        player: player(1),
+        counterparty: player(2),
+        give: EscrowBundle { coins: Coins::default(), assets: [(item.clone(), 65)].into_iter().collect() },
+        want: want.clone(),
+        expiry: None
+    }, &mut sink).await.expect_err("Escrow offered with insufficient assets");
+    let offer = state.apply(Action::OfferEscrow {
+        player: player(1),
+        counterparty: player(2),
+        give: give.clone(),
+        want: want.clone(),
+        expiry: None
+    }, &mut sink).await.expect("Escrow offer 1 failed").id;
+    assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), Some(32));
+    assert_eq!(state.perms(&Action::AcceptEscrow { target: offer }).map(|x| x.player), Ok(player(2)));
+
+    state.apply(Action::AcceptEscrow { target: offer }, &mut sink).await.expect("Escrow accept failed");
+    assert_eq!(state.get_assets(&player(2)).get(&item).cloned(), Some(32));
+    assert_eq!(state.get_bal(&player(1)), Coins::from_coins(50));
+    assert_eq!(state.get_bal(&player(2)), Coins::from_coins(950));
+    state.apply(Action::AcceptEscrow { target: offer }, &mut sink).await.expect_err("Escrow accepted twice");
+
+    let expired = state.apply(Action::OfferEscrow {
+        player: player(1),
+        counterparty: player(2),
+        give,
+        want,
+        expiry: Some(chrono::DateTime::UNIX_EPOCH)
+    }, &mut sink).await.expect("Escrow offer 2 failed").id;
+    assert_eq!(state.apply(Action::AcceptEscrow { target: expired }, &mut sink).await, Err(Error::EscrowExpired { id: expired }));
+    state.apply(Action::CancelEscrow { target: expired }, &mut sink).await.expect("Escrow cancel failed");
+    assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), Some(32));
+    assert!(state.get_escrows().is_empty());
+}
+
+#[tokio::test]
+async fn loans() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+    let future = chrono::Utc::now() + chrono::Duration::days(7);
+    let past = chrono::DateTime::UNIX_EPOCH;
+
+    state.apply(Action::Deposit {
+        player: player(1),
+        asset: DIAMOND_NAME.to_owned(),
+        count: 1,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::BuyCoins {
+        player: player(1),
+        n_diamonds: 1
+    }, &mut sink).await.expect("Buy coins failed");
+    state.apply(Action::Deposit {
+        player: player(2),
+        asset: item.clone(),
+        count: 64,
+        banker: PlayerId::the_bank(),
+        note: None,
+        reference: None
+    }, &mut sink).await.expect("Deposit 2 failed");
+
+    let collateral: std::collections::HashMap<AssetId, u64> = [(item.clone(), 64)].into_iter().collect();
+
+    // Borrow, and pay back
+    let repaid = state.apply(Action::OfferLoan {
+        lender: player(1),
+        borrower: player(2),
+        principal: Coins::from_coins(100),
+        repayment: Coins::from_coins(110),
+        collateral: collateral.clone(),
+        due: future
+    }, &mut sink).await.expect("Loan offer 1 failed").id;
+    state.apply(Action::RepayLoan { target: repaid }, &mut sink).await.expect_err("Repaid a loan that wasn't accepted");
+    state.apply(Action::AcceptLoan { target: repaid }, &mut sink).await.expect("Loan accept 1 failed");
+    assert_eq!(state.get_assets(&player(2)).get(&item).cloned(), None);
+    assert_eq!(state.get_bal(&player(2)), Coins::from_coins(100));
+    assert_eq!(state.apply(Action::LiquidateCollateral { target: repaid }, &mut sink).await, Err(Error::LoanNotDue { id: repaid, due: future }));
+    // They can't afford the interest yet
+    state.apply(Action::RepayLoan { target: repaid }, &mut sink).await.expect_err("Repaid a loan without enough coins");
+    state.apply(Action::TransferCoins { payer: player(1), payee: player(2), count: Coins::from_coins(10) }, &mut sink).await.expect("Transfer failed");
+    state.apply(Action::RepayLoan { target: repaid }, &mut sink).await.expect("Loan repay failed");
+    assert_eq!(state.get_assets(&player(2)).get(&item).cloned(), Some(64));
+    assert_eq!(state.get_bal(&player(1)), Coins::from_coins(1000));
+
+    // Let a loan default
+    let defaulted = state.apply(Action::OfferLoan {
+        lender: player(1),
+        borrower: player(2),
+        principal: Coins::from_coins(100),
+        repayment: Coins::from_coins(110),
+        collateral: collateral.clone(),
+        due: past
+    }, &mut sink).await.expect("Loan offer 2 failed").id;
+    assert_eq!(state.apply(Action::AcceptLoan { target: defaulted }, &mut sink).await, Err(Error::LoanOverdue { id: defaulted }));
+    state.apply(Action::CancelLoan { target: defaulted }, &mut sink).await.expect("Loan cancel failed");
+    assert_eq!(state.get_bal(&player(1)), Coins::from_coins(1000));
+
+    let defaulted = state.apply(Action::OfferLoan {
+        lender: player(1),
+        borrower: player(2),
+        principal: Coins::from_coins(100),
+        repayment: Coins::from_coins(110),
+        collateral,
+        due: chrono::Utc::now() + chrono::Duration::milliseconds(50)
+    }, &mut sink).await.expect("Loan offer 3 failed").id;
+    state.apply(Action::AcceptLoan { target: defaulted }, &mut sink).await.expect("Loan accept 3 failed");
+    state.apply(Action::CancelLoan { target: defaulted }, &mut sink).await.expect_err("Cancelled an accepted loan");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    state.apply(Action::LiquidateCollateral { target: defaulted }, &mut sink).await.expect("Liquidation failed");
+    assert_eq!(state.get_assets(&player(1)).get(&item).cloned(), Some(64));
+    assert!(state.get_loans().is_empty());
+}
+
+#[tokio::test]
+async fn etp_backing() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let product = "diamond_etp".to_owned();
+    let iron = "iron_ingot".to_owned();
+    let basket: std::collections::HashMap<AssetId, u64> = [(DIAMOND_NAME.to_owned(), 2), (iron.clone(), 16)].into_iter().collect();
+
+    assert_eq!(state.apply(Action::DefineEtp {
+        issuer: player(1),
+        product: DIAMOND_NAME.to_owned(),
+        basket: basket.clone()
+    }, &mut sink).await, Err(Error::EtpExists { product: DIAMOND_NAME.to_owned() }));
+    state.apply(Action::DefineEtp {
+        issuer: player(1),
+        product: product.clone(),
+        basket: basket.clone()
+    }, &mut sink).await.expect("Define failed");
+    assert_eq!(state.apply(Action::DefineEtp {
+        issuer: player(2),
+        product: product.clone(),
+        basket: basket.clone()
+    }, &mut sink).await, Err(Error::EtpExists { product: product.clone() }));
+
+    // Units held by the issuer don't need backing
+    state.apply(Action::IssueEtp { product: product.clone(), count: 10 }, &mut sink).await.expect("Issue failed");
+    assert_eq!(state.get_etp(&product).expect("Product disappeared").issued, 10);
+    state.check_etp_backing(&product).expect("Issuer's own units needed backing");
+    assert_eq!(state.apply(Action::DefineEtp {
+        issuer: player(1),
+        product: product.clone(),
+        basket
+    }, &mut sink).await, Err(Error::EtpUnitsOutstanding { product: product.clone() }));
+
+    // Units held by others do
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 4, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::TransferAsset { payer: player(1), payee: player(2), asset: product.clone(), count: 2 }, &mut sink).await.expect("Transfer failed");
+    assert_eq!(state.check_etp_backing(&product), Err(Error::EtpUnderbacked { product: product.clone(), asset: iron.clone(), amount_short: 32 }));
+    state.apply(Action::Deposit { player: player(1), asset: iron.clone(), count: 32, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 2 failed");
+    state.check_etp_backing(&product).expect("Backed product failed check");
+
+    // Only the issuer's own units can be removed
+    state.apply(Action::RemoveEtp { product: product.clone(), count: 9 }, &mut sink).await.expect_err("Removed units held by someone else");
+    state.apply(Action::RemoveEtp { product: product.clone(), count: 8 }, &mut sink).await.expect("Remove failed");
+    assert_eq!(state.get_etp(&product).expect("Product disappeared").issued, 2);
+}
+
+#[tokio::test]
+async fn etp_creation() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let product = "diamond_etp".to_owned();
+    let iron = "iron_ingot".to_owned();
+    let basket: std::collections::HashMap<AssetId, u64> = [(DIAMOND_NAME.to_owned(), 2), (iron.clone(), 16)].into_iter().collect();
+
+    state.apply(Action::DefineEtp {
+        issuer: player(1),
+        product: product.clone(),
+        basket
+    }, &mut sink).await.expect("Define failed");
+    state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 6, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::Deposit { player: player(2), asset: iron.clone(), count: 40, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 2 failed");
+
+    // Nothing should move if they can't afford the whole basket
+    assert_eq!(state.apply(Action::CreateUnits { player: player(2), product: product.clone(), count: 3 }, &mut sink).await,
+               Err(Error::OverdrawnAsset { asset: iron.clone(), amount_overdrawn: 8 }));
+    assert_eq!(state.get_assets(&player(2)).get(DIAMOND_NAME).cloned(), Some(6));
+
+    state.apply(Action::CreateUnits { player: player(2), product: product.clone(), count: 2 }, &mut sink).await.expect("Create failed");
+    assert_eq!(state.get_assets(&player(2)).get(&product).cloned(), Some(2));
+    assert_eq!(state.get_assets(&player(1)).get(&iron).cloned(), Some(32));
+    state.check_etp_backing(&product).expect("Created units were not backed");
+
+    // Redemption is limited by the units they hold
+    state.apply(Action::RedeemUnits { player: player(2), product: product.clone(), count: 3 }, &mut sink).await.expect_err("Redeemed units they didn't have");
+    state.apply(Action::RedeemUnits { player: player(2), product: product.clone(), count: 1 }, &mut sink).await.expect("Redeem failed");
+    assert_eq!(state.get_assets(&player(2)).get(&product).cloned(), Some(1));
+    assert_eq!(state.get_assets(&player(2)).get(&iron).cloned(), Some(24));
+    assert_eq!(state.get_etp(&product).expect("Product disappeared").issued, 1);
+
+    // ... and by the backing the issuer still has
+    state.apply(Action::TransferAsset { payer: player(1), payee: player(3), asset: iron.clone(), count: 16 }, &mut sink).await.expect("Transfer failed");
+    assert_eq!(state.apply(Action::RedeemUnits { player: player(2), product: product.clone(), count: 1 }, &mut sink).await,
+               Err(Error::OverdrawnAsset { asset: iron, amount_overdrawn: 16 }));
+    assert_eq!(state.get_assets(&player(2)).get(&product).cloned(), Some(1));
+}
+
+#[tokio::test]
+async fn etp_cap() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let product = "diamond_etp".to_owned();
+
+    state.apply(Action::DefineEtp {
+        issuer: player(1),
+        product: product.clone(),
+        basket: [(DIAMOND_NAME.to_owned(), 1)].into_iter().collect()
+    }, &mut sink).await.expect("Define failed");
+    state.apply(Action::UpdateEtpCap { product: product.clone(), cap: Some(10), banker: player(1) }, &mut sink).await.expect_err("Non-banker set a cap");
+    state.apply(Action::UpdateEtpCap { product: product.clone(), cap: Some(10), banker: PlayerId::the_bank() }, &mut sink).await.expect("Cap failed");
+
+    state.apply(Action::IssueEtp { product: product.clone(), count: 8 }, &mut sink).await.expect("Issue failed");
+    assert_eq!(state.apply(Action::IssueEtp { product: product.clone(), count: 3 }, &mut sink).await, Err(Error::EtpCapReached { product: product.clone(), cap: 10 }));
+    state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 3, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    assert_eq!(state.apply(Action::CreateUnits { player: player(2), product: product.clone(), count: 3 }, &mut sink).await, Err(Error::EtpCapReached { product: product.clone(), cap: 10 }));
+    state.apply(Action::CreateUnits { player: player(2), product: product.clone(), count: 2 }, &mut sink).await.expect("Create failed");
+
+    assert_eq!(state.get_etp_supply(&product), Ok(EtpSupply { issued: 10, outstanding: 2, held_by_issuer: 8 }));
+}
+
+#[tokio::test]
+async fn etp_split() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let product = "diamond_etp".to_owned();
+
+    state.apply(Action::DefineEtp {
+        issuer: player(1),
+        product: product.clone(),
+        basket: [(DIAMOND_NAME.to_owned(), 4)].into_iter().collect()
+    }, &mut sink).await.expect("Define failed");
+    state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 12, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::CreateUnits { player: player(2), product: product.clone(), count: 3 }, &mut sink).await.expect("Create failed");
+    state.apply(Action::IssueEtp { product: product.clone(), count: 1 }, &mut sink).await.expect("Issue failed");
+
+    // 4 for 1
+    state.apply(Action::SplitEtp { product: product.clone(), ratio: (4, 1) }, &mut sink).await.expect("Split failed");
+    assert_eq!(state.get_assets(&player(2)).get(&product).cloned(), Some(12));
+    assert_eq!(state.get_etp_supply(&product), Ok(EtpSupply { issued: 16, outstanding: 12, held_by_issuer: 4 }));
+    assert_eq!(state.get_etp(&product).expect("Product disappeared").basket.get(DIAMOND_NAME).cloned(), Some(1));
+    state.check_etp_backing(&product).expect("Split units were not backed");
+
+    // A basket of 1 diamond can't be split any further
+    assert_eq!(state.apply(Action::SplitEtp { product: product.clone(), ratio: (2, 1) }, &mut sink).await, Err(Error::InvalidSplit { product: product.clone() }));
+
+    // Units on the book can't be split
+    let order = state.apply(Action::SellOrder { player: player(2), asset: product.clone(), count: 1, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await.expect("Sell failed").id;
+    assert_eq!(state.apply(Action::SplitEtp { product: product.clone(), ratio: (1, 4) }, &mut sink).await, Err(Error::EtpUnitsLocked { product: product.clone() }));
+    state.apply(Action::CancelOrder { target: order }, &mut sink).await.expect("Cancel failed");
+
+    // 1 for 4 back again
+    state.apply(Action::SplitEtp { product: product.clone(), ratio: (1, 4) }, &mut sink).await.expect("Consolidation failed");
+    assert_eq!(state.get_assets(&player(2)).get(&product).cloned(), Some(3));
+    assert_eq!(state.get_etp_supply(&product), Ok(EtpSupply { issued: 4, outstanding: 3, held_by_issuer: 1 }));
+}
+
+#[tokio::test]
+async fn etp_allowlist() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let product = "private_etp".to_owned();
+
+    state.apply(Action::DefineEtp {
+        issuer: player(1),
+        product: product.clone(),
+        basket: Default::default()
+    }, &mut sink).await.expect("Define failed");
+    state.apply(Action::IssueEtp { product: product.clone(), count: 10 }, &mut sink).await.expect("Issue failed");
+    state.apply(Action::UpdateEtpAllowlist { product: product.clone(), allowlist: Some(vec![player(2)]) }, &mut sink).await.expect("Allowlist failed");
+
+    // Direct transfers
+    state.apply(Action::TransferAsset { payer: player(1), payee: player(2), asset: product.clone(), count: 5 }, &mut sink).await.expect("Allowed transfer failed");
+    assert_eq!(state.apply(Action::TransferAsset { payer: player(2), payee: player(3), asset: product.clone(), count: 1 }, &mut sink).await,
+               Err(Error::TransferRestricted { product: product.clone(), player: player(3) }));
+
+    // Orders
+    state.apply(Action::Deposit { player: player(3), asset: DIAMOND_NAME.to_owned(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(3), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    assert_eq!(state.apply(Action::BuyOrder { player: player(3), asset: product.clone(), count: 1, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await,
+               Err(Error::TransferRestricted { product: product.clone(), player: player(3) }));
+    state.apply(Action::TransferCoins { payer: player(3), payee: player(2), count: Coins::from_coins(10) }, &mut sink).await.expect("Transfer failed");
+    state.apply(Action::BuyOrder { player: player(2), asset: product.clone(), count: 1, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await.expect("Allowed buy failed");
+    // Taking someone off the list stops sellers matching their resting orders
+    state.apply(Action::UpdateEtpAllowlist { product: product.clone(), allowlist: Some(vec![]) }, &mut sink).await.expect("Allowlist update failed");
+    assert_eq!(state.apply(Action::SellOrder { player: player(1), asset: product.clone(), count: 1, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await,
+               Err(Error::TransferRestricted { product: product.clone(), player: player(2) }));
+
+    // Escrow
+    assert_eq!(state.apply(Action::OfferEscrow {
+        player: player(3),
+        counterparty: player(2),
+        give: EscrowBundle { coins: Coins::from_coins(1), assets: Default::default() },
+        want: EscrowBundle { coins: Coins::default(), assets: [(product.clone(), 1)].into_iter().collect() },
+        expiry: None
+    }, &mut sink).await, Err(Error::TransferRestricted { product: product.clone(), player: player(3) }));
+
+    // Lifting the restriction lets anyone hold it
+    state.apply(Action::UpdateEtpAllowlist { product: product.clone(), allowlist: None }, &mut sink).await.expect("Allowlist removal failed");
+    state.apply(Action::TransferAsset { payer: player(2), payee: player(3), asset: product.clone(), count: 1 }, &mut sink).await.expect("Unrestricted transfer failed");
+}
+
+#[tokio::test]
+async fn reverse() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    let deposit = state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed").id;
+    let transfer = state.apply(Action::TransferAsset { payer: player(1), payee: player(2), asset: item.clone(), count: 16 }, &mut sink).await.expect("Transfer failed").id;
+
+    let reverse = |target| Action::Reverse { target, reason: "Typo".to_owned(), banker: PlayerId::the_bank() };
+    state.apply(Action::Reverse { target: transfer, reason: "Typo".to_owned(), banker: player(1) }, &mut sink).await.expect_err("Non-banker reversed an action");
+    // The deposit can't be fully reversed while some of it has been given away
+    assert_eq!(state.apply(reverse(deposit), &mut sink).await, Err(Error::OverdrawnAsset { asset: item.clone(), amount_overdrawn: 16 }));
+    state.apply(reverse(transfer), &mut sink).await.expect("Reverse transfer failed");
+    assert_eq!(state.apply(reverse(transfer), &mut sink).await, Err(Error::NotReversible { id: transfer }));
+    state.apply(reverse(deposit), &mut sink).await.expect("Reverse deposit failed");
+    assert!(state.get_assets(&player(1)).is_empty());
+    assert!(state.get_assets(&player(2)).is_empty());
+
+    // Only simple movements can be reversed
+    let halt = state.apply(Action::HaltTrading { asset: item.clone(), banker: PlayerId::the_bank() }, &mut sink).await.expect("Halt failed").id;
+    assert_eq!(state.apply(reverse(halt), &mut sink).await, Err(Error::NotReversible { id: halt }));
+    assert_eq!(state.apply(reverse(1000), &mut sink).await, Err(Error::InvalidId { id: 1000 }));
+
+    // Only recent actions can be reversed, so that they don't all have to be kept
+    let old = state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed").id;
+    let recent = state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed").id;
+    while state.apply(Action::Deleted { reason: "Filler".to_owned(), banker: PlayerId::the_bank() }, &mut sink).await.expect("Delete failed").id < recent + REVERSAL_WINDOW - 1 {}
+    state.apply(reverse(recent), &mut sink).await.expect("Reverse at the edge of the window failed");
+    assert_eq!(state.apply(reverse(old), &mut sink).await, Err(Error::NotReversible { id: old }));
+}
+
+#[tokio::test]
+async fn scheduled() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+    let future = chrono::Utc::now() + chrono::Duration::milliseconds(50);
+
+    state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    let transfer = state.apply(Action::Schedule {
+        at: future,
+        action: Box::new(Action::TransferAsset { payer: player(1), payee: player(2), asset: item.clone(), count: 16 })
+    }, &mut sink).await.expect("Schedule transfer failed").id;
+    assert_eq!(state.perms(&Action::RunScheduled { target: transfer }), Ok(ActionPermissions { level: ActionLevel::Normal, player: player(1) }));
+    // Only bankers can schedule banker actions
+    state.apply(Action::Schedule {
+        at: future,
+        action: Box::new(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: player(1), note: None, reference: None })
+    }, &mut sink).await.expect_err("Non-banker scheduled a deposit");
+    state.apply(Action::Schedule {
+        at: future,
+        action: Box::new(Action::RunScheduled { target: transfer })
+    }, &mut sink).await.expect_err("Scheduled a scheduling action");
+
+    assert!(state.get_due_scheduled(chrono::Utc::now()).is_empty());
+    assert_eq!(state.apply(Action::RunScheduled { target: transfer }, &mut sink).await, Err(Error::NotDue { id: transfer, at: future }));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(state.get_due_scheduled(chrono::Utc::now()), vec![transfer]);
+    state.apply(Action::RunScheduled { target: transfer }, &mut sink).await.expect("Run failed");
+    assert_eq!(state.get_assets(&player(2)).get(&item).cloned(), Some(16));
+    assert!(state.get_scheduled().is_empty());
+}
+
+#[tokio::test]
+async fn scheduled_audit() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+    let start = chrono::Utc::now();
+    let deposit = state.apply_with_time(Action::Schedule {
+        at: start,
+        action: Box::new(Action::Deposit { player: player(1), asset: item.clone(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None })
+    }, start, &mut sink).await.expect("Schedule deposit failed").id;
+    state.apply_with_time(Action::RunScheduled { target: deposit }, start, &mut sink).await.expect("Run failed");
+    // The deposit is only counted once
+    assert_eq!(state.soft_audit().assets.get(&item).cloned(), Some(5));
+    assert_eq!(state.soft_audit(), state.hard_audit());
+}
+
+#[tokio::test]
+#[should_panic(expected = "Failed audit")]
+async fn replay_until_audits() {
+    let mut state = State::new();
+    let mut log = Vec::new();
+
+    state.apply(Action::Deposit { player: player(1), asset: "cobblestone".to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    // Replaying part of the log checks the books just as replaying all of it does
+    let mut past = State::new();
+    past.audit.add_asset("cobblestone".to_owned(), 1);
+    let _ = past.replay_until(&mut log.as_slice(), 1).await;
+}
+
+#[tokio::test]
+async fn freeze() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+    let reason = "Compromised".to_owned();
+
+    state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::FreezeAccount { player: player(1), reason: reason.clone(), banker: player(2) }, &mut sink).await.expect_err("Non-banker froze an account");
+    state.apply(Action::FreezeAccount { player: player(1), reason: reason.clone(), banker: PlayerId::the_bank() }, &mut sink).await.expect("Freeze failed");
+
+    let transfer = Action::TransferAsset { payer: player(1), payee: player(2), asset: item.clone(), count: 16 };
+    assert_eq!(state.apply(transfer.clone(), &mut sink).await, Err(Error::AccountFrozen { player: player(1), reason: reason.clone() }));
+    assert_eq!(state.apply(Action::SellOrder { player: player(1), asset: item.clone(), count: 1, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await,
+               Err(Error::AccountFrozen { player: player(1), reason }));
+    // Bankers can still fix things up
+    state.apply(Action::Undeposit { player: player(1), asset: item.clone(), count: 32, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Undeposit failed");
+
+    state.apply(Action::UnfreezeAccount { player: player(1), banker: PlayerId::the_bank() }, &mut sink).await.expect("Unfreeze failed");
+    state.apply(Action::UnfreezeAccount { player: player(1), banker: PlayerId::the_bank() }, &mut sink).await.expect_err("Unfroze an account twice");
+    state.apply(transfer, &mut sink).await.expect("Transfer failed");
+}
+
+#[tokio::test]
+async fn frozen_scheduled() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+    let reason = "Compromised".to_owned();
+    let start = chrono::Utc::now();
+
+    state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    let transfer = state.apply_with_time(Action::Schedule {
+        at: start,
+        action: Box::new(Action::TransferAsset { payer: player(1), payee: player(2), asset: item.clone(), count: 16 })
+    }, start, &mut sink).await.expect("Schedule transfer failed").id;
+    state.apply(Action::FreezeAccount { player: player(1), reason: reason.clone(), banker: PlayerId::the_bank() }, &mut sink).await.expect("Freeze failed");
+
+    assert_eq!(state.apply_with_time(Action::RunScheduled { target: transfer }, start, &mut sink).await, Err(Error::AccountFrozen { player: player(1), reason }));
+    // The failed action can still be cleared out
+    state.apply(Action::CancelScheduled { target: transfer }, &mut sink).await.expect("Cancel failed");
+    assert!(state.get_scheduled().is_empty());
+}
+
+#[tokio::test]
+async fn migrate() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 2 failed");
+    state.apply(Action::Deposit { player: player(2), asset: item.clone(), count: 8, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 3 failed");
+    let order = state.apply(Action::SellOrder { player: player(1), asset: item.clone(), count: 16, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await.expect("Sell failed").id;
+    let withdrawal = state.apply(Action::WithdrawalRequested { player: player(1), assets: [(item.clone(), 16)].into_iter().collect() }, &mut sink).await.expect("Withdrawal failed").id;
+
+    state.apply(Action::MigrateAccount { from: player(1), to: player(2), banker: PlayerId::the_bank() }, &mut sink).await.expect("Migrate failed");
+    assert!(state.get_assets(&player(1)).is_empty());
+    assert_eq!(state.get_assets(&player(2)).get(&item).cloned(), Some(40));
+    assert_eq!(state.get_bal(&player(1)), Coins::default());
+    assert!(!state.get_bal(&player(2)).is_zero());
+    assert_eq!(state.get_order(order).expect("Order disappeared").player, player(2));
+    assert_eq!(state.get_withdrawals().get(&withdrawal).expect("Withdrawal disappeared").player, player(2));
+    assert_eq!(state.get_migrated(&player(1)), Some(player(2)));
+
+    // The old account is gone for good
+    assert_eq!(state.apply(Action::TransferCoins { payer: player(1), payee: player(3), count: Coins::from_coins(1) }, &mut sink).await,
+               Err(Error::AccountMigrated { player: player(1), to: player(2) }));
+    assert_eq!(state.apply(Action::MigrateAccount { from: player(3), to: player(1), banker: PlayerId::the_bank() }, &mut sink).await,
+               Err(Error::AccountMigrated { player: player(1), to: player(2) }));
+    assert_eq!(state.apply(Action::MigrateAccount { from: player(1), to: player(3), banker: PlayerId::the_bank() }, &mut sink).await,
+               Err(Error::AccountMigrated { player: player(1), to: player(2) }));
+    assert_eq!(state.get_migrated(&player(1)), Some(player(2)));
+    state.apply(Action::CancelOrder { target: order }, &mut sink).await.expect("Cancel failed");
+    assert_eq!(state.get_assets(&player(2)).get(&item).cloned(), Some(56));
+}
+
+#[tokio::test]
+async fn order_limits() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 1 failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 2 failed");
+
+    state.apply(Action::UpdateOrderLimits {
+        player: None,
+        limits: OrderLimits { max_open_orders: Some(2), max_buy_coins: Some(Coins::from_coins(10)) },
+        banker: PlayerId::the_bank()
+    }, &mut sink).await.expect("Default limits failed");
+
+    let buy = |count| Action::BuyOrder { player: player(1), asset: item.clone(), count, coins_per: Coins::from_coins(1), display_count: None };
+    let sell = Action::SellOrder { player: player(1), asset: item.clone(), count: 1, coins_per: Coins::from_coins(2), display_count: None };
+    state.apply(buy(6), &mut sink).await.expect("Buy 1 failed");
+    assert_eq!(state.apply(buy(5), &mut sink).await, Err(Error::TooManyBuyCoins { max_buy_coins: Coins::from_coins(10) }));
+    state.apply(buy(4), &mut sink).await.expect("Buy 2 failed");
+    assert_eq!(state.apply(sell.clone(), &mut sink).await, Err(Error::TooManyOrders { max_open_orders: 2 }));
+
+    // A player's own limits override the defaults
+    state.apply(Action::UpdateOrderLimits {
+        player: Some(player(1)),
+        limits: OrderLimits::default(),
+        banker: PlayerId::the_bank()
+    }, &mut sink).await.expect("Player limits failed");
+    state.apply(sell, &mut sink).await.expect("Sell failed");
+    assert_eq!(state.get_order_limits(&player(2)).max_open_orders, Some(2));
+}
+
+#[tokio::test]
+async fn fee_report() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 2, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    let assets: std::collections::HashMap<AssetId, u64> = [(DIAMOND_NAME.to_owned(), 1)].into_iter().collect();
+    let fee = state.calc_withdrawal_fee(&assets).expect("Fee calculation failed");
+    let withdrawal = state.apply(Action::WithdrawalRequested { player: player(1), assets }, &mut sink).await.expect("Withdrawal failed").id;
+
+    // Fees only count once the bank has them
+    assert!(state.get_fee_report().is_empty());
+    state.apply(Action::WithdrawalCompleted { target: withdrawal, banker: PlayerId::the_bank() }, &mut sink).await.expect("Completion failed");
+    assert_eq!(state.get_fee_report(), [(FeeSource::Withdrawal, fee)].into_iter().collect());
+}
+
+#[tokio::test]
+async fn withdrawal_fees() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "shulker_shell_bundle".to_owned();
+    let assets: std::collections::HashMap<AssetId, u64> = [(item.clone(), 17)].into_iter().collect();
+
+    assert_eq!(state.calc_withdrawal_fee(&assets), Err(Error::UnknownAsset { asset: item.clone() }));
+    assert_eq!(state.apply(Action::UpdateAssetInfo {
+        asset_info: [(item.clone(), AssetInfo { stack_size: 0 })].into_iter().collect(),
+        banker: PlayerId::the_bank()
+    }, &mut sink).await, Err(Error::InvalidAssetInfo { asset: item.clone() }));
+    state.apply(Action::UpdateAssetInfo {
+        asset_info: [(item.clone(), AssetInfo { stack_size: 16 })].into_iter().collect(),
+        banker: PlayerId::the_bank()
+    }, &mut sink).await.expect("Asset info update failed");
+
+    // 17 items is 2 stacks
+    assert_eq!(state.calc_withdrawal_fee(&assets), Ok(Coins::from_millicoins(1040)));
+    state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 17, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    assert_eq!(state.apply(Action::WithdrawalRequested { player: player(1), assets: assets.clone() }, &mut sink).await,
+               Err(Error::OverdrawnCoins { amount_overdrawn: Coins::from_millicoins(1040) }));
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    let outcome = state.apply(Action::WithdrawalRequested { player: player(1), assets }, &mut sink).await.expect("Withdrawal failed");
+    assert_eq!(outcome.fees_paid, Coins::from_millicoins(1040));
+    assert_eq!(state.get_bal(&player(1)), Coins::from_millicoins(998_960));
+}
+
+#[tokio::test]
+async fn expedited() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let banker = player(9);
+    state.apply(Action::UpdateBankers { bankers: vec![PlayerId::the_bank(), banker.clone()], banker: PlayerId::the_bank() }, &mut sink).await.expect("Bankers update failed");
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 3, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    let assets: std::collections::HashMap<AssetId, u64> = [(DIAMOND_NAME.to_owned(), 1)].into_iter().collect();
+    let fee = state.calc_withdrawal_fee(&assets).expect("Fee calculation failed");
+    let normal = state.apply(Action::WithdrawalRequested { player: player(1), assets: assets.clone() }, &mut sink).await.expect("Withdrawal 1 failed").id;
+    let expedited = state.apply(Action::WithdrawalRequested { player: player(1), assets }, &mut sink).await.expect("Withdrawal 2 failed").id;
+    assert_eq!(state.get_next_withdrawal().map(|withdrawal| withdrawal.id), Some(normal));
+
+    let outcome = state.apply(Action::Expedited { target: expedited }, &mut sink).await.expect("Expedite failed");
+    assert_eq!(outcome.fees_paid, state.expedite_fee());
+    assert_eq!(state.apply(Action::Expedited { target: expedited }, &mut sink).await, Err(Error::AlreadyDone));
+    // Expedited withdrawals jump the queue
+    assert_eq!(state.get_next_withdrawal().map(|withdrawal| withdrawal.id), Some(expedited));
+
+    // The banker who delivers gets the credit for both fees
+    state.apply(Action::WithdrawalCompleted { target: expedited, banker }, &mut sink).await.expect("Completion failed");
+    let mut total = fee;
+    total.checked_add_assign(state.expedite_fee()).expect("Fee overflow");
+    assert_eq!(state.get_earnings(&player(9)), total);
+    assert_eq!(state.get_fee_report(), [(FeeSource::Withdrawal, fee), (FeeSource::Expedite, state.expedite_fee())].into_iter().collect());
+    assert_eq!(state.get_next_withdrawal().map(|withdrawal| withdrawal.id), Some(normal));
+}
+
+#[tokio::test]
+async fn claim_withdrawal() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::UpdateBankers { bankers: vec![player(8), player(9)], banker: PlayerId::the_bank() }, &mut sink).await.expect("Bankers update failed");
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 3, banker: player(8), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    let assets: std::collections::HashMap<AssetId, u64> = [(DIAMOND_NAME.to_owned(), 1)].into_iter().collect();
+    let first = state.apply(Action::WithdrawalRequested { player: player(1), assets: assets.clone() }, &mut sink).await.expect("Withdrawal 1 failed").id;
+    let second = state.apply(Action::WithdrawalRequested { player: player(1), assets }, &mut sink).await.expect("Withdrawal 2 failed").id;
+
+    state.apply(Action::ClaimWithdrawal { target: first, banker: player(1) }, &mut sink).await.expect_err("Non-banker claimed a withdrawal");
+    state.apply(Action::ClaimWithdrawal { target: first, banker: player(8) }, &mut sink).await.expect("Claim failed");
+    assert_eq!(state.apply(Action::ClaimWithdrawal { target: first, banker: player(9) }, &mut sink).await, Err(Error::WithdrawalClaimed { id: first, banker: player(8) }));
+    assert_eq!(state.get_unclaimed_withdrawals().into_keys().collect::<Vec<_>>(), vec![second]);
+    assert_eq!(state.get_next_withdrawal_for(&player(8)).map(|withdrawal| withdrawal.id), Some(first));
+    assert_eq!(state.get_next_withdrawal_for(&player(9)).map(|withdrawal| withdrawal.id), Some(second));
+
+    assert_eq!(state.apply(Action::WithdrawalCompleted { target: first, banker: player(9) }, &mut sink).await, Err(Error::WithdrawalClaimed { id: first, banker: player(8) }));
+    state.apply(Action::UnclaimWithdrawal { target: first, banker: player(9) }, &mut sink).await.expect("Unclaim failed");
+    state.apply(Action::WithdrawalCompleted { target: first, banker: player(9) }, &mut sink).await.expect("Completion failed");
+}
+
+#[tokio::test]
+async fn expire_withdrawals() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 3, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    let assets: std::collections::HashMap<AssetId, u64> = [(DIAMOND_NAME.to_owned(), 1)].into_iter().collect();
+    state.apply(Action::WithdrawalRequested { player: player(1), assets: assets.clone() }, &mut sink).await.expect("Withdrawal 1 failed");
+    let claimed = state.apply(Action::WithdrawalRequested { player: player(1), assets }, &mut sink).await.expect("Withdrawal 2 failed").id;
+    state.apply(Action::ClaimWithdrawal { target: claimed, banker: PlayerId::the_bank() }, &mut sink).await.expect("Claim failed");
+
+    assert_eq!(state.apply(Action::ExpireWithdrawals { banker: PlayerId::the_bank() }, &mut sink).await, Err(Error::AlreadyDone));
+    state.apply(Action::UpdateWithdrawalTtl { ttl_secs: Some(0), banker: PlayerId::the_bank() }, &mut sink).await.expect("TTL update failed");
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    state.apply(Action::ExpireWithdrawals { banker: PlayerId::the_bank() }, &mut sink).await.expect("Expiry failed");
+
+    // Claimed withdrawals are being gathered, so are left alone
+    assert_eq!(state.get_withdrawals().into_keys().collect::<Vec<_>>(), vec![claimed]);
+    assert_eq!(state.get_assets(&player(1)).get(DIAMOND_NAME).cloned(), Some(1));
+    let mut expected_bal = Coins::from_coins(1000);
+    expected_bal.checked_sub_assign(state.calc_withdrawal_fee(&[(DIAMOND_NAME.to_owned(), 1)].into_iter().collect()).expect("Fee calculation failed")).expect("Fee underflow");
+    assert_eq!(state.get_bal(&player(1)), expected_bal);
+}
+
+#[tokio::test]
+async fn replay_audit() {
+    let mut state = State::new();
+    let mut log = Vec::new();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 3, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut log).await.expect("Buy coins failed");
+    let withdrawal = state.apply(Action::WithdrawalRequested { player: player(1), assets: [(DIAMOND_NAME.to_owned(), 1)].into_iter().collect() }, &mut log).await.expect("Withdrawal failed").id;
+    state.apply(Action::WithdrawalCompleted { target: withdrawal, banker: PlayerId::the_bank() }, &mut log).await.expect("Completion failed");
+    let deposit = state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed").id;
+    state.apply(Action::Reverse { target: deposit, reason: "Typo".to_owned(), banker: PlayerId::the_bank() }, &mut log).await.expect("Reverse failed");
+    let audit = state.hard_audit();
+    assert_eq!(audit.assets.get(DIAMOND_NAME).cloned(), Some(1));
+
+    let mut replayed = State::new();
+    replayed.replay(&mut log.as_slice()).await.expect("Replay failed");
+    assert_eq!(replayed.hard_audit(), audit);
+    assert_eq!(serde_json::to_value(&replayed).expect("Serialise failed"), serde_json::to_value(&state).expect("Serialise failed"));
+}
+
+#[tokio::test]
+async fn action_notes() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let item = "cobblestone".to_owned();
+
+    let noted = state.apply(Action::Deposit {
+        player: player(1),
+        asset: item.clone(),
+        count: 64,
+        banker: PlayerId::the_bank(),
+        note: Some("Left in the lobby".to_owned()),
+        reference: Some("100 64 -200".to_owned())
+    }, &mut sink).await.expect("Deposit 1 failed").id;
+    state.apply(Action::Deposit { player: player(1), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit 2 failed");
+    state.apply(Action::Deposit { player: player(2), asset: item.clone(), count: 64, banker: PlayerId::the_bank(), note: Some("Not player 1".to_owned()), reference: None }, &mut sink).await.expect("Deposit 3 failed");
+
+    assert_eq!(state.get_action_notes(&player(1)), [(noted, ActionNote {
+        player: player(1),
+        banker: PlayerId::the_bank(),
+        note: Some("Left in the lobby".to_owned()),
+        reference: Some("100 64 -200".to_owned())
+    })].into_iter().collect());
+    // Old logs have no notes
+    let old: Action = serde_json::from_str(r#"{"Deposit":{"player":"1","asset":"cobblestone","count":1,"banker":"bank"}}"#).expect("Old deposit failed to parse");
+    assert!(matches!(old, Action::Deposit { note: None, reference: None, .. }));
+}
+
+#[tokio::test]
+async fn authorisation_requests() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    let reason = "Building a beacon".to_owned();
+    assert_eq!(state.apply(Action::RequestAuthorisation { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 2, reason: reason.clone() }, &mut sink).await, Err(Error::NotRestricted { asset: DIAMOND_NAME.to_owned() }));
+    state.apply(Action::UpdateRestricted { restricted_assets: vec![DIAMOND_NAME.to_owned()], banker: PlayerId::the_bank() }, &mut sink).await.expect("Restricted update failed");
+    let first = state.apply(Action::RequestAuthorisation { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 2, reason: reason.clone() }, &mut sink).await.expect("Request 1 failed").id;
+    let second = state.apply(Action::RequestAuthorisation { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 5, reason }, &mut sink).await.expect("Request 2 failed").id;
+    assert_eq!(state.get_next_authorisation_request().map(|request| request.id), Some(first));
+
+    state.apply(Action::ApproveAuthorisation { target: first, banker: player(1), expiry: None }, &mut sink).await.expect_err("Non-banker approved a request");
+    state.apply(Action::ApproveAuthorisation { target: first, banker: PlayerId::the_bank(), expiry: None }, &mut sink).await.expect("Approval failed");
+    state.apply(Action::DenyAuthorisation { target: second, banker: PlayerId::the_bank() }, &mut sink).await.expect("Denial failed");
+    assert_eq!(state.apply(Action::ApproveAuthorisation { target: second, banker: PlayerId::the_bank(), expiry: None }, &mut sink).await, Err(Error::InvalidId { id: second }));
+    assert!(state.get_authorisation_requests().is_empty());
+    assert_eq!(state.get_authorisation(&player(1), &DIAMOND_NAME.to_owned()).map(|auth| auth.count), Some(2));
+
+    // The approved amount can actually be withdrawn
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 4, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    state.apply(Action::WithdrawalRequested { player: player(1), assets: [(DIAMOND_NAME.to_owned(), 3)].into_iter().collect() }, &mut sink).await.expect_err("Withdrew more than authorised");
+    state.apply(Action::WithdrawalRequested { player: player(1), assets: [(DIAMOND_NAME.to_owned(), 2)].into_iter().collect() }, &mut sink).await.expect("Withdrawal failed");
+    assert_eq!(state.get_authorisation(&player(1), &DIAMOND_NAME.to_owned()).map(|auth| auth.count), Some(0));
+}
+
+#[tokio::test]
+async fn authorisation_expiry() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::UpdateRestricted { restricted_assets: vec![DIAMOND_NAME.to_owned()], banker: PlayerId::the_bank() }, &mut sink).await.expect("Restricted update failed");
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 5, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Buy coins failed");
+    let assets: std::collections::HashMap<AssetId, u64> = [(DIAMOND_NAME.to_owned(), 1)].into_iter().collect();
+
+    // An id expiry covers the next action only
+    let last_id = state.get_next_id() + 1;
+    state.apply(Action::AuthoriseRestricted { authorisee: player(1), banker: PlayerId::the_bank(), asset: DIAMOND_NAME.to_owned(), new_count: 4, expiry: Some(AuthExpiry::Id(last_id)) }, &mut sink).await.expect("Authorisation failed");
+    state.apply(Action::WithdrawalRequested { player: player(1), assets: assets.clone() }, &mut sink).await.expect("Withdrawal within expiry failed");
+    assert_eq!(state.apply(Action::WithdrawalRequested { player: player(1), assets: assets.clone() }, &mut sink).await, Err(Error::UnauthorisedWithdrawal { asset: DIAMOND_NAME.to_owned(), amount_overdrawn: Some(1) }));
+
+    // A time expiry in the past doesn't count at all
+    let past = chrono::Utc::now() - chrono::Duration::days(1);
+    state.apply(Action::AuthoriseRestricted { authorisee: player(1), banker: PlayerId::the_bank(), asset: DIAMOND_NAME.to_owned(), new_count: 4, expiry: Some(AuthExpiry::Time(past)) }, &mut sink).await.expect("Authorisation failed");
+    state.apply(Action::WithdrawalRequested { player: player(1), assets: assets.clone() }, &mut sink).await.expect_err("Withdrew with an expired authorisation");
+
+    // Approving a request doesn't revive what was left of an expired authorisation
+    let request = state.apply(Action::RequestAuthorisation { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 1, reason: "Tools".to_owned() }, &mut sink).await.expect("Request failed").id;
+    state.apply(Action::ApproveAuthorisation { target: request, banker: PlayerId::the_bank(), expiry: None }, &mut sink).await.expect("Approval failed");
+    assert_eq!(state.get_authorisation(&player(1), &DIAMOND_NAME.to_owned()).map(|auth| auth.count), Some(1));
+    state.apply(Action::WithdrawalRequested { player: player(1), assets }, &mut sink).await.expect("Withdrawal after approval failed");
+}
+
+#[tokio::test]
+async fn delegated_authorisers() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+    let other_asset = "netherite_ingot".to_owned();
+
+    state.apply(Action::UpdateRestricted { restricted_assets: vec![DIAMOND_NAME.to_owned(), other_asset.clone()], banker: PlayerId::the_bank() }, &mut sink).await.expect("Restricted update failed");
+    state.apply(Action::DelegateAuthoriser { asset: DIAMOND_NAME.to_owned(), delegate: Some(player(5)), banker: player(5) }, &mut sink).await.expect_err("Non-banker delegated to themselves");
+    state.apply(Action::DelegateAuthoriser { asset: DIAMOND_NAME.to_owned(), delegate: Some(player(5)), banker: PlayerId::the_bank() }, &mut sink).await.expect("Delegation failed");
+    assert_eq!(state.get_delegated_authoriser(&DIAMOND_NAME.to_owned()), Some(player(5)));
+
+    // The delegate acts as themselves, but only for their item
+    assert_eq!(state.perms(&Action::AuthoriseRestricted { authorisee: player(1), banker: player(5), asset: DIAMOND_NAME.to_owned(), new_count: 3, expiry: None }), Ok(ActionPermissions { level: ActionLevel::Normal, player: player(5) }));
+    state.apply(Action::AuthoriseRestricted { authorisee: player(1), banker: player(5), asset: DIAMOND_NAME.to_owned(), new_count: 3, expiry: None }, &mut sink).await.expect("Delegated authorisation failed");
+    assert_eq!(state.apply(Action::AuthoriseRestricted { authorisee: player(1), banker: player(5), asset: other_asset.clone(), new_count: 3, expiry: None }, &mut sink).await, Err(Error::IsNotABanker { player: player(5) }));
+
+    let diamonds = state.apply(Action::RequestAuthorisation { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 2, reason: "Tools".to_owned() }, &mut sink).await.expect("Request failed").id;
+    let other = state.apply(Action::RequestAuthorisation { player: player(1), asset: other_asset, count: 2, reason: "Armour".to_owned() }, &mut sink).await.expect("Request failed").id;
+    state.apply(Action::DenyAuthorisation { target: other, banker: player(5) }, &mut sink).await.expect_err("Delegate denied a request for another item");
+    state.apply(Action::ApproveAuthorisation { target: diamonds, banker: player(5), expiry: None }, &mut sink).await.expect("Delegated approval failed");
+    assert_eq!(state.get_authorisation(&player(1), &DIAMOND_NAME.to_owned()).map(|auth| auth.count), Some(5));
+
+    state.apply(Action::DelegateAuthoriser { asset: DIAMOND_NAME.to_owned(), delegate: None, banker: PlayerId::the_bank() }, &mut sink).await.expect("Revocation failed");
+    state.apply(Action::AuthoriseRestricted { authorisee: player(1), banker: player(5), asset: DIAMOND_NAME.to_owned(), new_count: 3, expiry: None }, &mut sink).await.expect_err("Revoked delegate authorised");
+}
+
+#[tokio::test]
+async fn two_banker_approval() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::UpdateBankers { bankers: vec![player(8), player(9)], banker: PlayerId::the_bank() }, &mut sink).await.expect("Bankers update failed");
+    let policy = ApprovalPolicy { undeposit_over: Some(10), bank_prices: false, bankers: true, proposal_ttl_secs: None };
+    state.apply(Action::UpdateApprovalPolicy { policy: policy.clone(), banker: player(8) }, &mut sink).await.expect("Policy update failed");
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 64, banker: player(8), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+
+    // Small undeposits go through, large ones need a second banker
+    state.apply(Action::Undeposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: player(8), note: None, reference: None }, &mut sink).await.expect("Small undeposit failed");
+    let undeposit = Action::Undeposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 50, banker: player(8), note: None, reference: None };
+    assert_eq!(state.apply(undeposit.clone(), &mut sink).await, Err(Error::NeedsApproval));
+    let proposal = state.apply(Action::Propose { action: Box::new(undeposit), title: Some("Fix deposit".to_owned()), description: Some("Typed 64 instead of 14".to_owned()) }, &mut sink).await.expect("Proposal failed").id;
+    assert_eq!(state.get_proposal(proposal).expect("Proposal missing").title.as_deref(), Some("Fix deposit"));
+    assert_eq!(state.apply(Action::Agree { proposal_id: proposal, banker: player(8) }, &mut sink).await, Err(Error::OwnProposal { id: proposal }));
+    state.apply(Action::Agree { proposal_id: proposal, banker: player(9) }, &mut sink).await.expect("Agreement failed");
+    assert_eq!(state.get_assets(&player(1)).get(DIAMOND_NAME).cloned(), Some(4));
+    assert!(state.get_proposals().is_empty());
+
+    // A disagreement throws the proposal away
+    let untitled = Action::Propose { action: Box::new(Action::UpdateBankers { bankers: vec![player(8)], banker: player(8) }), title: None, description: None };
+    // Proposals without a title are written as they were before titles were added, so they read back the same either way
+    let json = serde_json::to_value(&untitled).expect("Unable to serialise proposal");
+    assert!(json["Propose"].get("title").is_none() && json["Propose"].get("description").is_none());
+    assert_eq!(serde_json::from_value::<Action>(json).expect("Unable to parse proposal"), untitled);
+    let proposal = state.apply(untitled, &mut sink).await.expect("Proposal failed").id;
+    state.apply(Action::Disagree { proposal_id: proposal, banker: player(9) }, &mut sink).await.expect("Disagreement failed");
+    assert_eq!(state.apply(Action::Agree { proposal_id: proposal, banker: player(9) }, &mut sink).await, Err(Error::InvalidId { id: proposal }));
+    assert!(state.is_banker(&player(9)));
+
+    // Only banker actions can be proposed, and the policy can't be turned off alone
+    assert_eq!(state.apply(Action::Propose { action: Box::new(Action::BuyCoins { player: player(1), n_diamonds: 1 }), title: None, description: None }, &mut sink).await, Err(Error::NotProposable));
+    assert_eq!(state.apply(Action::UpdateApprovalPolicy { policy: ApprovalPolicy::default(), banker: player(8) }, &mut sink).await, Err(Error::NeedsApproval));
+    assert_eq!(state.get_approval_policy(), policy);
+}
+
+#[tokio::test]
+async fn scheduled_approval() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+    let start = chrono::Utc::now();
+
+    state.apply(Action::UpdateBankers { bankers: vec![player(8), player(9)], banker: PlayerId::the_bank() }, &mut sink).await.expect("Bankers update failed");
+    let policy = ApprovalPolicy { undeposit_over: Some(10), ..Default::default() };
+    state.apply(Action::UpdateApprovalPolicy { policy, banker: player(8) }, &mut sink).await.expect("Policy update failed");
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 64, banker: player(8), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+
+    // Scheduling an action doesn't get it past the policy
+    let schedule = Action::Schedule {
+        at: start,
+        action: Box::new(Action::Undeposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 50, banker: player(8), note: None, reference: None })
+    };
+    assert_eq!(state.apply_with_time(schedule.clone(), start, &mut sink).await, Err(Error::NeedsApproval));
+    assert!(state.get_scheduled().is_empty());
+
+    // Once agreed to, it runs without needing agreeing to again
+    let proposal = state.apply(Action::Propose { action: Box::new(schedule), title: None, description: None }, &mut sink).await.expect("Proposal failed").id;
+    let scheduled = state.apply_with_time(Action::Agree { proposal_id: proposal, banker: player(9) }, start, &mut sink).await.expect("Agreement failed").id;
+    assert!(state.get_scheduled().get(&scheduled).expect("Schedule missing").approved);
+    state.apply_with_time(Action::RunScheduled { target: scheduled }, start, &mut sink).await.expect("Run failed");
+    assert_eq!(state.get_assets(&player(1)).get(DIAMOND_NAME).cloned(), Some(14));
+}
+
+#[tokio::test]
+async fn proposal_expiry() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::UpdateBankers { bankers: vec![player(8), player(9)], banker: PlayerId::the_bank() }, &mut sink).await.expect("Bankers update failed");
+    assert_eq!(state.apply(Action::PruneProposals { banker: player(8) }, &mut sink).await, Err(Error::AlreadyDone));
+    let proposal = state.apply(Action::Propose { action: Box::new(Action::UpdateBankers { bankers: vec![player(8)], banker: player(8) }), title: None, description: None }, &mut sink).await.expect("Proposal failed").id;
+    assert!(state.get_expired_proposals(chrono::Utc::now()).is_empty());
+
+    state.apply(Action::UpdateApprovalPolicy { policy: ApprovalPolicy { proposal_ttl_secs: Some(0), ..Default::default() }, banker: player(8) }, &mut sink).await.expect("Policy update failed");
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    assert_eq!(state.get_expired_proposals(chrono::Utc::now()), vec![proposal]);
+    assert_eq!(state.apply(Action::Agree { proposal_id: proposal, banker: player(9) }, &mut sink).await, Err(Error::ProposalExpired { id: proposal }));
+    state.apply(Action::PruneProposals { banker: player(9) }, &mut sink).await.expect("Pruning failed");
+    assert!(state.get_proposals().is_empty());
+    assert!(state.is_banker(&player(9)));
+}
+
+#[tokio::test]
+async fn retract_proposal() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::UpdateBankers { bankers: vec![player(8), player(9)], banker: PlayerId::the_bank() }, &mut sink).await.expect("Bankers update failed");
+    let proposal = state.apply(Action::Propose { action: Box::new(Action::UpdateBankers { bankers: vec![player(8)], banker: player(8) }), title: None, description: None }, &mut sink).await.expect("Proposal failed").id;
+    assert_eq!(state.apply(Action::RetractProposal { proposal_id: proposal, player: player(9) }, &mut sink).await, Err(Error::NotProposer { id: proposal }));
+    state.apply(Action::RetractProposal { proposal_id: proposal, player: player(8) }, &mut sink).await.expect("Retraction failed");
+    assert_eq!(state.apply(Action::Agree { proposal_id: proposal, banker: player(9) }, &mut sink).await, Err(Error::InvalidId { id: proposal }));
+    assert!(state.get_proposals().is_empty());
+}
+
+#[tokio::test]
+async fn asset_distribution() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 6, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: player(3), asset: DIAMOND_NAME.to_owned(), count: 3, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    // Locked assets still count towards the holder
+    state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 4, coins_per: Coins::from_coins(10), display_count: None }, &mut sink).await.expect("Sell order failed");
+    state.apply(Action::TransferAsset { payer: player(3), payee: player(2), asset: DIAMOND_NAME.to_owned(), count: 3 }, &mut sink).await.expect("Transfer failed");
+
+    let distribution = state.get_asset_distribution(&DIAMOND_NAME.to_owned());
+    assert_eq!(distribution.total, 19);
+    assert_eq!(distribution.holders, vec![(player(1), 10), (player(2), 9)]);
+    assert!(state.get_asset_distribution(&"netherite_ingot".to_owned()).holders.is_empty());
+    state.hard_audit();
+}
+
+#[tokio::test]
+async fn market_stats() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 4, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(2), n_diamonds: 4 }, &mut sink).await.expect("Buy coins failed");
+    assert_eq!(state.get_market_stats(&DIAMOND_NAME.to_owned(), chrono::Utc::now()), MarketStats::default());
+
+    state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 1, coins_per: Coins::from_coins(10), display_count: None }, &mut sink).await.expect("Sell order failed");
+    state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 3, coins_per: Coins::from_coins(30), display_count: None }, &mut sink).await.expect("Sell order failed");
+    state.apply(Action::BuyOrder { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 4, coins_per: Coins::from_coins(30), display_count: None }, &mut sink).await.expect("Buy order failed");
+
+    let stats = state.get_market_stats(&DIAMOND_NAME.to_owned(), chrono::Utc::now());
+    assert_eq!(stats.last_price, Some(Coins::from_coins(30)));
+    assert_eq!(stats.volume, 4);
+    assert_eq!(stats.notional, Coins::from_coins(100));
+    assert_eq!(stats.high, Some(Coins::from_coins(30)));
+    assert_eq!(stats.low, Some(Coins::from_coins(10)));
+    assert_eq!(stats.vwap, Some(Coins::from_coins(25)));
+
+    // Old matches fall out of the window, but the last price stays
+    let stats = state.get_market_stats(&DIAMOND_NAME.to_owned(), chrono::Utc::now() + chrono::Duration::days(2));
+    assert_eq!(stats, MarketStats { last_price: Some(Coins::from_coins(30)), ..Default::default() });
+}
+
+#[tokio::test]
+async fn candles() {
+    let mut state = State::new();
+    let mut log = Vec::new();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 8, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(2), n_diamonds: 8 }, &mut log).await.expect("Buy coins failed");
+    let start = chrono::DateTime::from_timestamp(3600, 0).expect("Invalid time");
+    let mut live = analytics::CandleAggregator::new(chrono::TimeDelta::minutes(1)).expect("Invalid interval");
+    for (minute, count, coins) in [(0, 2, 30), (0, 3, 25), (1, 1, 40)] {
+        let time = start + chrono::TimeDelta::minutes(minute);
+        state.apply_with_time(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count, coins_per: Coins::from_coins(coins), display_count: None }, time, &mut log).await.expect("Sell order failed");
+        let outcome = state.apply_with_time(Action::BuyOrder { player: player(2), asset: DIAMOND_NAME.to_owned(), count, coins_per: Coins::from_coins(coins), display_count: None }, time, &mut log).await.expect("Buy order failed");
+        live.observe(time, &outcome);
+    }
+
+    let candles = live.get_candles(&DIAMOND_NAME.to_owned());
+    assert_eq!(candles.len(), 2);
+    assert_eq!((candles[0].start, candles[0].volume, candles[0].close), (start, 5, Coins::from_coins(25)));
+    let hourly = live.rollup(&DIAMOND_NAME.to_owned(), chrono::TimeDelta::hours(1)).expect("Rollup failed");
+    assert_eq!(hourly, vec![analytics::Candle { start, open: Coins::from_coins(30), high: Coins::from_coins(40), low: Coins::from_coins(25), close: Coins::from_coins(40), volume: 6 }]);
+    assert_eq!(live.rollup(&DIAMOND_NAME.to_owned(), chrono::TimeDelta::seconds(90)), Err(Error::InvalidInterval));
+
+    // Replaying gives the same candles
+    let mut replayed = analytics::CandleAggregator::new(chrono::TimeDelta::minutes(1)).expect("Invalid interval");
+    State::new().replay_with(&mut log.as_slice(), |time, _, outcome| replayed.observe(time, outcome)).await.expect("Replay failed");
+    assert_eq!(replayed.get_candles(&DIAMOND_NAME.to_owned()), candles);
+}
+
+#[tokio::test]
+async fn fill_history() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 8, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(2), n_diamonds: 8 }, &mut sink).await.expect("Buy coins failed");
+    state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 5, coins_per: Coins::from_coins(20), display_count: None }, &mut sink).await.expect("Sell order failed");
+    let first = state.apply(Action::BuyOrder { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 2, coins_per: Coins::from_coins(20), display_count: None }, &mut sink).await.expect("Buy order failed").id;
+    let second = state.apply(Action::BuyOrder { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 3, coins_per: Coins::from_coins(20), display_count: None }, &mut sink).await.expect("Buy order failed").id;
+
+    let buyer_fills = state.get_fills(&player(2), 0);
+    assert_eq!(buyer_fills.iter().map(|fill| (fill.id, fill.count)).collect::<Vec<_>>(), vec![(first, 2), (second, 3)]);
+    assert_eq!(buyer_fills[0], PlayerFill { id: first, asset: DIAMOND_NAME.to_owned(), side: OrderType::Buy, count: 2, coins_per: Coins::from_coins(20), counterparty: player(1), fee: Coins::default() });
+    let seller_fills = state.get_fills(&player(1), first);
+    assert_eq!(seller_fills.len(), 1);
+    assert_eq!((seller_fills[0].side.clone(), seller_fills[0].counterparty.clone()), (OrderType::Sell, player(2)));
+    assert!(state.get_fills(&player(1), second).is_empty());
+}
+
+#[tokio::test]
+async fn depth() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 20, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 10 }, &mut sink).await.expect("Buy coins failed");
+    for (count, coins) in [(1, 10), (2, 11), (3, 12)] {
+        state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count, coins_per: Coins::from_coins(coins), display_count: None }, &mut sink).await.expect("Sell order failed");
+        state.apply(Action::BuyOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count, coins_per: Coins::from_coins(coins - 5), display_count: None }, &mut sink).await.expect("Buy order failed");
+    }
+
+    let (buys, sells) = state.get_depth(&DIAMOND_NAME.to_owned(), 2).expect("Depth failed");
+    assert_eq!(buys, vec![
+        DepthLevel { coins_per: Coins::from_coins(7), count: 3, cumulative_count: 3, cumulative_notional: Coins::from_coins(21) },
+        DepthLevel { coins_per: Coins::from_coins(6), count: 2, cumulative_count: 5, cumulative_notional: Coins::from_coins(33) },
+    ]);
+    assert_eq!(sells, vec![
+        DepthLevel { coins_per: Coins::from_coins(10), count: 1, cumulative_count: 1, cumulative_notional: Coins::from_coins(10) },
+        DepthLevel { coins_per: Coins::from_coins(11), count: 2, cumulative_count: 3, cumulative_notional: Coins::from_coins(32) },
+    ]);
+    assert_eq!(state.get_depth(&DIAMOND_NAME.to_owned(), 10).expect("Depth failed").1.len(), 3);
+}
+
+#[tokio::test]
+async fn bbo() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+    let diamond = DIAMOND_NAME.to_owned();
+
+    assert_eq!(state.get_bbo(&diamond), (None, None));
+    state.apply(Action::Deposit { player: player(1), asset: diamond.clone(), count: 20, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: player(2), asset: diamond.clone(), count: 20, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(2), n_diamonds: 20 }, &mut sink).await.expect("Buy coins failed");
+
+    let sell_id = state.apply(Action::SellOrder { player: player(1), asset: diamond.clone(), count: 5, coins_per: Coins::from_coins(10), display_count: None }, &mut sink).await.expect("Sell order failed").id;
+    state.apply(Action::SellOrder { player: player(1), asset: diamond.clone(), count: 5, coins_per: Coins::from_coins(12), display_count: None }, &mut sink).await.expect("Sell order failed");
+    state.apply(Action::BuyOrder { player: player(2), asset: diamond.clone(), count: 4, coins_per: Coins::from_coins(8), display_count: Some(1) }, &mut sink).await.expect("Buy order failed");
+    assert_eq!(state.get_bbo(&diamond), (Some((Coins::from_coins(8), 1)), Some((Coins::from_coins(10), 5))));
+
+    // Partially filling the best sell shrinks it
+    state.apply(Action::BuyOrder { player: player(2), asset: diamond.clone(), count: 2, coins_per: Coins::from_coins(10), display_count: None }, &mut sink).await.expect("Buy order failed");
+    assert_eq!(state.get_bbo(&diamond).1, Some((Coins::from_coins(10), 3)));
+
+    // Cancelling it moves the best sell up
+    state.apply(Action::CancelOrder { target: sell_id }, &mut sink).await.expect("Cancel failed");
+    assert_eq!(state.get_bbo(&diamond).1, Some((Coins::from_coins(12), 5)));
+
+    // Filling the iceberg's visible amount refills it from the hidden amount
+    state.apply(Action::SellOrder { player: player(1), asset: diamond.clone(), count: 2, coins_per: Coins::from_coins(8), display_count: None }, &mut sink).await.expect("Sell order failed");
+    assert_eq!(state.get_bbo(&diamond).0, Some((Coins::from_coins(8), 1)));
+    state.hard_audit();
+}
+
+#[tokio::test]
+async fn replay_until() {
+    let mut state = State::new();
+    let mut log = Vec::new();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 4 }, &mut log).await.expect("Buy coins failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 6 }, &mut log).await.expect("Buy coins failed");
+
+    let mut past = State::new();
+    past.replay_until(&mut log.as_slice(), 2).await.expect("Replay failed");
+    assert_eq!(past.get_next_id(), 3);
+    assert_eq!(past.get_bal(&player(1)), Coins::from_coins(4000));
+
+    // Carrying on from the earlier state skips what it has already seen
+    past.replay_until(&mut log.as_slice(), 3).await.expect("Replay failed");
+    assert_eq!(serde_json::to_value(&past).expect("Serialise failed"), serde_json::to_value(&state).expect("Serialise failed"));
+
+    // We can't go past the end of the log
+    assert_eq!(past.replay_until(&mut log.as_slice(), 4).await, Err(Error::InvalidId { id: 4 }));
+}
+
+#[tokio::test]
+async fn balance_history() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    let bought = state.apply(Action::BuyCoins { player: player(1), n_diamonds: 4 }, &mut sink).await.expect("Buy coins failed").id;
+    let transferred = state.apply(Action::TransferCoins { payer: player(1), payee: player(2), count: Coins::from_coins(1000) }, &mut sink).await.expect("Transfer failed").id;
+    // A failed action leaves no trace
+    state.apply(Action::TransferCoins { payer: player(1), payee: player(2), count: Coins::from_coins(100000) }, &mut sink).await.expect_err("Overdrawn transfer succeeded");
+    let sold = state.apply(Action::SellCoins { player: player(1), n_diamonds: 1 }, &mut sink).await.expect("Sell coins failed").id;
+
+    assert_eq!(state.get_balance_history(&player(1), 0, u64::MAX), vec![
+        BalanceCheckpoint { id: bought, balance: Coins::from_coins(4000) },
+        BalanceCheckpoint { id: transferred, balance: Coins::from_coins(3000) },
+        BalanceCheckpoint { id: sold, balance: Coins::from_coins(2000) },
+    ]);
+    // The balance going into the range is included
+    assert_eq!(state.get_balance_history(&player(1), transferred + 1, sold), vec![
+        BalanceCheckpoint { id: transferred, balance: Coins::from_coins(3000) },
+        BalanceCheckpoint { id: sold, balance: Coins::from_coins(2000) },
+    ]);
+    assert_eq!(state.get_balance_history(&player(2), 0, bought), vec![]);
+    assert_eq!(state.get_balance_history(&player(2), 0, transferred), vec![BalanceCheckpoint { id: transferred, balance: Coins::from_coins(1000) }]);
+}
+
+#[tokio::test]
+async fn statement() {
+    let mut state = State::new();
+    let mut log = Vec::new();
+    let diamond = DIAMOND_NAME.to_owned();
+    let start = chrono::DateTime::from_timestamp(3600, 0).expect("Invalid time");
+
+    state.apply_with_time(Action::Deposit { player: player(1), asset: diamond.clone(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, start, &mut log).await.expect("Deposit failed");
+    state.apply_with_time(Action::Deposit { player: player(2), asset: diamond.clone(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, start, &mut log).await.expect("Deposit failed");
+    state.apply_with_time(Action::BuyCoins { player: player(2), n_diamonds: 5 }, start, &mut log).await.expect("Buy coins failed");
+    state.apply_with_time(Action::SellOrder { player: player(1), asset: diamond.clone(), count: 2, coins_per: Coins::from_coins(1500), display_count: None }, start, &mut log).await.expect("Sell order failed");
+    let bought = state.apply_with_time(Action::BuyOrder { player: player(2), asset: diamond.clone(), count: 2, coins_per: Coins::from_coins(1500), display_count: None }, start, &mut log).await.expect("Buy order failed").id;
+    let transferred = state.apply_with_time(Action::TransferCoins { payer: player(1), payee: player(3), count: Coins::from_coins(500) }, start, &mut log).await.expect("Transfer failed").id;
+    let reversed = state.apply_with_time(Action::Reverse { target: transferred, reason: "Mistake".to_owned(), banker: PlayerId::the_bank() }, start, &mut log).await.expect("Reverse failed").id;
+    let withdrawn = state.apply_with_time(Action::WithdrawalRequested { player: player(1), assets: [(diamond.clone(), 8)].into_iter().collect() }, start, &mut log).await.expect("Withdrawal failed");
+
+    // Start after the deposits, which shouldn't show up
+    let mut builder = analytics::StatementBuilder::new(player(1));
+    let mut past = State::new();
+    past.replay_until(&mut log.as_slice(), 2).await.expect("Replay failed");
+    past.replay_until_with(&mut log.as_slice(), withdrawn.id, |time, action, outcome| builder.observe(time, action, outcome)).await.expect("Replay failed");
+
+    let entry = |id, kind| analytics::StatementEntry { id, time: start, kind, counterparty: None, asset: None, items_in: 0, items_out: 0, coins_in: Coins::default(), coins_out: Coins::default() };
+    assert_eq!(builder.entries(), &[
+        analytics::StatementEntry { counterparty: Some(player(2)), asset: Some(diamond.clone()), items_out: 2, coins_in: Coins::from_coins(3000), ..entry(bought, analytics::StatementKind::Sold) },
+        analytics::StatementEntry { counterparty: Some(player(3)), coins_out: Coins::from_coins(500), ..entry(transferred, analytics::StatementKind::TransferOut) },
+        analytics::StatementEntry { counterparty: Some(player(3)), coins_in: Coins::from_coins(500), ..entry(reversed, analytics::StatementKind::Reversal) },
+        analytics::StatementEntry { asset: Some(diamond.clone()), items_out: 8, ..entry(withdrawn.id, analytics::StatementKind::Withdrawal) },
+        analytics::StatementEntry { coins_out: withdrawn.fees_paid, ..entry(withdrawn.id, analytics::StatementKind::Fee) },
+    ]);
+    let csv = builder.to_csv();
+    assert_eq!(csv.lines().count(), 6);
+    assert_eq!(csv.lines().nth(1), Some(format!("{bought},{},Sold,{},diamond,0,2,3000.000,0.000", start.to_rfc3339(), player(2)).as_str()));
+}
+
+#[tokio::test]
+async fn reserves_report() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+    let diamond = DIAMOND_NAME.to_owned();
+    let cobblestone = "cobblestone".to_owned();
+
+    state.apply(Action::Deposit { player: player(1), asset: diamond.clone(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::Deposit { player: player(2), asset: cobblestone.clone(), count: 64, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 4 }, &mut sink).await.expect("Buy coins failed");
+    state.apply(Action::SellOrder { player: player(2), asset: cobblestone.clone(), count: 14, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await.expect("Sell order failed");
+
+    let time = chrono::DateTime::from_timestamp(3600, 0).expect("Invalid time");
+    let report = state.get_reserves_report(time);
+    assert_eq!(report.id, 4);
+    assert_eq!(report.liabilities, ReserveTotals { coins: Coins::from_coins(4000), assets: [(diamond.clone(), 6), (cobblestone.clone(), 64)].into_iter().collect() });
+    assert_eq!(report.claims[&player(1)], ReserveTotals { coins: Coins::from_coins(4000), assets: [(diamond.clone(), 6)].into_iter().collect() });
+    assert_eq!(report.claims[&player(2)], ReserveTotals { coins: Coins::default(), assets: [(cobblestone.clone(), 50)].into_iter().collect() });
+    // The cobblestone in the sell order isn't in anyone's balance
+    assert_eq!(report.locked, ReserveTotals { coins: Coins::default(), assets: [(cobblestone.clone(), 14)].into_iter().collect() });
+    assert!(report.etps.is_empty());
+}
+
+#[tokio::test]
+async fn itemised_audit() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+    let product = "diamond_etp".to_owned();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::DefineEtp { issuer: player(1), product: product.clone(), basket: [(DIAMOND_NAME.to_owned(), 2)].into_iter().collect() }, &mut sink).await.expect("Define failed");
+    state.apply(Action::IssueEtp { product: product.clone(), count: 5 }, &mut sink).await.expect("Issue failed");
+    state.apply(Action::TransferAsset { payer: player(1), payee: player(2), asset: product.clone(), count: 3 }, &mut sink).await.expect("Transfer failed");
+    state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 4, coins_per: Coins::from_coins(1000), display_count: None }, &mut sink).await.expect("Sell order failed");
+
+    let audit = state.get_itemised_audit();
+    assert_eq!(audit.free.assets, [(DIAMOND_NAME.to_owned(), 6), (product.clone(), 5)].into_iter().collect());
+    assert_eq!(audit.order.assets, [(DIAMOND_NAME.to_owned(), 4)].into_iter().collect());
+    assert_eq!(audit.locked, audit.order);
+    assert_eq!(audit.free.clone() + audit.locked.clone(), state.hard_audit());
+    // The 3 units held by player 2 need 6 diamonds behind them
+    assert_eq!(audit.etp_backing[&product].assets, [(DIAMOND_NAME.to_owned(), 6)].into_iter().collect());
+}
+
+#[tokio::test]
+async fn running_audit() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 4 }, &mut sink).await.expect("Buy coins failed");
+    // A failed action leaves the running audit alone
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 40 }, &mut sink).await.expect_err("Overdrawn buy coins succeeded");
+    state.apply(Action::BuyOrder { player: player(1), asset: "cobblestone".to_owned(), count: 10, coins_per: Coins::from_coins(10), display_count: None }, &mut sink).await.expect("Buy order failed");
+    state.apply(Action::WithdrawalRequested { player: player(1), assets: [(DIAMOND_NAME.to_owned(), 2)].into_iter().collect() }, &mut sink).await.expect("Withdrawal failed");
+
+    assert_eq!(state.soft_audit(), state.hard_audit());
+    assert_eq!(state.soft_audit(), Audit { coins: Coins::from_coins(4000), assets: [(DIAMOND_NAME.to_owned(), 6)].into_iter().collect() });
+}
+
+#[tokio::test]
+async fn signed_actions() {
+    use base64::prelude::*;
+    use ring::signature::KeyPair;
+
+    let mut state = State::new();
+    let mut log = Vec::new();
+    let rng = ring::rand::SystemRandom::new();
+    let keys: Vec<_> = (0..2).map(|_| {
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).expect("Key generation failed");
+        ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).expect("Key parsing failed")
+    }).collect();
+    let sign = |key: &ring::signature::Ed25519KeyPair, id: u64, action: &Action| {
+        let payload = serde_json::to_string(&SignedAction { id, action: action.clone() }).expect("Serialise failed");
+        ActionSignature {
+            signature: BASE64_STANDARD.encode(key.sign(payload.as_bytes())),
+            public_key: BASE64_STANDARD.encode(key.public_key()),
+            payload
+        }
+    };
+
+    assert_eq!(state.apply(Action::SetSigningKey { player: player(1), public_key: Some("bm90IGEga2V5".to_owned()) }, &mut log).await, Err(Error::InvalidSigningKey));
+    let public_key = BASE64_STANDARD.encode(keys[0].public_key());
+    state.apply(Action::SetSigningKey { player: player(1), public_key: Some(public_key.clone()) }, &mut log).await.expect("Set key failed");
+    assert_eq!(state.get_signing_key(&player(1)), Some(public_key));
+
+    let action = Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None };
+    let transfer = Action::TransferCoins { payer: player(1), payee: player(2), count: Coins::from_coins(1) };
+    // Only the acting player's key will do
+    let next = state.get_next_id();
+    assert_eq!(state.apply_signed(action.clone(), sign(&keys[0], next, &action), chrono::Utc::now(), &mut log).await, Err(Error::InvalidSignature));
+    assert_eq!(state.apply_signed(transfer.clone(), sign(&keys[1], next, &transfer), chrono::Utc::now(), &mut log).await, Err(Error::InvalidSignature));
+    // The signature must cover this action
+    assert_eq!(state.apply_signed(transfer.clone(), sign(&keys[0], next, &action), chrono::Utc::now(), &mut log).await, Err(Error::InvalidSignature));
+    state.apply(action, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 1 }, &mut log).await.expect("Buy coins failed");
+    // ... at the id it was signed for
+    assert_eq!(state.apply_signed(transfer.clone(), sign(&keys[0], next, &transfer), chrono::Utc::now(), &mut log).await, Err(Error::InvalidSignature));
+    let signed = sign(&keys[0], state.get_next_id(), &transfer);
+    state.apply_signed(transfer.clone(), signed.clone(), chrono::Utc::now(), &mut log).await.expect("Signed transfer failed");
+    // Once it has been published, it can't be submitted again
+    assert_eq!(state.apply_signed(transfer.clone(), signed, chrono::Utc::now(), &mut log).await, Err(Error::InvalidSignature));
+    assert_eq!(state.get_bal(&player(2)), Coins::from_coins(1));
+
+    // Replay checks the signatures too
+    State::new().replay(&mut log.as_slice()).await.expect("Replay failed");
+    let tampered = String::from_utf8(log).expect("Log not UTF-8").replace("\"payee\":\"2\"", "\"payee\":\"3\"");
+    assert_eq!(State::new().replay(&mut tampered.as_bytes()).await, Err(Error::InvalidSignature));
+}
+
+#[tokio::test]
+async fn binary_log() {
+    let mut state = State::new();
+    state.set_log_format(LogFormat::Binary).expect("Set format failed");
+    let mut log = Vec::new();
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 4 }, &mut log).await.expect("Buy coins failed");
+    assert_eq!(state.set_log_format(LogFormat::JsonLines), Err(Error::AlreadyDone));
+    assert!(log.starts_with(BINARY_HEADER));
+
+    // Replay works out the format by itself, and carries on writing in it
+    let mut replayed = State::new();
+    replayed.replay(&mut log.as_slice()).await.expect("Replay failed");
+    assert_eq!(replayed.get_log_format(), LogFormat::Binary);
+    assert_eq!(replayed.get_bal(&player(1)), state.get_bal(&player(1)));
+    replayed.apply(Action::SellCoins { player: player(1), n_diamonds: 1 }, &mut log).await.expect("Sell coins failed");
+    assert_eq!(log.windows(BINARY_HEADER.len()).filter(|window| *window == BINARY_HEADER).count(), 1);
+
+    // Converting to JSON lines and back gives the same file
+    let mut json = Vec::new();
+    convert_log(&mut log.as_slice(), &mut json, LogFormat::JsonLines).await.expect("Convert failed");
+    assert_eq!(String::from_utf8(json.clone()).expect("Log not UTF-8").lines().count(), 3);
+    let mut from_json = State::new();
+    from_json.replay(&mut json.as_slice()).await.expect("Replay failed");
+    assert_eq!(from_json.get_log_format(), LogFormat::JsonLines);
+    assert_eq!(from_json.get_bal(&player(1)), replayed.get_bal(&player(1)));
+    let mut binary = Vec::new();
+    convert_log(&mut json.as_slice(), &mut binary, LogFormat::Binary).await.expect("Convert failed");
+    assert_eq!(binary, log);
+}
+
+#[tokio::test]
+async fn log_versions() {
+    // Actions from before versioning, without the fields added since, and with the legacy src/trade.rs millicoin amounts
+    let legacy = concat!(
+        r#"{"id":1,"time":"2024-01-01T00:00:00Z","action":{"Deposit":{"player":"1","asset":"diamond","count":10,"banker":"bank"}}}"#, "\n",
+        r#"{"id":2,"time":"2024-01-01T00:00:00Z","action":{"SellOrder":{"player":"1","asset":"diamond","count":5,"coins_per":"1.000"}}}"#, "\n",
+        r#"{"id":3,"time":"2024-01-01T00:00:00Z","action":{"BuyCoins":{"player":"1","n_diamonds":1}}}"#, "\n",
+        r#"{"id":4,"time":"2024-01-01T00:00:00Z","action":{"TransferCoins":{"payer":"1","payee":"2","count":1500}}}"#, "\n"
+    );
+    let mut state = State::new();
+    state.replay(&mut legacy.as_bytes()).await.expect("Legacy replay failed");
+    assert_eq!(state.get_assets(&player(1)).get(DIAMOND_NAME), Some(&4));
+    assert_eq!(state.get_bal(&player(2)), Coins::from_millicoins(1500));
+
+    // Migrating writes the current version down, without changing what the actions do
+    let mut migrated = Vec::new();
+    migrate::migrate_log(&mut legacy.as_bytes(), &mut migrated).await.expect("Migrate failed");
+    let migrated = String::from_utf8(migrated).expect("Log not UTF-8");
+    assert_eq!(migrated.matches(&format!("\"version\":{}", migrate::CURRENT_VERSION)).count(), 4);
+    assert!(migrated.contains(r#""count":"1.5c""#));
+    let mut from_migrated = State::new();
+    from_migrated.replay(&mut migrated.as_bytes()).await.expect("Migrated replay failed");
+    assert_eq!(from_migrated.get_assets(&player(1)), state.get_assets(&player(1)));
+    assert_eq!(from_migrated.get_bal(&player(2)), state.get_bal(&player(2)));
+
+    // Logs from the future are refused rather than misread
+    let future = migrated.replacen(&format!("\"version\":{}", migrate::CURRENT_VERSION), "\"version\":99", 1);
+    assert_eq!(State::new().replay(&mut future.as_bytes()).await, Err(Error::UnsupportedVersion { version: Some(99) }));
+}
+
+#[tokio::test]
+async fn snapshots() {
+    let mut state = State::new();
+    let mut log = Vec::new();
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 4 }, &mut log).await.expect("Buy coins failed");
+    state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 3, coins_per: Coins::from_coins(2000), display_count: None }, &mut log).await.expect("Sell order failed");
+    let saved = serde_json::to_string(&state.snapshot()).expect("Serialise failed");
+    state.apply(Action::BuyOrder { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 1, coins_per: Coins::from_coins(2000), display_count: None }, &mut log).await.expect_err("Unfunded buy order succeeded");
+    state.apply(Action::TransferCoins { payer: player(1), payee: player(2), count: Coins::from_coins(3000) }, &mut log).await.expect("Transfer failed");
+    state.apply(Action::BuyOrder { player: player(2), asset: DIAMOND_NAME.to_owned(), count: 1, coins_per: Coins::from_coins(2000), display_count: None }, &mut log).await.expect("Buy order failed");
+
+    // Carrying on from the snapshot ends up in the same place as replaying everything
+    let snapshot: Snapshot = serde_json::from_str(&saved).expect("Deserialise failed");
+    assert_eq!(snapshot.get_next_id(), 4);
+    let mut resumed = State::from_snapshot(snapshot.clone()).expect("Load failed");
+    resumed.replay(&mut log.as_slice()).await.expect("Resume failed");
+    let mut replayed = State::new();
+    replayed.replay(&mut log.as_slice()).await.expect("Replay failed");
+    assert_eq!(serde_json::to_value(&resumed).expect("Serialise failed"), serde_json::to_value(&replayed).expect("Serialise failed"));
+    assert_eq!(serde_json::to_value(&resumed).expect("Serialise failed"), serde_json::to_value(&state).expect("Serialise failed"));
+    assert_eq!(resumed.get_depth(&DIAMOND_NAME.to_owned(), 10), state.get_depth(&DIAMOND_NAME.to_owned(), 10));
+
+    // The snapshot won't carry on from a trade file it didn't come from
+    let tampered = String::from_utf8(log.clone()).expect("Log not UTF-8").replacen("\"count\":10", "\"count\":11", 1);
+    assert_eq!(State::from_snapshot(snapshot.clone()).expect("Load failed").replay(&mut tampered.as_bytes()).await, Err(Error::SnapshotMismatch));
+    // Nor from one missing actions it has applied
+    let first_line = log.iter().position(|byte| *byte == b'\n').expect("Log empty") + 1;
+    assert_eq!(State::from_snapshot(snapshot.clone()).expect("Load failed").replay(&mut &log[..first_line]).await, Err(Error::SnapshotMismatch));
+
+    // Following on with just the rest of the log works too, as when mirroring a remote
+    let rest = String::from_utf8(log).expect("Log not UTF-8").lines().skip(3).map(|line| format!("{line}\n")).collect::<String>();
+    let mut followed = State::from_snapshot(snapshot).expect("Load failed");
+    followed.replay(&mut rest.as_bytes()).await.expect("Follow failed");
+    followed.replay(&mut "".as_bytes()).await.expect("Empty follow failed");
+    assert_eq!(serde_json::to_value(&followed).expect("Serialise failed"), serde_json::to_value(&state).expect("Serialise failed"));
+}
+
+#[tokio::test]
+async fn snapshot_map_order() {
+    let mut state = State::new();
+    let mut log = Vec::new();
+    let assets: std::collections::HashMap<AssetId, u64> = ["stone", "granite", "diorite", "andesite", "deepslate", "calcite", "tuff", "cobblestone"]
+        .into_iter().map(|asset| (asset.to_owned(), 1)).collect();
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    state.apply(Action::BuyCoins { player: player(1), n_diamonds: 10 }, &mut log).await.expect("Buy coins failed");
+    for asset in assets.keys() {
+        state.apply(Action::Deposit { player: player(1), asset: asset.clone(), count: 1, banker: PlayerId::the_bank(), note: None, reference: None }, &mut log).await.expect("Deposit failed");
+    }
+    state.apply(Action::WithdrawalRequested { player: player(1), assets }, &mut log).await.expect("Withdrawal failed");
+
+    // Maps come back in a different order each time they are read, which mustn't change the digest
+    for _ in 0..10 {
+        let snapshot: Snapshot = serde_json::from_str(&serde_json::to_string(&state.snapshot()).expect("Serialise failed")).expect("Deserialise failed");
+        State::from_snapshot(snapshot).expect("Load failed").replay(&mut log.as_slice()).await.expect("Resume failed");
+        let mut replayed = State::new();
+        replayed.replay(&mut log.as_slice()).await.expect("Replay failed");
+        assert_eq!(replayed.get_log_digest(), state.get_log_digest());
+    }
+}
+
+#[tokio::test]
+async fn involved_players() {
+    let mut state = State::new();
+    let mut sink = WriteSink::default();
+    state.apply(Action::Deposit { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, &mut sink).await.expect("Deposit failed");
+    let sell = state.apply(Action::SellOrder { player: player(1), asset: DIAMOND_NAME.to_owned(), count: 5, coins_per: Coins::from_coins(1), display_count: None }, &mut sink).await.expect("Sell order failed");
+
+    assert_eq!(state.get_involved(&Action::TransferCoins { payer: player(1), payee: player(2), count: Coins::from_coins(1) }), [player(1), player(2)].into());
+    assert_eq!(state.get_involved(&Action::FreezeAccount { player: player(1), reason: "test".to_owned(), banker: PlayerId::the_bank() }), [player(1), PlayerId::the_bank()].into());
+    // Actions that only name another action involve whoever would act
+    assert_eq!(state.get_involved(&Action::CancelOrder { target: sell.id }), [player(1)].into());
+    assert_eq!(state.get_involved(&Action::Propose { action: Box::new(Action::UnfreezeAccount { player: player(2), banker: player(3) }), title: None, description: None }), [player(2), player(3)].into());
+
+    assert_eq!(state.get_involved_assets(&Action::CancelOrder { target: sell.id }), [DIAMOND_NAME.to_owned()].into());
+    assert_eq!(state.get_involved_assets(&Action::TransferAsset { payer: player(1), payee: player(2), asset: "cobblestone".to_owned(), count: 1 }), ["cobblestone".to_owned()].into());
+    assert_eq!(state.get_involved_assets(&Action::WithdrawalRequested { player: player(1), assets: [("cobblestone".to_owned(), 1), ("stone".to_owned(), 2)].into() }), ["cobblestone".to_owned(), "stone".to_owned()].into());
+    assert!(state.get_involved_assets(&Action::TransferCoins { payer: player(1), payee: player(2), count: Coins::from_coins(1) }).is_empty());
+}
+
+#[tokio::test]
+async fn events() {
+    use analytics::{Event, EventKind, EventLog};
+
+    let mut state = State::new();
+    let mut log = Vec::new();
+    let diamond = DIAMOND_NAME.to_owned();
+    let start = chrono::DateTime::from_timestamp(3600, 0).expect("Invalid time");
+
+    state.apply_with_time(Action::Deposit { player: player(1), asset: diamond.clone(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, start, &mut log).await.expect("Deposit failed");
+    state.apply_with_time(Action::Deposit { player: player(2), asset: diamond.clone(), count: 10, banker: PlayerId::the_bank(), note: None, reference: None }, start, &mut log).await.expect("Deposit failed");
+    state.apply_with_time(Action::BuyCoins { player: player(2), n_diamonds: 5 }, start, &mut log).await.expect("Buy coins failed");
+    let sold = state.apply_with_time(Action::SellOrder { player: player(1), asset: diamond.clone(), count: 4, coins_per: Coins::from_coins(1500), display_count: None }, start, &mut log).await.expect("Sell order failed").id;
+    let bought = state.apply_with_time(Action::BuyOrder { player: player(2), asset: diamond.clone(), count: 2, coins_per: Coins::from_coins(1500), display_count: None }, start, &mut log).await.expect("Buy order failed").id;
+    // Running a scheduled action gives the events of the action itself
+    let scheduled = state.apply_with_time(Action::Schedule { at: start, action: Box::new(Action::CancelOrder { target: sold }) }, start, &mut log).await.expect("Schedule failed").id;
+    let run = state.apply_with_time(Action::RunScheduled { target: scheduled }, start, &mut log).await.expect("Run failed").id;
+    let withdrawal = state.apply_with_time(Action::WithdrawalRequested { player: player(1), assets: [(diamond.clone(), 1)].into_iter().collect() }, start, &mut log).await.expect("Withdrawal failed").id;
+    let completed = state.apply_with_time(Action::WithdrawalCompleted { target: withdrawal, banker: PlayerId::the_bank() }, start, &mut log).await.expect("Completion failed").id;
+
+    let mut events = EventLog::default();
+    State::new().replay_with(&mut log.as_slice(), |time, action, outcome| events.observe(time, action, outcome)).await.expect("Replay failed");
+    let expected = [
+        Event { id: bought, time: start, kind: EventKind::OrderFilled { resting_order: sold, asset: diamond.clone(), buyer: player(2), seller: player(1), count: 2, coins_per: Coins::from_coins(1500) } },
+        Event { id: run, time: start, kind: EventKind::OrderCancelled { order_id: sold } },
+        Event { id: completed, time: start, kind: EventKind::WithdrawalReady { withdrawal_id: withdrawal } }
+    ];
+    assert_eq!(events.get_events(1), expected);
+    assert_eq!(events.get_events(bought + 1), expected[1..]);
+    assert_eq!(events.get_events(completed + 1), []);
+}
@@ -1,17 +1,41 @@
+use serde::{Deserialize, Serialize};
+
 use crate::Coins;
 
-use super::{AssetId, Audit, Auditable, Error, PlayerId};
+use super::{AssetId, AssetInfo, Audit, Auditable, Error, PlayerId};
+
+/// Calculate the fee for withdrawing assets: a flat fee, plus a fee for every stack (or part of a stack) of each asset
+pub fn calc_withdrawal_fee(
+    withdraw_flat: Coins,
+    withdraw_per_stack: Coins,
+    asset_info: &std::collections::HashMap<AssetId, AssetInfo>,
+    assets: &std::collections::HashMap<AssetId, u64>
+) -> Result<Coins, Error> {
+    let mut total_fee = withdraw_flat;
+    for (asset, count) in assets {
+        let stack_size = asset_info.get(asset).ok_or(Error::UnknownAsset{asset:asset.clone()})?.stack_size;
+        total_fee.checked_add_assign(withdraw_per_stack.checked_mul(count.div_ceil(stack_size))?)?
+    }
+    Ok(total_fee)
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PendingWithdrawal {
     pub id: u64,
     pub player: PlayerId,
     pub assets: std::collections::HashMap<AssetId, u64>,
     pub expedited: bool,
-    pub total_fee: Coins
+    /// All the fees paid, including the expedite fee
+    pub total_fee: Coins,
+    /// The part of the total fee paid to expedite the withdrawal
+    pub expedite_fee: Coins,
+    /// The banker who has said they are gathering the items, if any
+    pub assignee: Option<PlayerId>,
+    /// When the player asked for the withdrawal
+    pub requested: chrono::DateTime<chrono::Utc>
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct WithdrawalTracker {
     pending_normal_withdrawals: std::collections::BTreeMap<u64, PendingWithdrawal>,
     pending_expedited_withdrawals: std::collections::BTreeMap<u64, PendingWithdrawal>,
@@ -37,8 +61,34 @@ impl WithdrawalTracker {
     pub fn get_next_withdrawal(&self) -> Option<PendingWithdrawal> {
         self.pending_expedited_withdrawals.values().next().or_else(|| self.pending_normal_withdrawals.values().next()).cloned()
     }
-    pub fn track_withdrawal(&mut self, id: u64, player: PlayerId, assets: std::collections::HashMap<AssetId, u64>, total_fee: Coins) {
-        self.pending_normal_withdrawals.insert(id, PendingWithdrawal{ id, player, assets: assets.clone(), expedited: false, total_fee });
+    /// List all withdrawals that no banker has claimed
+    pub fn get_unclaimed_withdrawals(&self) -> std::collections::BTreeMap<u64, PendingWithdrawal> {
+        let mut ret = self.get_withdrawals();
+        ret.retain(|_, withdrawal| withdrawal.assignee.is_none());
+        ret
+    }
+    /// Get the next withdrawal that the given banker should complete, skipping those claimed by other bankers
+    pub fn get_next_withdrawal_for(&self, banker: &PlayerId) -> Option<PendingWithdrawal> {
+        let available = |withdrawal: &&PendingWithdrawal| withdrawal.assignee.as_ref().is_none_or(|assignee| assignee == banker);
+        self.pending_expedited_withdrawals.values().find(available)
+        .or_else(|| self.pending_normal_withdrawals.values().find(available)).cloned()
+    }
+    /// List the unclaimed withdrawals requested before the given time
+    pub fn get_requested_before(&self, cutoff: chrono::DateTime<chrono::Utc>) -> Vec<u64> {
+        self.pending_normal_withdrawals.values().chain(self.pending_expedited_withdrawals.values())
+        .filter(|withdrawal| withdrawal.assignee.is_none() && withdrawal.requested < cutoff)
+        .map(|withdrawal| withdrawal.id)
+        .collect()
+    }
+    /// Mark a withdrawal as being gathered by a banker, or unmark it with None
+    pub fn set_assignee(&mut self, id: u64, assignee: Option<PlayerId>) -> Result<(), Error> {
+        let Some(withdrawal) = self.pending_normal_withdrawals.get_mut(&id).or_else(|| self.pending_expedited_withdrawals.get_mut(&id))
+        else { return Err(Error::InvalidId { id }); };
+        withdrawal.assignee = assignee;
+        Ok(())
+    }
+    pub fn track_withdrawal(&mut self, id: u64, player: PlayerId, assets: std::collections::HashMap<AssetId, u64>, total_fee: Coins, requested: chrono::DateTime<chrono::Utc>) {
+        self.pending_normal_withdrawals.insert(id, PendingWithdrawal{ id, player, assets: assets.clone(), expedited: false, total_fee, expedite_fee: Coins::default(), assignee: None, requested });
         self.current_audit += Audit{coins: total_fee, assets}
     }
     pub fn expedite(&mut self, id: u64, fee: Coins) -> Result<(), Error> {
@@ -50,11 +100,28 @@ impl WithdrawalTracker {
         // Give them the expedited flag, and track the money
         entry.expedited = true;
         entry.total_fee.checked_add_assign(fee).expect("Withdraw fee overflow");
+        entry.expedite_fee = fee;
         self.current_audit.add_coins(fee);
         // Insert them into the expedited list
         self.pending_expedited_withdrawals.insert(id, entry);
         Ok(())
     }
+    /// Hand one player's withdrawals over to another player
+    pub fn migrate(&mut self, from: &PlayerId, to: &PlayerId) {
+        for withdrawal in self.pending_normal_withdrawals.values_mut().chain(self.pending_expedited_withdrawals.values_mut()) {
+            if &withdrawal.player == from {
+                withdrawal.player = to.clone();
+            }
+            if withdrawal.assignee.as_ref() == Some(from) {
+                withdrawal.assignee = Some(to.clone());
+            }
+        }
+    }
+    /// Stop tracking a withdrawal that won't happen, so that everything can be refunded
+    pub fn cancel(&mut self, id: u64) -> Result<PendingWithdrawal, Error> {
+        // As far as we're concerned, this is the same as it being taken out
+        self.complete(id)
+    }
     pub fn complete(&mut self, id: u64) -> Result<PendingWithdrawal, Error> {
         // Try to take out the pending transaction
         let Some(res) = self.pending_normal_withdrawals.remove(&id).or_else(|| self.pending_expedited_withdrawals.remove(&id))
@@ -71,7 +138,7 @@ impl WithdrawalTracker {
 impl Auditable for WithdrawalTracker {
     fn soft_audit(&self) -> Audit { self.current_audit.clone() }
 
-    fn hard_audit(&self) -> Audit {
+    fn try_hard_audit(&self) -> Result<Audit, String> {
         let mut new_audit = Audit::default();
         for withdrawal in self.pending_normal_withdrawals.values().chain(self.pending_expedited_withdrawals.values()) {
             for (asset, count) in &withdrawal.assets {
@@ -80,8 +147,8 @@ impl Auditable for WithdrawalTracker {
             new_audit.add_coins(withdrawal.total_fee);
         }
         if new_audit != self.current_audit {
-            panic!("Recalculated withdrawal audit differs from soft audit");
+            return Err("Recalculated withdrawal audit differs from soft audit".to_owned());
         }
-        new_audit
+        Ok(new_audit)
     }
 }
@@ -0,0 +1,116 @@
+use std::io::Write;
+
+use itertools::Itertools;
+use poise::{serenity_prelude::{self as serenity, CreateEmbed}, CreateReply};
+use tpex::{ApplyOutcome, Coins};
+
+use crate::db::AlertDirection;
+use super::{Context, Data, Error};
+
+/// The most alerts one user can have waiting at once
+const MAX_ALERTS: usize = 25;
+
+// Commands that handle price alerts
+#[poise::command(slash_command, ephemeral, subcommands("add", "list", "remove"))]
+pub async fn alert(_ctx: Context<'_>) -> Result<(), Error> { panic!("alert metacommand called!"); }
+
+/// Get a DM when an item trades above or below a price
+#[poise::command(slash_command, ephemeral)]
+async fn add(ctx: Context<'_>,
+    #[description = "The item you want to watch"]
+    asset: String,
+    #[description = "Whether to tell you when it trades above or below the price"]
+    direction: AlertDirection,
+    #[description = "The Coin(s) per item to watch for"]
+    coins_per: String
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let coins_per: Coins = coins_per.parse()?;
+    let user = ctx.author().id;
+    if ctx.data().db.get_alerts(user).await?.len() >= MAX_ALERTS {
+        ctx.reply(format!("You can only have {MAX_ALERTS} alerts at once. Remove some first?")).await?;
+        return Ok(());
+    }
+    let id = ctx.data().db.add_alert(user, &asset, direction, coins_per).await?;
+    ctx.reply(format!("You will be sent a DM when {asset} trades {direction} {coins_per} (alert ID no. {id})")).await?;
+    Ok(())
+}
+
+/// Lists your price alerts
+#[poise::command(slash_command, ephemeral)]
+async fn list(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let alerts = ctx.data().db.get_alerts(ctx.author().id).await?;
+    if alerts.is_empty() {
+        ctx.reply("You have no price alerts").await?;
+        return Ok(());
+    }
+    ctx.send(CreateReply::default()
+        .embed(CreateEmbed::new()
+            .field("ID", alerts.iter().map(|alert| alert.id).join("\n"), true)
+            .field("Item", alerts.iter().map(|alert| &alert.asset).join("\n"), true)
+            .field("When", alerts.iter().map(|alert| format!("{} {}", alert.direction, alert.coins_per)).join("\n"), true)
+        )
+    ).await?;
+    Ok(())
+}
+
+/// Removes a price alert
+#[poise::command(slash_command, ephemeral)]
+async fn remove(ctx: Context<'_>,
+    #[description = "The id for the alert"]
+    id: i64
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if ctx.data().db.remove_alert(ctx.author().id, id).await? {
+        ctx.reply("Alert removed").await?;
+    }
+    else {
+        ctx.reply("You have no alert with that id. Recheck the id?").await?;
+    }
+    Ok(())
+}
+
+/// DM the owners of any alerts set off by an action's trades, removing the alerts as they go off
+async fn check_alerts(data: &Data, http: &serenity::Http, outcome: &ApplyOutcome) -> Result<(), Error> {
+    let Some(asset) = &outcome.asset
+    else { return Ok(()); };
+    if outcome.fills.is_empty() { return Ok(()); }
+
+    for alert in data.db.get_asset_alerts(asset).await? {
+        let Some(fill) = outcome.fills.iter().find(|fill| alert.direction.triggered_by(fill.coins_per, alert.coins_per))
+        else { continue; };
+        // Only tell them once, even if something else removed it first
+        if !data.db.remove_alert(alert.user, alert.id).await? { continue; }
+        let message = serenity::CreateMessage::new()
+            .content(format!("{asset} just traded at {}, which is {} your alert at {} (ID no. {})", fill.coins_per, alert.direction, alert.coins_per, alert.id));
+        if let Err(err) = alert.user.direct_message(http, message).await {
+            let _ = writeln!(std::io::stderr(), "Could not send alert {} to {}: {err}", alert.id, alert.user);
+        }
+    }
+    Ok(())
+}
+
+/// Check price alerts after each action the mirror applies
+///
+/// This runs for as long as the bot does
+pub async fn watch_alerts(data: std::sync::Arc<Data>, http: std::sync::Arc<serenity::Http>) {
+    let mut outcomes = {
+        // Catch up first, so that old trades don't set off new alerts
+        let _state = data.sync().await;
+        data.stream()
+    };
+    loop {
+        let outcome = match outcomes.recv().await {
+            Ok((_, outcome)) => outcome,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                let _ = writeln!(std::io::stderr(), "Fell behind checking price alerts, skipping {missed} actions");
+                continue;
+            },
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return
+        };
+        if let Err(err) = check_alerts(&data, &http, &outcome).await {
+            let _ = writeln!(std::io::stderr(), "Could not check price alerts: {err}");
+        }
+    }
+}
@@ -8,7 +8,7 @@ use tpex::{Action, PlayerId};
 
 use super::{player_id, Context, Error};
 // Commands that handle withdrawals
-#[poise::command(slash_command, ephemeral, subcommands("raw", "deposit", "complete", "current", "authorise", "undeposit"), check = check)]
+#[poise::command(slash_command, ephemeral, subcommands("raw", "deposit", "complete", "claim", "current", "authorise", "undeposit", "halt", "resume"), check = check)]
 pub async fn banker(_ctx: Context<'_>) -> Result<(), Error> { panic!("Banker metacommand called."); }
 
 pub async fn check(ctx: Context<'_>) -> Result<bool, Error> {
@@ -41,6 +41,7 @@ pub async fn raw(
 
 /// Mark resources as deposited for a user
 #[poise::command(slash_command,ephemeral, check = check)]
+#[allow(clippy::too_many_arguments)]
 pub async fn deposit(
     ctx: Context<'_>,
     #[description = "The depositing user"]
@@ -52,7 +53,11 @@ pub async fn deposit(
     #[description = "The amount of that asset to be deposited"]
     count: u64,
     #[description = "The amount of that asset to be deposited, again"]
-    count_again: u64
+    count_again: u64,
+    #[description = "A note to keep with the deposit"]
+    note: Option<String>,
+    #[description = "Where the deposit can be checked, e.g. chest coordinates or a screenshot"]
+    reference: Option<String>
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     if asset != asset_again || count != count_again {
@@ -63,7 +68,7 @@ pub async fn deposit(
     let banker = player_id(ctx.author());
     let response = format!("Deposited {count} {asset} for {player}.");
 
-    ctx.data().apply(Action::Deposit { player: player.clone(), asset: asset.clone(), count, banker }).await?;
+    ctx.data().apply(Action::Deposit { player: player.clone(), asset: asset.clone(), count, banker, note, reference }).await?;
 
     if asset == tpex::DIAMOND_NAME {
         ctx.data().apply(Action::BuyCoins { player, n_diamonds: count }).await?;
@@ -73,6 +78,7 @@ pub async fn deposit(
 }
 /// Mark resources as deposited for a user
 #[poise::command(slash_command,ephemeral, check = check)]
+#[allow(clippy::too_many_arguments)]
 pub async fn undeposit(
     ctx: Context<'_>,
     #[description = "The depositing user"]
@@ -84,7 +90,11 @@ pub async fn undeposit(
     #[description = "The amount of that asset to be removed"]
     count: u64,
     #[description = "The amount of that asset to be removed, again"]
-    count_again: u64
+    count_again: u64,
+    #[description = "A note to keep with the removal"]
+    note: Option<String>,
+    #[description = "Where the removal can be checked, e.g. chest coordinates or a screenshot"]
+    reference: Option<String>
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     if asset != asset_again || count != count_again {
@@ -99,7 +109,7 @@ pub async fn undeposit(
     let player = player_id(&player);
     let banker = player_id(ctx.author());
     let response = format!("Deposited {count} {asset} for {player}.");
-    ctx.data().apply(Action::Undeposit { player, asset, count, banker }).await?;
+    ctx.data().apply(Action::Undeposit { player, asset, count, banker, note, reference }).await?;
     ctx.reply(response).await?;
     Ok(())
 }
@@ -118,7 +128,7 @@ pub async fn reserve(
     let response = format!("Added {count} {asset} to the reserve.");
     // Do these back to back, but not necessarily consecutively
     {
-        ctx.data().apply(Action::Deposit { player: PlayerId::the_bank(), asset: asset.clone(), count, banker }).await?;
+        ctx.data().apply(Action::Deposit { player: PlayerId::the_bank(), asset: asset.clone(), count, banker, note: None, reference: None }).await?;
         ctx.data().apply(Action::Invest { player: PlayerId::the_bank(), asset, count }).await?;
     }
     ctx.reply(response).await?;
@@ -139,11 +149,25 @@ pub async fn complete(
     Ok(())
 }
 
+/// Mark a withdrawal as being gathered by you, so that no other banker does
+#[poise::command(slash_command,ephemeral, check = check)]
+pub async fn claim(
+    ctx: Context<'_>,
+    #[description = "The ID of the withdrawal to claim"]
+    withdrawal_id: u64
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let banker = player_id(ctx.author());
+    ctx.data().apply(Action::ClaimWithdrawal { target: withdrawal_id, banker }).await?;
+    ctx.reply("Withdrawal claimed.").await?;
+    Ok(())
+}
+
 /// Gets the next withdrawal that needs to be completed
 #[poise::command(slash_command,ephemeral, check = check)]
 pub async fn current(ctx: Context<'_>) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    let Some(current) = ctx.data().sync().await.get_next_withdrawal()
+    let Some(current) = ctx.data().sync().await.get_next_withdrawal_for(&player_id(ctx.author()))
     else {
         ctx.reply("No withdrawals left.").await?;
         return Ok(());
@@ -168,6 +192,30 @@ pub async fn authorise(ctx: Context<'_>,
     new_count: u64
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    ctx.data().apply(Action::AuthoriseRestricted { authorisee: player_id(&player), banker: player_id(ctx.author()), asset, new_count }).await?;
+    ctx.data().apply(Action::AuthoriseRestricted { authorisee: player_id(&player), banker: player_id(ctx.author()), asset, new_count, expiry: None }).await?;
+    Ok(())
+}
+
+/// Stop all new orders for an item
+#[poise::command(slash_command,ephemeral, check = check)]
+pub async fn halt(ctx: Context<'_>,
+    #[description = "The item to halt trading for"]
+    asset: String
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    ctx.data().apply(Action::HaltTrading { asset: asset.clone(), banker: player_id(ctx.author()) }).await?;
+    ctx.reply(format!("Trading of {asset} halted.")).await?;
+    Ok(())
+}
+
+/// Allow new orders for a halted item
+#[poise::command(slash_command,ephemeral, check = check)]
+pub async fn resume(ctx: Context<'_>,
+    #[description = "The item to resume trading for"]
+    asset: String
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    ctx.data().apply(Action::ResumeTrading { asset: asset.clone(), banker: player_id(ctx.author()) }).await?;
+    ctx.reply(format!("Trading of {asset} resumed.")).await?;
     Ok(())
 }
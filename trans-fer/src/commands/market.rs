@@ -0,0 +1,136 @@
+use chrono::DurationRound;
+use itertools::Itertools;
+use poise::{serenity_prelude::CreateEmbed, CreateReply};
+use tpex::{analytics::{Candle, CandleAggregator}, Coins};
+
+use super::{Context, Error};
+
+/// How many price levels are shown on each side of the book
+const DEPTH_LEVELS: usize = 10;
+/// How wide the bars on the depth chart can get
+const DEPTH_WIDTH: u64 = 20;
+/// The blocks used to draw sparklines, from lowest to highest
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// Commands that show what the market is doing
+#[poise::command(slash_command, ephemeral, subcommands("depth", "chart"))]
+pub async fn market(_ctx: Context<'_>) -> Result<(), Error> { panic!("market metacommand called!"); }
+
+/// Draw a bar for an amount, out of the biggest amount shown
+fn bar(amount: u64, max: u64) -> String {
+    let len = if max == 0 { 0 } else { (amount * DEPTH_WIDTH).div_ceil(max) };
+    "#".repeat(len as usize)
+}
+
+/// Draw one block per value, scaled between the smallest and largest
+fn sparkline(values: &[u64]) -> String {
+    let (Some(min), Some(max)) = (values.iter().min(), values.iter().max())
+    else { return String::new(); };
+    values.iter().map(|value| {
+        let idx = if max == min { 0 } else { ((value - min) * (SPARKS.len() as u64 - 1) / (max - min)) as usize };
+        SPARKS[idx]
+    }).collect()
+}
+
+/// Spread candles out into one per interval, carrying the price across intervals with no trading
+fn fill_gaps(candles: &[Candle], interval: chrono::TimeDelta, end: chrono::DateTime<chrono::Utc>, count: usize) -> Vec<(Coins, u64)> {
+    let mut ret = Vec::with_capacity(count);
+    let first = end - interval * (count as i32 - 1);
+    let mut candles = candles.iter().peekable();
+    let mut last_close = None;
+    // Start off from the last trade before the window
+    while let Some(candle) = candles.next_if(|candle| candle.start < first) {
+        last_close = Some(candle.close);
+    }
+    for i in 0..count {
+        let start = first + interval * i as i32;
+        match candles.next_if(|candle| candle.start == start) {
+            Some(candle) => {
+                last_close = Some(candle.close);
+                ret.push((candle.close, candle.volume));
+            },
+            None => if let Some(close) = last_close { ret.push((close, 0)); }
+        }
+    }
+    ret
+}
+
+/// Shows the order book for an item, with how much you could trade at each price
+#[poise::command(slash_command, ephemeral)]
+async fn depth(ctx: Context<'_>,
+    #[description = "The item you want to see the order book for"]
+    asset: String
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let (buy_levels, sell_levels) = ctx.data().sync().await.get_prices(&asset);
+    if buy_levels.is_empty() && sell_levels.is_empty() {
+        ctx.reply(format!("Nobody is trading {asset} right now")).await?;
+        return Ok(());
+    }
+
+    // Cumulative amounts, working out from the best price on each side
+    let asks = sell_levels.into_iter().take(DEPTH_LEVELS).scan(0, |total, (price, amount)| { *total += amount; Some((price, amount, *total)) }).collect_vec();
+    let bids = buy_levels.into_iter().rev().take(DEPTH_LEVELS).scan(0, |total, (price, amount)| { *total += amount; Some((price, amount, *total)) }).collect_vec();
+    let max = asks.iter().chain(bids.iter()).map(|(_, _, total)| *total).max().unwrap_or_default();
+
+    let line = |(price, amount, total): &(Coins, u64, u64)| format!("{:>12} {amount:>8} {}", price.to_string(), bar(*total, max));
+    let spread = match (asks.first(), bids.first()) {
+        (Some((ask, _, _)), Some((bid, _, _))) => format!("--- spread {} ---", ask.checked_sub(*bid)?),
+        _ => "---".to_owned()
+    };
+    // Asks go on top so that prices fall down the chart
+    let chart = asks.iter().rev().map(line)
+        .chain(std::iter::once(spread))
+        .chain(bids.iter().map(line))
+        .join("\n");
+    ctx.send(CreateReply::default()
+        .content(format!("Order book for {asset}:"))
+        .embed(CreateEmbed::new()
+            .description(format!("```\n{:>12} {:>8}\n{chart}\n```", "Coins per", "Amount"))
+        )
+    ).await?;
+    Ok(())
+}
+
+/// Charts the price and volume of an item
+#[poise::command(slash_command, ephemeral)]
+async fn chart(ctx: Context<'_>,
+    #[description = "The item you want to chart"]
+    asset: String,
+    #[description = "How many hours to chart (Defaults to 24)"]
+    #[min = 2]
+    #[max = 168]
+    hours: Option<u16>
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let hours = hours.unwrap_or(24) as usize;
+    let interval = chrono::TimeDelta::hours(1);
+    let now = chrono::Utc::now();
+
+    // The mirror doesn't keep candles, so build them from the whole log
+    let mut candles = CandleAggregator::new(interval)?;
+    let log = ctx.data().remote.get_state(0).await?;
+    tpex::State::new().replay_with(&mut log.as_slice(), |time, _, outcome| candles.observe(time, outcome)).await?;
+    let points = fill_gaps(&candles.get_candles(&asset), interval, now.duration_trunc(interval)?, hours);
+    let stats = ctx.data().sync().await.get_market_stats(&asset, now);
+
+    if points.is_empty() {
+        ctx.reply(format!("{asset} has never been traded")).await?;
+        return Ok(());
+    }
+    let (prices, volumes): (Vec<u64>, Vec<u64>) = points.iter().map(|(price, volume)| (price.millicoins(), *volume)).unzip();
+    let (low, high) = points.iter().map(|(price, _)| *price).minmax().into_option().expect("No points to chart");
+    let or_dash = |coins: Option<Coins>| coins.map(|x| x.to_string()).unwrap_or("-".to_owned());
+    ctx.send(CreateReply::default()
+        .content(format!("{asset} over the last {hours} hours:"))
+        .embed(CreateEmbed::new()
+            .description(format!("```\nPrice  {}\nVolume {}\n```\nPrice ranged from {low} to {high}", sparkline(&prices), sparkline(&volumes)))
+            .field("Last", or_dash(stats.last_price), true)
+            .field("24h high", or_dash(stats.high), true)
+            .field("24h low", or_dash(stats.low), true)
+            .field("24h volume", stats.volume.to_string(), true)
+            .field("24h VWAP", or_dash(stats.vwap), true)
+        )
+    ).await?;
+    Ok(())
+}
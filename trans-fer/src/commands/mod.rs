@@ -2,11 +2,20 @@ mod withdraw;
 mod order;
 mod banker;
 mod token;
+mod market;
+mod alert;
+mod proposal;
+
+use std::io::Write;
 
 use tpex::{AssetId, Auditable, Coins, PlayerId};
 use poise::serenity_prelude::{self as serenity, CreateEmbed};
 use itertools::Itertools;
 
+pub use alert::watch_alerts;
+pub use proposal::watch_proposals;
+
+#[allow(dead_code)]
 #[derive(Debug, PartialEq, Clone, Default)]
 #[derive(sqlx::FromRow)]
 pub struct AutoConversion {
@@ -16,9 +25,14 @@ pub struct AutoConversion {
     pub scale: u64
 }
 
+/// How long to wait before following the trade log again after the server turns us away
+const FOLLOW_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
 pub struct Data {
     pub state: tpex_api::Mirrored,
-    // pub db: Database
+    pub db: crate::db::Database,
+    /// Where new proposals are posted for bankers to vote on, if anywhere
+    pub proposal_channel: Option<serenity::ChannelId>
 }
 impl std::ops::Deref for Data {
     type Target = tpex_api::Mirrored;
@@ -29,6 +43,26 @@ impl std::ops::Deref for Data {
 pub(crate) type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, std::sync::Arc<Data>, Error>;
 
+/// Keep the mirror following the trade log, so that the watchers hear about actions made outside the bot
+///
+/// This runs for as long as the bot does
+pub async fn follow(data: std::sync::Arc<Data>) {
+    loop {
+        if let Err(err) = data.follow().await {
+            let _ = writeln!(std::io::stderr(), "Could not follow the trade log: {err}");
+        }
+        tokio::time::sleep(FOLLOW_RETRY_DELAY).await;
+    }
+}
+
+/// Handle anything that isn't a command, such as presses of buttons that outlive their command
+pub async fn handle_event(ctx: &serenity::Context, event: &serenity::FullEvent, data: &Data) -> Result<(), Error> {
+    if let serenity::FullEvent::InteractionCreate { interaction: serenity::Interaction::Component(mci) } = event {
+        proposal::handle_vote(ctx, data, mci).await?;
+    }
+    Ok(())
+}
+
 fn player_id(user: &serenity::User) -> PlayerId {
     #[allow(deprecated)]
     PlayerId::evil_constructor(user.id.to_string())
@@ -205,6 +239,8 @@ pub fn get_commands() -> Vec<poise::Command<std::sync::Arc<Data>, Error>> {
         withdraw::withdraw(),
         order::order(),
         banker::banker(),
-        token::token()
+        token::token(),
+        market::market(),
+        alert::alert()
     ]
 }
@@ -2,13 +2,19 @@ use itertools::Itertools;
 use poise::{serenity_prelude::{self as serenity, CreateEmbed, CreateInteractionResponseMessage}, CreateReply};
 
 use crate::commands::player_id;
-use tpex::{Action, Coins};
+use tpex::{Action, ApplyOutcome, Coins};
 
 use super::{Context, Error};
 // Commands that handle orders
 #[poise::command(slash_command, ephemeral, subcommands("buy", "sell", "pending", "price", "cancel", "list"))]
 pub async fn order(_ctx: Context<'_>) -> Result<(), Error> { panic!("order metacommand called!"); }
 
+/// Summarise what happened to a freshly placed order
+fn describe_outcome(outcome: &ApplyOutcome) -> String {
+    let matched: u64 = outcome.fills.iter().map(|fill| fill.count).sum();
+    format!("{matched} matched instantly, {} left on the order book", outcome.amount_rested)
+}
+
 /// Lists all the items being sold and bought
 #[poise::command(slash_command, ephemeral)]
 async fn list(ctx: Context<'_>) -> Result<(), Error> {
@@ -61,7 +67,9 @@ async fn buy(ctx: Context<'_>,
     #[description = "The amount you want to order"]
     amount: u64,
     #[description = "The price you want to pay per item"]
-    coins_per: String
+    coins_per: String,
+    #[description = "Only show this many on the order book at a time (Defaults to all of them)"]
+    display_count: Option<u64>
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     let coins_per: Coins = coins_per.parse()?;
@@ -123,11 +131,11 @@ async fn buy(ctx: Context<'_>,
             },
             x if x == &buy_id => {
                 // Place the order
-                match ctx.data().apply(Action::BuyOrder { player: player_id(ctx.author()), asset: item, count: amount, coins_per }).await {
-                    Ok(id) => {
+                match ctx.data().apply(Action::BuyOrder { player: player_id(ctx.author()), asset: item, count: amount, coins_per, display_count }).await {
+                    Ok(outcome) => {
                         mci.create_response(ctx, serenity::CreateInteractionResponse::UpdateMessage(CreateInteractionResponseMessage::new()
                             .components(Vec::new())
-                            .content(format!("Your buy order of the following (ID no. {id}) has been sent:"))
+                            .content(format!("Your buy order of the following (ID no. {}) has been sent ({}):", outcome.id, describe_outcome(&outcome)))
                             .ephemeral(true)
                         )).await?;
                     },
@@ -153,7 +161,9 @@ async fn sell(ctx: Context<'_>,
     #[description = "The amount you want to order"]
     amount: u64,
     #[description = "The Coin(s) you want to get per item"]
-    coins_per: String
+    coins_per: String,
+    #[description = "Only show this many on the order book at a time (Defaults to all of them)"]
+    display_count: Option<u64>
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     let coins_per: Coins = coins_per.parse()?;
@@ -216,11 +226,11 @@ async fn sell(ctx: Context<'_>,
             },
             x if x == &sell_id => {
                 // Place the order
-                match ctx.data().apply(Action::SellOrder { player: player_id(ctx.author()), asset: item, count: amount, coins_per }).await {
-                    Ok(id) => {
+                match ctx.data().apply(Action::SellOrder { player: player_id(ctx.author()), asset: item, count: amount, coins_per, display_count }).await {
+                    Ok(outcome) => {
                         mci.create_response(ctx, serenity::CreateInteractionResponse::UpdateMessage(CreateInteractionResponseMessage::new()
                             .components(Vec::new())
-                            .content(format!("Your sell order of the following (ID no. {id}) has been sent:"))
+                            .content(format!("Your sell order of the following (ID no. {}) has been sent ({}):", outcome.id, describe_outcome(&outcome)))
                             .ephemeral(true)
                         )).await?;
                     },
@@ -340,7 +350,8 @@ async fn pending(ctx: Context<'_>) -> Result<(), Error> {
                 .field("ID", order.id.to_string(), true)
                 .field("Type", format!("{}", order.order_type), true)
                 .field("Item", order.asset.clone(), true)
-                .field("Remaining", order.amount_remaining.to_string(), true)
+                .field("Remaining", order.amount_total().to_string(), true)
+                .field("Hidden", order.amount_hidden.to_string(), true)
                 .field("Coins per item", order.coins_per.to_string(), true)
             )
             .components(vec![components.clone()])
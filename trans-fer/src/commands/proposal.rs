@@ -0,0 +1,150 @@
+use std::io::Write;
+
+use poise::serenity_prelude::{self as serenity, CreateEmbed};
+use tpex::{Action, ApplyOutcome};
+
+use crate::commands::{player_id, user_id};
+use super::{Data, Error};
+
+/// Proposed actions are cut down to this many characters, to fit in an embed field
+const MAX_ACTION_LEN: usize = 1000;
+
+/// Mention a player if they're a discord user, or just name them otherwise
+fn mention(player: &tpex::PlayerId) -> String {
+    #[allow(deprecated)]
+    user_id(player).map(|user| format!("<@{user}>")).unwrap_or_else(|| player.evil_deref().clone())
+}
+
+/// The buttons a banker presses to vote on a proposal
+fn vote_buttons(proposal_id: u64) -> Vec<serenity::CreateActionRow> {
+    vec![
+        serenity::CreateActionRow::Buttons(vec![
+            serenity::CreateButton::new(format!("proposal_agree_{proposal_id}"))
+                .label("Agree")
+                .style(serenity::ButtonStyle::Success),
+            serenity::CreateButton::new(format!("proposal_disagree_{proposal_id}"))
+                .label("Disagree")
+                .style(serenity::ButtonStyle::Danger)
+        ])
+    ]
+}
+
+/// Post a new proposal to the proposal channel, for bankers to vote on
+async fn post_proposal(data: &Data, http: &serenity::Http, channel: serenity::ChannelId, proposal_id: u64) -> Result<(), Error> {
+    // It could have been settled before we got to it
+    let Ok(proposal) = data.sync().await.get_proposal(proposal_id)
+    else { return Ok(()); };
+
+    let mut action = serde_json::to_string_pretty(&proposal.action)?;
+    if action.len() > MAX_ACTION_LEN {
+        let cut = (0..=MAX_ACTION_LEN).rev().find(|i| action.is_char_boundary(*i)).unwrap_or_default();
+        action.truncate(cut);
+        action.push_str("\n...");
+    }
+    let mut embed = CreateEmbed::new()
+        .title(proposal.title.unwrap_or_else(|| format!("Proposal no. {proposal_id}")))
+        .field("ID", proposal_id.to_string(), true)
+        .field("Proposer", mention(&proposal.proposer), true)
+        .field("Proposed", format!("<t:{}:R>", proposal.created.timestamp()), true)
+        .field("Action", format!("```json\n{action}\n```"), false);
+    if let Some(description) = proposal.description {
+        embed = embed.description(description);
+    }
+    let message = channel.send_message(http, serenity::CreateMessage::new()
+        .content("Waiting for another banker to agree (0/1)")
+        .embed(embed)
+        .components(vote_buttons(proposal_id))
+    ).await?;
+    data.db.add_proposal_message(proposal_id, channel, message.id).await?;
+    Ok(())
+}
+
+/// Say how a posted proposal was settled, taking away its buttons
+async fn settle_proposal(data: &Data, http: &serenity::Http, proposal_id: u64, status: String) -> Result<(), Error> {
+    let Some(posted) = data.db.remove_proposal_message(proposal_id).await?
+    else { return Ok(()); };
+    posted.channel.edit_message(http, posted.message, serenity::EditMessage::new()
+        .content(status)
+        .components(Vec::new())
+    ).await?;
+    Ok(())
+}
+
+/// Update the proposal channel for an applied action, if it had anything to do with proposals
+async fn check_proposals(data: &Data, http: &serenity::Http, channel: serenity::ChannelId, action: &Action, outcome: &ApplyOutcome) -> Result<(), Error> {
+    match action {
+        // Proposals take the id of the action that made them
+        Action::Propose { .. } => post_proposal(data, http, channel, outcome.id).await,
+        Action::Agree { proposal_id, banker } =>
+            settle_proposal(data, http, *proposal_id, format!("Agreed to by {} (1/1), so it has gone ahead", mention(banker))).await,
+        Action::Disagree { proposal_id, banker } =>
+            settle_proposal(data, http, *proposal_id, format!("Disagreed with by {}, so it has been thrown away", mention(banker))).await,
+        Action::RetractProposal { proposal_id, .. } =>
+            settle_proposal(data, http, *proposal_id, "Retracted by the proposer".to_owned()).await,
+        Action::PruneProposals { .. } => {
+            // Pruning doesn't say which proposals went, so look for the ones that are gone
+            let open = data.sync().await.get_proposals();
+            for posted in data.db.get_proposal_messages().await? {
+                if !open.contains_key(&posted.proposal_id) {
+                    settle_proposal(data, http, posted.proposal_id, "Expired before another banker agreed".to_owned()).await?;
+                }
+            }
+            Ok(())
+        },
+        _ => Ok(())
+    }
+}
+
+/// Post proposals to the proposal channel as they are made, and keep their messages up to date as they are voted on
+///
+/// This runs for as long as the bot does
+pub async fn watch_proposals(data: std::sync::Arc<Data>, http: std::sync::Arc<serenity::Http>, channel: serenity::ChannelId) {
+    let mut applied = {
+        // Catch up first, so that old proposals aren't posted again
+        let _state = data.sync().await;
+        data.stream()
+    };
+    loop {
+        let (action, outcome) = match applied.recv().await {
+            Ok(applied) => applied,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                let _ = writeln!(std::io::stderr(), "Fell behind posting proposals, skipping {missed} actions");
+                continue;
+            },
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return
+        };
+        if let Err(err) = check_proposals(&data, &http, channel, &action, &outcome).await {
+            let _ = writeln!(std::io::stderr(), "Could not update proposal channel: {err}");
+        }
+    }
+}
+
+/// Vote on a proposal when a banker presses one of its buttons
+///
+/// Returns false if the button wasn't a vote
+pub async fn handle_vote(ctx: &serenity::Context, data: &Data, mci: &serenity::ComponentInteraction) -> Result<bool, Error> {
+    let custom_id = mci.data.custom_id.as_str();
+    let (agree, proposal_id) =
+        if let Some(id) = custom_id.strip_prefix("proposal_agree_") { (true, id) }
+        else if let Some(id) = custom_id.strip_prefix("proposal_disagree_") { (false, id) }
+        else { return Ok(false); };
+    let Ok(proposal_id) = proposal_id.parse()
+    else { return Ok(false); };
+
+    let banker = player_id(&mci.user);
+    let reply = if !data.sync().await.is_banker(&banker) {
+        "Only bankers can vote on proposals!".to_owned()
+    }
+    else {
+        let action = if agree { Action::Agree { proposal_id, banker } } else { Action::Disagree { proposal_id, banker } };
+        match data.apply(action).await {
+            Ok(_) => format!("You have {} proposal no. {proposal_id}", if agree { "agreed to" } else { "disagreed with" }),
+            Err(e) => format!("Vote failed: {e}")
+        }
+    };
+    mci.create_response(ctx, serenity::CreateInteractionResponse::Message(serenity::CreateInteractionResponseMessage::new()
+        .content(reply)
+        .ephemeral(true)
+    )).await?;
+    Ok(true)
+}
@@ -96,7 +96,7 @@ async fn create(ctx: Context<'_>,
             },
             x if x == &confirm_id => {
                 // Create the token
-                let args = TokenPostArgs{level: level.into(), user: player_id(ctx.author())};
+                let args = TokenPostArgs{level: level.into(), user: player_id(ctx.author()), scopes: None, ttl_secs: None};
 
                 match ctx.data().remote.create_token(&args).await {
                     Ok(token) => {
@@ -126,7 +126,7 @@ async fn delete(ctx: Context<'_>,
     #[description = "The value of the token you want to delete"]
     token: String
 ) -> Result<(), Error> {
-    match ctx.data().remote.delete_token(&tpex_api::TokenDeleteArgs { token: Some(token.parse()?) }).await {
+    match ctx.data().remote.delete_token(&tpex_api::TokenDeleteArgs { token: Some(token.parse()?), id: None }).await {
         Ok(()) => ctx.reply("Token deleted").await,
         Err(e) => ctx.reply(format!("Unable to delete token: {e}")).await,
     }?;
@@ -6,6 +6,7 @@ use poise::{serenity_prelude::{self as serenity, CreateInteractionResponseMessag
 
 use super::{Context, Error};
 
+#[allow(dead_code)]
 #[derive(Debug, poise::Modal)]
 struct SetItemCountModal {
     item: String,
@@ -244,10 +245,10 @@ pub async fn new(ctx: Context<'_>) -> Result<(), Error> {
 
                     // Try to withdraw the items
                     match data.apply(Action::WithdrawalRequested { player, assets: basket.clone() }).await {
-                        Ok(withdraw_id) => {
+                        Ok(outcome) => {
                             check_modal.interaction.create_response(serenity_ctx.http, serenity::CreateInteractionResponse::UpdateMessage(CreateInteractionResponseMessage::new()
                                 .components(Vec::new())
-                                .content(format!("Your withdrawal of the following (ID no. {withdraw_id}) has been accepted:"))
+                                .content(format!("Your withdrawal of the following (ID no. {}) has been accepted, costing {}:", outcome.id, outcome.fees_paid))
                                 .ephemeral(true)
                             )).await?;
                         },
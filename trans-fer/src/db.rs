@@ -0,0 +1,154 @@
+use std::str::FromStr;
+
+use poise::serenity_prelude as serenity;
+use sqlx::Row;
+use tpex::{AssetId, Coins};
+
+/// Which way the price has to move for an alert to go off
+#[derive(Debug, PartialEq, Eq, Clone, Copy, poise::ChoiceParameter)]
+pub enum AlertDirection {
+    #[name = "above"]
+    Above,
+    #[name = "below"]
+    Below,
+}
+impl AlertDirection {
+    fn as_str(self) -> &'static str {
+        match self {
+            AlertDirection::Above => "above",
+            AlertDirection::Below => "below",
+        }
+    }
+    /// Returns true if a trade at the given price sets off an alert at the target
+    pub fn triggered_by(self, coins_per: Coins, target: Coins) -> bool {
+        match self {
+            AlertDirection::Above => coins_per >= target,
+            AlertDirection::Below => coins_per <= target,
+        }
+    }
+}
+impl std::fmt::Display for AlertDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(self.as_str()) }
+}
+impl FromStr for AlertDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "above" => Ok(AlertDirection::Above),
+            "below" => Ok(AlertDirection::Below),
+            _ => Err(format!("Unknown alert direction {s}"))
+        }
+    }
+}
+
+/// A user wanting to be told when an item trades past a price
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PriceAlert {
+    pub id: i64,
+    pub user: serenity::UserId,
+    pub asset: AssetId,
+    pub direction: AlertDirection,
+    pub coins_per: Coins
+}
+
+/// Where the bot posted a proposal for bankers to vote on
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ProposalMessage {
+    pub proposal_id: u64,
+    pub channel: serenity::ChannelId,
+    pub message: serenity::MessageId
+}
+
+/// The bot's own records, which TPEx doesn't need to know about
+///
+/// DATABASE_URL points at the API's database, so query! can't check these and they are checked at runtime instead
+pub struct Database {
+    pool: sqlx::SqlitePool
+}
+impl Database {
+    pub async fn new(url: &str) -> sqlx::Result<Database> {
+        let opt = sqlx::sqlite::SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let ret = Database {
+            pool: sqlx::SqlitePool::connect_with(opt).await?
+        };
+
+        sqlx::migrate!("../migrations/trans-fer").run(&ret.pool).await?;
+
+        Ok(ret)
+    }
+    pub async fn add_alert(&self, user: serenity::UserId, asset: &AssetId, direction: AlertDirection, coins_per: Coins) -> sqlx::Result<i64> {
+        let millicoins = i64::try_from(coins_per.millicoins()).map_err(|_| sqlx::Error::Protocol(format!("{coins_per} is too much to store")))?;
+        let id = sqlx::query("INSERT INTO price_alerts(user_id, asset, direction, millicoins) VALUES (?, ?, ?, ?)")
+        .bind(user.to_string())
+        .bind(asset)
+        .bind(direction.as_str())
+        .bind(millicoins)
+        .execute(&self.pool).await?
+        .last_insert_rowid();
+        Ok(id)
+    }
+    /// List a user's alerts, oldest first
+    pub async fn get_alerts(&self, user: serenity::UserId) -> sqlx::Result<Vec<PriceAlert>> {
+        let rows = sqlx::query("SELECT id, user_id, asset, direction, millicoins FROM price_alerts WHERE user_id = ? ORDER BY id")
+        .bind(user.to_string())
+        .fetch_all(&self.pool).await?;
+        rows.iter().map(Self::read_alert).collect()
+    }
+    /// List every alert on an item, oldest first
+    pub async fn get_asset_alerts(&self, asset: &AssetId) -> sqlx::Result<Vec<PriceAlert>> {
+        let rows = sqlx::query("SELECT id, user_id, asset, direction, millicoins FROM price_alerts WHERE asset = ? ORDER BY id")
+        .bind(asset)
+        .fetch_all(&self.pool).await?;
+        rows.iter().map(Self::read_alert).collect()
+    }
+    /// Remove one of a user's alerts, returning false if they had no such alert
+    pub async fn remove_alert(&self, user: serenity::UserId, id: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query("DELETE FROM price_alerts WHERE id = ? AND user_id = ?")
+        .bind(id)
+        .bind(user.to_string())
+        .execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+    /// Remember where a proposal was posted
+    pub async fn add_proposal_message(&self, proposal_id: u64, channel: serenity::ChannelId, message: serenity::MessageId) -> sqlx::Result<()> {
+        sqlx::query("INSERT INTO proposal_messages(proposal_id, channel_id, message_id) VALUES (?, ?, ?)")
+        .bind(proposal_id as i64)
+        .bind(channel.to_string())
+        .bind(message.to_string())
+        .execute(&self.pool).await?;
+        Ok(())
+    }
+    /// List the proposals that have been posted and not yet settled
+    pub async fn get_proposal_messages(&self) -> sqlx::Result<Vec<ProposalMessage>> {
+        let rows = sqlx::query("SELECT proposal_id, channel_id, message_id FROM proposal_messages ORDER BY proposal_id")
+        .fetch_all(&self.pool).await?;
+        rows.iter().map(Self::read_proposal_message).collect()
+    }
+    /// Forget where a proposal was posted, returning where it was if it had been
+    pub async fn remove_proposal_message(&self, proposal_id: u64) -> sqlx::Result<Option<ProposalMessage>> {
+        let row = sqlx::query("DELETE FROM proposal_messages WHERE proposal_id = ? RETURNING proposal_id, channel_id, message_id")
+        .bind(proposal_id as i64)
+        .fetch_optional(&self.pool).await?;
+        row.as_ref().map(Self::read_proposal_message).transpose()
+    }
+    fn read_proposal_message(row: &sqlx::sqlite::SqliteRow) -> sqlx::Result<ProposalMessage> {
+        Ok(ProposalMessage {
+            proposal_id: row.try_get::<i64, _>("proposal_id")?.try_into().map_err(decode)?,
+            channel: serenity::ChannelId::new(row.try_get::<String, _>("channel_id")?.parse().map_err(decode)?),
+            message: serenity::MessageId::new(row.try_get::<String, _>("message_id")?.parse().map_err(decode)?)
+        })
+    }
+    fn read_alert(row: &sqlx::sqlite::SqliteRow) -> sqlx::Result<PriceAlert> {
+        Ok(PriceAlert {
+            id: row.try_get("id")?,
+            user: serenity::UserId::new(row.try_get::<String, _>("user_id")?.parse().map_err(decode)?),
+            asset: row.try_get("asset")?,
+            direction: row.try_get::<String, _>("direction")?.parse().map_err(decode)?,
+            coins_per: Coins::from_millicoins(row.try_get::<i64, _>("millicoins")?.try_into().map_err(decode)?)
+        })
+    }
+}
+
+/// Complain about a column that doesn't hold what we put in it
+fn decode(err: impl std::fmt::Display) -> sqlx::Error { sqlx::Error::Decode(err.to_string().into()) }
@@ -4,6 +4,7 @@ use tokio::io::AsyncReadExt;
 use std::io::Write;
 
 mod commands;
+mod db;
 
 
 #[derive(clap::Parser)]
@@ -11,6 +12,9 @@ struct Args {
     endpoint: String,
     db: String,
     assets: Option<std::path::PathBuf>,
+    /// The channel to post proposals in for bankers to vote on
+    #[arg(long)]
+    proposal_channel: Option<u64>,
 }
 
 #[tokio::main]
@@ -24,9 +28,6 @@ async fn main() {
     let args = Args::parse();
     // The code here just starts the discord bot, as we respond to commands
 
-    // Database setup
-    // let mut db = self::db::DatabaseConnection::new(std::env::var("DATABASE_URL").expect("missing DATABASE_URL")).await.expect("Failed to init database");
-
     let remote_url = args.endpoint.parse().expect("Could not parse remote url");
 
     let remote_token: tpex_api::Token = std::env::var("TPEX_TOKEN").expect("Missing TPEX_TOKEN environment variable").parse().expect("Could not parse TPEX_TOKEN");
@@ -38,7 +39,8 @@ async fn main() {
     let mut client = {
         let data = commands::Data{
             state: tpex_api::Mirrored::new(remote_url, remote_token),
-            // db: commands::Database::new(&args.db).await
+            db: db::Database::new(&args.db).await.expect("Could not connect to DB"),
+            proposal_channel: args.proposal_channel.map(serenity::ChannelId::new)
         };
         if let Some(asset_path) = args.assets {
             let mut assets = String::new();
@@ -51,12 +53,19 @@ async fn main() {
         let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
             commands: commands::get_commands(),
+            event_handler: |ctx, event, _framework, data| Box::pin(commands::handle_event(ctx, event, data)),
             ..Default::default()
         })
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-                Ok(std::sync::Arc::new(data))
+                let data = std::sync::Arc::new(data);
+                tokio::spawn(commands::follow(data.clone()));
+                tokio::spawn(commands::watch_alerts(data.clone(), ctx.http.clone()));
+                if let Some(channel) = data.proposal_channel {
+                    tokio::spawn(commands::watch_proposals(data.clone(), ctx.http.clone(), channel));
+                }
+                Ok(data)
             })
         })
         .build();